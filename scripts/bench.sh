@@ -0,0 +1,19 @@
+#!/bin/sh
+# Time every program in benches/ on both engines.
+# Usage: scripts/bench.sh [path to velvet binary]
+set -e
+BIN=$(realpath "${1:-target/release/velvet}")
+ROOT=$(pwd)
+WORK=$(mktemp -d)
+trap 'rm -rf "$WORK"' EXIT
+echo '{"name": "bench", "version": "0.1.0"}' > "$WORK/velvet.json"
+cd "$WORK"
+for bench in "$ROOT"/benches/*.velvet; do
+    cp "$bench" main.velvet
+    for engine in tree vm; do
+        start=$(date +%s.%N)
+        "$BIN" start --engine=$engine > /dev/null
+        end=$(date +%s.%N)
+        awk -v name="$(basename "$bench")" -v engine=$engine -v start="$start" -v end="$end" 'BEGIN { printf "%-20s %-5s %.3fs\n", name, engine, end - start }'
+    done
+done
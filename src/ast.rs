@@ -1,31 +1,88 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
     Say(Expr),
     Val(String, Option<Expr>, Option<String>),
+    Let(String, Option<Expr>, Option<String>),
     Const(String, Expr, Option<String>),
-    Fun(String, Vec<(String, String)>, Option<String>, Vec<Statement>),
+    Fun(String, Vec<Param>, Option<String>, Vec<Statement>),
+    Type(String, Vec<Param>),
+    Trait(String, Vec<(String, Vec<Param>, Option<String>)>, Vec<Statement>),
+    Impl(String, Option<String>, Vec<Statement>),
     If(Expr, Vec<Statement>, Option<Vec<Statement>>),
-    For(String, Expr, Vec<Statement>),
+    For(Target, Expr, Vec<Statement>),
     While(Expr, Vec<Statement>),
     Break,
     Continue,
-    Try(String, Vec<Statement>, Vec<Statement>),
-    Match(Expr, Vec<(String, Vec<Statement>)>),
+    Try(Vec<Statement>, Vec<(String, Option<String>, Vec<Statement>)>, Option<Vec<Statement>>),
+    Throw(Option<Expr>, usize),
+    Match(Expr, Vec<(Pattern, Vec<Statement>)>),
+    Unpack(Target, Expr, bool),
+    Assign(Target, Expr),
     Expr(Expr),
     Return(Expr),
+    Yield(Expr),
     Import(String, String),
     Test(String, Vec<Statement>),
+    Attributed(Vec<Attribute>, Box<Statement>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     String(String),
     Number(f64),
     Bool(bool),
     Ident(String),
+    Local(usize, String),
     Binary(Box<Expr>, String, Box<Expr>),
     Unary(String, Box<Expr>),
     Call(String, Vec<Expr>),
+    Named(String, Box<Expr>),
+    MethodCall(Box<Expr>, String, Vec<Expr>),
     List(Vec<Expr>),
-    Index(String, Box<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    ListComp(Box<Expr>, Vec<Clause>),
+    MapComp(Box<Expr>, Box<Expr>, Vec<Clause>),
+    Tuple(Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Field(Box<Expr>, String),
+    SafeField(Box<Expr>, String),
+    Propagate(Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    Match(Box<Expr>, Vec<(Pattern, Expr)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub args: Vec<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub type_anno: String,
+    pub default: Option<Expr>,
+    pub variadic: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Clause {
+    For(Target, Expr),
+    If(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Name(String),
+    Tuple(Vec<Target>),
+    List(Vec<Target>, Option<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Wildcard,
+    Literal(String),
+    Bind(String),
+    Variant(String, Option<Box<Pattern>>),
 }
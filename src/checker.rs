@@ -0,0 +1,838 @@
+use crate::ast::*;
+use crate::methods;
+use crate::resolver;
+use crate::runtime::BUILTIN_TYPES;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Const,
+    Val,
+    Let,
+}
+
+#[derive(Clone, Default)]
+struct Scope {
+    kinds: HashMap<String, Kind>,
+    initialised: HashSet<String>,
+    unassigned: HashSet<String>,
+    optional: HashSet<String>,
+    checked: HashSet<String>,
+    locals: HashSet<String>,
+}
+
+impl Scope {
+    fn declare(&mut self, name: &str, kind: Kind, assigned: bool, type_anno: Option<&str>) {
+        let optional = type_anno.is_some_and(|t| t.ends_with('?'));
+        self.kinds.insert(name.to_string(), kind);
+        self.locals.insert(name.to_string());
+        if assigned || optional {
+            self.unassigned.remove(name);
+            self.initialised.insert(name.to_string());
+        } else {
+            self.unassigned.insert(name.to_string());
+            self.initialised.remove(name);
+        }
+        if optional {
+            self.optional.insert(name.to_string());
+        } else {
+            self.optional.remove(name);
+        }
+        self.checked.remove(name);
+    }
+
+    fn narrowed(&self, names: &[String]) -> Scope {
+        let mut scope = self.clone();
+        scope.checked.extend(names.iter().cloned());
+        scope
+    }
+
+    fn merge(paths: Vec<Scope>, fallback: &Scope) -> Scope {
+        let mut paths = paths.into_iter();
+        let mut merged = match paths.next() {
+            Some(first) => first,
+            None => return fallback.clone(),
+        };
+        for path in paths {
+            merged.kinds.extend(path.kinds);
+            merged.initialised.extend(path.initialised);
+            merged.unassigned.extend(path.unassigned);
+            merged.optional.extend(path.optional);
+            merged.checked.retain(|name| path.checked.contains(name));
+        }
+        merged
+    }
+}
+
+struct Checker {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+    frames: Vec<Vec<String>>,
+    deprecated: HashMap<String, Option<String>>,
+    functions: Vec<String>,
+    returns: HashMap<String, usize>,
+    signatures: HashMap<String, Vec<Param>>,
+    types: HashSet<String>,
+    traits: HashMap<String, Vec<String>>,
+    impls: HashSet<(String, String)>,
+}
+
+// Errors, then warnings
+pub fn check(statements: &[Statement]) -> (Vec<String>, Vec<String>) {
+    let mut checker = Checker {
+        errors: Vec::new(),
+        warnings: Vec::new(),
+        frames: Vec::new(),
+        deprecated: HashMap::new(),
+        functions: Vec::new(),
+        returns: HashMap::new(),
+        signatures: HashMap::new(),
+        types: HashSet::new(),
+        traits: HashMap::new(),
+        impls: HashSet::new(),
+    };
+    for stmt in statements {
+        let Statement::Attributed(attributes, target) = stmt else {
+            continue;
+        };
+        let (Statement::Fun(name, ..) | Statement::Type(name, _) | Statement::Const(name, ..)) =
+            target.as_ref()
+        else {
+            continue;
+        };
+        if let Some(attribute) = attributes.iter().find(|a| a.name == "deprecated") {
+            let note = match attribute.args.first() {
+                Some(Expr::String(note)) => Some(note.clone()),
+                _ => None,
+            };
+            checker.deprecated.insert(name.clone(), note);
+        }
+    }
+    checker.check_block(statements, &mut Scope::default());
+    (checker.errors, checker.warnings)
+}
+
+impl Checker {
+    fn error(&mut self, message: String) {
+        match self.functions.last() {
+            Some(name) => self.errors.push(format!("in fun '{}': {}", name, message)),
+            None => self.errors.push(message),
+        }
+    }
+
+    fn warning(&mut self, message: String) {
+        match self.functions.last() {
+            Some(name) => self
+                .warnings
+                .push(format!("in fun '{}': {}", name, message)),
+            None => self.warnings.push(message),
+        }
+    }
+
+    // A function only sees its own locals and the globals, never those of a function around it
+    fn check_capture(&mut self, name: &str, scope: &Scope) {
+        let Some((own, outer)) = self.frames.split_last() else {
+            return;
+        };
+        if scope.locals.contains(name) || own.contains(&name.to_string()) {
+            return;
+        }
+        if let Some(depth) = outer
+            .iter()
+            .rposition(|names| names.contains(&name.to_string()))
+        {
+            let enclosing = self.functions[depth].clone();
+            self.error(format!(
+                "'{}' belongs to the enclosing function '{}'; nested functions cannot capture it, so pass it as an argument",
+                name, enclosing
+            ));
+        }
+    }
+
+    // Every use is reported, while running a program only warns at the first
+    fn check_deprecated(&mut self, name: &str, scope: &Scope) {
+        let shadowed = !self.functions.is_empty() && scope.locals.contains(name);
+        match self.deprecated.get(name).cloned() {
+            Some(Some(note)) if !shadowed => {
+                self.warning(format!("'{}' is deprecated: {}", name, note))
+            }
+            Some(None) if !shadowed => self.warning(format!("'{}' is deprecated", name)),
+            _ => {}
+        }
+    }
+
+    fn bind(
+        &mut self,
+        scope: &mut Scope,
+        name: &str,
+        kind: Kind,
+        assigned: bool,
+        type_anno: Option<&str>,
+    ) {
+        if scope.kinds.get(name) == Some(&Kind::Const) && scope.locals.contains(name) {
+            return self.error(format!("Cannot redeclare constant '{}'", name));
+        }
+        scope.declare(name, kind, assigned, type_anno);
+    }
+
+    fn check_block(&mut self, stmts: &[Statement], scope: &mut Scope) -> bool {
+        for stmt in stmts {
+            if self.check_stmt(stmt, scope) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn check_branch(&mut self, stmts: &[Statement], mut scope: Scope, live: &mut Vec<Scope>) {
+        if !self.check_block(stmts, &mut scope) {
+            live.push(scope);
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &Statement, scope: &mut Scope) -> bool {
+        match stmt {
+            Statement::Say(expr) | Statement::Expr(expr) => self.check_expr(expr, scope, false),
+            Statement::Val(ident, expr, type_anno) | Statement::Let(ident, expr, type_anno) => {
+                if let Some(expr) = expr {
+                    self.check_expr(expr, scope, false);
+                    if let Some(type_anno) = type_anno {
+                        self.check_conforms(expr, type_anno);
+                    }
+                }
+                let kind = if matches!(stmt, Statement::Let(..)) {
+                    Kind::Let
+                } else {
+                    Kind::Val
+                };
+                self.bind(scope, ident, kind, expr.is_some(), type_anno.as_deref());
+            }
+            Statement::Const(ident, expr, type_anno) => {
+                self.check_expr(expr, scope, false);
+                if !is_constant(expr, scope) {
+                    self.error(format!(
+                        "Constant '{}' must be initialised with a compile-time constant expression",
+                        ident
+                    ));
+                }
+                self.bind(scope, ident, Kind::Const, true, type_anno.as_deref());
+            }
+            Statement::Unpack(target, expr, mutable) => {
+                self.check_expr(expr, scope, false);
+                self.check_arity(target, self.shape(expr));
+                let kind = if *mutable { Kind::Let } else { Kind::Val };
+                for name in target_names(target) {
+                    self.bind(scope, &name, kind, true, None);
+                }
+            }
+            Statement::Assign(target, expr) => {
+                self.check_expr(expr, scope, false);
+                self.check_arity(target, self.shape(expr));
+                for ident in target_names(target) {
+                    match scope.kinds.get(&ident) {
+                        Some(Kind::Const) => self.error(format!("Cannot assign to constant '{}'", ident)),
+                        Some(Kind::Val) if scope.initialised.contains(&ident) => self.error(format!(
+                            "Cannot assign twice to immutable variable '{}' (declare it with 'let' to make it mutable)",
+                            ident
+                        )),
+                        _ => {}
+                    }
+                    scope.unassigned.remove(&ident);
+                    scope.checked.remove(&ident);
+                    scope.initialised.insert(ident);
+                }
+            }
+            Statement::Fun(name, params, _, body) => {
+                self.check_function(name, params, body, scope);
+                self.bind(scope, name, Kind::Val, true, None);
+            }
+            Statement::Type(name, fields) => {
+                for field in fields {
+                    if let Some(default) = &field.default {
+                        self.check_expr(default, scope, false);
+                    }
+                }
+                self.signatures.insert(name.clone(), fields.clone());
+                self.types.insert(name.clone());
+                self.bind(scope, name, Kind::Val, true, None);
+            }
+            Statement::Trait(name, signatures, defaults) => {
+                for default in defaults {
+                    if let Statement::Fun(method, params, _, body) = default {
+                        self.check_function(&format!("{}.{}", name, method), params, body, scope);
+                    }
+                }
+                self.traits.insert(
+                    name.clone(),
+                    signatures
+                        .iter()
+                        .map(|(method, _, _)| method.clone())
+                        .collect(),
+                );
+            }
+            Statement::Impl(type_name, trait_name, methods) => {
+                if !self.types.contains(type_name) && !BUILTIN_TYPES.contains(&type_name.as_str()) {
+                    self.error(format!("Unknown type '{}' in impl", type_name));
+                }
+                let mut names = HashSet::new();
+                for method in methods {
+                    if let Statement::Fun(method, params, _, body) = method {
+                        self.check_function(
+                            &format!("{}.{}", type_name, method),
+                            params,
+                            body,
+                            scope,
+                        );
+                        if let Some(arity) = methods::operator_arity(method) {
+                            if params.len() != arity || params[0].name != "self" {
+                                let expected = if arity == 1 {
+                                    "(self)"
+                                } else {
+                                    "(self, other)"
+                                };
+                                self.error(format!(
+                                    "Operator method '{}.{}' must take {}",
+                                    type_name, method, expected
+                                ));
+                            }
+                        }
+                        names.insert(method.clone());
+                    }
+                }
+                if let Some(trait_name) = trait_name {
+                    match self.traits.get(trait_name).cloned() {
+                        Some(required) => {
+                            for missing in required.iter().filter(|m| !names.contains(*m)) {
+                                self.error(format!(
+                                    "type {} does not implement trait {} (missing method '{}')",
+                                    type_name, trait_name, missing
+                                ));
+                            }
+                        }
+                        None => self.error(format!("Unknown trait '{}'", trait_name)),
+                    }
+                    self.impls.insert((type_name.clone(), trait_name.clone()));
+                }
+            }
+            Statement::If(condition, then_block, else_block) => {
+                self.check_expr(condition, scope, true);
+                let (then_names, else_names) = narrowing(condition);
+                let mut live = Vec::new();
+                self.check_branch(then_block, scope.narrowed(&then_names), &mut live);
+                match else_block {
+                    Some(else_block) => {
+                        self.check_branch(else_block, scope.narrowed(&else_names), &mut live)
+                    }
+                    None => live.push(scope.narrowed(&else_names)),
+                }
+                if live.is_empty() {
+                    return true;
+                }
+                *scope = Scope::merge(live, scope);
+            }
+            Statement::For(target, expr, body) => {
+                self.check_expr(expr, scope, true);
+                self.check_arity(target, self.element_shape(expr));
+                let mut inner = scope.clone();
+                for name in target_names(target) {
+                    self.bind(&mut inner, &name, Kind::Val, true, None);
+                }
+                self.check_block(body, &mut inner);
+                *scope = Scope::merge(vec![scope.clone(), inner], scope);
+            }
+            Statement::While(condition, body) => {
+                self.check_expr(condition, scope, true);
+                let mut inner = scope.narrowed(&narrowing(condition).0);
+                self.check_block(body, &mut inner);
+                *scope = Scope::merge(vec![scope.clone(), inner], scope);
+            }
+            Statement::Break | Statement::Continue => return true,
+            Statement::Try(try_block, catches, finally_block) => {
+                let mut live = Vec::new();
+                self.check_branch(try_block, scope.clone(), &mut live);
+                for (error_ident, _, catch_block) in catches {
+                    let mut inner = scope.clone();
+                    self.bind(&mut inner, error_ident, Kind::Val, true, None);
+                    self.check_branch(catch_block, inner, &mut live);
+                }
+                let diverges = live.is_empty();
+                *scope = Scope::merge(live, scope);
+                if let Some(finally_block) = finally_block {
+                    if self.check_block(finally_block, scope) {
+                        return true;
+                    }
+                }
+                return diverges;
+            }
+            Statement::Throw(expr, _) => {
+                if let Some(expr) = expr {
+                    self.check_expr(expr, scope, false);
+                }
+                return true;
+            }
+            Statement::Match(expr, branches) => {
+                self.check_expr(expr, scope, false);
+                let mut live = Vec::new();
+                let mut exhaustive = false;
+                for (pattern, statements) in branches {
+                    let mut inner = scope.clone();
+                    self.bind_pattern(pattern, &mut inner);
+                    exhaustive |= matches!(pattern, Pattern::Wildcard | Pattern::Bind(_));
+                    self.check_branch(statements, inner, &mut live);
+                }
+                if !exhaustive {
+                    live.push(scope.clone());
+                }
+                if live.is_empty() {
+                    return true;
+                }
+                *scope = Scope::merge(live, scope);
+            }
+            Statement::Return(expr) => {
+                self.check_expr(expr, scope, false);
+                return true;
+            }
+            Statement::Yield(expr) => {
+                if self.functions.is_empty() {
+                    self.error("'yield' outside of a function".to_string());
+                }
+                self.check_expr(expr, scope, false);
+            }
+            Statement::Import(_, _) => {}
+            Statement::Test(_, body) => {
+                self.check_block(body, &mut scope.clone());
+            }
+            Statement::Attributed(attributes, target) => {
+                if let Statement::Fun(name, params, _, _) = target.as_ref() {
+                    if attributes.iter().any(|a| a.name == "test") && !params.is_empty() {
+                        self.error(format!("Test function '{}' must not take parameters", name));
+                    }
+                }
+                return self.check_stmt(target, scope);
+            }
+        }
+        false
+    }
+
+    fn check_expr(&mut self, expr: &Expr, scope: &Scope, needs_value: bool) {
+        match expr {
+            Expr::String(_) | Expr::Number(_) | Expr::Bool(_) => {}
+            Expr::Ident(id) | Expr::Local(_, id) => {
+                self.check_deprecated(id, scope);
+                self.check_capture(id, scope);
+                self.check_ident(id, scope, needs_value);
+            }
+            Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+                self.check_expr(left, scope, true);
+                let (then_names, else_names) = narrowing(left);
+                let names = if op == "and" { then_names } else { else_names };
+                self.check_expr(right, &scope.narrowed(&names), true);
+            }
+            Expr::Binary(left, op, right) => {
+                let operands_need_value = !matches!(op.as_str(), "??" | "==" | "!=");
+                self.check_expr(left, scope, operands_need_value);
+                self.check_expr(right, scope, operands_need_value);
+            }
+            Expr::Unary(_, expr) => self.check_expr(expr, scope, true),
+            Expr::Call(name, args) => {
+                for arg in args {
+                    self.check_expr(arg, scope, false);
+                }
+                self.check_deprecated(name, scope);
+                self.check_capture(name, scope);
+                self.check_call(name, args);
+            }
+            Expr::MethodCall(target, _, args) => {
+                self.check_expr(target, scope, true);
+                for arg in args {
+                    self.check_expr(arg, scope, false);
+                }
+            }
+            Expr::Named(_, expr) => self.check_expr(expr, scope, false),
+            Expr::List(elements) | Expr::Tuple(elements) => {
+                for element in elements {
+                    self.check_expr(element, scope, false);
+                }
+            }
+            Expr::ListComp(element, clauses) => {
+                let inner = self.check_clauses(clauses, scope);
+                self.check_expr(element, &inner, false);
+            }
+            Expr::MapComp(key, value, clauses) => {
+                let inner = self.check_clauses(clauses, scope);
+                self.check_expr(key, &inner, true);
+                self.check_expr(value, &inner, false);
+            }
+            Expr::Map(entries) => {
+                for (key, value) in entries {
+                    self.check_expr(key, scope, true);
+                    self.check_expr(value, scope, false);
+                }
+            }
+            Expr::Index(target, index) => {
+                self.check_expr(target, scope, true);
+                self.check_expr(index, scope, true);
+            }
+            Expr::Slice(target, start, end, step) => {
+                self.check_expr(target, scope, true);
+                for bound in [start, end, step].into_iter().flatten() {
+                    self.check_expr(bound, scope, true);
+                }
+            }
+            Expr::Field(target, _) => self.check_expr(target, scope, true),
+            Expr::SafeField(target, _) | Expr::Propagate(target) => {
+                self.check_expr(target, scope, false)
+            }
+            Expr::If(condition, then_expr, else_expr) => {
+                self.check_expr(condition, scope, true);
+                let (then_names, else_names) = narrowing(condition);
+                self.check_expr(then_expr, &scope.narrowed(&then_names), needs_value);
+                self.check_expr(else_expr, &scope.narrowed(&else_names), needs_value);
+                self.check_agree(
+                    "Branches of 'if' expression",
+                    [then_expr.as_ref(), else_expr.as_ref()],
+                );
+            }
+            Expr::Match(expr, arms) => {
+                self.check_expr(expr, scope, false);
+                for (pattern, arm) in arms {
+                    let mut inner = scope.clone();
+                    self.bind_pattern(pattern, &mut inner);
+                    self.check_expr(arm, &inner, needs_value);
+                }
+                self.check_agree(
+                    "Arms of 'match' expression",
+                    arms.iter().map(|(_, arm)| arm),
+                );
+            }
+        }
+    }
+
+    fn check_agree<'a>(&mut self, what: &str, exprs: impl IntoIterator<Item = &'a Expr>) {
+        let mut types: Vec<String> = Vec::new();
+        for type_name in exprs.into_iter().filter_map(|e| self.static_type(e)) {
+            if !types.contains(&type_name) {
+                types.push(type_name);
+            }
+        }
+        if types.len() > 1 {
+            self.error(format!(
+                "{} have different types: {}",
+                what,
+                types.join(", ")
+            ));
+        }
+    }
+
+    fn static_type(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::String(_) => Some("str".to_string()),
+            Expr::Number(_) => Some("f64".to_string()),
+            Expr::Bool(_) => Some("bool".to_string()),
+            Expr::List(_) | Expr::ListComp(..) => Some("list".to_string()),
+            Expr::Map(_) | Expr::MapComp(..) => Some("map".to_string()),
+            Expr::Tuple(_) => Some("tuple".to_string()),
+            Expr::Unary(op, _) if op == "-" => Some("f64".to_string()),
+            Expr::Unary(op, _) if op == "!" => Some("bool".to_string()),
+            Expr::Binary(_, op, _)
+                if matches!(
+                    op.as_str(),
+                    "==" | "!=" | "<" | "<=" | ">" | ">=" | "and" | "or"
+                ) =>
+            {
+                Some("bool".to_string())
+            }
+            Expr::Call(name, _) if self.types.contains(name) => Some(name.clone()),
+            Expr::If(_, then_expr, else_expr) => {
+                let then_type = self.static_type(then_expr);
+                if then_type == self.static_type(else_expr) {
+                    then_type
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn check_clauses(&mut self, clauses: &[Clause], scope: &Scope) -> Scope {
+        let mut inner = scope.clone();
+        for clause in clauses {
+            match clause {
+                Clause::For(target, expr) => {
+                    self.check_expr(expr, &inner, true);
+                    self.check_arity(target, self.element_shape(expr));
+                    for name in target_names(target) {
+                        self.bind(&mut inner, &name, Kind::Val, true, None);
+                    }
+                }
+                Clause::If(condition) => self.check_expr(condition, &inner, true),
+            }
+        }
+        inner
+    }
+
+    fn check_function(&mut self, name: &str, params: &[Param], body: &[Statement], scope: &Scope) {
+        let mut inner = scope.clone();
+        inner.locals.clear();
+        let mut names: Vec<String> = params.iter().map(|p| p.name.clone()).collect();
+        resolver::declarations(body, &mut names);
+        self.frames.push(names);
+        self.functions.push(name.to_string());
+        for param in params {
+            if let Some(default) = &param.default {
+                self.check_expr(default, &inner, false);
+            }
+            let type_anno = if param.variadic {
+                "list"
+            } else {
+                &param.type_anno
+            };
+            inner.declare(&param.name, Kind::Val, true, Some(type_anno));
+        }
+        self.signatures.insert(name.to_string(), params.to_vec());
+        self.check_block(body, &mut inner);
+        self.functions.pop();
+        self.frames.pop();
+        let mut arities = Vec::new();
+        returned_arities(body, &mut arities);
+        match arities.first() {
+            Some(&Some(n)) if arities.iter().all(|a| *a == Some(n)) => {
+                self.returns.insert(name.to_string(), n)
+            }
+            _ => self.returns.remove(name),
+        };
+    }
+
+    fn check_call(&mut self, name: &str, args: &[Expr]) {
+        let params = self.signatures.get(name).cloned();
+        let mut seen = HashSet::new();
+        let mut position = 0;
+        for arg in args {
+            match arg {
+                Expr::Named(arg_name, expr) => {
+                    if !seen.insert(arg_name) {
+                        self.error(format!(
+                            "Duplicate named argument '{}' in call to '{}'",
+                            arg_name, name
+                        ));
+                        continue;
+                    }
+                    let Some(params) = &params else { continue };
+                    match params.iter().find(|p| &p.name == arg_name && !p.variadic) {
+                        Some(param) => self.check_conforms(expr, &param.type_anno),
+                        None => self.error(format!(
+                            "Unknown named argument '{}' in call to '{}'",
+                            arg_name, name
+                        )),
+                    }
+                }
+                expr => {
+                    if let Some(param) = params
+                        .as_ref()
+                        .and_then(|p| p.get(position))
+                        .filter(|p| !p.variadic)
+                    {
+                        self.check_conforms(expr, &param.type_anno);
+                    }
+                    position += 1;
+                }
+            }
+        }
+    }
+
+    fn check_conforms(&mut self, expr: &Expr, type_anno: &str) {
+        if let Expr::Call(type_name, _) = expr {
+            let implemented = self
+                .impls
+                .contains(&(type_name.clone(), type_anno.to_string()));
+            if self.traits.contains_key(type_anno) && self.types.contains(type_name) && !implemented
+            {
+                self.error(format!(
+                    "type {} does not implement trait {}",
+                    type_name, type_anno
+                ));
+            }
+        }
+    }
+
+    fn shape(&self, expr: &Expr) -> Option<(&'static str, usize)> {
+        match expr {
+            Expr::Tuple(elements) => Some(("tuple", elements.len())),
+            Expr::List(elements) => Some(("list", elements.len())),
+            Expr::Call(name, _) if self.returns.contains_key(name) => {
+                Some(("tuple", self.returns[name]))
+            }
+            Expr::Call(name, _) if name == "divmod" => Some(("tuple", 2)),
+            _ => None,
+        }
+    }
+
+    fn element_shape(&self, expr: &Expr) -> Option<(&'static str, usize)> {
+        match expr {
+            Expr::Call(name, _) if name == "enumerate" || name == "zip" => Some(("tuple", 2)),
+            Expr::ListComp(element, _) => self.shape(element),
+            Expr::List(elements) => {
+                let first = self.shape(elements.first()?)?;
+                elements
+                    .iter()
+                    .all(|e| self.shape(e) == Some(first))
+                    .then_some(first)
+            }
+            _ => None,
+        }
+    }
+
+    fn check_arity(&mut self, target: &Target, shape: Option<(&str, usize)>) {
+        let Some((kind, len)) = shape else { return };
+        let message = match target {
+            Target::Tuple(targets) if kind == "tuple" && targets.len() != len => {
+                format!(
+                    "Cannot unpack tuple of length {} into {} names",
+                    len,
+                    targets.len()
+                )
+            }
+            Target::List(targets, rest)
+                if kind == "list"
+                    && (len < targets.len() || (rest.is_none() && len != targets.len())) =>
+            {
+                let names = if rest.is_some() {
+                    format!("at least {}", targets.len())
+                } else {
+                    targets.len().to_string()
+                };
+                format!("Cannot unpack list of length {} into {} names", len, names)
+            }
+            Target::Tuple(_) if kind != "tuple" => {
+                format!("Expected tuple to unpack, got {}", kind)
+            }
+            Target::List(..) if kind != "list" => format!("Expected list to unpack, got {}", kind),
+            _ => return,
+        };
+        self.error(message);
+    }
+
+    fn bind_pattern(&mut self, pattern: &Pattern, scope: &mut Scope) {
+        match pattern {
+            Pattern::Bind(name) => self.bind(scope, name, Kind::Val, true, None),
+            Pattern::Variant(_, Some(inner)) => self.bind_pattern(inner, scope),
+            _ => {}
+        }
+    }
+
+    fn check_ident(&mut self, id: &str, scope: &Scope, needs_value: bool) {
+        if scope.unassigned.contains(id) {
+            self.error(format!(
+                "Variable '{}' may be used before it is assigned",
+                id
+            ));
+        } else if needs_value && scope.optional.contains(id) && !scope.checked.contains(id) {
+            self.error(format!(
+                "Optional '{}' must be checked before use (use '??', '?.' or 'if {} != none')",
+                id, id
+            ));
+        }
+    }
+}
+
+fn is_constant(expr: &Expr, scope: &Scope) -> bool {
+    match expr {
+        Expr::String(_) | Expr::Number(_) | Expr::Bool(_) => true,
+        Expr::Ident(id) => id == "none" || scope.kinds.get(id) == Some(&Kind::Const),
+        Expr::Unary(_, expr) => is_constant(expr, scope),
+        Expr::Binary(left, _, right) => is_constant(left, scope) && is_constant(right, scope),
+        Expr::List(elements) | Expr::Tuple(elements) => {
+            elements.iter().all(|e| is_constant(e, scope))
+        }
+        _ => false,
+    }
+}
+
+fn target_names(target: &Target) -> Vec<String> {
+    match target {
+        Target::Name(name) if name == "_" => Vec::new(),
+        Target::Name(name) => vec![name.clone()],
+        Target::Tuple(targets) => targets.iter().flat_map(target_names).collect(),
+        Target::List(targets, rest) => {
+            let mut names: Vec<String> = targets.iter().flat_map(target_names).collect();
+            names.extend(rest.iter().filter(|r| *r != "_").cloned());
+            names
+        }
+    }
+}
+
+fn returned_arities(stmts: &[Statement], arities: &mut Vec<Option<usize>>) {
+    for stmt in stmts {
+        match stmt {
+            Statement::Return(Expr::Tuple(elements)) => arities.push(Some(elements.len())),
+            Statement::Return(_) => arities.push(None),
+            Statement::If(_, then_block, else_block) => {
+                returned_arities(then_block, arities);
+                if let Some(else_block) = else_block {
+                    returned_arities(else_block, arities);
+                }
+            }
+            Statement::For(_, _, body) | Statement::While(_, body) => {
+                returned_arities(body, arities)
+            }
+            Statement::Try(try_block, catches, finally_block) => {
+                returned_arities(try_block, arities);
+                for (_, _, catch_block) in catches {
+                    returned_arities(catch_block, arities);
+                }
+                if let Some(finally_block) = finally_block {
+                    returned_arities(finally_block, arities);
+                }
+            }
+            Statement::Match(_, branches) => {
+                for (_, statements) in branches {
+                    returned_arities(statements, arities);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn narrowing(condition: &Expr) -> (Vec<String>, Vec<String>) {
+    match condition {
+        Expr::Binary(left, op, right) if op == "!=" || op == "==" => {
+            let name = match (left.as_ref(), right.as_ref()) {
+                (Expr::Ident(name), Expr::Ident(none)) | (Expr::Ident(none), Expr::Ident(name))
+                    if none == "none" =>
+                {
+                    name.clone()
+                }
+                _ => return (Vec::new(), Vec::new()),
+            };
+            if op == "!=" {
+                (vec![name], Vec::new())
+            } else {
+                (Vec::new(), vec![name])
+            }
+        }
+        Expr::Call(name, args) if args.len() == 1 && (name == "is_some" || name == "is_none") => {
+            match &args[0] {
+                Expr::Ident(id) if name == "is_some" => (vec![id.clone()], Vec::new()),
+                Expr::Ident(id) => (Vec::new(), vec![id.clone()]),
+                _ => (Vec::new(), Vec::new()),
+            }
+        }
+        Expr::Binary(left, op, right) if op == "and" => {
+            let (mut then_names, _) = narrowing(left);
+            then_names.extend(narrowing(right).0);
+            (then_names, Vec::new())
+        }
+        Expr::Binary(left, op, right) if op == "or" => {
+            let (_, mut else_names) = narrowing(left);
+            else_names.extend(narrowing(right).1);
+            (Vec::new(), else_names)
+        }
+        Expr::Unary(op, expr) if op == "!" => {
+            let (then_names, else_names) = narrowing(expr);
+            (else_names, then_names)
+        }
+        _ => (Vec::new(), Vec::new()),
+    }
+}
@@ -1,12 +1,17 @@
-use std::process;
-
 pub fn print_help() {
     println!("\x1b[1;34mVelvet CLI v1.4\x1b[0m");
     println!("\x1b[1;36m  vel help\x1b[0m           - Show this help");
     println!("\x1b[1;36m  vel start [file]\x1b[0m   - Run program (default: main.velvet)");
+    println!("\x1b[1;36m  vel start [file] --engine=vm\x1b[0m - Run program on the bytecode VM");
+    println!("\x1b[1;36m  vel start [file] -O\x1b[0m - Run program after optimizing it");
+    println!("\x1b[1;36m  vel start [file] --jit\x1b[0m - Compile hot numeric functions to native code (needs the 'jit' feature)");
     println!("\x1b[1;36m  vel update\x1b[0m         - Update libraries");
     println!("\x1b[1;36m  vel install <.> <manager> install <lib>\x1b[0m - Install library (e.g., vel install <.> gem install bundler)");
     println!("\x1b[1;36m  vel build\x1b[0m          - Compile to executable");
+    println!("\x1b[1;36m  vel build -O\x1b[0m       - Compile to executable after optimizing");
+    println!("\x1b[1;36m  vel build --target c\x1b[0m - Compile to executable through C99 and cc");
+    println!("\x1b[1;36m  vel build --target js\x1b[0m - Compile to an ES module (velvet_out.mjs)");
+    println!("\x1b[1;36m  vel build --target python\x1b[0m - Compile to an importable Python 3.10+ module (velvet_out.py and velvet_runtime.py)");
     println!("\x1b[1;36m  vel init\x1b[0m           - Init new project");
     println!("\x1b[1;36m  vel debug [file]\x1b[0m   - Run with debug output");
     println!("\x1b[1;36m  vel test\x1b[0m           - Run tests");
@@ -14,8 +19,9 @@ pub fn print_help() {
     println!("\x1b[1;36m  vel version\x1b[0m        - Show version");
     println!("\x1b[1;36m  vel repl\x1b[0m           - Start REPL");
     println!("\x1b[1;36m  vel fmt\x1b[0m            - Format files");
+    println!("\x1b[1;36m  vel migrate\x1b[0m        - Rewrite old syntax in project files");
     println!("\x1b[1;36m  vel list-libs\x1b[0m      - List libraries");
-    println!("\x1b[1;36m  vel check\x1b[0m          - Check project config");
+    println!("\x1b[1;36m  vel check\x1b[0m          - Check project config and code");
 }
 
 pub fn success(message: &str) {
@@ -26,6 +32,10 @@ pub fn error(message: &str) {
     eprintln!("\x1b[1;31mERR:\x1b[0m {}", message);
 }
 
+pub fn warning(message: &str) {
+    eprintln!("\x1b[1;33mWARN:\x1b[0m {}", message);
+}
+
 pub fn info(message: &str) {
     println!("\x1b[1;33mINFO:\x1b[0m {}", message);
 }
@@ -1,130 +1,1108 @@
 use crate::ast::*;
-use std::fs::File;
-use std::io::Write;
+use crate::interpreter::contains_yield;
+use crate::resolver::{self, Scope};
+use crate::utils;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+const PRELUDE: &str = include_str!("prelude.rs");
+const RUNTIME: &str = include_str!("runtime.rs");
+const METHODS: &str = include_str!("methods.rs");
+const SOURCE: &str = "velvet_out.rs";
+const BINARY: &str = "velvet_out";
+pub const OUTPUTS: &[&str] = &[SOURCE, BINARY];
 
 pub fn compile(statements: Vec<Statement>) -> Result<(), String> {
-    let mut output = File::create("velvet_out.rs")?;
-    writeln!(output, "use std::collections::HashMap;\nfn main() {{ let mut env: HashMap<String, f64> = HashMap::new();")?;
-    for stmt in statements {
-        compile_stmt(&mut output, &stmt, 1)?;
-    }
-    writeln!(output, "}}")?;
-    std::process::Command::new("rustc")
-        .arg("velvet_out.rs")
-        .arg("-o")
-        .arg("velvet_out")
+    let mut codegen = Codegen::default();
+    let (statements, scope) = resolver::program(&statements);
+    codegen.specialise(&statements);
+    let mut ctx = Context::new(&scope);
+    ctx.toplevel = true;
+    let body = codegen.block(&mut ctx, &statements)?;
+    let source = format!(
+        "{}\nmod runtime {{\n{}}}\n\nmod methods {{\n{}}}\n{}\nfn run(env: &mut Env) -> R<()> {{\n{}    Ok(())\n}}\n\nfn main() {{\n    if let Err(message) = outcome(run(&mut Env::new({}))) {{\n        eprintln!(\"{{}}\", message);\n        std::process::exit(1);\n    }}\n}}\n",
+        PRELUDE, RUNTIME, METHODS, codegen.items, body, scope.len
+    );
+    utils::write_file(SOURCE, &source)?;
+    let status = Command::new("rustc")
+        .args(["--edition", "2021", SOURCE, "-o", BINARY])
         .status()
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| format!("Cannot run rustc: {}", e))?;
+    if !status.success() {
+        return Err(format!("rustc failed on {}", SOURCE));
+    }
     Ok(())
 }
 
-fn compile_stmt(output: &mut File, stmt: &Statement, indent: usize) -> Result<(), String> {
-    let indent_str = "    ".repeat(indent);
-    match stmt {
-        Statement::Say(expr) => writeln!(output, "{}println!(\"{{}}\", {});", indent_str, compile_expr(expr)?)?,
-        Statement::Val(ident, expr, type_anno) => {
-            let type_str = type_anno.as_deref().unwrap_or("f64");
-            if let Some(e) = expr {
-                writeln!(output, "{}let mut {}: {} = {};", indent_str, ident, type_str, compile_expr(e)?)?;
-            } else {
-                writeln!(output, "{}let mut {}: {};", indent_str, ident, type_str)?;
+#[derive(Default)]
+struct Codegen {
+    items: String,
+    next_id: usize,
+    modules: HashMap<String, String>,
+    natives: HashMap<String, (String, Signature)>,
+}
+
+enum Region {
+    Loop(String),
+    Try(String),
+}
+
+struct Context {
+    names: HashMap<String, usize>,
+    regions: Vec<Region>,
+    indent: usize,
+    toplevel: bool,
+}
+
+impl Context {
+    fn new(scope: &Scope) -> Self {
+        Context { names: scope.names.clone(), regions: Vec::new(), indent: 1, toplevel: false }
+    }
+
+    fn line(&self, code: &str) -> String {
+        format!("{}{}\n", "    ".repeat(self.indent), code)
+    }
+
+    fn slot(&self, name: &str) -> String {
+        match self.names.get(name) {
+            Some(slot) => format!("Some({})", slot),
+            None => "None".to_string(),
+        }
+    }
+
+    fn try_label(&self) -> Option<&str> {
+        self.regions.iter().rev().find_map(|region| match region {
+            Region::Try(label) => Some(label.as_str()),
+            Region::Loop(_) => None,
+        })
+    }
+
+    // Errors inside a try region leave through its label so the catch and finally blocks still run
+    fn fallible(&self, code: String) -> String {
+        match self.try_label() {
+            Some(label) => format!("check!({}, {})", label, code),
+            None => format!("{}?", code),
+        }
+    }
+
+    fn fail(&self, signal: &str) -> String {
+        match self.try_label() {
+            Some(label) => format!("break {} Err({})", label, signal),
+            None => format!("return Err({})", signal),
+        }
+    }
+
+    fn raise(&self, kind: &str, message: &str) -> String {
+        self.fail(&format!("Signal::from(ErrorValue::new({:?}, {:?}))", kind, message))
+    }
+
+    fn jump(&self, signal: &str) -> String {
+        match self.regions.last() {
+            Some(Region::Loop(label)) if signal == "Signal::Break" => format!("break {}", label),
+            Some(Region::Loop(label)) => format!("continue {}", label),
+            _ => self.fail(signal),
+        }
+    }
+
+    fn dispatch(&self, result: &str) -> String {
+        let handled = match self.regions.last() {
+            Some(Region::Loop(label)) => {
+                format!("match signal {{ Signal::Break => break {}, Signal::Continue => continue {}, signal => {} }}", label, label, self.fail("signal"))
             }
+            _ => format!("{};", self.fail("signal")),
+        };
+        self.line(&format!("if let Err(signal) = {} {{ {} }}", result, handled))
+    }
+}
+
+fn mutability(mutable: bool) -> &'static str {
+    if mutable {
+        "Mutability::Mutable"
+    } else {
+        "Mutability::Immutable"
+    }
+}
+
+impl Codegen {
+    fn id(&mut self, prefix: &str, name: &str) -> String {
+        self.next_id += 1;
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+        format!("{}{}_{}", prefix, self.next_id, name)
+    }
+
+    fn label(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("'{}{}", prefix, self.next_id)
+    }
+
+    fn body(&mut self, stmts: &[Statement], scope: &Scope) -> Result<String, String> {
+        let mut ctx = Context::new(scope);
+        self.block(&mut ctx, stmts)
+    }
+
+    fn block(&mut self, ctx: &mut Context, stmts: &[Statement]) -> Result<String, String> {
+        let mut code = String::new();
+        for stmt in stmts {
+            code.push_str(&self.stmt(ctx, stmt)?);
+        }
+        Ok(code)
+    }
+
+    fn nested(&mut self, ctx: &mut Context, stmts: &[Statement], region: Option<Region>) -> Result<String, String> {
+        ctx.indent += 1;
+        let pushed = region.is_some();
+        ctx.regions.extend(region);
+        let code = self.block(ctx, stmts);
+        if pushed {
+            ctx.regions.pop();
         }
-        Statement::Const(ident, expr, type_anno) => {
-            let type_str = type_anno.as_deref().unwrap_or("f64");
-            writeln!(output, "{}const {}: {} = {};", indent_str, ident, type_str, compile_expr(expr)?)?;
+        ctx.indent -= 1;
+        code
+    }
+
+    // A labeled block yielding `R<()>`; errors and signals raised inside end up in its value
+    fn region(&mut self, ctx: &mut Context, result: &str, stmts: &[Statement], prelude: &str) -> Result<String, String> {
+        let label = self.label("t");
+        let mut code = ctx.line(&format!("let {}: R<()> = {}: {{", result, label));
+        if !prelude.is_empty() {
+            code.push_str(&format!("{}    {}\n", "    ".repeat(ctx.indent), prelude));
         }
-        Statement::Fun(name, params, ret_type, body) => {
-            writeln!(output, "{}fn {}(", indent_str, name)?;
-            for (i, (param, type_anno)) in params.iter().enumerate() {
-                write!(output, "{}{}: {}", indent_str, param, type_anno)?;
-                if i < params.len() - 1 { write!(output, ", ")?; }
+        code.push_str(&self.nested(ctx, stmts, Some(Region::Try(label)))?);
+        code.push_str(&ctx.line("    Ok(())"));
+        code.push_str(&ctx.line("};"));
+        Ok(code)
+    }
+
+    fn stmt(&mut self, ctx: &mut Context, stmt: &Statement) -> Result<String, String> {
+        Ok(match stmt {
+            Statement::Say(expr) => ctx.line(&format!("{};", ctx.fallible(format!("say({})", self.mutating(ctx, expr))))),
+            Statement::Val(name, expr, type_anno) | Statement::Let(name, expr, type_anno) => {
+                let value = match expr {
+                    Some(expr) => self.mutating(ctx, expr),
+                    None if type_anno.as_deref().is_some_and(|t| t.ends_with('?')) => "Value::None".to_string(),
+                    None => "Value::Unset".to_string(),
+                };
+                let mut code = format!("{{ let value = {}; ", value);
+                if let Some(type_anno) = type_anno {
+                    code.push_str(&format!("{}; ", ctx.fallible(format!("check_type(&value, {:?})", type_anno))));
+                }
+                let define = format!("env.define({}, {:?}, value, {})", ctx.slot(name), name, mutability(matches!(stmt, Statement::Let(..))));
+                ctx.line(&format!("{}{}; }}", code, ctx.fallible(define)))
             }
-            writeln!(output, ") {} {{", ret_type.as_deref().unwrap_or(""))?;
-            for stmt in body {
-                compile_stmt(output, stmt, indent + 1)?;
+            Statement::Const(name, expr, type_anno) => {
+                let slot = ctx.slot(name);
+                let mut code = ctx.line(&format!("if env.bound({}, {:?}) {{ {}; }}", slot, name, ctx.raise("Error", &format!("Const '{}' redefinition", name))));
+                let mut line = format!("{{ let value = {}; ", self.expr(ctx, expr));
+                if let Some(type_anno) = type_anno {
+                    line.push_str(&format!("{}; ", ctx.fallible(format!("check_type(&value, {:?})", type_anno))));
+                }
+                let define = format!("env.define({}, {:?}, value, Mutability::Const)", slot, name);
+                code.push_str(&ctx.line(&format!("{}{}; }}", line, ctx.fallible(define))));
+                code
             }
-            writeln!(output, "{}}}", indent_str)?;
-        }
-        Statement::If(condition, then_block, else_block) => {
-            writeln!(output, "{}if {} {{", indent_str, compile_expr(condition)?)?;
-            for stmt in then_block {
-                compile_stmt(output, stmt, indent + 1)?;
+            Statement::Fun(name, params, ret_type, body) => {
+                let id = match self.natives.get(name) {
+                    Some((id, _)) if ctx.toplevel && ctx.indent == 1 => id.clone(),
+                    _ => self.id("f", name),
+                };
+                let function = self.function(id, params, ret_type, body)?;
+                let define = format!("env.define({}, {:?}, function_value({}), Mutability::Immutable)", ctx.slot(name), name, function);
+                ctx.line(&format!("{};", ctx.fallible(define)))
+            }
+            Statement::Type(name, fields) => {
+                let id = self.id("f", name);
+                let constructor = self.function(id, fields, &None, &[])?;
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|field| {
+                        let default = field.default.as_ref().map_or("None".to_string(), |default| format!("Some(ast::Expr({:?}))", format!("{:?}", default)));
+                        format!(
+                            "ast::Param {{ name: String::from({:?}), type_anno: String::from({:?}), default: {}, variadic: {} }}",
+                            field.name, field.type_anno, default, field.variadic
+                        )
+                    })
+                    .collect();
+                let value = format!("define_type({:?}, {}, vec![{}])", name, constructor, fields.join(", "));
+                let define = format!("env.define({}, {:?}, value, Mutability::Immutable)", ctx.slot(name), name);
+                ctx.line(&format!("{{ let value = {}; {}; }}", value, ctx.fallible(define)))
             }
-            if let Some(else_block) = else_block {
-                writeln!(output, "{}}} else {{", indent_str)?;
-                for stmt in else_block {
-                    compile_stmt(output, stmt, indent + 1)?;
+            Statement::Trait(name, signatures, defaults) => {
+                let required: Vec<String> = signatures.iter().map(|(method, _, _)| format!("{:?}", method)).collect();
+                let defaults = self.method_table(defaults)?;
+                ctx.line(&format!("define_trait({:?}, &[{}], {});", name, required.join(", "), defaults))
+            }
+            Statement::Impl(type_name, trait_name, methods) => {
+                let methods = self.method_table(methods)?;
+                let define = format!("define_impl(env.get({}, {:?}), {:?}, {:?}, {})", ctx.slot(type_name), type_name, type_name, trait_name.as_deref(), methods);
+                ctx.line(&format!("{};", ctx.fallible(define)))
+            }
+            Statement::If(condition, then_block, else_block) => {
+                let condition = ctx.fallible(format!("truthy({})", self.expr(ctx, condition)));
+                let mut code = ctx.line(&format!("if {} {{", condition));
+                code.push_str(&self.nested(ctx, then_block, None)?);
+                if let Some(else_block) = else_block {
+                    code.push_str(&ctx.line("} else {"));
+                    code.push_str(&self.nested(ctx, else_block, None)?);
+                }
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::While(condition, body) => {
+                let label = self.label("l");
+                let condition = ctx.fallible(format!("truthy({})", self.expr(ctx, condition)));
+                let mut code = ctx.line(&format!("{}: while {} {{", label, condition));
+                code.push_str(&self.nested(ctx, body, Some(Region::Loop(label)))?);
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::For(target, expr, body) => {
+                let label = self.label("l");
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&format!("let iter = {};", ctx.fallible(format!("to_iter({})", self.expr(ctx, expr))))));
+                code.push_str(&ctx.line(&format!("{}: while let Some(value) = {} {{", label, ctx.fallible("next_value(&iter)".to_string()))));
+                code.push_str(&ctx.line(&format!("    {}", self.bind(ctx, target, "value", "Mutability::Immutable", false))));
+                code.push_str(&self.nested(ctx, body, Some(Region::Loop(label)))?);
+                code.push_str(&ctx.line("}"));
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Break => ctx.line(&format!("{};", ctx.jump("Signal::Break"))),
+            Statement::Continue => ctx.line(&format!("{};", ctx.jump("Signal::Continue"))),
+            Statement::Try(try_block, catches, finally_block) => {
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&self.region(ctx, "mut result", try_block, "")?);
+                if !catches.is_empty() {
+                    code.push_str(&ctx.line("if let Err(Signal::Error(error)) = result {"));
+                    ctx.indent += 1;
+                    for (i, (ident, kind, block)) in catches.iter().enumerate() {
+                        let matches = kind.as_ref().map_or("true".to_string(), |kind| format!("error.matches({:?})", kind));
+                        code.push_str(&ctx.line(&format!("{}if {} {{", if i == 0 { "result = " } else { "} else " }, matches)));
+                        ctx.indent += 1;
+                        let define = format!("env.define({}, {:?}, Value::Error(Box::new(error.clone())), Mutability::Immutable)", ctx.slot(ident), ident);
+                        code.push_str(&ctx.line(&format!("{};", ctx.fallible(define))));
+                        code.push_str(&ctx.line("begin_catch(error);"));
+                        code.push_str(&self.region(ctx, "caught", block, "")?);
+                        code.push_str(&ctx.line("end_catch();"));
+                        code.push_str(&ctx.line("caught"));
+                        ctx.indent -= 1;
+                    }
+                    code.push_str(&ctx.line("} else {"));
+                    code.push_str(&ctx.line("    Err(Signal::Error(error))"));
+                    code.push_str(&ctx.line("};"));
+                    ctx.indent -= 1;
+                    code.push_str(&ctx.line("}"));
+                }
+                if let Some(finally_block) = finally_block {
+                    code.push_str(&self.block(ctx, finally_block)?);
+                }
+                code.push_str(&ctx.dispatch("result"));
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Throw(Some(expr), line) => ctx.line(&format!("{{ let value = {}; {}; }}", self.expr(ctx, expr), ctx.fail(&format!("throw(value, {})", line)))),
+            Statement::Throw(None, _) => ctx.line(&format!("{};", ctx.fail("rethrow()"))),
+            Statement::Match(expr, branches) => {
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&format!("let subject = {};", self.expr(ctx, expr))));
+                code.push_str(&ctx.line("let mut bindings = Vec::new();"));
+                for (i, (pattern, block)) in branches.iter().enumerate() {
+                    let test = format!("if match_pattern(&{}, &subject, &mut bindings) {{", self.pattern(ctx, pattern));
+                    code.push_str(&ctx.line(&if i == 0 { test } else { format!("}} else {}", test) }));
+                    code.push_str(&ctx.line(&format!("    {};", ctx.fallible("env.define_all(bindings, Mutability::Immutable)".to_string()))));
+                    code.push_str(&self.nested(ctx, block, None)?);
+                }
+                if !branches.is_empty() {
+                    code.push_str(&ctx.line("}"));
+                }
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Unpack(target, expr, mutable) => {
+                let value = self.mutating(ctx, expr);
+                ctx.line(&format!("{{ let value = {}; {} }}", value, self.bind(ctx, target, "value", mutability(*mutable), false)))
+            }
+            Statement::Assign(target, expr) => {
+                let value = self.mutating(ctx, expr);
+                ctx.line(&format!("{{ let value = {}; {} }}", value, self.bind(ctx, target, "value", "", true)))
+            }
+            Statement::Expr(expr) => ctx.line(&format!("{};", self.mutating(ctx, expr))),
+            Statement::Return(expr) => ctx.line(&format!("{{ let value = {}; {}; }}", self.mutating(ctx, expr), ctx.fail("Signal::Return(value)"))),
+            Statement::Yield(_) => ctx.line(&format!("{};", ctx.raise("Error", "'yield' is only allowed inside a function"))),
+            Statement::Import(module, _) => {
+                let path = format!("{}.velvet", module);
+                if !Path::new(&path).exists() {
+                    return Ok(ctx.line(&format!("{};", ctx.raise("ImportError", &format!("Module '{}' not found", module)))));
+                }
+                let id = match self.modules.get(module) {
+                    Some(id) => id.clone(),
+                    None => {
+                        let id = self.id("module", module);
+                        self.modules.insert(module.clone(), id.clone());
+                        let source = utils::read_file(&path)?;
+                        let (ast, scope) = resolver::program(&crate::parser::parse(&source)?);
+                        let body = self.body(&ast, &scope)?;
+                        self.items.push_str(&format!("fn {}() -> R<()> {{\n    let env = &mut Env::new({});\n{}    Ok(())\n}}\n\n", id, scope.len, body));
+                        id
+                    }
+                };
+                ctx.line(&format!("{};", ctx.fallible(format!("{}()", id))))
+            }
+            Statement::Test(_, body) => {
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&ctx.line("let globals = snapshot();"));
+                code.push_str(&self.region(ctx, "result", body, "let env = &mut env.clone();")?);
+                code.push_str(&ctx.line("restore(globals);"));
+                code.push_str(&ctx.dispatch("result"));
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Attributed(attributes, target) => {
+                let mut code = self.stmt(ctx, target)?;
+                let name = match target.as_ref() {
+                    Statement::Fun(name, ..) | Statement::Type(name, _) | Statement::Const(name, ..) => name,
+                    _ => return Ok(code),
+                };
+                for attribute in attributes {
+                    match attribute.name.as_str() {
+                        "deprecated" => {
+                            let note = match attribute.args.first() {
+                                Some(Expr::String(note)) => Some(note.as_str()),
+                                _ => None,
+                            };
+                            code.push_str(&ctx.line(&format!("deprecate({:?}, {:?});", name, note)));
+                        }
+                        "memoize" => code.push_str(&ctx.line(&format!("memoize({:?});", name))),
+                        _ => {}
+                    }
                 }
+                code
+            }
+        })
+    }
+
+    // Unpacks `value` into `target` and declares (or assigns) every name it binds
+    fn bind(&self, ctx: &Context, target: &Target, value: &str, mutability: &str, assign: bool) -> String {
+        let unpack = ctx.fallible(format!("unpack(&{}, {}, &mut bindings)", self.target(ctx, target), value));
+        let store = if assign { "env.assign_all(bindings)".to_string() } else { format!("env.define_all(bindings, {})", mutability) };
+        format!("let mut bindings = Vec::new(); {}; {};", unpack, ctx.fallible(store))
+    }
+
+    fn target(&self, ctx: &Context, target: &Target) -> String {
+        match target {
+            Target::Name(name) if name == "_" => "Target::Name(None, \"_\")".to_string(),
+            Target::Name(name) => format!("Target::Name({}, {:?})", ctx.slot(name), name),
+            Target::Tuple(targets) => {
+                let targets: Vec<String> = targets.iter().map(|t| self.target(ctx, t)).collect();
+                format!("Target::Tuple(&[{}])", targets.join(", "))
+            }
+            Target::List(targets, rest) => {
+                let targets: Vec<String> = targets.iter().map(|t| self.target(ctx, t)).collect();
+                let rest = rest.as_ref().map_or("None".to_string(), |rest| format!("Some(&{})", self.target(ctx, &Target::Name(rest.clone()))));
+                format!("Target::List(&[{}], {})", targets.join(", "), rest)
             }
-            writeln!(output, "{}}}", indent_str)?;
         }
-        Statement::For(ident, expr, body) => {
-            writeln!(output, "{}for {} in {} {{", indent_str, ident, compile_expr(expr)?)?;
-            for stmt in body {
-                compile_stmt(output, stmt, indent + 1)?;
+    }
+
+    fn pattern(&self, ctx: &Context, pattern: &Pattern) -> String {
+        match pattern {
+            Pattern::Wildcard => "Pattern::Wildcard".to_string(),
+            Pattern::Literal(literal) => format!("Pattern::Literal({:?})", literal),
+            Pattern::Bind(name) => format!("Pattern::Bind({}, {:?})", ctx.slot(name), name),
+            Pattern::Variant(name, inner) => {
+                let inner = inner.as_ref().map_or("None".to_string(), |inner| format!("Some(&{})", self.pattern(ctx, inner)));
+                format!("Pattern::Variant({:?}, {})", name, inner)
             }
-            writeln!(output, "{}}}", indent_str)?;
         }
-        Statement::While(condition, body) => {
-            writeln!(output, "{}while {} {{", indent_str, compile_expr(condition)?)?;
-            for stmt in body {
-                compile_stmt(output, stmt, indent + 1)?;
+    }
+
+    fn method_table(&mut self, methods: &[Statement]) -> Result<String, String> {
+        let mut table = Vec::new();
+        for method in methods {
+            if let Statement::Fun(name, params, ret_type, body) = method {
+                let id = self.id("f", name);
+                table.push(format!("({:?}, {})", name, self.function(id, params, ret_type, body)?));
+            }
+        }
+        Ok(format!("vec![{}]", table.join(", ")))
+    }
+
+    // Emits the function's code as items and returns an expression building its `Function`
+    fn function(&mut self, id: String, params: &[Param], ret_type: &Option<String>, body: &[Statement]) -> Result<String, String> {
+        let function = resolver::function(params, ret_type, body);
+        let mut parameters = Vec::new();
+        for (i, param) in function.params.iter().enumerate() {
+            let default = match &param.default {
+                Some(default) => {
+                    let value = self.expr(&Context::new(&function.scope), default);
+                    self.items.push_str(&format!("fn {}_default{}(env: &mut Env) -> R<Value> {{\n    Ok({})\n}}\n\n", id, i, value));
+                    format!("Some({}_default{})", id, i)
+                }
+                None => "None".to_string(),
+            };
+            parameters.push(format!(
+                "Parameter {{ name: {:?}, type_anno: {:?}, variadic: {}, default: {} }}",
+                param.name, param.type_anno, param.variadic, default
+            ));
+        }
+        let code = if function.generator {
+            let steps = self.steps(&function.scope, &id, &function.body)?;
+            self.items.push_str(&format!("const {}_STEPS: &[Step] = {};\n\n", id.to_uppercase(), steps));
+            format!("Code::Generator({}_STEPS)", id.to_uppercase())
+        } else {
+            let mut body = self.body(&function.body, &function.scope)?;
+            if let Some(entry) = self.native(&id, &function) {
+                body = entry + &body;
             }
-            writeln!(output, "{}}}", indent_str)?;
+            self.items.push_str(&format!("fn {}(env: &mut Env) -> R<()> {{\n{}    Ok(())\n}}\n\n", id, body));
+            format!("Code::Body({})", id)
+        };
+        self.items.push_str(&format!("const {}_PARAMS: &[Parameter] = &[{}];\n\n", id.to_uppercase(), parameters.join(", ")));
+        Ok(format!(
+            "Function {{ params: {}_PARAMS, slots: {}, ret_type: {:?}, code: {} }}",
+            id.to_uppercase(),
+            function.scope.len,
+            function.ret_type.as_deref(),
+            code
+        ))
+    }
+
+    // Top-level functions with a native signature call each other directly; dropping one whose
+    // body cannot be specialised may leave its callers unable to specialise as well
+    fn specialise(&mut self, statements: &[Statement]) {
+        let mut names = Vec::new();
+        resolver::declarations(statements, &mut names);
+        let mut candidates = Vec::new();
+        for stmt in statements {
+            let Statement::Fun(name, params, ret_type, body) = stmt else { continue };
+            let function = resolver::function(params, ret_type, body);
+            if names.iter().filter(|n| *n == name).count() > 1 {
+                continue;
+            }
+            if let Some(signature) = signature(&function) {
+                let id = self.id("f", name);
+                self.natives.insert(name.clone(), (id, signature));
+                candidates.push((name, function));
+            }
+        }
+        while let Some(i) = candidates.iter().position(|(_, function)| Specialiser::translate(&self.natives, function).is_none()) {
+            let (name, _) = candidates.remove(i);
+            self.natives.remove(name);
+        }
+    }
+
+    // Emits `<id>_native` and returns the entry that runs it when every argument unboxes
+    fn native(&mut self, id: &str, function: &resolver::Function) -> Option<String> {
+        let (params, ret) = signature(function)?;
+        let body = Specialiser::translate(&self.natives, function)?;
+        let decls: Vec<String> = function.params.iter().zip(&params).map(|(param, ty)| format!("{}: {}", var(&param.name), ty.rust())).collect();
+        let fallthrough = if always_returns(&function.body) { "" } else { "    missing_return()\n" };
+        self.items.push_str(&format!("fn {}_native({}) -> R<{}> {{\n{}{}}}\n\n", id, decls.join(", "), ret.rust(), body, fallthrough));
+        let binds: Vec<String> = (0..params.len()).map(|i| format!("Some(a{}), ", i)).collect();
+        let args: Vec<String> = params.iter().enumerate().map(|(i, ty)| format!("native_arg::<{}>(env, {}), ", ty.rust(), i)).collect();
+        let names: Vec<String> = (0..params.len()).map(|i| format!("a{}", i)).collect();
+        Some(format!(
+            "    if let ({}) = ({}) {{\n        return Err(Signal::Return({}_native({})?.boxed()));\n    }}\n",
+            binds.concat(),
+            args.concat(),
+            id,
+            names.join(", ")
+        ))
+    }
+
+    fn step_fn(&mut self, id: &str, signature: &str, body: String) -> String {
+        let name = self.id(&format!("{}_step", id), "");
+        self.items.push_str(&format!("fn {}{} {{\n{}}}\n\n", name, signature, body));
+        name
+    }
+
+    // Lowers a generator body into `Step`s; statements without a yield stay compiled code
+    fn steps(&mut self, scope: &Scope, id: &str, stmts: &[Statement]) -> Result<String, String> {
+        let mut steps = Vec::new();
+        let mut pending = String::new();
+        let ctx = Context::new(scope);
+        for stmt in stmts {
+            if !contains_yield(std::slice::from_ref(stmt)) || matches!(stmt, Statement::Test(..)) {
+                pending.push_str(&self.stmt(&mut Context::new(scope), stmt)?);
+                continue;
+            }
+            if !pending.is_empty() {
+                let body = std::mem::take(&mut pending) + "    Ok(())\n";
+                steps.push(format!("Step::Run({})", self.step_fn(id, "(env: &mut Env) -> R<()>", body)));
+            }
+            let condition = |codegen: &mut Self, condition: &Expr| {
+                let body = format!("    truthy({})\n", codegen.expr(&ctx, condition));
+                codegen.step_fn(id, "(env: &mut Env) -> R<bool>", body)
+            };
+            steps.push(match stmt {
+                Statement::Yield(expr) => {
+                    let body = format!("    Ok({})\n", self.mutating(&ctx, expr));
+                    format!("Step::Yield({})", self.step_fn(id, "(env: &mut Env) -> R<Value>", body))
+                }
+                Statement::If(test, then_block, else_block) => {
+                    let test = condition(self, test);
+                    let then_block = self.steps(scope, id, then_block)?;
+                    let else_block = match else_block {
+                        Some(block) => format!("Some({})", self.steps(scope, id, block)?),
+                        None => "None".to_string(),
+                    };
+                    format!("Step::If({}, {}, {})", test, then_block, else_block)
+                }
+                Statement::While(test, body) => {
+                    let test = condition(self, test);
+                    format!("Step::While({}, {})", test, self.steps(scope, id, body)?)
+                }
+                Statement::For(target, expr, body) => {
+                    let iterable = format!("    Ok({})\n", self.expr(&ctx, expr));
+                    let iterable = self.step_fn(id, "(env: &mut Env) -> R<Value>", iterable);
+                    let bind = format!("    {}\n    Ok(())\n", self.bind(&ctx, target, "value", "Mutability::Immutable", false));
+                    let bind = self.step_fn(id, "(env: &mut Env, value: Value) -> R<()>", bind);
+                    format!("Step::For({}, {}, {})", iterable, bind, self.steps(scope, id, body)?)
+                }
+                Statement::Try(try_block, catches, finally_block) => {
+                    let try_block = self.steps(scope, id, try_block)?;
+                    let mut handlers = Vec::new();
+                    for (ident, kind, block) in catches {
+                        handlers.push(format!(
+                            "Catch {{ slot: {}, ident: {:?}, kind: {:?}, body: {} }}",
+                            ctx.slot(ident),
+                            ident,
+                            kind.as_deref(),
+                            self.steps(scope, id, block)?
+                        ));
+                    }
+                    let finally_block = match finally_block {
+                        Some(block) => format!("Some({})", self.steps(scope, id, block)?),
+                        None => "None".to_string(),
+                    };
+                    format!("Step::Try({}, &[{}], {})", try_block, handlers.join(", "), finally_block)
+                }
+                Statement::Match(expr, branches) => {
+                    let mut body = format!("    let subject = {};\n    let mut bindings = Vec::new();\n", self.expr(&ctx, expr));
+                    let mut arms = Vec::new();
+                    for (i, (pattern, block)) in branches.iter().enumerate() {
+                        body.push_str(&format!(
+                            "    if match_pattern(&{}, &subject, &mut bindings) {{\n        env.define_all(bindings, Mutability::Immutable)?;\n        return Ok(Some({}));\n    }}\n",
+                            self.pattern(&ctx, pattern),
+                            i
+                        ));
+                        arms.push(self.steps(scope, id, block)?);
+                    }
+                    body.push_str("    Ok(None)\n");
+                    let select = self.step_fn(id, "(env: &mut Env) -> R<Option<usize>>", body);
+                    format!("Step::Match({}, &[{}])", select, arms.join(", "))
+                }
+                other => unreachable!("statement without yield: {:?}", other),
+            });
         }
-        Statement::Break => writeln!(output, "{}break;", indent_str)?,
-        Statement::Continue => writeln!(output, "{}continue;", indent_str)?,
-        Statement::Try(_, try_block, catch_block) => {
-            writeln!(output, "{}// Try block", indent_str)?;
-            for stmt in try_block {
-                compile_stmt(output, stmt, indent + 1)?;
+        if !pending.is_empty() {
+            let body = pending + "    Ok(())\n";
+            steps.push(format!("Step::Run({})", self.step_fn(id, "(env: &mut Env) -> R<()>", body)));
+        }
+        Ok(format!("&[{}]", steps.join(", ")))
+    }
+
+    fn args(&self, ctx: &Context, args: &[Expr]) -> String {
+        if !args.iter().any(|arg| matches!(arg, Expr::Named(..))) {
+            let values: Vec<String> = args.iter().map(|arg| self.expr(ctx, arg)).collect();
+            return format!("(vec![{}], Vec::new())", values.join(", "));
+        }
+        let mut code = "{ let mut args: Args = (Vec::new(), Vec::new()); ".to_string();
+        for arg in args {
+            match arg {
+                Expr::Named(name, expr) => code.push_str(&format!("args.1.push((String::from({:?}), {})); ", name, self.expr(ctx, expr))),
+                expr => code.push_str(&format!("args.0.push({}); ", self.expr(ctx, expr))),
             }
-            writeln!(output, "{}// Catch block", indent_str)?;
-            for stmt in catch_block {
-                compile_stmt(output, stmt, indent + 1)?;
+        }
+        code + "args }"
+    }
+
+    // Statement-level method calls on a variable may change it in place
+    fn mutating(&self, ctx: &Context, expr: &Expr) -> String {
+        if let Expr::MethodCall(target, method, args) = expr {
+            if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                let mutate = ctx.fallible(format!("env.mutate({}, {:?}, {:?}, receiver, args)", ctx.slot(name), name, method));
+                let call = ctx.fallible(format!("call_method(receiver, {:?}, args)", method));
+                return format!(
+                    "{{ let receiver = {}; let mutating = mutates(&receiver, {:?}); let args = {}; if mutating {{ {} }} else {{ {} }} }}",
+                    self.expr(ctx, target),
+                    method,
+                    self.args(ctx, args),
+                    mutate,
+                    call
+                );
             }
         }
-        Statement::Match(expr, branches) => {
-            writeln!(output, "{}match {} {{", indent_str, compile_expr(expr)?)?;
-            for (pattern, statements) in branches {
-                writeln!(output, "{}{} => {{", indent_str, pattern)?;
-                for stmt in statements {
-                    compile_stmt(output, stmt, indent + 1)?;
+        self.expr(ctx, expr)
+    }
+
+    fn exprs(&self, ctx: &Context, exprs: &[Expr]) -> String {
+        exprs.iter().map(|e| self.expr(ctx, e)).collect::<Vec<_>>().join(", ")
+    }
+
+    fn expr(&self, ctx: &Context, expr: &Expr) -> String {
+        match expr {
+            Expr::String(s) => format!("Value::String(Rc::from({:?}))", s),
+            Expr::Number(n) => format!("Value::Number({:?})", n),
+            Expr::Bool(b) => format!("Value::Bool({})", b),
+            Expr::Ident(id) if id == "none" => ctx.fallible("env.read_none()".to_string()),
+            Expr::Ident(id) => ctx.fallible(format!("env.read(None, {:?})", id)),
+            Expr::Local(slot, id) => ctx.fallible(format!("env.read(Some({}), {:?})", slot, id)),
+            Expr::Binary(left, op, right) if op == "??" => format!(
+                "match {} {{ Value::None => {}, Value::Some(value) => *value, value => value }}",
+                self.expr(ctx, left),
+                self.expr(ctx, right)
+            ),
+            Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+                let left = ctx.fallible(format!("truthy({})", self.expr(ctx, left)));
+                let right = ctx.fallible(format!("truthy({})", self.expr(ctx, right)));
+                format!("Value::Bool({} {} {})", left, if op == "and" { "&&" } else { "||" }, right)
+            }
+            Expr::Binary(left, op, right) => ctx.fallible(format!("binary({:?}, {}, {})", op, self.expr(ctx, left), self.expr(ctx, right))),
+            Expr::Unary(op, inner) => ctx.fallible(format!("unary({:?}, {})", op, self.expr(ctx, inner))),
+            Expr::Call(name, args) => ctx.fallible(format!("call(env, {}, {:?}, {})", ctx.slot(name), name, self.args(ctx, args))),
+            Expr::Named(name, _) => format!("{{ {}; }}", ctx.raise("ArgumentError", &format!("Named argument '{}' is only allowed in a call", name))),
+            Expr::MethodCall(target, method, args) => {
+                let mut code = format!("{{ let receiver = {}; ", self.expr(ctx, target));
+                if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                    code.push_str(&format!("{}; ", ctx.fallible(format!("forbid_mutation(&receiver, {:?}, {:?})", method, name))));
+                }
+                let call = ctx.fallible(format!("call_method(receiver, {:?}, {})", method, self.args(ctx, args)));
+                format!("{}{} }}", code, call)
+            }
+            Expr::List(elements) => format!("Value::List(Rc::new(vec![{}]))", self.exprs(ctx, elements)),
+            Expr::Tuple(elements) => format!("Value::Tuple(vec![{}])", self.exprs(ctx, elements)),
+            Expr::Map(entries) => {
+                let mut code = "{ let mut map = Vec::new(); ".to_string();
+                for (key, value) in entries {
+                    code.push_str(&format!("insert_entry(&mut map, {}, {}); ", self.expr(ctx, key), self.expr(ctx, value)));
                 }
-                writeln!(output, "{}}}", indent_str)?;
+                code + "Value::Map(Rc::new(map)) }"
+            }
+            Expr::ListComp(element, clauses) => {
+                let emit = format!("items.push({});", self.expr(ctx, element));
+                format!("{{ let env = &mut env.clone(); let mut items = Vec::new(); {} Value::List(Rc::new(items)) }}", self.clauses(ctx, clauses, emit))
+            }
+            Expr::MapComp(key, value, clauses) => {
+                let emit = format!("insert_entry(&mut items, {}, {});", self.expr(ctx, key), self.expr(ctx, value));
+                format!("{{ let env = &mut env.clone(); let mut items = Vec::new(); {} Value::Map(Rc::new(items)) }}", self.clauses(ctx, clauses, emit))
+            }
+            Expr::Index(target, index) => ctx.fallible(format!("index({}, {})", self.expr(ctx, target), self.expr(ctx, index))),
+            Expr::Slice(target, start, end, step) => {
+                let bounds: Vec<String> = [start, end, step]
+                    .iter()
+                    .map(|bound| match bound {
+                        Some(bound) => format!("Some({})", ctx.fallible(format!("number({})", self.expr(ctx, bound)))),
+                        None => "None".to_string(),
+                    })
+                    .collect();
+                ctx.fallible(format!("slice({}, {})", self.expr(ctx, target), bounds.join(", ")))
+            }
+            Expr::Field(target, field) => ctx.fallible(format!("get_field({}, {:?})", self.expr(ctx, target), field)),
+            Expr::SafeField(target, field) => ctx.fallible(format!("safe_field({}, {:?})", self.expr(ctx, target), field)),
+            Expr::Propagate(inner) => format!(
+                "match {} {{ Ok(value) => value, Err(failed) => {} }}",
+                ctx.fallible(format!("propagate({})", self.expr(ctx, inner))),
+                ctx.fail("Signal::Return(failed)")
+            ),
+            Expr::If(condition, then_expr, else_expr) => format!(
+                "if {} {{ {} }} else {{ {} }}",
+                ctx.fallible(format!("truthy({})", self.expr(ctx, condition))),
+                self.expr(ctx, then_expr),
+                self.expr(ctx, else_expr)
+            ),
+            Expr::Match(subject, arms) => {
+                let mut code = format!("{{ let subject = {}; let mut bindings = Vec::new(); ", self.expr(ctx, subject));
+                for (pattern, arm) in arms {
+                    let define = ctx.fallible("env.define_all(bindings, Mutability::Immutable)".to_string());
+                    code.push_str(&format!(
+                        "if match_pattern(&{}, &subject, &mut bindings) {{ let env = &mut env.clone(); {}; {} }} else ",
+                        self.pattern(ctx, pattern),
+                        define,
+                        self.expr(ctx, arm)
+                    ));
+                }
+                format!("{}{{ {} }} }}", code, ctx.fallible("no_match(&subject)".to_string()))
             }
-            writeln!(output, "{}}}", indent_str)?;
         }
-        Statement::Expr(expr) => writeln!(output, "{}{};", indent_str, compile_expr(expr)?)?,
-        Statement::Return(expr) => writeln!(output, "{}return {};", indent_str, compile_expr(expr)?)?,
-        Statement::Import(module, _source) => writeln!(output, "{}mod {};", indent_str, module)?,
-        Statement::Test(name, body) => {
-            writeln!(output, "{}// Test: {}", indent_str, name)?;
-            for stmt in body {
-                compile_stmt(output, stmt, indent + 1)?;
+    }
+
+    fn clauses(&self, ctx: &Context, clauses: &[Clause], emit: String) -> String {
+        match clauses.split_first() {
+            None => emit,
+            Some((Clause::If(condition), rest)) => {
+                format!("if {} {{ {} }}", ctx.fallible(format!("truthy({})", self.expr(ctx, condition))), self.clauses(ctx, rest, emit))
             }
+            Some((Clause::For(target, expr), rest)) => format!(
+                "{{ let iter = {}; while let Some(value) = {} {{ {} {} }} }}",
+                ctx.fallible(format!("to_iter({})", self.expr(ctx, expr))),
+                ctx.fallible("next_value(&iter)".to_string()),
+                self.bind(ctx, target, "value", "Mutability::Immutable", false),
+                self.clauses(ctx, rest, emit)
+            ),
         }
     }
-    Ok(())
 }
 
-fn compile_expr(expr: &Expr) -> Result<String, String> {
-    match expr {
-        Expr::String(s) => Ok(format!("\"{}\"", s)),
-        Expr::Number(n) => Ok(n.to_string()),
-        Expr::Bool(b) => Ok(b.to_string()),
-        Expr::Ident(id) => Ok(id.clone()),
-        Expr::Binary(left, op, right) => Ok(format!("({} {} {})", compile_expr(left)?, op, compile_expr(right)?)),
-        Expr::Unary(op, expr) => Ok(format!("{}{}", op, compile_expr(expr)?)),
-        Expr::Call(name, args) => {
-            let args_str = args.iter().map(compile_expr).collect::<Result<Vec<_>, _>>()?.join(", ");
-            Ok(format!("{}({})", name, args_str))
-        }
-        Expr::List(elements) => {
-            let list_str = elements.iter().map(compile_expr).collect::<Result<Vec<_>, _>>()?.join(", ");
-            Ok(format!("vec![{}]", list_str))
-        }
-        Expr::Index(ident, index) => Ok(format!("{}[{}]", ident, compile_expr(index)?))
+// Native code for fully annotated functions: f64, bool, str and list values stay unboxed and
+// only become a `Value` when they reach `say`, a dynamic call or the function's caller.
+#[derive(Debug, Clone, PartialEq)]
+enum Ty {
+    Number,
+    Bool,
+    Str,
+    List(Box<Ty>),
+    Value,
+}
+
+type Signature = (Vec<Ty>, Ty);
+
+impl Ty {
+    fn parse(name: &str) -> Option<Ty> {
+        match name {
+            "f64" => Some(Ty::Number),
+            "bool" => Some(Ty::Bool),
+            "str" => Some(Ty::Str),
+            "list" => Some(Ty::List(Box::new(Ty::Value))),
+            _ => None,
+        }
+    }
+
+    fn rust(&self) -> String {
+        match self {
+            Ty::Number => "f64".to_string(),
+            Ty::Bool => "bool".to_string(),
+            Ty::Str => "String".to_string(),
+            Ty::List(element) => format!("Vec<{}>", element.rust()),
+            Ty::Value => "Value".to_string(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Ty::Number => "f64",
+            Ty::Bool => "bool",
+            Ty::Str => "str",
+            Ty::List(_) => "list",
+            Ty::Value => "value",
+        }
+    }
+}
+
+fn signature(function: &resolver::Function) -> Option<Signature> {
+    if function.generator || function.params.iter().any(|p| p.default.is_some() || p.variadic) {
+        return None;
+    }
+    let params = function.params.iter().map(|p| Ty::parse(&p.type_anno)).collect::<Option<Vec<_>>>()?;
+    Some((params, Ty::parse(function.ret_type.as_deref()?)?))
+}
+
+fn always_returns(stmts: &[Statement]) -> bool {
+    match stmts.last() {
+        Some(Statement::Return(_)) => true,
+        Some(Statement::If(_, then_block, Some(else_block))) => always_returns(then_block) && always_returns(else_block),
+        _ => false,
+    }
+}
+
+fn var(name: &str) -> String {
+    format!("v_{}", name)
+}
+
+// Anything can be boxed on the way into a dynamic slot, but nothing is unboxed implicitly
+fn coerce(code: String, from: &Ty, to: &Ty) -> Option<String> {
+    match (from, to) {
+        _ if from == to => Some(code),
+        (_, Ty::Value) => Some(format!("({}).boxed()", code)),
+        (Ty::List(_), Ty::List(element)) if **element == Ty::Value => Some(format!("({}).into_iter().map(Native::boxed).collect::<Vec<Value>>()", code)),
+        _ => None,
+    }
+}
+
+struct Specialiser<'a> {
+    natives: &'a HashMap<String, (String, Signature)>,
+    scope: &'a Scope,
+    scopes: Vec<HashMap<String, (Ty, bool)>>,
+    ret: Ty,
+    loops: usize,
+    indent: usize,
+}
+
+impl Specialiser<'_> {
+    // The native body, or None when the function uses anything without a native lowering
+    fn translate(natives: &HashMap<String, (String, Signature)>, function: &resolver::Function) -> Option<String> {
+        let (params, ret) = signature(function)?;
+        let names = function.params.iter().zip(params).map(|(param, ty)| (param.name.clone(), (ty, false))).collect();
+        let mut s = Specialiser { natives, scope: &function.scope, scopes: vec![names], ret, loops: 0, indent: 1 };
+        s.block(&function.body)
+    }
+
+    fn line(&self, code: &str) -> String {
+        format!("{}{}\n", "    ".repeat(self.indent), code)
+    }
+
+    fn lookup(&self, name: &str) -> Option<(Ty, bool)> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).cloned()
+    }
+
+    fn block(&mut self, stmts: &[Statement]) -> Option<String> {
+        let mut code = String::new();
+        for stmt in stmts {
+            code.push_str(&self.stmt(stmt)?);
+        }
+        Some(code)
+    }
+
+    fn nested(&mut self, stmts: &[Statement], names: HashMap<String, (Ty, bool)>) -> Option<String> {
+        self.indent += 1;
+        self.scopes.push(names);
+        let code = self.block(stmts);
+        self.scopes.pop();
+        self.indent -= 1;
+        code
+    }
+
+    fn stmt(&mut self, stmt: &Statement) -> Option<String> {
+        Some(match stmt {
+            Statement::Say(expr) => {
+                let (code, ty) = self.expr(expr)?;
+                self.line(&format!("say({})?;", coerce(code, &ty, &Ty::Value)?))
+            }
+            Statement::Val(name, Some(expr), type_anno) | Statement::Let(name, Some(expr), type_anno) => {
+                if self.lookup(name).is_some() {
+                    return None;
+                }
+                let (mut code, mut ty) = self.expr(expr)?;
+                if let Some(type_anno) = type_anno {
+                    let declared = Ty::parse(type_anno)?;
+                    code = coerce(code, &ty, &declared)?;
+                    ty = declared;
+                }
+                let mutable = matches!(stmt, Statement::Let(..));
+                let line = self.line(&format!("let {}{}: {} = {};", if mutable { "mut " } else { "" }, var(name), ty.rust(), code));
+                self.scopes.last_mut()?.insert(name.clone(), (ty, mutable));
+                line
+            }
+            Statement::Assign(Target::Name(name), expr) => {
+                let (declared, true) = self.lookup(name)? else { return None };
+                let (code, ty) = self.expr(expr)?;
+                self.line(&format!("{} = {};", var(name), coerce(code, &ty, &declared)?))
+            }
+            Statement::If(condition, then_block, else_block) => {
+                let mut code = self.line(&format!("if {} {{", self.condition(condition)?));
+                code.push_str(&self.nested(then_block, HashMap::new())?);
+                if let Some(else_block) = else_block {
+                    code.push_str(&self.line("} else {"));
+                    code.push_str(&self.nested(else_block, HashMap::new())?);
+                }
+                code + &self.line("}")
+            }
+            Statement::While(condition, body) => {
+                let mut code = self.line(&format!("while {} {{", self.condition(condition)?));
+                self.loops += 1;
+                code.push_str(&self.nested(body, HashMap::new())?);
+                self.loops -= 1;
+                code + &self.line("}")
+            }
+            Statement::For(Target::Name(name), iterable, body) => {
+                // A binding that cannot change is iterated in place rather than copied first
+                let (iterable, Ty::List(element)) = (match iterable {
+                    Expr::Local(_, name) if self.lookup(name).is_some_and(|(_, mutable)| !mutable) => {
+                        self.place(iterable).map(|(code, ty)| (format!("{}.iter().cloned()", code), ty))
+                    }
+                    _ => self.expr(iterable),
+                })?
+                else {
+                    return None;
+                };
+                let mut names = HashMap::new();
+                let binding = if name == "_" {
+                    "_".to_string()
+                } else if self.lookup(name).is_none() {
+                    names.insert(name.clone(), (*element, false));
+                    var(name)
+                } else {
+                    return None;
+                };
+                let mut code = self.line(&format!("for {} in {} {{", binding, iterable));
+                self.loops += 1;
+                code.push_str(&self.nested(body, names)?);
+                self.loops -= 1;
+                code + &self.line("}")
+            }
+            Statement::Break if self.loops > 0 => self.line("break;"),
+            Statement::Continue if self.loops > 0 => self.line("continue;"),
+            // A returned local is moved out, unless a `for` loop may still be borrowing it
+            Statement::Return(expr) => {
+                let (code, ty) = if self.loops == 0 { self.place(expr)? } else { self.expr(expr)? };
+                self.line(&format!("return Ok({});", coerce(code, &ty, &self.ret)?))
+            }
+            Statement::Expr(Expr::MethodCall(target, method, args)) if method == "push" && args.len() == 1 => {
+                let Expr::Local(_, name) = target.as_ref() else { return None };
+                let (Ty::List(element), true) = self.lookup(name)? else { return None };
+                let (code, ty) = self.expr(&args[0])?;
+                self.line(&format!("{}.push({});", var(name), coerce(code, &ty, &element)?))
+            }
+            Statement::Expr(expr) => {
+                let (code, _) = self.expr(expr)?;
+                self.line(&format!("{};", code))
+            }
+            _ => return None,
+        })
+    }
+
+    fn condition(&self, expr: &Expr) -> Option<String> {
+        match self.expr(expr)? {
+            (code, Ty::Bool) => Some(code),
+            _ => None,
+        }
+    }
+
+    // A local list or string is borrowed rather than cloned when only read through
+    fn place(&self, expr: &Expr) -> Option<(String, Ty)> {
+        match expr {
+            Expr::Local(_, name) => Some((var(name), self.lookup(name)?.0)),
+            _ => self.expr(expr),
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> Option<(String, Ty)> {
+        Some(match expr {
+            Expr::Number(n) if *n < 0.0 => (format!("({:?})", n), Ty::Number),
+            Expr::Number(n) => (format!("{:?}", n), Ty::Number),
+            Expr::Bool(b) => (b.to_string(), Ty::Bool),
+            Expr::String(s) => (format!("String::from({:?})", s), Ty::Str),
+            Expr::Local(_, name) => match self.lookup(name)? {
+                (ty @ (Ty::Number | Ty::Bool), _) => (var(name), ty),
+                (ty, _) => (format!("{}.clone()", var(name)), ty),
+            },
+            Expr::Unary(op, inner) => match (op.as_str(), self.expr(inner)?) {
+                ("-", (code, Ty::Number)) => (format!("(-{})", code), Ty::Number),
+                ("!", (code, Ty::Bool)) => (format!("(!{})", code), Ty::Bool),
+                _ => return None,
+            },
+            Expr::Binary(left, op, right) => self.binary(left, op, right)?,
+            Expr::If(condition, then_expr, else_expr) => {
+                let condition = self.condition(condition)?;
+                let (then_code, ty) = self.expr(then_expr)?;
+                let (else_code, else_ty) = self.expr(else_expr)?;
+                if ty != else_ty {
+                    return None;
+                }
+                (format!("(if {} {{ {} }} else {{ {} }})", condition, then_code, else_code), ty)
+            }
+            Expr::Call(name, args) => self.call(name, args)?,
+            Expr::MethodCall(target, method, args) => self.method(target, method, args)?,
+            Expr::List(elements) => {
+                let mut items = Vec::new();
+                for element in elements {
+                    items.push(self.expr(element)?);
+                }
+                let element = match items.split_first() {
+                    Some(((_, first), rest)) if rest.iter().all(|(_, ty)| ty == first) => first.clone(),
+                    _ => Ty::Value,
+                };
+                let codes = items.into_iter().map(|(code, ty)| coerce(code, &ty, &element)).collect::<Option<Vec<_>>>()?;
+                (format!("vec![{}]", codes.join(", ")), Ty::List(Box::new(element)))
+            }
+            Expr::Index(target, index) => {
+                let (target, Ty::List(element)) = self.place(target)? else { return None };
+                let (index, Ty::Number) = self.expr(index)? else { return None };
+                (format!("{{ let items = &{}; let index = {}; items[at(index, items.len())?].clone() }}", target, index), *element)
+            }
+            _ => return None,
+        })
+    }
+
+    // String operands are only read by `format!` and comparisons, so locals are borrowed
+    fn operand(&self, expr: &Expr) -> Option<(String, Ty)> {
+        match self.place(expr)? {
+            (code, Ty::Str) => Some((code, Ty::Str)),
+            _ => self.expr(expr),
+        }
+    }
+
+    fn binary(&self, left: &Expr, op: &str, right: &Expr) -> Option<(String, Ty)> {
+        let (l, left_ty) = self.operand(left)?;
+        let (r, right_ty) = self.operand(right)?;
+        if op == "+" && (left_ty == Ty::Str || right_ty == Ty::Str) {
+            let l = coerce(l.clone(), &left_ty, &Ty::Str).or_else(|| coerce(l, &left_ty, &Ty::Value))?;
+            let r = coerce(r.clone(), &right_ty, &Ty::Str).or_else(|| coerce(r, &right_ty, &Ty::Value))?;
+            return Some((format!("format!(\"{{}}{{}}\", {}, {})", l, r), Ty::Str));
+        }
+        if left_ty != right_ty {
+            return None;
+        }
+        Some(match (op, left_ty) {
+            ("+" | "-" | "*", Ty::Number) => (format!("({} {} {})", l, op, r), Ty::Number),
+            ("/", Ty::Number) => (format!("divide({}, {})?", l, r), Ty::Number),
+            ("<" | "<=" | ">" | ">=", Ty::Number) => (format!("({} {} {})", l, op, r), Ty::Bool),
+            ("==" | "!=", _) => (format!("({} {} {})", l, op, r), Ty::Bool),
+            ("and", Ty::Bool) => (format!("({} && {})", l, r), Ty::Bool),
+            ("or", Ty::Bool) => (format!("({} || {})", l, r), Ty::Bool),
+            _ => return None,
+        })
+    }
+
+    // Calls go straight to the callee's native code while the global still holds that function
+    fn call(&self, name: &str, args: &[Expr]) -> Option<(String, Ty)> {
+        if self.scope.names.contains_key(name) {
+            return None;
+        }
+        let (id, (params, ret)) = self.natives.get(name)?;
+        if args.len() != params.len() {
+            return None;
+        }
+        let mut values = Vec::new();
+        for (arg, param) in args.iter().zip(params) {
+            let (code, ty) = self.expr(arg)?;
+            values.push(format!("{}, ", coerce(code, &ty, param)?));
+        }
+        let direct: Vec<String> = (0..args.len()).map(|i| format!("args.{}", i)).collect();
+        let boxed: Vec<String> = (0..args.len()).map(|i| format!("args.{}.boxed()", i)).collect();
+        let code = format!(
+            "{{ let args = ({}); if defines({:?}, {}) {{ enter({:?}, || {}_native({})) }} else {{ call(&Env::new(0), None, {:?}, (vec![{}], Vec::new())).and_then(|value| unboxed(value, {:?})) }} }}?",
+            values.concat(),
+            name,
+            id,
+            name,
+            id,
+            direct.join(", "),
+            name,
+            boxed.join(", "),
+            ret.name()
+        );
+        Some((code, ret.clone()))
+    }
+
+    fn method(&self, target: &Expr, method: &str, args: &[Expr]) -> Option<(String, Ty)> {
+        let (receiver, ty) = self.place(target)?;
+        let mut values = Vec::new();
+        for arg in args {
+            values.push(self.expr(arg)?);
+        }
+        Some(match (&ty, method, values.as_slice()) {
+            (Ty::Number, "abs" | "ceil" | "floor" | "round" | "sqrt", []) => (format!("{}.{}()", receiver, method), Ty::Number),
+            (Ty::Number, "pow" | "min" | "max", [(arg, Ty::Number)]) => {
+                let method = if method == "pow" { "powf" } else { method };
+                (format!("{}.{}({})", receiver, method, arg), Ty::Number)
+            }
+            (Ty::Number, "to_str", []) => (format!("{}.to_string()", receiver), Ty::Str),
+            (Ty::Str, "len", []) => (format!("({}.chars().count() as f64)", receiver), Ty::Number),
+            (Ty::Str, "upper" | "lower", []) => (format!("{}.to_{}case()", receiver, method), Ty::Str),
+            (Ty::Str, "trim", []) => (format!("{}.trim().to_string()", receiver), Ty::Str),
+            (Ty::Str, "contains" | "starts_with" | "ends_with", [(arg, Ty::Str)]) => (format!("{}.{}(&*{})", receiver, method, arg), Ty::Bool),
+            (Ty::List(_), "len", []) => (format!("({}.len() as f64)", receiver), Ty::Number),
+            (Ty::List(_), "is_empty", []) => (format!("{}.is_empty()", receiver), Ty::Bool),
+            _ => return None,
+        })
     }
 }
@@ -0,0 +1,767 @@
+use crate::ast::*;
+use crate::methods;
+use crate::resolver;
+use crate::utils;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+const PRELUDE: &str = include_str!("prelude.c");
+const SOURCE: &str = "velvet_out.c";
+const BINARY: &str = "velvet_out";
+pub const OUTPUTS: &[&str] = &[SOURCE, BINARY];
+
+pub fn compile(statements: Vec<Statement>) -> Result<(), String> {
+    let mut codegen = Codegen::default();
+    let (statements, scope) = resolver::program(&statements);
+    resolver::declarations(&statements, &mut codegen.declared);
+    let mut ctx = Context::new(&scope.names, false);
+    let body = codegen.block(&mut ctx, &statements)?;
+    let source = format!(
+        "{}\n{}static void run(Env *env) {{\n{}{}}}\n\nint main(void) {{\n    setvbuf(stdout, NULL, _IOLBF, 0);\n    run(env_new({}));\n    return 0;\n}}\n",
+        PRELUDE,
+        codegen.items,
+        ctx.temps(),
+        body,
+        scope.len
+    );
+    utils::write_file(SOURCE, &source)?;
+    let status = Command::new("cc")
+        .args(["-std=c99", "-O2", SOURCE, "-o", BINARY, "-lm"])
+        .status()
+        .map_err(|e| format!("Cannot run cc: {}", e))?;
+    if !status.success() {
+        return Err(format!("cc failed on {}", SOURCE));
+    }
+    Ok(())
+}
+
+fn unsupported<T>(feature: &str) -> Result<T, String> {
+    Err(format!("{} is not supported by the C backend", feature))
+}
+
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            // Escaped so '??' never forms a trigraph
+            b'?' => out.push_str("\\?"),
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out + "\""
+}
+
+fn c_number(n: f64) -> String {
+    if n.is_nan() {
+        "NAN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "HUGE_VAL" } else { "-HUGE_VAL" }.to_string()
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+fn literal(expr: &Expr) -> bool {
+    matches!(expr, Expr::String(_) | Expr::Number(_) | Expr::Bool(_))
+}
+
+fn values(values: &[String]) -> String {
+    if values.is_empty() {
+        return "0, NULL".to_string();
+    }
+    format!("{}, (Value[]){{{}}}", values.len(), values.join(", "))
+}
+
+fn mutability(mutable: bool) -> &'static str {
+    if mutable {
+        "MUTABLE"
+    } else {
+        "IMMUTABLE"
+    }
+}
+
+#[derive(Default)]
+struct Codegen {
+    items: String,
+    next_id: usize,
+    modules: HashMap<String, String>,
+    strings: HashMap<String, String>,
+    declared: Vec<String>,
+    propagates: bool,
+}
+
+enum Region {
+    Loop,
+    Try,
+}
+
+// One C function's worth of state: `t[]` holds the temporaries that fix evaluation order
+struct Context {
+    names: HashMap<String, usize>,
+    regions: Vec<Region>,
+    indent: usize,
+    function: bool,
+    temps: usize,
+}
+
+impl Context {
+    fn new(names: &HashMap<String, usize>, function: bool) -> Self {
+        Context { names: names.clone(), regions: Vec::new(), indent: 1, function, temps: 0 }
+    }
+
+    fn line(&self, code: &str) -> String {
+        format!("{}{}\n", "    ".repeat(self.indent), code)
+    }
+
+    fn slot(&self, name: &str) -> String {
+        match self.names.get(name) {
+            Some(slot) => slot.to_string(),
+            None => "-1".to_string(),
+        }
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("t[{}]", self.temps - 1)
+    }
+
+    fn temps(&self) -> String {
+        format!("    Value t[{}];\n", self.temps.max(1))
+    }
+
+    fn in_try(&self) -> bool {
+        self.regions.iter().any(|region| matches!(region, Region::Try))
+    }
+
+    // Inside a try region the signal goes to its handler; a function body returns it instead
+    fn fail(&self, signal: &str) -> String {
+        if self.in_try() || !self.function {
+            format!("resignal({})", signal)
+        } else {
+            format!("return unwind({})", signal)
+        }
+    }
+
+    fn raise(&self, kind: &str, message: &str) -> String {
+        format!("error({}, \"%s\", {})", c_string(kind), c_string(message))
+    }
+
+    fn jump(&self, keyword: &str, signal: &str) -> String {
+        match self.regions.last() {
+            Some(Region::Loop) => keyword.to_string(),
+            _ => self.fail(&format!("signal_of({})", signal)),
+        }
+    }
+
+    fn dispatch(&self, result: &str) -> String {
+        let handled = match self.regions.last() {
+            Some(Region::Loop) => format!(
+                "if ({0}.kind == SIGNAL_BREAK) break; if ({0}.kind == SIGNAL_CONTINUE) continue; {1};",
+                result,
+                self.fail(result)
+            ),
+            _ => format!("{};", self.fail(result)),
+        };
+        self.line(&format!("if ({}.kind != SIGNAL_NONE) {{ {} }}", result, handled))
+    }
+}
+
+impl Codegen {
+    fn id(&mut self, prefix: &str, name: &str) -> String {
+        self.next_id += 1;
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+        format!("{}{}_{}", prefix, self.next_id, name)
+    }
+
+    fn label(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}", prefix, self.next_id)
+    }
+
+    // String literals are static and never freed
+    fn string(&mut self, s: &str) -> String {
+        if let Some(id) = self.strings.get(s) {
+            return format!("interned(&{})", id);
+        }
+        let id = self.label("s");
+        self.items.push_str(&format!("static Str {} = {{-1, {}}};\n\n", id, c_string(s)));
+        self.strings.insert(s.to_string(), id.clone());
+        format!("interned(&{})", id)
+    }
+
+    fn block(&mut self, ctx: &mut Context, stmts: &[Statement]) -> Result<String, String> {
+        let mut code = String::new();
+        for stmt in stmts {
+            code.push_str(&self.stmt(ctx, stmt)?);
+        }
+        Ok(code)
+    }
+
+    fn nested(&mut self, ctx: &mut Context, stmts: &[Statement], region: Option<Region>) -> Result<String, String> {
+        ctx.indent += 1;
+        let pushed = region.is_some();
+        ctx.regions.extend(region);
+        let code = self.block(ctx, stmts);
+        if pushed {
+            ctx.regions.pop();
+        }
+        ctx.indent -= 1;
+        code
+    }
+
+    // Runs `stmts` under a fresh handler and leaves whatever they raised in the signal `result`
+    fn region(&mut self, ctx: &mut Context, result: &str, stmts: &[Statement], prelude: &str, epilogue: &str) -> Result<String, String> {
+        let handler = self.label("h");
+        let mut code = ctx.line(&format!("Handler {};", handler));
+        code.push_str(&ctx.line(&format!("Signal {};", result)));
+        code.push_str(&ctx.line(&format!("if (setjmp({}.buf) == 0) {{", handler)));
+        code.push_str(&ctx.line(&format!("    push_handler(&{}, env);", handler)));
+        if !prelude.is_empty() {
+            code.push_str(&ctx.line(&format!("    {}", prelude)));
+        }
+        code.push_str(&self.nested(ctx, stmts, Some(Region::Try))?);
+        if !epilogue.is_empty() {
+            code.push_str(&ctx.line(&format!("    {}", epilogue)));
+        }
+        code.push_str(&ctx.line(&format!("    pop_handler(&{});", handler)));
+        code.push_str(&ctx.line(&format!("    {} = no_signal();", result)));
+        code.push_str(&ctx.line("} else {"));
+        code.push_str(&ctx.line(&format!("    {} = caught();", result)));
+        code.push_str(&ctx.line("}"));
+        Ok(code)
+    }
+
+    fn stmt(&mut self, ctx: &mut Context, stmt: &Statement) -> Result<String, String> {
+        Ok(match stmt {
+            Statement::Say(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("{{ Value value = {}; say(value); release(value); }}", value))
+            }
+            Statement::Val(name, expr, type_anno) | Statement::Let(name, expr, type_anno) => {
+                let value = match expr {
+                    Some(expr) => self.mutating(ctx, expr)?,
+                    None if type_anno.as_deref().is_some_and(|t| t.ends_with('?')) => "none()".to_string(),
+                    None => "unset()".to_string(),
+                };
+                let mut code = format!("{{ Value value = {}; ", value);
+                if let Some(type_anno) = type_anno {
+                    code.push_str(&format!("check_type(value, {}); ", c_string(type_anno)));
+                }
+                let mutable = matches!(stmt, Statement::Let(..));
+                ctx.line(&format!("{}env_define(env, {}, {}, value, {}); release(value); }}", code, ctx.slot(name), c_string(name), mutability(mutable)))
+            }
+            Statement::Const(name, expr, type_anno) => {
+                let slot = ctx.slot(name);
+                let mut code = ctx.line(&format!("if (env_bound(env, {}, {})) {};", slot, c_string(name), ctx.raise("Error", &format!("Const '{}' redefinition", name))));
+                let mut line = format!("{{ Value value = {}; ", self.expr(ctx, expr)?);
+                if let Some(type_anno) = type_anno {
+                    line.push_str(&format!("check_type(value, {}); ", c_string(type_anno)));
+                }
+                code.push_str(&ctx.line(&format!("{}env_define(env, {}, {}, value, CONST); release(value); }}", line, slot, c_string(name))));
+                code
+            }
+            Statement::Fun(name, params, ret_type, body) => {
+                let function = self.function(name, params, ret_type, body)?;
+                ctx.line(&format!("env_define(env, {}, {}, function_value(&{}), IMMUTABLE);", ctx.slot(name), c_string(name), function))
+            }
+            Statement::Type(name, _) => return unsupported(&format!("Type '{}'", name)),
+            Statement::Trait(name, ..) => return unsupported(&format!("Trait '{}'", name)),
+            Statement::Impl(name, ..) => return unsupported(&format!("Impl for '{}'", name)),
+            Statement::If(condition, then_block, else_block) => {
+                let condition = self.expr(ctx, condition)?;
+                let mut code = ctx.line(&format!("if (truthy({})) {{", condition));
+                code.push_str(&self.nested(ctx, then_block, None)?);
+                if let Some(else_block) = else_block {
+                    code.push_str(&ctx.line("} else {"));
+                    code.push_str(&self.nested(ctx, else_block, None)?);
+                }
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::While(condition, body) => {
+                let condition = self.expr(ctx, condition)?;
+                let mut code = ctx.line(&format!("while (truthy({})) {{", condition));
+                code.push_str(&self.nested(ctx, body, Some(Region::Loop))?);
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::For(target, expr, body) => {
+                let (iter, value) = (self.label("i"), self.label("v"));
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                let source = self.expr(ctx, expr)?;
+                code.push_str(&ctx.line(&format!("Iter {} = iter_of(env, {});", iter, source)));
+                code.push_str(&ctx.line(&format!("Value {};", value)));
+                code.push_str(&ctx.line(&format!("while (iter_next(&{}, &{})) {{", iter, value)));
+                code.push_str(&ctx.line(&format!("    {}", self.bind_item(ctx, target, &value))));
+                code.push_str(&self.nested(ctx, body, Some(Region::Loop))?);
+                code.push_str(&ctx.line("}"));
+                code.push_str(&ctx.line(&format!("iter_end(env, &{});", iter)));
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Break => ctx.line(&format!("{};", ctx.jump("break", "SIGNAL_BREAK"))),
+            Statement::Continue => ctx.line(&format!("{};", ctx.jump("continue", "SIGNAL_CONTINUE"))),
+            Statement::Try(try_block, catches, finally_block) => {
+                let result = self.label("s");
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&self.region(ctx, &result, try_block, "", "")?);
+                if !catches.is_empty() {
+                    let error = self.label("e");
+                    code.push_str(&ctx.line(&format!("if ({}.kind == SIGNAL_ERROR) {{", result)));
+                    ctx.indent += 1;
+                    code.push_str(&ctx.line(&format!("ErrorValue *{} = {}.error;", error, result)));
+                    for (i, (ident, kind, block)) in catches.iter().enumerate() {
+                        let matches = kind.as_ref().map_or("1".to_string(), |kind| format!("error_matches({}, {})", error, c_string(kind)));
+                        code.push_str(&ctx.line(&format!("{}if ({}) {{", if i == 0 { "" } else { "} else " }, matches)));
+                        ctx.indent += 1;
+                        code.push_str(&ctx.line(&format!("env_define(env, {}, {}, error_value({}), IMMUTABLE);", ctx.slot(ident), c_string(ident), error)));
+                        code.push_str(&ctx.line(&format!("begin_catch({});", error)));
+                        let caught = self.label("s");
+                        code.push_str(&self.region(ctx, &caught, block, "", "")?);
+                        code.push_str(&ctx.line("end_catch();"));
+                        code.push_str(&ctx.line(&format!("{} = {};", result, caught)));
+                        ctx.indent -= 1;
+                    }
+                    code.push_str(&ctx.line("}"));
+                    ctx.indent -= 1;
+                    code.push_str(&ctx.line("}"));
+                }
+                if let Some(finally_block) = finally_block {
+                    code.push_str(&self.block(ctx, finally_block)?);
+                }
+                code.push_str(&ctx.dispatch(&result));
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Throw(Some(expr), line) => {
+                let value = self.expr(ctx, expr)?;
+                ctx.line(&format!("{{ Value value = {}; Signal signal = throw_value(value, {}); release(value); {}; }}", value, line, ctx.fail("signal")))
+            }
+            Statement::Throw(None, _) => ctx.line(&format!("{};", ctx.fail("rethrow()"))),
+            Statement::Match(expr, branches) => {
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                let subject = self.expr(ctx, expr)?;
+                code.push_str(&ctx.line(&format!("Value subject = {};", subject)));
+                code.push_str(&ctx.line("Bindings bindings = {0};"));
+                for (i, (pattern, block)) in branches.iter().enumerate() {
+                    let test = format!("if (match_pattern(&{}, subject, &bindings)) {{", self.pattern(ctx, pattern));
+                    code.push_str(&ctx.line(&if i == 0 { test } else { format!("}} else {}", test) }));
+                    code.push_str(&ctx.line("    define_all(env, &bindings, IMMUTABLE);"));
+                    code.push_str(&ctx.line("    release(subject);"));
+                    code.push_str(&self.nested(ctx, block, None)?);
+                }
+                if branches.is_empty() {
+                    code.push_str(&ctx.line("release(subject);"));
+                } else {
+                    code.push_str(&ctx.line("} else {"));
+                    code.push_str(&ctx.line("    release(subject);"));
+                    code.push_str(&ctx.line("}"));
+                }
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Unpack(target, expr, mutable) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("{{ Value value = {}; {} release(value); }}", value, self.bind(ctx, target, "value", mutability(*mutable), false)))
+            }
+            Statement::Assign(target, expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("{{ Value value = {}; {} release(value); }}", value, self.bind(ctx, target, "value", "", true)))
+            }
+            Statement::Expr(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("release({});", value))
+            }
+            Statement::Return(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                if ctx.function && !ctx.in_try() {
+                    ctx.line(&format!("return {};", value))
+                } else {
+                    ctx.line(&format!("{};", ctx.fail(&format!("returned({})", value))))
+                }
+            }
+            Statement::Yield(_) => ctx.line(&format!("{};", ctx.raise("Error", "'yield' is only allowed inside a function"))),
+            Statement::Import(module, _) => {
+                let path = format!("{}.velvet", module);
+                if !Path::new(&path).exists() {
+                    return Ok(ctx.line(&format!("{};", ctx.raise("ImportError", &format!("Module '{}' not found", module)))));
+                }
+                let id = match self.modules.get(module) {
+                    Some(id) => id.clone(),
+                    None => {
+                        let id = self.id("module", module);
+                        self.modules.insert(module.clone(), id.clone());
+                        let source = utils::read_file(&path)?;
+                        let (ast, scope) = resolver::program(&crate::parser::parse(&source)?);
+                        resolver::declarations(&ast, &mut self.declared);
+                        let mut inner = Context::new(&scope.names, false);
+                        let body = self.block(&mut inner, &ast)?;
+                        self.items.push_str(&format!("static void {}(void) {{\n    Env *env = env_new({});\n{}{}    env_leave(env);\n}}\n\n", id, scope.len, inner.temps(), body));
+                        id
+                    }
+                };
+                ctx.line(&format!("{}();", id))
+            }
+            Statement::Test(_, body) => {
+                let (globals, outer, result) = (self.label("g"), self.label("env"), self.label("s"));
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&format!("Globals *{} = snapshot();", globals)));
+                code.push_str(&ctx.line(&format!("Env *{} = env;", outer)));
+                code.push_str(&self.region(ctx, &result, body, &format!("Env *env = env_clone({});", outer), "env_leave(env);")?);
+                code.push_str(&ctx.line(&format!("restore({});", globals)));
+                code.push_str(&ctx.dispatch(&result));
+                ctx.indent -= 1;
+                code.push_str(&ctx.line("}"));
+                code
+            }
+            Statement::Attributed(attributes, _) => {
+                let name = attributes.first().map_or("", |attribute| attribute.name.as_str());
+                return unsupported(&format!("Attribute '#[{}]'", name));
+            }
+        })
+    }
+
+    // Unpacks `value` into `target` and declares (or assigns) every name it binds
+    fn bind(&mut self, ctx: &Context, target: &Target, value: &str, mutability: &str, assign: bool) -> String {
+        match target {
+            Target::Name(name) if name == "_" => String::new(),
+            Target::Name(name) if assign => format!("env_assign(env, {}, {}, {});", ctx.slot(name), c_string(name), value),
+            Target::Name(name) => format!("env_define(env, {}, {}, {}, {});", ctx.slot(name), c_string(name), value, mutability),
+            _ => {
+                let target = self.target(ctx, target);
+                let store = if assign { "assign_all(env, &bindings)".to_string() } else { format!("define_all(env, &bindings, {})", mutability) };
+                format!("{{ Bindings bindings = {{0}}; unpack(&{}, {}, &bindings); {}; }}", target, value, store)
+            }
+        }
+    }
+
+    // Binds a loop variable, which the loop then lets go of
+    fn bind_item(&mut self, ctx: &Context, target: &Target, value: &str) -> String {
+        let bind = self.bind(ctx, target, value, "IMMUTABLE", false);
+        let release = format!("release({});", value);
+        if bind.is_empty() {
+            release
+        } else {
+            format!("{} {}", bind, release)
+        }
+    }
+
+    // Targets and patterns become static data; each returns the name of its definition
+    fn target(&mut self, ctx: &Context, target: &Target) -> String {
+        let value = self.target_value(ctx, target);
+        let id = self.label("target");
+        self.items.push_str(&format!("static const Target {} = {};\n\n", id, value));
+        id
+    }
+
+    fn target_value(&mut self, ctx: &Context, target: &Target) -> String {
+        match target {
+            Target::Name(name) => format!("{{T_NAME, {}, {}, 0, NULL, NULL}}", ctx.slot(name), c_string(name)),
+            Target::Tuple(targets) => format!("{{T_TUPLE, -1, NULL, {}, {}, NULL}}", targets.len(), self.targets(ctx, targets)),
+            Target::List(targets, rest) => {
+                let items = self.targets(ctx, targets);
+                let rest = match rest {
+                    Some(rest) => format!("&{}", self.target(ctx, &Target::Name(rest.clone()))),
+                    None => "NULL".to_string(),
+                };
+                format!("{{T_LIST, -1, NULL, {}, {}, {}}}", targets.len(), items, rest)
+            }
+        }
+    }
+
+    fn targets(&mut self, ctx: &Context, targets: &[Target]) -> String {
+        if targets.is_empty() {
+            return "NULL".to_string();
+        }
+        let values: Vec<String> = targets.iter().map(|target| self.target_value(ctx, target)).collect();
+        let id = self.label("targets");
+        self.items.push_str(&format!("static const Target {}[] = {{{}}};\n\n", id, values.join(", ")));
+        id
+    }
+
+    fn pattern(&mut self, ctx: &Context, pattern: &Pattern) -> String {
+        let value = match pattern {
+            Pattern::Wildcard => "{P_WILDCARD, -1, NULL, NULL}".to_string(),
+            Pattern::Literal(literal) => format!("{{P_LITERAL, -1, {}, NULL}}", c_string(literal)),
+            Pattern::Bind(name) => format!("{{P_BIND, {}, {}, NULL}}", ctx.slot(name), c_string(name)),
+            Pattern::Variant(name, inner) => {
+                let inner = match inner {
+                    Some(inner) => format!("&{}", self.pattern(ctx, inner)),
+                    None => "NULL".to_string(),
+                };
+                format!("{{P_VARIANT, -1, {}, {}}}", c_string(name), inner)
+            }
+        };
+        let id = self.label("pattern");
+        self.items.push_str(&format!("static const Pattern {} = {};\n\n", id, value));
+        id
+    }
+
+    // Emits the function's code as items and returns the name of its `Function`
+    fn function(&mut self, name: &str, params: &[Param], ret_type: &Option<String>, body: &[Statement]) -> Result<String, String> {
+        let function = resolver::function(params, ret_type, body);
+        if function.generator {
+            return unsupported(&format!("Generator '{}'", name));
+        }
+        let id = self.id("f", name);
+        let outer = std::mem::replace(&mut self.propagates, false);
+        let mut parameters = Vec::new();
+        for (i, param) in function.params.iter().enumerate() {
+            if param.variadic {
+                return unsupported(&format!("Variadic parameter '{}'", param.name));
+            }
+            let fallback = match &param.default {
+                Some(default) => {
+                    let mut ctx = Context::new(&function.scope.names, true);
+                    let value = self.expr(&mut ctx, default)?;
+                    self.items.push_str(&format!("static Value {}_default{}(Env *env) {{\n{}    return {};\n}}\n\n", id, i, ctx.temps(), value));
+                    format!("{}_default{}", id, i)
+                }
+                None => "NULL".to_string(),
+            };
+            parameters.push(format!("{{{}, {}, {}}}", c_string(&param.name), c_string(&param.type_anno), fallback));
+        }
+        let mut ctx = Context::new(&function.scope.names, true);
+        let body = self.block(&mut ctx, &function.body)?;
+        let propagates = std::mem::replace(&mut self.propagates, outer);
+        self.items.push_str(&format!("static Value {}(Env *env) {{\n{}{}    return unset();\n}}\n\n", id, ctx.temps(), body));
+        let params = if parameters.is_empty() {
+            "NULL".to_string()
+        } else {
+            self.items.push_str(&format!("static const Parameter {}_params[] = {{{}}};\n\n", id, parameters.join(", ")));
+            format!("{}_params", id)
+        };
+        let ret_type = function.ret_type.as_deref().map_or("NULL".to_string(), c_string);
+        self.items.push_str(&format!(
+            "static const Function {}_function = {{{}, {}, {}, {}, {}, {}}};\n\n",
+            id,
+            params,
+            parameters.len(),
+            function.scope.len,
+            ret_type,
+            id,
+            propagates as u8
+        ));
+        Ok(format!("{}_function", id))
+    }
+
+    // Evaluates `exprs` left to right into temporaries, since C leaves argument order open;
+    // returns the assignments to run first and the operands to use afterwards
+    fn sequence(&mut self, ctx: &mut Context, exprs: &[&Expr]) -> Result<(String, Vec<String>), String> {
+        let mut code = String::new();
+        let mut operands = Vec::new();
+        for expr in exprs {
+            let value = self.expr(ctx, expr)?;
+            if literal(expr) {
+                operands.push(value);
+            } else {
+                let temp = ctx.temp();
+                code.push_str(&format!("{} = {}, ", temp, value));
+                operands.push(temp);
+            }
+        }
+        Ok((code, operands))
+    }
+
+    // Runs `call` after `code` and releases the temporaries among `operands` once it has used them
+    fn consume(&self, ctx: &mut Context, code: &str, operands: &[String], call: &str) -> String {
+        let temps: Vec<&String> = operands.iter().filter(|operand| operand.starts_with("t[")).collect();
+        if temps.is_empty() {
+            return format!("({}{})", code, call);
+        }
+        let result = ctx.temp();
+        let releases: String = temps.iter().map(|temp| format!("release({}), ", temp)).collect();
+        format!("({}{} = {}, {}{})", code, result, call, releases, result)
+    }
+
+    fn args<'a>(&self, args: &'a [Expr]) -> Result<Vec<&'a Expr>, String> {
+        args.iter()
+            .map(|arg| match arg {
+                Expr::Named(name, _) => unsupported(&format!("Named argument '{}'", name)),
+                arg => Ok(arg),
+            })
+            .collect()
+    }
+
+    // Statement-level method calls on a variable may change it in place
+    fn mutating(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        if let Expr::MethodCall(target, method, args) = expr {
+            if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                let receiver = ctx.temp();
+                let value = self.expr(ctx, target)?;
+                let (args, mut operands) = self.sequence(ctx, &self.args(args)?)?;
+                let (method, values) = (c_string(method), values(&operands));
+                // The receiver lets go of the list first so that env_mutate need not copy it
+                let call = format!(
+                    "mutates({0}, {1}) ? (release({0}), {0} = none(), env_mutate(env, {2}, {3}, {1}, {4})) : call_method({0}, {1}, {4})",
+                    receiver,
+                    method,
+                    ctx.slot(name),
+                    c_string(name),
+                    values
+                );
+                let code = format!("{} = {}, {}", receiver, value, args);
+                operands.push(receiver);
+                return Ok(self.consume(ctx, &code, &operands, &call));
+            }
+        }
+        self.expr(ctx, expr)
+    }
+
+    fn expr(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        Ok(match expr {
+            Expr::String(s) => self.string(s),
+            Expr::Number(n) => format!("num({})", c_number(*n)),
+            Expr::Bool(b) => format!("boolean({})", *b as u8),
+            Expr::Ident(id) if id == "none" => "env_read_none(env)".to_string(),
+            Expr::Ident(id) => format!("env_read(env, -1, {})", c_string(id)),
+            Expr::Local(slot, id) => format!("env_read(env, {}, {})", slot, c_string(id)),
+            Expr::Binary(left, op, right) if op == "??" => {
+                let value = ctx.temp();
+                let left = self.expr(ctx, left)?;
+                format!("({0} = {1}, coalesce(&{0}) ? {2} : {0})", value, left, self.expr(ctx, right)?)
+            }
+            Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+                let left = self.expr(ctx, left)?;
+                format!("boolean(truthy({}) {} truthy({}))", left, if op == "and" { "&&" } else { "||" }, self.expr(ctx, right)?)
+            }
+            Expr::Binary(left, op, right) => {
+                let (code, operands) = self.sequence(ctx, &[left, right])?;
+                self.consume(ctx, &code, &operands, &format!("binary({}, {}, {})", c_string(op), operands[0], operands[1]))
+            }
+            Expr::Unary(op, inner) => format!("unary({}, {})", c_string(op), self.expr(ctx, inner)?),
+            Expr::Call(name, args) => {
+                if (name == "iter" || methods::ITERATOR_METHODS.contains(&name.as_str())) && !self.declared.contains(name) && !ctx.names.contains_key(name) {
+                    return unsupported(&format!("Builtin '{}'", name));
+                }
+                let (code, operands) = self.sequence(ctx, &self.args(args)?)?;
+                self.consume(ctx, &code, &operands, &format!("call(env, {}, {}, {})", ctx.slot(name), c_string(name), values(&operands)))
+            }
+            Expr::Named(name, _) => format!("({}, none())", ctx.raise("ArgumentError", &format!("Named argument '{}' is only allowed in a call", name))),
+            Expr::MethodCall(target, method, args) => {
+                let receiver = ctx.temp();
+                let mut code = format!("{} = {}, ", receiver, self.expr(ctx, target)?);
+                if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                    code.push_str(&format!("forbid_mutation({}, {}, {}), ", receiver, c_string(method), c_string(name)));
+                }
+                let (args, mut operands) = self.sequence(ctx, &self.args(args)?)?;
+                let call = format!("call_method({}, {}, {})", receiver, c_string(method), values(&operands));
+                operands.push(receiver);
+                self.consume(ctx, &(code + &args), &operands, &call)
+            }
+            Expr::List(elements) | Expr::Tuple(elements) => {
+                let elements: Vec<&Expr> = elements.iter().collect();
+                let (code, operands) = self.sequence(ctx, &elements)?;
+                let build = if matches!(expr, Expr::List(_)) { "list_of" } else { "tuple_of" };
+                self.consume(ctx, &code, &operands, &format!("{}({})", build, values(&operands)))
+            }
+            Expr::Map(_) => return unsupported("Map literal"),
+            Expr::MapComp(..) => return unsupported("Map comprehension"),
+            Expr::ListComp(element, clauses) => {
+                let id = self.label("comprehension");
+                let mut inner = Context::new(&ctx.names, ctx.function);
+                let body = self.clauses(&mut inner, clauses, element)?;
+                self.items.push_str(&format!(
+                    "static Value {}(Env *outer) {{\n{}    Env *env = env_clone(outer);\n    List *items = list_new(0);\n{}    env_leave(env);\n    return list_value(items);\n}}\n\n",
+                    id,
+                    inner.temps(),
+                    body
+                ));
+                format!("{}(env)", id)
+            }
+            Expr::Index(target, index) => {
+                let (code, operands) = self.sequence(ctx, &[target, index])?;
+                self.consume(ctx, &code, &operands, &format!("index_value({}, {})", operands[0], operands[1]))
+            }
+            Expr::Slice(target, start, end, step) => {
+                let target_value = ctx.temp();
+                let mut code = format!("{} = {}, ", target_value, self.expr(ctx, target)?);
+                let mut bounds = Vec::new();
+                for bound in [start, end, step] {
+                    match bound {
+                        Some(bound) => {
+                            let temp = ctx.temp();
+                            code.push_str(&format!("{0} = {1}, number({0}), ", temp, self.expr(ctx, bound)?));
+                            bounds.push(format!("&{}", temp));
+                        }
+                        None => bounds.push("NULL".to_string()),
+                    }
+                }
+                let call = format!("slice({}, {})", target_value, bounds.join(", "));
+                self.consume(ctx, &code, &[target_value], &call)
+            }
+            Expr::Field(target, field) | Expr::SafeField(target, field) => {
+                let (code, operands) = self.sequence(ctx, &[target])?;
+                let access = if matches!(expr, Expr::Field(..)) { "get_field" } else { "safe_field" };
+                self.consume(ctx, &code, &operands, &format!("{}({}, {})", access, operands[0], c_string(field)))
+            }
+            Expr::Propagate(inner) => {
+                self.propagates = true;
+                let (code, operands) = self.sequence(ctx, &[inner])?;
+                self.consume(ctx, &code, &operands, &format!("propagate({})", operands[0]))
+            }
+            Expr::If(condition, then_expr, else_expr) => {
+                let condition = self.expr(ctx, condition)?;
+                let then_expr = self.expr(ctx, then_expr)?;
+                format!("(truthy({}) ? {} : {})", condition, then_expr, self.expr(ctx, else_expr)?)
+            }
+            Expr::Match(subject, arms) => {
+                let id = self.label("match");
+                let mut inner = Context::new(&ctx.names, ctx.function);
+                let mut body = String::from("    Bindings bindings = {0};\n");
+                for (pattern, arm) in arms {
+                    let pattern = self.pattern(&inner, pattern);
+                    let arm = self.expr(&mut inner, arm)?;
+                    body.push_str(&format!(
+                        "    if (match_pattern(&{}, subject, &bindings)) {{\n        Env *env = env_clone(outer);\n        define_all(env, &bindings, IMMUTABLE);\n        Value result = {};\n        env_leave(env);\n        return result;\n    }}\n",
+                        pattern, arm
+                    ));
+                }
+                self.items.push_str(&format!("static Value {}(Env *outer, Value subject) {{\n{}{}    return no_match(subject);\n}}\n\n", id, inner.temps(), body));
+                let (code, operands) = self.sequence(ctx, &[subject])?;
+                self.consume(ctx, &code, &operands, &format!("{}(env, {})", id, operands[0]))
+            }
+        })
+    }
+
+    fn clauses(&mut self, ctx: &mut Context, clauses: &[Clause], element: &Expr) -> Result<String, String> {
+        Ok(match clauses.split_first() {
+            None => {
+                let value = self.expr(ctx, element)?;
+                ctx.line(&format!("list_push(items, {});", value))
+            }
+            Some((Clause::If(condition), rest)) => {
+                let condition = self.expr(ctx, condition)?;
+                let mut code = ctx.line(&format!("if (truthy({})) {{", condition));
+                ctx.indent += 1;
+                code.push_str(&self.clauses(ctx, rest, element)?);
+                ctx.indent -= 1;
+                code + &ctx.line("}")
+            }
+            Some((Clause::For(target, expr), rest)) => {
+                let (iter, value) = (self.label("i"), self.label("v"));
+                let source = self.expr(ctx, expr)?;
+                let mut code = ctx.line(&format!("{{ Iter {} = iter_of(env, {}); Value {};", iter, source, value));
+                code.push_str(&ctx.line(&format!("while (iter_next(&{}, &{})) {{", iter, value)));
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&self.bind_item(ctx, target, &value)));
+                code.push_str(&self.clauses(ctx, rest, element)?);
+                ctx.indent -= 1;
+                code + &ctx.line(&format!("}} iter_end(env, &{}); }}", iter))
+            }
+        })
+    }
+}
@@ -0,0 +1,591 @@
+use crate::ast::*;
+use crate::resolver;
+use crate::utils;
+use std::collections::HashMap;
+use std::path::Path;
+
+const PRELUDE: &str = include_str!("prelude.js");
+const MODULE: &str = "velvet_out.mjs";
+pub const OUTPUTS: &[&str] = &[MODULE];
+
+pub fn compile(statements: Vec<Statement>) -> Result<(), String> {
+    let mut codegen = Codegen::default();
+    let (statements, scope) = resolver::program(&statements);
+    resolver::declarations(&statements, &mut codegen.declared);
+    let mut ctx = Context::new(&scope.names, false);
+    let body = codegen.block(&mut ctx, &statements)?;
+    let mut exports = Vec::new();
+    for name in resolver::exports(&statements) {
+        let id = codegen.id("export", &name);
+        codegen.items.push_str(&format!("function {}(...args) {{\n    return exported({}, args);\n}}\n\n", id, js_string(&name)));
+        exports.push((id, name));
+    }
+    let mut source = format!("{}\n{}function run(env) {{\n{}}}\n\nstart(() => run(new Env({})));\n", PRELUDE, codegen.items, body, scope.len);
+    if !exports.is_empty() {
+        let names: Vec<String> = exports.iter().map(|(id, name)| format!("{} as {}", id, name)).collect();
+        source.push_str(&format!("export {{ {} }};\n", names.join(", ")));
+    }
+    utils::write_file(MODULE, &source)
+}
+
+fn unsupported<T>(feature: &str) -> Result<T, String> {
+    Err(format!("{} is not supported by the JS backend", feature))
+}
+
+fn js_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{0}'..='\u{1f}' | '\u{7f}' | '\u{2028}' | '\u{2029}' => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+fn js_number(n: f64) -> String {
+    if n.is_nan() {
+        "NaN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+fn mutability(mutable: bool) -> &'static str {
+    if mutable {
+        "MUTABLE"
+    } else {
+        "IMMUTABLE"
+    }
+}
+
+#[derive(Default)]
+struct Codegen {
+    items: String,
+    next_id: usize,
+    modules: HashMap<String, String>,
+    declared: Vec<String>,
+}
+
+// One JS function's worth of state; break and continue stay native inside its loops
+struct Context {
+    names: HashMap<String, usize>,
+    loops: usize,
+    indent: usize,
+    function: bool,
+    generator: bool,
+}
+
+impl Context {
+    fn new(names: &HashMap<String, usize>, function: bool) -> Self {
+        Context { names: names.clone(), loops: 0, indent: 1, function, generator: false }
+    }
+
+    fn line(&self, code: &str) -> String {
+        format!("{}{}\n", "    ".repeat(self.indent), code)
+    }
+
+    fn slot(&self, name: &str) -> String {
+        match self.names.get(name) {
+            Some(slot) => slot.to_string(),
+            None => "-1".to_string(),
+        }
+    }
+
+    fn raise(&self, kind: &str, message: &str) -> String {
+        format!("error({}, {})", js_string(kind), js_string(message))
+    }
+
+    fn jump(&self, keyword: &str, signal: &str) -> String {
+        if self.loops > 0 {
+            format!("{};", keyword)
+        } else {
+            format!("throw new Signal({});", signal)
+        }
+    }
+}
+
+impl Codegen {
+    fn id(&mut self, prefix: &str, name: &str) -> String {
+        self.next_id += 1;
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+        format!("{}{}_{}", prefix, self.next_id, name)
+    }
+
+    fn label(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}{}", prefix, self.next_id)
+    }
+
+    fn block(&mut self, ctx: &mut Context, stmts: &[Statement]) -> Result<String, String> {
+        let mut code = String::new();
+        for stmt in stmts {
+            code.push_str(&self.stmt(ctx, stmt)?);
+        }
+        Ok(code)
+    }
+
+    fn nested(&mut self, ctx: &mut Context, stmts: &[Statement], in_loop: bool) -> Result<String, String> {
+        ctx.indent += 1;
+        ctx.loops += in_loop as usize;
+        let code = self.block(ctx, stmts);
+        ctx.loops -= in_loop as usize;
+        ctx.indent -= 1;
+        code
+    }
+
+    fn stmt(&mut self, ctx: &mut Context, stmt: &Statement) -> Result<String, String> {
+        Ok(match stmt {
+            Statement::Say(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("say({});", value))
+            }
+            Statement::Val(name, expr, type_anno) | Statement::Let(name, expr, type_anno) => {
+                let value = match expr {
+                    Some(expr) => self.mutating(ctx, expr)?,
+                    None if type_anno.as_deref().is_some_and(|t| t.ends_with('?')) => "null".to_string(),
+                    None => "UNSET".to_string(),
+                };
+                let mut code = format!("{{ const value = {}; ", value);
+                if let Some(type_anno) = type_anno {
+                    code.push_str(&format!("checkType(value, {}); ", js_string(type_anno)));
+                }
+                let mutable = matches!(stmt, Statement::Let(..));
+                ctx.line(&format!("{}env.define({}, {}, value, {}); }}", code, ctx.slot(name), js_string(name), mutability(mutable)))
+            }
+            Statement::Const(name, expr, type_anno) => {
+                let slot = ctx.slot(name);
+                let mut code = ctx.line(&format!("if (env.bound({}, {})) {{ {}; }}", slot, js_string(name), ctx.raise("Error", &format!("Const '{}' redefinition", name))));
+                let mut line = format!("{{ const value = {}; ", self.expr(ctx, expr)?);
+                if let Some(type_anno) = type_anno {
+                    line.push_str(&format!("checkType(value, {}); ", js_string(type_anno)));
+                }
+                code.push_str(&ctx.line(&format!("{}env.define({}, {}, value, CONST); }}", line, slot, js_string(name))));
+                code
+            }
+            Statement::Fun(name, params, ret_type, body) => {
+                let function = self.function(name, params, ret_type, body)?;
+                ctx.line(&format!("env.define({}, {}, {}, IMMUTABLE);", ctx.slot(name), js_string(name), function))
+            }
+            Statement::Type(name, _) => return unsupported(&format!("Type '{}'", name)),
+            Statement::Trait(name, ..) => return unsupported(&format!("Trait '{}'", name)),
+            Statement::Impl(name, ..) => return unsupported(&format!("Impl for '{}'", name)),
+            Statement::If(condition, then_block, else_block) => {
+                let condition = self.expr(ctx, condition)?;
+                let mut code = ctx.line(&format!("if (truthy({})) {{", condition));
+                code.push_str(&self.nested(ctx, then_block, false)?);
+                if let Some(else_block) = else_block {
+                    code.push_str(&ctx.line("} else {"));
+                    code.push_str(&self.nested(ctx, else_block, false)?);
+                }
+                code + &ctx.line("}")
+            }
+            Statement::While(condition, body) => {
+                let condition = self.expr(ctx, condition)?;
+                let mut code = ctx.line(&format!("while (truthy({})) {{", condition));
+                code.push_str(&self.nested(ctx, body, true)?);
+                code + &ctx.line("}")
+            }
+            Statement::For(target, expr, body) => {
+                let item = self.label("item");
+                let source = self.expr(ctx, expr)?;
+                let mut code = ctx.line(&format!("for (const {} of iterate({})) {{", item, source));
+                code.push_str(&ctx.line(&format!("    {}", self.bind(ctx, target, &item, "IMMUTABLE", false))));
+                code.push_str(&self.nested(ctx, body, true)?);
+                code + &ctx.line("}")
+            }
+            Statement::Break => ctx.line(&ctx.jump("break", "BREAK")),
+            Statement::Continue => ctx.line(&ctx.jump("continue", "CONTINUE")),
+            Statement::Try(try_block, catches, finally_block) => {
+                let mut code = ctx.line("try {");
+                code.push_str(&self.nested(ctx, try_block, false)?);
+                if !catches.is_empty() {
+                    let (signal, error) = (self.label("signal"), self.label("error"));
+                    code.push_str(&ctx.line(&format!("}} catch ({}) {{", signal)));
+                    ctx.indent += 1;
+                    code.push_str(&ctx.line(&format!("const {} = caught({});", error, signal)));
+                    for (i, (ident, kind, block)) in catches.iter().enumerate() {
+                        let matches = kind.as_ref().map_or("true".to_string(), |kind| format!("errorMatches({}, {})", error, js_string(kind)));
+                        code.push_str(&ctx.line(&format!("{}if ({}) {{", if i == 0 { "" } else { "} else " }, matches)));
+                        ctx.indent += 1;
+                        code.push_str(&ctx.line(&format!("env.define({}, {}, {}, IMMUTABLE);", ctx.slot(ident), js_string(ident), error)));
+                        code.push_str(&ctx.line(&format!("handling.push({});", error)));
+                        code.push_str(&ctx.line("try {"));
+                        code.push_str(&self.nested(ctx, block, false)?);
+                        code.push_str(&ctx.line("} finally {"));
+                        code.push_str(&ctx.line("    handling.pop();"));
+                        code.push_str(&ctx.line("}"));
+                        ctx.indent -= 1;
+                    }
+                    code.push_str(&ctx.line("} else {"));
+                    code.push_str(&ctx.line(&format!("    throw {};", signal)));
+                    code.push_str(&ctx.line("}"));
+                    ctx.indent -= 1;
+                }
+                code.push_str(&ctx.line("} finally {"));
+                if let Some(finally_block) = finally_block {
+                    code.push_str(&self.nested(ctx, finally_block, false)?);
+                }
+                code + &ctx.line("}")
+            }
+            Statement::Throw(Some(expr), line) => {
+                let value = self.expr(ctx, expr)?;
+                ctx.line(&format!("throw throwValue({}, {});", value, line))
+            }
+            Statement::Throw(None, _) => ctx.line("throw rethrow();"),
+            Statement::Match(expr, branches) => {
+                let subject = self.expr(ctx, expr)?;
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&format!("const subject = {};", subject)));
+                code.push_str(&ctx.line("const bindings = [];"));
+                for (i, (pattern, block)) in branches.iter().enumerate() {
+                    let test = format!("if (matchPattern({}, subject, bindings)) {{", self.pattern(ctx, pattern));
+                    code.push_str(&ctx.line(&if i == 0 { test } else { format!("}} else {}", test) }));
+                    code.push_str(&ctx.line("    env.defineAll(bindings, IMMUTABLE);"));
+                    code.push_str(&self.nested(ctx, block, false)?);
+                }
+                if !branches.is_empty() {
+                    code.push_str(&ctx.line("}"));
+                }
+                ctx.indent -= 1;
+                code + &ctx.line("}")
+            }
+            Statement::Unpack(target, expr, mutable) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("{{ const value = {}; {} }}", value, self.bind(ctx, target, "value", mutability(*mutable), false)))
+            }
+            Statement::Assign(target, expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("{{ const value = {}; {} }}", value, self.bind(ctx, target, "value", "", true)))
+            }
+            Statement::Expr(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("{};", value))
+            }
+            Statement::Return(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                if ctx.function {
+                    ctx.line(&format!("return {};", value))
+                } else {
+                    ctx.line(&format!("throw returned({});", value))
+                }
+            }
+            Statement::Yield(expr) if ctx.generator => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("yield {};", value))
+            }
+            Statement::Yield(_) => ctx.line(&format!("{};", ctx.raise("Error", "'yield' is only allowed inside a function"))),
+            Statement::Import(module, _) => {
+                let path = format!("{}.velvet", module);
+                if !Path::new(&path).exists() {
+                    return Ok(ctx.line(&format!("{};", ctx.raise("ImportError", &format!("Module '{}' not found", module)))));
+                }
+                let id = match self.modules.get(module) {
+                    Some(id) => id.clone(),
+                    None => {
+                        let id = self.id("module", module);
+                        self.modules.insert(module.clone(), id.clone());
+                        let source = utils::read_file(&path)?;
+                        let (ast, scope) = resolver::program(&crate::parser::parse(&source)?);
+                        resolver::declarations(&ast, &mut self.declared);
+                        let mut inner = Context::new(&scope.names, false);
+                        let body = self.block(&mut inner, &ast)?;
+                        self.items.push_str(&format!("function {}() {{\n    const env = new Env({});\n{}}}\n\n", id, scope.len, body));
+                        id
+                    }
+                };
+                ctx.line(&format!("{}();", id))
+            }
+            Statement::Test(_, body) => {
+                let (globals, outer) = (self.label("globals"), self.label("outer"));
+                let mut code = ctx.line("{");
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&format!("const {} = snapshot();", globals)));
+                code.push_str(&ctx.line(&format!("const {} = env;", outer)));
+                code.push_str(&ctx.line("try {"));
+                code.push_str(&ctx.line(&format!("    const env = {}.clone();", outer)));
+                code.push_str(&self.nested(ctx, body, false)?);
+                code.push_str(&ctx.line("} finally {"));
+                code.push_str(&ctx.line(&format!("    restore({});", globals)));
+                code.push_str(&ctx.line("}"));
+                ctx.indent -= 1;
+                code + &ctx.line("}")
+            }
+            // `#[inline]` only matters to the optimizer, which has already run
+            Statement::Attributed(attributes, target) => match attributes.iter().find(|a| a.name != "export" && a.name != "inline") {
+                Some(attribute) => return unsupported(&format!("Attribute '#[{}]'", attribute.name)),
+                None => return self.stmt(ctx, target),
+            },
+        })
+    }
+
+    // Unpacks `value` into `target` and declares (or assigns) every name it binds
+    fn bind(&mut self, ctx: &Context, target: &Target, value: &str, mutability: &str, assign: bool) -> String {
+        match target {
+            Target::Name(name) if name == "_" => String::new(),
+            Target::Name(name) if assign => format!("env.assign({}, {}, {});", ctx.slot(name), js_string(name), value),
+            Target::Name(name) => format!("env.define({}, {}, {}, {});", ctx.slot(name), js_string(name), value, mutability),
+            _ => {
+                let target = self.target(ctx, target);
+                let store = if assign { "env.assignAll(bindings)".to_string() } else { format!("env.defineAll(bindings, {})", mutability) };
+                format!("{{ const bindings = []; unpack({}, {}, bindings); {}; }}", target, value, store)
+            }
+        }
+    }
+
+    // Targets and patterns become module constants; each returns the constant's name
+    fn target(&mut self, ctx: &Context, target: &Target) -> String {
+        let value = self.target_value(ctx, target);
+        let id = self.label("target");
+        self.items.push_str(&format!("const {} = {};\n\n", id, value));
+        id
+    }
+
+    fn target_value(&self, ctx: &Context, target: &Target) -> String {
+        match target {
+            Target::Name(name) => format!("{{ kind: \"name\", slot: {}, name: {} }}", ctx.slot(name), js_string(name)),
+            Target::Tuple(targets) => format!("{{ kind: \"tuple\", items: [{}] }}", self.targets(ctx, targets)),
+            Target::List(targets, rest) => {
+                let rest = match rest {
+                    Some(rest) => self.target_value(ctx, &Target::Name(rest.clone())),
+                    None => "null".to_string(),
+                };
+                format!("{{ kind: \"list\", items: [{}], rest: {} }}", self.targets(ctx, targets), rest)
+            }
+        }
+    }
+
+    fn targets(&self, ctx: &Context, targets: &[Target]) -> String {
+        targets.iter().map(|target| self.target_value(ctx, target)).collect::<Vec<_>>().join(", ")
+    }
+
+    fn pattern(&mut self, ctx: &Context, pattern: &Pattern) -> String {
+        let value = self.pattern_value(ctx, pattern);
+        let id = self.label("pattern");
+        self.items.push_str(&format!("const {} = {};\n\n", id, value));
+        id
+    }
+
+    fn pattern_value(&self, ctx: &Context, pattern: &Pattern) -> String {
+        match pattern {
+            Pattern::Wildcard => "{ kind: \"wildcard\" }".to_string(),
+            Pattern::Literal(literal) => format!("{{ kind: \"literal\", text: {} }}", js_string(literal)),
+            Pattern::Bind(name) => format!("{{ kind: \"bind\", slot: {}, name: {} }}", ctx.slot(name), js_string(name)),
+            Pattern::Variant(name, inner) => {
+                let inner = match inner {
+                    Some(inner) => self.pattern_value(ctx, inner),
+                    None => "null".to_string(),
+                };
+                format!("{{ kind: \"variant\", name: {}, inner: {} }}", js_string(name), inner)
+            }
+        }
+    }
+
+    // Emits the function's code as items and returns the name of its `Func`
+    fn function(&mut self, name: &str, params: &[Param], ret_type: &Option<String>, body: &[Statement]) -> Result<String, String> {
+        let function = resolver::function(params, ret_type, body);
+        let id = self.id("f", name);
+        let mut parameters = Vec::new();
+        for param in &function.params {
+            if param.variadic {
+                parameters.push(format!("{{ name: {}, fallback: null, variadic: true }}", js_string(&param.name)));
+                continue;
+            }
+            let fallback = match &param.default {
+                Some(default) => {
+                    let mut ctx = Context::new(&function.scope.names, true);
+                    format!("(env) => {}", self.expr(&mut ctx, default)?)
+                }
+                None => "null".to_string(),
+            };
+            parameters.push(format!("{{ name: {}, fallback: {} }}", js_string(&param.name), fallback));
+        }
+        let mut ctx = Context::new(&function.scope.names, true);
+        ctx.generator = function.generator;
+        let body = self.block(&mut ctx, &function.body)?;
+        let ret_type = function.ret_type.as_deref().map_or("null".to_string(), js_string);
+        self.items.push_str(&format!(
+            "function{} {}(env) {{\n{}}}\n\nconst {}_function = new Func([{}], {}, {}, {}, {});\n\n",
+            if function.generator { "*" } else { "" },
+            id,
+            body,
+            id,
+            parameters.join(", "),
+            function.scope.len,
+            ret_type,
+            id,
+            function.generator
+        ));
+        Ok(format!("{}_function", id))
+    }
+
+    // The trailing arguments of a call; named ones are split from the rest at run time so
+    // every argument is still evaluated in order
+    fn args(&mut self, ctx: &mut Context, args: &[Expr]) -> Result<String, String> {
+        let mut values = Vec::new();
+        for arg in args {
+            match arg {
+                Expr::Named(name, value) => values.push(format!("new Named({}, {})", js_string(name), self.expr(ctx, value)?)),
+                arg => values.push(self.expr(ctx, arg)?),
+            }
+        }
+        if args.iter().any(|arg| matches!(arg, Expr::Named(..))) {
+            return Ok(format!("...split([{}])", values.join(", ")));
+        }
+        Ok(format!("[{}]", values.join(", ")))
+    }
+
+    // Statement-level method calls on a variable may change it in place
+    fn mutating(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        if let Expr::MethodCall(target, method, args) = expr {
+            if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                let receiver = self.expr(ctx, target)?;
+                let args = self.args(ctx, args)?;
+                return Ok(format!("env.mutate({}, {}, {}, {}, {})", ctx.slot(name), js_string(name), receiver, js_string(method), args));
+            }
+        }
+        self.expr(ctx, expr)
+    }
+
+    fn expr(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        Ok(match expr {
+            Expr::String(s) => js_string(s),
+            Expr::Number(n) => js_number(*n),
+            Expr::Bool(b) => b.to_string(),
+            Expr::Ident(id) if id == "none" => "env.readNone()".to_string(),
+            Expr::Ident(id) => format!("env.read(-1, {})", js_string(id)),
+            Expr::Local(slot, id) => format!("env.read({}, {})", slot, js_string(id)),
+            Expr::Binary(left, op, right) if op == "??" => {
+                let left = self.expr(ctx, left)?;
+                format!("coalesce({}, () => {})", left, self.expr(ctx, right)?)
+            }
+            Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+                let left = self.expr(ctx, left)?;
+                format!("(truthy({}) {} truthy({}))", left, if op == "and" { "&&" } else { "||" }, self.expr(ctx, right)?)
+            }
+            Expr::Binary(left, op, right) => {
+                let left = self.expr(ctx, left)?;
+                format!("binary({}, {}, {})", js_string(op), left, self.expr(ctx, right)?)
+            }
+            Expr::Unary(op, inner) => format!("unary({}, {})", js_string(op), self.expr(ctx, inner)?),
+            Expr::Call(name, args) => {
+                format!("call(env, {}, {}, {})", ctx.slot(name), js_string(name), self.args(ctx, args)?)
+            }
+            Expr::Named(name, _) => ctx.raise("ArgumentError", &format!("Named argument '{}' is only allowed in a call", name)),
+            Expr::MethodCall(target, method, args) => {
+                let mut receiver = self.expr(ctx, target)?;
+                if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                    receiver = format!("forbidMutation({}, {}, {})", receiver, js_string(method), js_string(name));
+                }
+                format!("callMethod({}, {}, {})", receiver, js_string(method), self.args(ctx, args)?)
+            }
+            Expr::List(elements) | Expr::Tuple(elements) => {
+                let mut values = Vec::new();
+                for element in elements {
+                    values.push(self.expr(ctx, element)?);
+                }
+                let build = if matches!(expr, Expr::List(_)) { "listOf" } else { "tupleOf" };
+                format!("{}([{}])", build, values.join(", "))
+            }
+            Expr::Map(entries) => {
+                let mut pairs = Vec::new();
+                for (key, value) in entries {
+                    let key = self.expr(ctx, key)?;
+                    pairs.push(format!("[{}, {}]", key, self.expr(ctx, value)?));
+                }
+                format!("mapOf([{}])", pairs.join(", "))
+            }
+            Expr::ListComp(element, clauses) => {
+                let mut inner = Context::new(&ctx.names, ctx.function);
+                let emit = format!("items.push(retain({}));", self.expr(&mut inner, element)?);
+                self.comprehension(&mut inner, clauses, &emit, "items")?
+            }
+            Expr::MapComp(key, value, clauses) => {
+                let mut inner = Context::new(&ctx.names, ctx.function);
+                let key = self.expr(&mut inner, key)?;
+                let emit = format!("insertEntry(items, {}, {});", key, self.expr(&mut inner, value)?);
+                self.comprehension(&mut inner, clauses, &emit, "new MapValue(items)")?
+            }
+            Expr::Index(target, index) => {
+                let target = self.expr(ctx, target)?;
+                format!("indexValue({}, {})", target, self.expr(ctx, index)?)
+            }
+            Expr::Slice(target, start, end, step) => {
+                let mut values = vec![self.expr(ctx, target)?];
+                for bound in [start, end, step] {
+                    match bound {
+                        Some(bound) => values.push(format!("number({})", self.expr(ctx, bound)?)),
+                        None => values.push("UNSET".to_string()),
+                    }
+                }
+                format!("slice({})", values.join(", "))
+            }
+            Expr::Field(target, field) => format!("getField({}, {})", self.expr(ctx, target)?, js_string(field)),
+            Expr::SafeField(target, field) => format!("safeField({}, {})", self.expr(ctx, target)?, js_string(field)),
+            Expr::Propagate(inner) => format!("propagate({})", self.expr(ctx, inner)?),
+            Expr::If(condition, then_expr, else_expr) => {
+                let condition = self.expr(ctx, condition)?;
+                let then_expr = self.expr(ctx, then_expr)?;
+                format!("(truthy({}) ? {} : {})", condition, then_expr, self.expr(ctx, else_expr)?)
+            }
+            Expr::Match(subject, arms) => {
+                let id = self.label("match");
+                let mut inner = Context::new(&ctx.names, ctx.function);
+                let mut body = String::from("    const bindings = [];\n");
+                for (pattern, arm) in arms {
+                    let pattern = self.pattern(&inner, pattern);
+                    let arm = self.expr(&mut inner, arm)?;
+                    body.push_str(&format!(
+                        "    if (matchPattern({}, subject, bindings)) {{\n        const env = outer.clone();\n        env.defineAll(bindings, IMMUTABLE);\n        return {};\n    }}\n",
+                        pattern, arm
+                    ));
+                }
+                self.items.push_str(&format!("function {}(outer, subject) {{\n{}    return noMatch(subject);\n}}\n\n", id, body));
+                format!("{}(env, {})", id, self.expr(ctx, subject)?)
+            }
+        })
+    }
+
+    // Emits the comprehension as a function collecting into `items` and returns its call
+    fn comprehension(&mut self, ctx: &mut Context, clauses: &[Clause], emit: &str, result: &str) -> Result<String, String> {
+        let id = self.label("comprehension");
+        let body = self.clauses(ctx, clauses, emit)?;
+        self.items.push_str(&format!(
+            "function {}(outer) {{\n    const env = outer.clone();\n    const items = [];\n{}    return {};\n}}\n\n",
+            id, body, result
+        ));
+        Ok(format!("{}(env)", id))
+    }
+
+    fn clauses(&mut self, ctx: &mut Context, clauses: &[Clause], emit: &str) -> Result<String, String> {
+        Ok(match clauses.split_first() {
+            None => ctx.line(emit),
+            Some((Clause::If(condition), rest)) => {
+                let condition = self.expr(ctx, condition)?;
+                let mut code = ctx.line(&format!("if (truthy({})) {{", condition));
+                ctx.indent += 1;
+                code.push_str(&self.clauses(ctx, rest, emit)?);
+                ctx.indent -= 1;
+                code + &ctx.line("}")
+            }
+            Some((Clause::For(target, expr), rest)) => {
+                let item = self.label("item");
+                let source = self.expr(ctx, expr)?;
+                let mut code = ctx.line(&format!("for (const {} of iterate({})) {{", item, source));
+                ctx.indent += 1;
+                code.push_str(&ctx.line(&self.bind(ctx, target, &item, "IMMUTABLE", false)));
+                code.push_str(&self.clauses(ctx, rest, emit)?);
+                ctx.indent -= 1;
+                code + &ctx.line("}")
+            }
+        })
+    }
+}
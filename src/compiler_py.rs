@@ -0,0 +1,1157 @@
+use crate::ast::*;
+use crate::methods::{BOOL_RESULTS, MUTATING, NUMBER_RESULTS, OPTION_RESULTS, STRING_RESULTS};
+use crate::resolver;
+use crate::runtime::{BUILTINS, BUILTIN_TYPES, CONTAINER_TYPES, ERROR_KINDS};
+use crate::utils;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+const RUNTIME: &str = include_str!("prelude.py");
+const MODULE: &str = "velvet_out.py";
+const RUNTIME_MODULE: &str = "velvet_runtime.py";
+pub const OUTPUTS: &[&str] = &[MODULE, RUNTIME_MODULE];
+// Python keywords; Velvet names that clash get a trailing '_', as do names starting with '_rt', which the module keeps for itself
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del", "elif", "else", "except", "finally", "for",
+    "from", "global", "if", "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+const HEADER: &str = "# Built by `vel build --target python`; needs Python 3.10+ and velvet_runtime.py next to it\n\ntry:\n    from . import velvet_runtime as _rt\nexcept ImportError:\n    import velvet_runtime as _rt\n\n_rt.start(__name__)\n";
+
+pub fn compile(statements: Vec<Statement>) -> Result<(), String> {
+    let mut codegen = Codegen::default();
+    let (statements, _) = resolver::program(&statements);
+    resolver::declarations(&statements, &mut codegen.declared);
+    returns(&statements, &mut codegen.returns);
+    let mut ctx = Context::new(false, &statements, &codegen.returns);
+    codegen.globals = final_bindings(&statements, &ctx.scalars);
+    let body = codegen.block(&mut ctx, &statements)?;
+    let exports: Vec<String> = resolver::exports(&statements).iter().map(|name| py_string(&py_name(name))).collect();
+    let source = format!("{}\n__all__ = [{}]\n\n{}{}", HEADER, exports.join(", "), codegen.items, body);
+    utils::write_file(RUNTIME_MODULE, RUNTIME)?;
+    utils::write_file(MODULE, &source)
+}
+
+fn unsupported<T>(feature: &str) -> Result<T, String> {
+    Err(format!("{} is not supported by the Python backend", feature))
+}
+
+// Comprehensions and match expressions get their own Python scope, so the resolver's renames can go
+fn py_name(name: &str) -> String {
+    let name = name.split('#').next().unwrap_or(name);
+    if KEYWORDS.contains(&name.trim_end_matches('_')) || name.starts_with("_rt") {
+        format!("{}_", name)
+    } else {
+        name.to_string()
+    }
+}
+
+fn py_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{0}'..='\u{1f}' | '\u{7f}' => out.push_str(&format!("\\x{:02x}", c as u32)),
+            _ => out.push(c),
+        }
+    }
+    out + "\""
+}
+
+// Always a float, so native arithmetic never switches to Python's unbounded ints
+fn py_number(n: f64) -> String {
+    if n.is_nan() {
+        "_rt.nan".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "_rt.inf" } else { "-_rt.inf" }.to_string()
+    } else {
+        format!("{:?}", n)
+    }
+}
+
+fn scalar_type(type_anno: &str) -> bool {
+    let type_anno = type_anno.trim_end_matches('?');
+    type_anno == "void" || (BUILTIN_TYPES.contains(&type_anno) && !CONTAINER_TYPES.contains(&type_anno))
+}
+
+// Values that can never be a list or map
+fn scalar(expr: &Expr, returns: &HashMap<String, Option<String>>) -> bool {
+    match expr {
+        Expr::String(_) | Expr::Number(_) | Expr::Bool(_) | Expr::Unary(..) | Expr::Tuple(_) => true,
+        Expr::Ident(name) => name == "none",
+        Expr::Binary(_, op, _) => op != "??",
+        Expr::Field(_, field) => field != "stack",
+        Expr::MethodCall(_, method, _) => {
+            [NUMBER_RESULTS, STRING_RESULTS, BOOL_RESULTS, OPTION_RESULTS].iter().any(|methods| methods.contains(&method.as_str()))
+        }
+        Expr::Call(name, _) => match returns.get(name) {
+            Some(ret_type) => ret_type.as_deref().is_some_and(scalar_type),
+            None => (BUILTINS.contains(&name.as_str()) && !["unwrap", "unwrap_or", "collect"].contains(&name.as_str())) || ERROR_KINDS.contains(&name.as_str()),
+        },
+        _ => false,
+    }
+}
+
+// Declared return types by function name; None when unknown or declared more than once
+fn returns(stmts: &[Statement], out: &mut HashMap<String, Option<String>>) {
+    for stmt in stmts {
+        match stmt {
+            Statement::Fun(name, _, ret_type, body) => {
+                let ret_type = if out.contains_key(name) { None } else { ret_type.clone() };
+                out.insert(name.clone(), ret_type);
+                returns(body, out);
+            }
+            _ => each_block(stmt, |block| returns(block, out)),
+        }
+    }
+}
+
+fn each_block<'a>(stmt: &'a Statement, mut visit: impl FnMut(&'a [Statement])) {
+    match stmt {
+        Statement::If(_, then_block, else_block) => {
+            visit(then_block);
+            visit(else_block.as_deref().unwrap_or_default());
+        }
+        Statement::For(_, _, body) | Statement::While(_, body) | Statement::Test(_, body) => visit(body),
+        Statement::Try(try_block, catches, finally_block) => {
+            visit(try_block);
+            for (_, _, block) in catches {
+                visit(block);
+            }
+            visit(finally_block.as_deref().unwrap_or_default());
+        }
+        Statement::Match(_, branches) => {
+            for (_, block) in branches {
+                visit(block);
+            }
+        }
+        Statement::Attributed(_, target) => visit(std::slice::from_ref(target)),
+        _ => {}
+    }
+}
+
+// Whether every value stored in each name of a scope is a scalar
+fn stores(stmts: &[Statement], returns: &HashMap<String, Option<String>>, out: &mut HashMap<String, bool>) {
+    for stmt in stmts {
+        let mut found = Vec::new();
+        match stmt {
+            Statement::Val(name, expr, type_anno) | Statement::Let(name, expr, type_anno) => {
+                let typed = type_anno.as_deref().is_some_and(scalar_type);
+                found.push((name.clone(), typed || expr.as_ref().is_none_or(|expr| scalar(expr, returns))));
+            }
+            Statement::Const(name, expr, _) => found.push((name.clone(), scalar(expr, returns))),
+            Statement::Fun(name, ..) => found.push((name.clone(), true)),
+            Statement::Assign(Target::Name(name), expr) => found.push((name.clone(), scalar(expr, returns))),
+            Statement::Unpack(target, ..) | Statement::Assign(target, _) | Statement::For(target, ..) => {
+                let mut names = Vec::new();
+                resolver::target_names(target, &mut names);
+                found.extend(names.into_iter().map(|name| (name, false)));
+            }
+            Statement::Try(_, catches, _) => found.extend(catches.iter().map(|(ident, ..)| (ident.clone(), true))),
+            Statement::Match(_, branches) => {
+                let mut names = Vec::new();
+                branches.iter().for_each(|(pattern, _)| resolver::pattern_names(pattern, &mut names));
+                found.extend(names.into_iter().map(|name| (name, false)));
+            }
+            _ => {}
+        }
+        if let Statement::Say(expr) | Statement::Expr(expr) | Statement::Val(_, Some(expr), _) | Statement::Let(_, Some(expr), _) | Statement::Assign(_, expr) = stmt {
+            found.extend(receiver(expr).map(|name| (name.to_string(), false)));
+        }
+        for (name, is_scalar) in found {
+            *out.entry(name).or_insert(true) &= is_scalar;
+        }
+        each_block(stmt, |block| stores(block, returns, out));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Num,
+    Str,
+    Bool,
+}
+
+fn declared_type(type_anno: &str) -> Option<Type> {
+    match type_anno {
+        "f64" => Some(Type::Num),
+        "str" => Some(Type::Str),
+        "bool" => Some(Type::Bool),
+        _ => None,
+    }
+}
+
+// The type every value of `expr` has, when it can be told without running it; operators that fail on other types still tell
+fn type_of(expr: &Expr, types: &HashMap<String, Type>) -> Option<Type> {
+    match expr {
+        Expr::Number(_) => Some(Type::Num),
+        Expr::String(_) => Some(Type::Str),
+        Expr::Bool(_) => Some(Type::Bool),
+        Expr::Ident(name) | Expr::Local(_, name) => types.get(name).copied(),
+        Expr::Binary(left, op, right) => match op.as_str() {
+            "+" => match (type_of(left, types), type_of(right, types)) {
+                (Some(Type::Str), _) | (_, Some(Type::Str)) => Some(Type::Str),
+                (Some(Type::Num), Some(Type::Num)) => Some(Type::Num),
+                _ => None,
+            },
+            "-" | "*" | "/" => Some(Type::Num),
+            "==" | "!=" | "<" | "<=" | ">" | ">=" | "and" | "or" => Some(Type::Bool),
+            _ => None,
+        },
+        Expr::Unary(op, _) => Some(if op == "-" { Type::Num } else { Type::Bool }),
+        Expr::MethodCall(_, method, _) => [(NUMBER_RESULTS, Type::Num), (STRING_RESULTS, Type::Str), (BOOL_RESULTS, Type::Bool)]
+            .iter()
+            .find(|(methods, _)| methods.contains(&method.as_str()))
+            .map(|(_, found)| *found),
+        Expr::If(_, then_expr, else_expr) => type_of(then_expr, types).filter(|t| type_of(else_expr, types) == Some(*t)),
+        _ => None,
+    }
+}
+
+// What each store in a scope puts in a name: the value's expression, or its declared type
+enum Store<'a> {
+    Value(&'a Expr),
+    Declared(Type),
+    Unknown,
+}
+
+fn collect_stores<'a>(stmts: &'a [Statement], out: &mut Vec<(String, Store<'a>)>) {
+    for stmt in stmts {
+        match stmt {
+            Statement::Val(name, Some(expr), type_anno) | Statement::Let(name, Some(expr), type_anno) | Statement::Const(name, expr, type_anno) => {
+                let declared = type_anno.as_deref().and_then(declared_type);
+                out.push((name.clone(), declared.map_or(Store::Value(expr), Store::Declared)));
+            }
+            Statement::Assign(Target::Name(name), expr) => out.push((name.clone(), Store::Value(expr))),
+            Statement::Import(module, _) => {
+                let mut names = Vec::new();
+                module_names(module, &mut Vec::new(), &mut names);
+                out.extend(names.into_iter().map(|name| (name, Store::Unknown)));
+            }
+            Statement::Assign(target, _) => {
+                let mut names = Vec::new();
+                resolver::target_names(target, &mut names);
+                out.extend(names.into_iter().map(|name| (name, Store::Unknown)));
+            }
+            _ => {
+                let mut names = HashSet::new();
+                definitions(std::slice::from_ref(stmt), &mut names);
+                out.extend(names.into_iter().map(|name| (name, Store::Unknown)));
+            }
+        }
+        if !matches!(stmt, Statement::Val(..) | Statement::Let(..) | Statement::Const(..) | Statement::Assign(..) | Statement::Import(..)) {
+            each_block(stmt, |block| collect_stores(block, out));
+        }
+    }
+}
+
+// The globals an imported module, and the modules it imports, may set
+fn module_names(module: &str, seen: &mut Vec<String>, names: &mut Vec<String>) {
+    if seen.iter().any(|other| other == module) {
+        return;
+    }
+    seen.push(module.to_string());
+    let Some(ast) = utils::read_file(&format!("{}.velvet", module)).ok().and_then(|source| crate::parser::parse(&source).ok()) else {
+        return;
+    };
+    let (ast, _) = resolver::program(&ast);
+    resolver::declarations(&ast, names);
+    for stmt in &ast {
+        if let Statement::Import(inner, _) = stmt {
+            module_names(inner, seen, names);
+        }
+    }
+}
+
+// Names a scope only ever stores values of one known type in. Each name starts with the first type one of its stores
+// gives, and names are dropped until every store agrees with what is left
+fn scope_types(stmts: &[Statement]) -> HashMap<String, Type> {
+    let mut stores = Vec::new();
+    collect_stores(stmts, &mut stores);
+    let unknown: HashSet<&str> = stores.iter().filter(|(_, store)| matches!(store, Store::Unknown)).map(|(name, _)| name.as_str()).collect();
+    let stored = |store: &Store, types: &HashMap<String, Type>| match store {
+        Store::Value(expr) => type_of(expr, types),
+        Store::Declared(declared) => Some(*declared),
+        Store::Unknown => None,
+    };
+    let mut types = HashMap::new();
+    loop {
+        let before = types.len();
+        for (name, store) in &stores {
+            if !unknown.contains(name.as_str()) && !types.contains_key(name) {
+                if let Some(found) = stored(store, &types) {
+                    types.insert(name.clone(), found);
+                }
+            }
+        }
+        if types.len() == before {
+            break;
+        }
+    }
+    loop {
+        let before = types.len();
+        for (name, store) in &stores {
+            if types.contains_key(name) && stored(store, &types) != types.get(name).copied() {
+                types.remove(name);
+            }
+        }
+        if types.len() == before {
+            return types;
+        }
+    }
+}
+
+// The variable a statement-level call to a mutating method would change
+fn receiver(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::MethodCall(target, method, _) if MUTATING.contains(&method.as_str()) => match target.as_ref() {
+            Expr::Ident(name) | Expr::Local(_, name) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Names a scope declares itself, as opposed to globals it only assigns or changes
+fn definitions(stmts: &[Statement], names: &mut HashSet<String>) {
+    for stmt in stmts {
+        let mut found = Vec::new();
+        match stmt {
+            Statement::Val(name, ..) | Statement::Let(name, ..) | Statement::Const(name, ..) | Statement::Fun(name, ..) => found.push(name.clone()),
+            Statement::Unpack(target, ..) | Statement::For(target, ..) => resolver::target_names(target, &mut found),
+            Statement::Try(_, catches, _) => found.extend(catches.iter().map(|(ident, ..)| ident.clone())),
+            Statement::Match(_, branches) => branches.iter().for_each(|(pattern, _)| resolver::pattern_names(pattern, &mut found)),
+            _ => {}
+        }
+        names.extend(found);
+        each_block(stmt, |block| definitions(block, names));
+    }
+}
+
+// Assigned names and receivers of mutating calls, in the order they first appear
+fn changed(stmts: &[Statement], names: &mut Vec<String>) {
+    for stmt in stmts {
+        let mut found = Vec::new();
+        if let Statement::Assign(target, _) = stmt {
+            resolver::target_names(target, &mut found);
+        }
+        if let Statement::Say(expr) | Statement::Expr(expr) | Statement::Val(_, Some(expr), _) | Statement::Let(_, Some(expr), _) | Statement::Assign(_, expr) = stmt {
+            found.extend(receiver(expr).map(str::to_string));
+        }
+        for name in found {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        each_block(stmt, |block| changed(block, names));
+    }
+}
+
+// How each top-level name ends up declared, for functions that read or assign globals
+fn final_bindings(stmts: &[Statement], scalars: &HashMap<String, bool>) -> HashMap<String, Binding> {
+    let mut bindings = HashMap::new();
+    collect_bindings(stmts, scalars, &mut bindings);
+    bindings
+}
+
+fn collect_bindings(stmts: &[Statement], scalars: &HashMap<String, bool>, bindings: &mut HashMap<String, Binding>) {
+    for stmt in stmts {
+        let mut found = Vec::new();
+        match stmt {
+            Statement::Val(name, expr, _) => found.push((name.clone(), if expr.is_some() { Kind::Immutable } else { Kind::Unassigned })),
+            Statement::Let(name, ..) => found.push((name.clone(), Kind::Mutable)),
+            Statement::Const(name, ..) => found.push((name.clone(), Kind::Const)),
+            Statement::Fun(name, ..) => found.push((name.clone(), Kind::Immutable)),
+            Statement::Unpack(target, _, mutable) => {
+                let mut names = Vec::new();
+                resolver::target_names(target, &mut names);
+                found.extend(names.into_iter().map(|name| (name, if *mutable { Kind::Mutable } else { Kind::Immutable })));
+            }
+            _ => {
+                let mut names = HashSet::new();
+                definitions(std::slice::from_ref(stmt), &mut names);
+                found.extend(names.into_iter().map(|name| (name, Kind::Immutable)));
+            }
+        }
+        for (name, kind) in found {
+            let is_scalar = scalars.get(&name).copied().unwrap_or(false);
+            bindings.insert(name, Binding { kind, scalar: is_scalar, error: false });
+        }
+        if !matches!(stmt, Statement::Try(..) | Statement::Match(..) | Statement::For(..)) {
+            each_block(stmt, |block| collect_bindings(block, scalars, bindings));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Const,
+    Immutable,
+    // A `val` declared without a value, which may be assigned once
+    Unassigned,
+    Mutable,
+}
+
+#[derive(Clone, Copy)]
+struct Binding {
+    kind: Kind,
+    scalar: bool,
+    error: bool,
+}
+
+#[derive(Default)]
+struct Codegen {
+    items: String,
+    modules: HashMap<String, String>,
+    declared: Vec<String>,
+    returns: HashMap<String, Option<String>>,
+    globals: HashMap<String, Binding>,
+}
+
+// One Python scope: what each name is bound to so far, so assignments can be checked while generating
+struct Context {
+    names: HashMap<String, Binding>,
+    scalars: HashMap<String, bool>,
+    types: HashMap<String, Type>,
+    indent: usize,
+    loops: usize,
+    function: bool,
+}
+
+impl Context {
+    fn new(function: bool, stmts: &[Statement], returns: &HashMap<String, Option<String>>) -> Self {
+        let mut scalars = HashMap::new();
+        stores(stmts, returns, &mut scalars);
+        Context { names: HashMap::new(), scalars, types: scope_types(stmts), indent: 0, loops: 0, function }
+    }
+
+    fn line(&self, code: &str) -> String {
+        format!("{}{}\n", "    ".repeat(self.indent), code)
+    }
+
+    fn declare(&mut self, name: &str, kind: Kind) {
+        let is_scalar = self.scalars.get(name).copied().unwrap_or(false);
+        self.names.insert(name.to_string(), Binding { kind, scalar: is_scalar, error: false });
+    }
+
+    // Names bound by unpacking, patterns and parameters, whose type is never known
+    fn bind(&mut self, names: &[String]) {
+        for name in names {
+            self.declare(name, Kind::Immutable);
+            self.types.remove(name);
+        }
+    }
+
+    fn declare_target(&mut self, target: &Target, kind: Kind) {
+        let mut names = Vec::new();
+        resolver::target_names(target, &mut names);
+        for name in &names {
+            self.declare(name, kind);
+            self.types.remove(name);
+        }
+    }
+
+    fn inner(&self) -> Context {
+        Context { names: self.names.clone(), scalars: self.scalars.clone(), types: self.types.clone(), indent: self.indent, loops: 0, function: self.function }
+    }
+}
+
+impl Codegen {
+    fn binding(&self, ctx: &Context, name: &str) -> Option<Binding> {
+        match ctx.names.get(name) {
+            Some(binding) => Some(*binding),
+            None if ctx.function => self.globals.get(name).copied(),
+            None => None,
+        }
+    }
+
+    // Whether `expr` may be the very list a `let` variable holds, which must be copied before it is kept elsewhere
+    fn aliases(&self, ctx: &Context, expr: &Expr) -> bool {
+        match expr {
+            Expr::Ident(name) if name == "none" => false,
+            Expr::Ident(name) | Expr::Local(_, name) => self.binding(ctx, name).is_none_or(|b| b.kind == Kind::Mutable && !b.scalar),
+            Expr::Binary(left, op, right) if op == "??" => self.aliases(ctx, left) || self.aliases(ctx, right),
+            Expr::If(_, then_expr, else_expr) => self.aliases(ctx, then_expr) || self.aliases(ctx, else_expr),
+            Expr::Match(_, arms) => arms.iter().any(|(_, arm)| self.aliases(ctx, arm)),
+            _ => false,
+        }
+    }
+
+    // Whether `expr` builds a value nothing else refers to
+    fn fresh(&self, ctx: &Context, expr: &Expr) -> bool {
+        match expr {
+            Expr::List(_) | Expr::ListComp(..) | Expr::Map(_) | Expr::MapComp(..) | Expr::Slice(..) => true,
+            Expr::Ident(name) | Expr::Local(_, name) if name != "none" => self.binding(ctx, name).is_some_and(|b| b.scalar),
+            Expr::MethodCall(_, method, _) => !["pop", "remove", "min", "max"].contains(&method.as_str()),
+            Expr::If(_, then_expr, else_expr) => self.fresh(ctx, then_expr) && self.fresh(ctx, else_expr),
+            _ => scalar(expr, &self.returns),
+        }
+    }
+
+    fn kept(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        let value = self.expr(ctx, expr)?;
+        Ok(if self.aliases(ctx, expr) { format!("_rt.copy({})", value) } else { value })
+    }
+
+    fn block(&mut self, ctx: &mut Context, stmts: &[Statement]) -> Result<String, String> {
+        let mut code = String::new();
+        for stmt in stmts {
+            code.push_str(&self.stmt(ctx, stmt)?);
+        }
+        if code.trim().is_empty() {
+            code = ctx.line("pass");
+        }
+        Ok(code)
+    }
+
+    fn nested(&mut self, ctx: &mut Context, stmts: &[Statement], in_loop: bool) -> Result<String, String> {
+        ctx.indent += 1;
+        let loops = ctx.loops;
+        if in_loop {
+            ctx.loops += 1;
+        }
+        let code = self.block(ctx, stmts);
+        ctx.loops = loops;
+        ctx.indent -= 1;
+        code
+    }
+
+    fn stmt(&mut self, ctx: &mut Context, stmt: &Statement) -> Result<String, String> {
+        Ok(match stmt {
+            Statement::Say(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&format!("_rt.say({})", value))
+            }
+            Statement::Val(name, expr, type_anno) | Statement::Let(name, expr, type_anno) => {
+                let mutable = matches!(stmt, Statement::Let(..));
+                let mut value = match expr {
+                    Some(expr) => {
+                        let value = self.mutating(ctx, expr)?;
+                        let copied = if mutable { !self.fresh(ctx, expr) } else { self.aliases(ctx, expr) };
+                        if copied { format!("_rt.copy({})", value) } else { value }
+                    }
+                    None if type_anno.as_deref().is_some_and(|t| t.ends_with('?')) => "None".to_string(),
+                    None => "_rt.UNSET".to_string(),
+                };
+                if let (Some(type_anno), Some(expr)) = (type_anno, expr) {
+                    if !literal_of(expr, type_anno) {
+                        value = format!("_rt.check({}, {})", value, py_string(type_anno));
+                    }
+                }
+                if self.binding(ctx, name).is_some_and(|b| b.kind == Kind::Const) {
+                    return Ok(ctx.line(&format!("_rt.redeclared({}, {})", py_string(name), value)));
+                }
+                let kind = match (mutable, expr) {
+                    (true, _) => Kind::Mutable,
+                    (false, Some(_)) => Kind::Immutable,
+                    (false, None) => Kind::Unassigned,
+                };
+                ctx.declare(name, kind);
+                ctx.line(&format!("{} = {}", py_name(name), value))
+            }
+            Statement::Const(name, expr, type_anno) => {
+                if ctx.names.contains_key(name) {
+                    return Ok(ctx.line(&format!("_rt.fail(\"Error\", {})", py_string(&format!("Const '{}' redefinition", name)))));
+                }
+                let mut value = self.kept(ctx, expr)?;
+                if let Some(type_anno) = type_anno {
+                    if !literal_of(expr, type_anno) {
+                        value = format!("_rt.check({}, {})", value, py_string(type_anno));
+                    }
+                }
+                ctx.declare(name, Kind::Const);
+                ctx.line(&format!("{} = {}", py_name(name), value))
+            }
+            Statement::Fun(name, params, ret_type, body) => {
+                if self.binding(ctx, name).is_some_and(|b| b.kind == Kind::Const) {
+                    return Ok(ctx.line(&format!("_rt.redeclared({}, None)", py_string(name))));
+                }
+                let code = self.function(ctx, name, params, ret_type, body)?;
+                ctx.declare(name, Kind::Immutable);
+                code
+            }
+            Statement::Type(name, _) => return unsupported(&format!("Type '{}'", name)),
+            Statement::Trait(name, ..) => return unsupported(&format!("Trait '{}'", name)),
+            Statement::Impl(name, ..) => return unsupported(&format!("Impl for '{}'", name)),
+            Statement::If(condition, then_block, else_block) => self.if_chain(ctx, "if", condition, then_block, else_block)?,
+            Statement::While(condition, body) => {
+                let condition = self.condition(ctx, condition)?;
+                ctx.line(&format!("while {}:", condition)) + &self.nested(ctx, body, true)?
+            }
+            Statement::For(target, expr, body) => {
+                let source = self.expr(ctx, expr)?;
+                ctx.declare_target(target, Kind::Immutable);
+                ctx.line(&format!("for {} in _rt.iterate({}):", py_target(target, true), source)) + &self.nested(ctx, body, true)?
+            }
+            Statement::Break => ctx.line(if ctx.loops > 0 { "break" } else { "raise _rt.Escape(\"break\")" }),
+            Statement::Continue => ctx.line(if ctx.loops > 0 { "continue" } else { "raise _rt.Escape(\"continue\")" }),
+            Statement::Try(try_block, catches, finally_block) => {
+                let mut code = ctx.line("try:") + &self.nested(ctx, try_block, false)?;
+                if !catches.is_empty() {
+                    code.push_str(&ctx.line("except _rt.Catchable as _rt_raised:"));
+                    ctx.indent += 1;
+                    let mut chained = false;
+                    for (ident, kind, block) in catches {
+                        let catch_all = kind.as_deref().is_none_or(|kind| kind == "Error");
+                        if catch_all && chained {
+                            code.push_str(&ctx.line("else:"));
+                        } else if !catch_all {
+                            let keyword = if chained { "elif" } else { "if" };
+                            code.push_str(&ctx.line(&format!("{} _rt.matches(_rt_raised, {}):", keyword, py_string(kind.as_deref().unwrap_or_default()))));
+                        }
+                        let indented = chained || !catch_all;
+                        ctx.indent += indented as usize;
+                        ctx.declare(ident, Kind::Immutable);
+                        if let Some(binding) = ctx.names.get_mut(ident) {
+                            binding.error = true;
+                            binding.scalar = true;
+                        }
+                        code.push_str(&ctx.line(&format!("{} = _rt.caught(_rt_raised)", py_name(ident))));
+                        code.push_str(&self.block(ctx, block)?);
+                        ctx.indent -= indented as usize;
+                        chained = true;
+                        if catch_all {
+                            break;
+                        }
+                    }
+                    if !catches.iter().any(|(_, kind, _)| kind.as_deref().is_none_or(|kind| kind == "Error")) {
+                        code.push_str(&ctx.line("else:"));
+                        code.push_str(&ctx.line("    raise"));
+                    }
+                    ctx.indent -= 1;
+                }
+                match finally_block {
+                    Some(finally_block) => code + &ctx.line("finally:") + &self.nested(ctx, finally_block, false)?,
+                    None if catches.is_empty() => code + &ctx.line("finally:") + &ctx.line("    pass"),
+                    None => code,
+                }
+            }
+            Statement::Throw(Some(expr), line) => {
+                let value = self.expr(ctx, expr)?;
+                ctx.line(&format!("raise _rt.thrown({}, {})", value, line))
+            }
+            Statement::Throw(None, _) => ctx.line("raise _rt.rethrow()"),
+            Statement::Match(expr, branches) => {
+                let subject = self.expr(ctx, expr)?;
+                if branches.is_empty() {
+                    return Ok(ctx.line(&subject));
+                }
+                let mut code = ctx.line(&format!("match {}:", subject));
+                ctx.indent += 1;
+                for (pattern, block) in branches {
+                    let mut names = Vec::new();
+                    resolver::pattern_names(pattern, &mut names);
+                    ctx.bind(&names);
+                    code.push_str(&ctx.line(&format!("case {}:", py_pattern(pattern))));
+                    code.push_str(&self.nested(ctx, block, false)?);
+                    if matches!(pattern, Pattern::Wildcard) {
+                        break;
+                    }
+                }
+                ctx.indent -= 1;
+                code
+            }
+            Statement::Unpack(target, expr, mutable) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.declare_target(target, if *mutable { Kind::Mutable } else { Kind::Immutable });
+                ctx.line(&format!("{} = {}", py_target(target, true), value))
+            }
+            Statement::Assign(target, expr) => self.assign(ctx, target, expr)?,
+            Statement::Expr(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                ctx.line(&value)
+            }
+            Statement::Return(expr) => {
+                let value = self.mutating(ctx, expr)?;
+                let local = matches!(expr, Expr::Ident(name) | Expr::Local(_, name) if ctx.function && ctx.names.contains_key(name));
+                let value = if self.aliases(ctx, expr) && !local { format!("_rt.copy({})", value) } else { value };
+                if ctx.function {
+                    ctx.line(&format!("return {}", value))
+                } else if matches!(expr, Expr::String(_) | Expr::Number(_) | Expr::Bool(_)) || matches!(expr, Expr::Ident(n) if n == "none") {
+                    ctx.line("_rt.exit()")
+                } else {
+                    ctx.line(&value) + &ctx.line("_rt.exit()")
+                }
+            }
+            Statement::Yield(expr) if ctx.function => {
+                let value = self.kept(ctx, expr)?;
+                ctx.line(&format!("yield {}", value))
+            }
+            Statement::Yield(_) => ctx.line("_rt.fail(\"Error\", \"'yield' is only allowed inside a function\")"),
+            Statement::Import(module, _) => {
+                let path = format!("{}.velvet", module);
+                if !Path::new(&path).exists() {
+                    return Ok(ctx.line(&format!("_rt.fail(\"ImportError\", {})", py_string(&format!("Module '{}' not found", module)))));
+                }
+                let id = match self.modules.get(module) {
+                    Some(id) => id.clone(),
+                    None => {
+                        let id = format!("_rt_import_{}", module.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect::<String>());
+                        self.modules.insert(module.clone(), id.clone());
+                        let source = utils::read_file(&path)?;
+                        let (ast, _) = resolver::program(&crate::parser::parse(&source)?);
+                        let mut names = Vec::new();
+                        resolver::declarations(&ast, &mut names);
+                        self.declared.extend(names.iter().cloned());
+                        returns(&ast, &mut self.returns);
+                        let mut inner = Context::new(false, &ast, &self.returns);
+                        inner.indent = 1;
+                        let body = self.block(&mut inner, &ast)?;
+                        let mut globals: Vec<String> = Vec::new();
+                        for name in names.iter().map(|name| py_name(name)) {
+                            if !globals.contains(&name) {
+                                globals.push(name);
+                            }
+                        }
+                        let declaration = if globals.is_empty() { String::new() } else { format!("    global {}\n", globals.join(", ")) };
+                        self.items.push_str(&format!("def {}():\n{}{}\n\n", id, declaration, body));
+                        ctx.names.extend(inner.names);
+                        id
+                    }
+                };
+                ctx.line(&format!("{}()", id))
+            }
+            Statement::Test(_, body) => {
+                let names = ctx.names.clone();
+                let code = ctx.line("with _rt.isolated():") + &self.nested(ctx, body, false)?;
+                ctx.names = names;
+                code
+            }
+            // `#[inline]` only matters to the optimizer, which has already run
+            Statement::Attributed(attributes, target) => match attributes.iter().find(|a| a.name != "export" && a.name != "inline") {
+                Some(attribute) => return unsupported(&format!("Attribute '#[{}]'", attribute.name)),
+                None => return self.stmt(ctx, target),
+            },
+        })
+    }
+
+    fn if_chain(&mut self, ctx: &mut Context, keyword: &str, condition: &Expr, then_block: &[Statement], else_block: &Option<Vec<Statement>>) -> Result<String, String> {
+        let condition = self.condition(ctx, condition)?;
+        let mut code = ctx.line(&format!("{} {}:", keyword, condition)) + &self.nested(ctx, then_block, false)?;
+        match else_block.as_deref() {
+            Some([Statement::If(condition, then_block, else_block)]) => code.push_str(&self.if_chain(ctx, "elif", condition, then_block, else_block)?),
+            Some(else_block) => code.push_str(&(ctx.line("else:") + &self.nested(ctx, else_block, false)?)),
+            None => {}
+        }
+        Ok(code)
+    }
+
+    fn assign(&mut self, ctx: &mut Context, target: &Target, expr: &Expr) -> Result<String, String> {
+        let value = self.mutating(ctx, expr)?;
+        if let Target::Name(name) = target {
+            if name == "_" {
+                return Ok(ctx.line(&value));
+            }
+        }
+        let mut names = Vec::new();
+        resolver::target_names(target, &mut names);
+        for name in &names {
+            let refusal = match self.binding(ctx, name).map(|b| b.kind) {
+                Some(Kind::Mutable | Kind::Unassigned) => continue,
+                Some(Kind::Immutable) => "immutable",
+                Some(Kind::Const) => "constant",
+                None => "undeclared",
+            };
+            return Ok(ctx.line(&format!("_rt.{}({}, {})", refusal, py_string(name), value)));
+        }
+        let copied = matches!(target, Target::Name(_)) && !self.fresh(ctx, expr) && !self.binding(ctx, &names[0]).is_some_and(|b| b.kind == Kind::Unassigned);
+        let value = if copied || self.aliases(ctx, expr) { format!("_rt.copy({})", value) } else { value };
+        for name in &names {
+            if let Some(binding) = ctx.names.get_mut(name).filter(|b| b.kind == Kind::Unassigned) {
+                binding.kind = Kind::Immutable;
+            }
+        }
+        Ok(ctx.line(&format!("{} = {}", py_target(target, true), value)))
+    }
+
+    fn function(&mut self, ctx: &Context, name: &str, params: &[Param], ret_type: &Option<String>, body: &[Statement]) -> Result<String, String> {
+        let function = resolver::function(params, ret_type, body);
+        let mut inner = Context::new(true, &function.body, &self.returns);
+        inner.indent = ctx.indent + 1;
+        let mut signature = Vec::new();
+        let mut prologue = String::new();
+        for param in &function.params {
+            let py = py_name(&param.name);
+            match &param.default {
+                _ if param.variadic => {
+                    signature.push(format!("*{}", py));
+                    prologue.push_str(&inner.line(&format!("{0} = [*{0}]", py)));
+                }
+                Some(default) if matches!(default, Expr::String(_) | Expr::Number(_) | Expr::Bool(_)) || matches!(default, Expr::Ident(n) if n == "none") => {
+                    signature.push(format!("{}={}", py, self.expr(&mut inner, default)?))
+                }
+                Some(default) => {
+                    signature.push(format!("{}=_rt.UNSET", py));
+                    prologue.push_str(&inner.line(&format!("if {} is _rt.UNSET:", py)));
+                    let value = self.kept(&mut inner, default)?;
+                    prologue.push_str(&inner.line(&format!("    {} = {}", py, value)));
+                }
+                None => signature.push(py),
+            }
+            inner.bind(std::slice::from_ref(&param.name));
+            if let Some(binding) = inner.names.get_mut(&param.name) {
+                binding.scalar = scalar_type(&param.type_anno) && !param.variadic;
+            }
+        }
+        let mut defined = HashSet::new();
+        definitions(&function.body, &mut defined);
+        let mut assigned = Vec::new();
+        changed(&function.body, &mut assigned);
+        for name in assigned {
+            if defined.contains(&name) || inner.names.contains_key(&name) {
+                continue;
+            }
+            let global = self.globals.get(&name).copied();
+            let kind = global.map_or(Kind::Mutable, |b| b.kind);
+            let is_scalar = global.is_some_and(|b| b.scalar) && inner.scalars.get(&name).copied().unwrap_or(true);
+            inner.names.insert(name.clone(), Binding { kind, scalar: is_scalar, error: false });
+            inner.types.remove(&name);
+            prologue.push_str(&inner.line(&format!("{} = _rt.inherit({})", py_name(&name), py_string(&py_name(&name)))));
+        }
+        let mut code = self.block(&mut inner, &function.body)?;
+        let typed = function.ret_type.as_deref().is_some_and(|t| t != "void");
+        if typed && !function.generator && !matches!(function.body.last(), Some(Statement::Return(_))) {
+            code.push_str(&inner.line("return _rt.UNSET"));
+        }
+        let decorator = if typed { format!("@_rt.function({}, returns=True)", py_string(name)) } else { format!("@_rt.function({})", py_string(name)) };
+        let mut out = ctx.line(&decorator) + &ctx.line(&format!("def {}({}):", py_name(name), signature.join(", "))) + &prologue + &code;
+        if ctx.indent == 0 {
+            out = format!("\n{}\n", out);
+        }
+        Ok(out)
+    }
+
+    // Named args become markers, which only the runtime's own calls take
+    fn args(&mut self, ctx: &mut Context, args: &[Expr]) -> Result<Vec<String>, String> {
+        let mut values = Vec::new();
+        for arg in args {
+            match arg {
+                Expr::Named(name, value) => values.push(format!("_rt.Named({}, {})", py_string(&py_name(name)), self.kept(ctx, value)?)),
+                arg => values.push(self.kept(ctx, arg)?),
+            }
+        }
+        Ok(values)
+    }
+
+    // A call to a Velvet function, with Python keyword args where Python syntax allows them
+    fn call(&mut self, ctx: &mut Context, name: &str, args: &[Expr]) -> Result<String, String> {
+        let first = args.iter().position(|arg| matches!(arg, Expr::Named(..))).unwrap_or(args.len());
+        let mut names = Vec::new();
+        let keywords = args[first..].iter().all(|arg| matches!(arg, Expr::Named(name, _) if !names.contains(&name) && { names.push(name); true }));
+        if !keywords {
+            let mut values = vec![py_string(name), py_name(name)];
+            values.extend(self.args(ctx, args)?);
+            return Ok(format!("_rt.invoke({})", values.join(", ")));
+        }
+        let mut values = Vec::new();
+        for arg in args {
+            match arg {
+                Expr::Named(name, value) => values.push(format!("{}={}", py_name(name), self.kept(ctx, value)?)),
+                arg => values.push(self.kept(ctx, arg)?),
+            }
+        }
+        Ok(format!("{}({})", py_name(name), values.join(", ")))
+    }
+
+    fn condition(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        let value = self.expr(ctx, expr)?;
+        let boolean = match expr {
+            Expr::Call(name, _) => name.starts_with("is_") && BUILTINS.contains(&name.as_str()) && !self.declared.contains(name),
+            _ => type_of(expr, &ctx.types) == Some(Type::Bool),
+        };
+        Ok(if boolean { value } else { format!("_rt.truthy({})", value) })
+    }
+
+    fn clauses(&mut self, inner: &mut Context, clauses: &[Clause]) -> Result<String, String> {
+        let mut parts = Vec::new();
+        for clause in clauses {
+            match clause {
+                Clause::For(target, source) => {
+                    let source = self.expr(inner, source)?;
+                    inner.declare_target(target, Kind::Immutable);
+                    parts.push(format!("for {} in _rt.iterate({})", py_target(target, true), source));
+                }
+                Clause::If(condition) => parts.push(format!("if {}", self.condition(inner, condition)?)),
+            }
+        }
+        Ok(parts.join(" "))
+    }
+
+    // Whether Python's own operator gives what the runtime helper would, for operands of the types known here
+    fn native(&self, ctx: &Context, left: &Expr, op: &str, right: &Expr) -> bool {
+        let (left_type, right_type) = (type_of(left, &ctx.types), type_of(right, &ctx.types));
+        let numbers = left_type == Some(Type::Num) && right_type == Some(Type::Num);
+        match op {
+            "+" => left_type == right_type && matches!(left_type, Some(Type::Num | Type::Str)),
+            "-" | "*" | "<" | "<=" | ">" | ">=" => numbers,
+            "/" => numbers && matches!(right, Expr::Number(n) if *n != 0.0),
+            "==" | "!=" => left_type.is_some() && left_type == right_type,
+            "and" | "or" => left_type == Some(Type::Bool) && right_type == Some(Type::Bool),
+            _ => false,
+        }
+    }
+
+    // How tightly Python binds `expr`, when it comes out as a native operator
+    fn precedence(&self, ctx: &Context, expr: &Expr) -> Option<u8> {
+        match expr {
+            Expr::Binary(left, op, right) if self.native(ctx, left, op, right) => Some(match op.as_str() {
+                "or" => 1,
+                "and" => 2,
+                "+" | "-" => 5,
+                "*" | "/" => 6,
+                _ => 4,
+            }),
+            Expr::Unary(op, inner) if matches!(inner.as_ref(), Expr::Number(_)) || type_of(inner, &ctx.types) == Some(if op == "-" { Type::Num } else { Type::Bool }) => {
+                Some(if op == "-" { 7 } else { 3 })
+            }
+            _ => None,
+        }
+    }
+
+    // An operand of a native operator, in parentheses unless it binds at least as tightly as `min`
+    fn operand(&mut self, ctx: &mut Context, expr: &Expr, min: u8) -> Result<String, String> {
+        let value = self.expr(ctx, expr)?;
+        Ok(if self.precedence(ctx, expr).is_some_and(|found| found < min) { format!("({})", value) } else { value })
+    }
+
+    // Statement-level method calls on a variable may change it in place
+    fn mutating(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        if let Expr::MethodCall(target, method, args) = expr {
+            if let (Expr::Ident(name) | Expr::Local(_, name), true) = (target.as_ref(), MUTATING.contains(&method.as_str())) {
+                let mut values = vec![py_name(name), py_string(method)];
+                values.extend(self.args(ctx, args)?);
+                match self.binding(ctx, name).map(|b| b.kind) {
+                    Some(Kind::Const) => values.push(format!("constant={}", py_string(name))),
+                    Some(Kind::Immutable | Kind::Unassigned) => values.push(format!("immutable={}", py_string(name))),
+                    _ => {}
+                }
+                return Ok(format!("_rt.update({})", values.join(", ")));
+            }
+        }
+        self.expr(ctx, expr)
+    }
+
+    fn expr(&mut self, ctx: &mut Context, expr: &Expr) -> Result<String, String> {
+        Ok(match expr {
+            Expr::String(s) => py_string(s),
+            Expr::Number(n) => py_number(*n),
+            Expr::Bool(b) => if *b { "True" } else { "False" }.to_string(),
+            Expr::Ident(id) if id == "none" => "None".to_string(),
+            Expr::Ident(id) | Expr::Local(_, id) => py_name(id),
+            Expr::Binary(left, op, right) if op == "??" => {
+                let left = self.expr(ctx, left)?;
+                format!("_rt.coalesce({}, lambda: {})", left, self.expr(ctx, right)?)
+            }
+            Expr::Binary(left, op, right) if self.native(ctx, left, op, right) => {
+                // Comparisons chain in Python, so one never takes another unparenthesized
+                let level = self.precedence(ctx, expr).unwrap_or_default();
+                let left = self.operand(ctx, left, if level == 4 { 5 } else { level })?;
+                format!("{} {} {}", left, op, self.operand(ctx, right, level + 1)?)
+            }
+            Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+                let left = self.expr(ctx, left)?;
+                format!("(_rt.truthy({}) {} _rt.truthy({}))", left, op, self.expr(ctx, right)?)
+            }
+            Expr::Binary(left, op, right) => {
+                let helper = match op.as_str() {
+                    "+" => "add",
+                    "-" => "sub",
+                    "*" => "mul",
+                    "/" => "div",
+                    "==" => "eq",
+                    "!=" => "ne",
+                    "<" => "lt",
+                    "<=" => "le",
+                    ">" => "gt",
+                    ">=" => "ge",
+                    _ => return unsupported(&format!("Operator '{}'", op)),
+                };
+                let left = self.expr(ctx, left)?;
+                format!("_rt.{}({}, {})", helper, left, self.expr(ctx, right)?)
+            }
+            Expr::Unary(op, inner) => match (op.as_str(), inner.as_ref()) {
+                ("-", Expr::Number(n)) => py_number(-n),
+                ("-", inner) if type_of(inner, &ctx.types) == Some(Type::Num) => format!("-{}", self.operand(ctx, inner, 8)?),
+                ("!", inner) if type_of(inner, &ctx.types) == Some(Type::Bool) => format!("not {}", self.operand(ctx, inner, 4)?),
+                ("-", inner) => format!("_rt.neg({})", self.expr(ctx, inner)?),
+                ("!", inner) => format!("_rt.not_({})", self.expr(ctx, inner)?),
+                _ => return unsupported(&format!("Unary operator '{}'", op)),
+            },
+            Expr::Call(name, args) => {
+                if self.declared.contains(name) || ctx.names.contains_key(name) {
+                    return self.call(ctx, name, args);
+                }
+                let named = args.iter().any(|arg| matches!(arg, Expr::Named(..)));
+                let args = self.args(ctx, args)?;
+                if BUILTINS.contains(&name.as_str()) && !named {
+                    format!("_rt.{}({})", name, args.join(", "))
+                } else if ERROR_KINDS.contains(&name.as_str()) && args.len() == 1 && !named {
+                    format!("_rt.error({}, {})", py_string(name), args[0])
+                } else {
+                    let helper = if BUILTINS.contains(&name.as_str()) || ERROR_KINDS.contains(&name.as_str()) { "_rt.builtin" } else { "_rt.missing" };
+                    let mut values = vec![py_string(name)];
+                    values.extend(args);
+                    format!("{}({})", helper, values.join(", "))
+                }
+            }
+            Expr::Named(name, _) => format!("_rt.fail(\"ArgumentError\", {})", py_string(&format!("Named argument '{}' is only allowed in a call", name))),
+            Expr::MethodCall(target, method, args) => {
+                let mut values = vec![self.expr(ctx, target)?, py_string(method)];
+                values.extend(self.args(ctx, args)?);
+                if let (Expr::Ident(name) | Expr::Local(_, name), true) = (target.as_ref(), MUTATING.contains(&method.as_str())) {
+                    values.push(format!("changes={}", py_string(name)));
+                }
+                format!("_rt.method({})", values.join(", "))
+            }
+            Expr::List(elements) | Expr::Tuple(elements) => {
+                let values = self.args(ctx, elements)?;
+                match expr {
+                    Expr::List(_) => format!("[{}]", values.join(", ")),
+                    _ if values.len() == 1 => format!("({},)", values[0]),
+                    _ => format!("({})", values.join(", ")),
+                }
+            }
+            Expr::Map(entries) => {
+                let mut values = Vec::new();
+                for (key, value) in entries {
+                    let key = self.kept(ctx, key)?;
+                    values.push(format!("{}: {}", key, self.kept(ctx, value)?));
+                }
+                format!("{{{}}}", values.join(", "))
+            }
+            Expr::MapComp(key, value, clauses) => {
+                let mut inner = ctx.inner();
+                let clauses = self.clauses(&mut inner, clauses)?;
+                let key = self.kept(&mut inner, key)?;
+                format!("{{{}: {} {}}}", key, self.kept(&mut inner, value)?, clauses)
+            }
+            Expr::ListComp(element, clauses) => {
+                let mut inner = ctx.inner();
+                let clauses = self.clauses(&mut inner, clauses)?;
+                format!("[{} {}]", self.kept(&mut inner, element)?, clauses)
+            }
+            Expr::Index(target, index) => {
+                let target = self.expr(ctx, target)?;
+                format!("_rt.at({}, {})", target, self.expr(ctx, index)?)
+            }
+            Expr::Slice(target, start, end, step) => {
+                let mut values = vec![self.expr(ctx, target)?];
+                for bound in [start, end, step] {
+                    match bound {
+                        Some(bound) => values.push(self.expr(ctx, bound)?),
+                        None => values.push("None".to_string()),
+                    }
+                }
+                format!("_rt.slice_of({})", values.join(", "))
+            }
+            Expr::Field(target, field) => {
+                let caught = match target.as_ref() {
+                    Expr::Ident(name) | Expr::Local(_, name) => self.binding(ctx, name).is_some_and(|b| b.error),
+                    _ => false,
+                };
+                let target = self.expr(ctx, target)?;
+                if caught && ["kind", "message", "location"].contains(&field.as_str()) {
+                    format!("{}.{}", target, field)
+                } else {
+                    format!("_rt.field({}, {})", target, py_string(field))
+                }
+            }
+            Expr::SafeField(target, field) => format!("_rt.safe_field({}, {})", self.expr(ctx, target)?, py_string(field)),
+            Expr::Propagate(inner) => format!("_rt.propagate({})", self.expr(ctx, inner)?),
+            Expr::If(condition, then_expr, else_expr) => {
+                let condition = self.condition(ctx, condition)?;
+                let then_expr = self.expr(ctx, then_expr)?;
+                format!("({} if {} else {})", then_expr, condition, self.expr(ctx, else_expr)?)
+            }
+            Expr::Match(subject, arms) => {
+                let mut values = vec![self.expr(ctx, subject)?];
+                for (pattern, arm) in arms {
+                    let mut inner = ctx.inner();
+                    let mut names = Vec::new();
+                    resolver::pattern_names(pattern, &mut names);
+                    inner.bind(&names);
+                    let params: Vec<String> = names.iter().map(|name| py_name(name)).collect();
+                    let lambda = if params.is_empty() { "lambda".to_string() } else { format!("lambda {}", params.join(", ")) };
+                    values.push(format!("({}, {}: {})", choice(pattern), lambda, self.expr(&mut inner, arm)?));
+                    if matches!(pattern, Pattern::Wildcard) {
+                        break;
+                    }
+                }
+                format!("_rt.choose({})", values.join(", "))
+            }
+        })
+    }
+}
+
+// A value literal already of the declared type needs no check
+fn literal_of(expr: &Expr, type_anno: &str) -> bool {
+    match expr {
+        Expr::Number(_) => type_anno.trim_end_matches('?') == "f64",
+        Expr::String(_) => type_anno.trim_end_matches('?') == "str",
+        Expr::Bool(_) => type_anno.trim_end_matches('?') == "bool",
+        Expr::List(_) | Expr::ListComp(..) => type_anno.trim_end_matches('?') == "list",
+        Expr::Map(_) | Expr::MapComp(..) => type_anno.trim_end_matches('?') == "map",
+        Expr::Ident(name) => name == "none" && type_anno.ends_with('?'),
+        _ => false,
+    }
+}
+
+fn py_target(target: &Target, top: bool) -> String {
+    match target {
+        Target::Name(name) if name == "_" => "_".to_string(),
+        Target::Name(name) => py_name(name),
+        Target::Tuple(targets) => {
+            let items: Vec<String> = targets.iter().map(|target| py_target(target, false)).collect();
+            let joined = if items.len() == 1 { format!("{},", items[0]) } else { items.join(", ") };
+            if top {
+                joined
+            } else {
+                format!("({})", joined)
+            }
+        }
+        Target::List(targets, rest) => {
+            let mut items: Vec<String> = targets.iter().map(|target| py_target(target, false)).collect();
+            if let Some(rest) = rest {
+                items.push(format!("*{}", if rest == "_" { "_".to_string() } else { py_name(rest) }));
+            }
+            format!("[{}]", items.join(", "))
+        }
+    }
+}
+
+fn variant_class(name: &str) -> &'static str {
+    match name {
+        "ok" => "_rt.Ok",
+        "err" => "_rt.Err",
+        _ => "_rt.Some",
+    }
+}
+
+// Literal patterns compare the text of the value, so `3` also matches the string "3"
+fn py_pattern(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Literal(text) if text == "true" || text == "false" => format!("{} | {}", if text == "true" { "True" } else { "False" }, py_string(text)),
+        Pattern::Literal(text) => match text.parse::<f64>() {
+            Ok(n) if n.is_finite() && format!("{}", n) == *text => format!("{} | {}", py_number(n), py_string(text)),
+            _ => py_string(text),
+        },
+        Pattern::Bind(name) => py_name(name),
+        Pattern::Variant(name, Some(inner)) => format!("{}({})", variant_class(name), py_pattern(inner)),
+        Pattern::Variant(..) => "None".to_string(),
+    }
+}
+
+// The runtime's description of a pattern, for `choose`
+fn choice(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Wildcard => "_rt.ANY".to_string(),
+        Pattern::Literal(text) => py_string(text),
+        Pattern::Bind(_) => "_rt.BIND".to_string(),
+        Pattern::Variant(name, Some(inner)) => format!("{}({})", variant_class(name), choice(inner)),
+        Pattern::Variant(..) => "None".to_string(),
+    }
+}
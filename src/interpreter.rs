@@ -1,41 +1,299 @@
 use crate::ast::*;
 use crate::cli;
-use crate::runtime::Value;
-use std::collections::HashMap;
+use crate::methods;
+use crate::resolver::{self, Function, Scope};
+use crate::runtime::{to_iter, Engine, ErrorValue, Iter, LazyIter, Value, BUILTIN_TYPES};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub enum Signal {
+    Break,
+    Continue,
+    Return(Value),
+    Error(ErrorValue),
+}
+
+impl From<ErrorValue> for Signal {
+    fn from(mut error: ErrorValue) -> Self {
+        error.stack = call_stack();
+        Signal::Error(error)
+    }
+}
+
+type Args = (Vec<Value>, Vec<(String, Value)>);
+type TestResults = Vec<(String, Result<(), String>)>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mutability {
+    Const,
+    Immutable,
+    Mutable,
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    value: Value,
+    mutability: Mutability,
+}
+
+#[derive(Clone)]
+struct Env {
+    globals: Rc<RefCell<HashMap<String, Binding>>>,
+    scope: Rc<Scope>,
+    slots: Vec<Option<Binding>>,
+}
+
+impl Env {
+    fn new(globals: Rc<RefCell<HashMap<String, Binding>>>, scope: Rc<Scope>) -> Self {
+        let slots = vec![None; scope.len];
+        Env { globals, scope, slots }
+    }
+
+    fn with<T>(&self, name: &str, f: impl FnOnce(Option<&Binding>) -> T) -> T {
+        match self.scope.names.get(name) {
+            Some(&slot) => self.with_slot(slot, name, f),
+            None => f(self.globals.borrow().get(name)),
+        }
+    }
+
+    fn with_slot<T>(&self, slot: usize, name: &str, f: impl FnOnce(Option<&Binding>) -> T) -> T {
+        match &self.slots[slot] {
+            Some(binding) => f(Some(binding)),
+            None => f(self.globals.borrow().get(name)),
+        }
+    }
+
+    fn update<T>(&mut self, name: &str, f: impl FnOnce(&mut Binding) -> T) -> Option<T> {
+        match self.scope.names.get(name) {
+            Some(&slot) => {
+                if self.slots[slot].is_none() {
+                    self.slots[slot] = self.globals.borrow().get(name).cloned();
+                }
+                self.slots[slot].as_mut().map(f)
+            }
+            None => self.globals.borrow_mut().get_mut(name).map(f),
+        }
+    }
+
+    fn set(&mut self, name: &str, binding: Binding) {
+        match self.scope.names.get(name) {
+            Some(&slot) => self.slots[slot] = Some(binding),
+            None => {
+                self.globals.borrow_mut().insert(name.to_string(), binding);
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<Value> {
+        self.with(name, |binding| binding.map(|b| b.value.clone()))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<String>,
+    handling: Vec<ErrorValue>,
+    methods: HashMap<String, HashMap<String, Value>>,
+    traits: HashMap<String, (Vec<String>, HashMap<String, Value>)>,
+    impls: HashSet<(String, String)>,
+    deprecated: HashMap<String, Option<String>>,
+    memos: HashMap<String, HashMap<String, Value>>,
+    constructors: HashMap<String, Rc<Function>>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+fn call_stack() -> Vec<String> {
+    STATE.with(|s| s.borrow().calls.iter().rev().cloned().collect())
+}
 
 pub fn run(statements: Vec<Statement>, debug: bool) -> Result<(), String> {
-    let mut env = HashMap::with_capacity(statements.len());
-    execute_block(&statements, &mut env, debug)?;
+    let (statements, scope) = resolver::program(&statements);
+    let mut env = Env::new(Rc::new(RefCell::new(HashMap::with_capacity(statements.len()))), scope);
+    STATE.with(|s| *s.borrow_mut() = State::default());
+    outcome(execute_block(&statements, &mut env, debug))
+}
+
+pub fn run_tests(statements: Vec<Statement>) -> Result<TestResults, String> {
+    let (statements, scope) = resolver::program(&statements);
+    let mut env = Env::new(Rc::new(RefCell::new(HashMap::with_capacity(statements.len()))), scope);
+    STATE.with(|s| *s.borrow_mut() = State::default());
+    outcome(execute_block(&statements, &mut env, false))?;
+    let mut results = Vec::new();
+    for stmt in &statements {
+        let Statement::Attributed(attributes, target) = stmt else { continue };
+        let Statement::Fun(name, ..) = target.as_ref() else { continue };
+        if attributes.iter().any(|a| a.name == "test") {
+            let func = env.get(name).unwrap();
+            let result = call_function(name, &func, Vec::new(), Vec::new(), &env, false).map(|_| ());
+            results.push((name.clone(), outcome(result)));
+        }
+    }
+    Ok(results)
+}
+
+fn outcome(result: Result<(), Signal>) -> Result<(), String> {
+    match result {
+        Ok(()) | Err(Signal::Return(_)) => Ok(()),
+        Err(Signal::Break) => Err("'break' outside of a loop".to_string()),
+        Err(Signal::Continue) => Err("'continue' outside of a loop".to_string()),
+        Err(Signal::Error(e)) => Err(format!("Uncaught {}", e.trace())),
+    }
+}
+
+fn warn_deprecated(name: &str) {
+    let note = STATE.with(|s| s.borrow_mut().deprecated.remove(name));
+    match note {
+        Some(Some(note)) => cli::warning(&format!("'{}' is deprecated: {}", name, note)),
+        Some(None) => cli::warning(&format!("'{}' is deprecated", name)),
+        None => {}
+    }
+}
+
+fn define(env: &mut Env, name: &str, value: Value, mutability: Mutability) -> Result<(), Signal> {
+    let existing = match env.scope.names.get(name) {
+        Some(&slot) => env.slots[slot].as_ref().map(|b| b.mutability),
+        None => env.globals.borrow().get(name).map(|b| b.mutability),
+    };
+    if existing == Some(Mutability::Const) {
+        return Err(ErrorValue::new("Error", format!("Cannot redeclare constant '{}'", name)).into());
+    }
+    env.set(name, Binding { value, mutability });
     Ok(())
 }
 
-fn execute_stmt(stmt: &Statement, env: &mut HashMap<String, Value>, debug: bool) -> Result<(), String> {
+fn assign(env: &mut Env, ident: &str, value: Value) -> Result<(), Signal> {
+    env.update(ident, |binding| match binding.mutability {
+        Mutability::Const => Err(ErrorValue::new("Error", format!("Cannot assign to constant '{}'", ident)).into()),
+        Mutability::Immutable if binding.value != Value::Unset => Err(ErrorValue::new(
+            "Error",
+            format!("Cannot assign twice to immutable variable '{}' (declare it with 'let' to make it mutable)", ident),
+        )
+        .into()),
+        _ => {
+            binding.value = value;
+            Ok(())
+        }
+    })
+    .ok_or_else(|| ErrorValue::new("NameError", format!("Var '{}' not found", ident)))?
+}
+
+fn read(id: &str, binding: Option<&Binding>) -> Result<Value, Signal> {
+    match binding {
+        Some(Binding { value: Value::Unset, .. }) => Err(ErrorValue::new("NameError", format!("Variable '{}' is used before it is assigned", id)).into()),
+        Some(binding) => {
+            if binding.mutability == Mutability::Const {
+                warn_deprecated(id);
+            }
+            Ok(binding.value.clone())
+        }
+        None => Err(ErrorValue::new("NameError", format!("Var '{}' not found", id)).into()),
+    }
+}
+
+pub fn unpack(target: &Target, value: Value, bindings: &mut Vec<(String, Value)>) -> Result<(), ErrorValue> {
+    match target {
+        Target::Name(name) if name == "_" => Ok(()),
+        Target::Name(name) => {
+            bindings.push((name.clone(), value));
+            Ok(())
+        }
+        Target::Tuple(targets) => match value {
+            Value::Tuple(values) if values.len() == targets.len() => {
+                for (target, value) in targets.iter().zip(values) {
+                    unpack(target, value, bindings)?;
+                }
+                Ok(())
+            }
+            Value::Tuple(values) => {
+                Err(ErrorValue::new("ValueError", format!("Cannot unpack tuple of length {} into {} names", values.len(), targets.len())))
+            }
+            other => Err(ErrorValue::new("TypeError", format!("Expected tuple to unpack, got {}", other))),
+        },
+        Target::List(targets, rest) => {
+            let values = value.as_list()?;
+            if values.len() < targets.len() || (rest.is_none() && values.len() != targets.len()) {
+                let names = if rest.is_some() { format!("at least {}", targets.len()) } else { targets.len().to_string() };
+                return Err(ErrorValue::new("ValueError", format!("Cannot unpack list of length {} into {} names", values.len(), names)));
+            }
+            for (target, value) in targets.iter().zip(values.iter()) {
+                unpack(target, value.clone(), bindings)?;
+            }
+            if let Some(rest) = rest {
+                unpack(&Target::Name(rest.clone()), Value::List(Rc::new(values[targets.len()..].to_vec())), bindings)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn execute_stmt(stmt: &Statement, env: &mut Env, debug: bool) -> Result<(), Signal> {
     if debug {
         cli::debug(&format!("Stmt: {:?}", stmt));
     }
     match stmt {
         Statement::Say(expr) => {
-            println!("{}", eval_expr(expr, env, debug)?);
+            let value = eval_mutating(expr, env, debug)?;
+            println!("{}", Walker::new(env, debug).display(value)?);
             Ok(())
         }
-        Statement::Val(ident, expr, type_anno) => {
-            let value = expr.as_ref().map_or(Value::None, |e| eval_expr(e, env, debug).unwrap());
-            check_type(&value, type_anno)?;
-            env.insert(ident.clone(), value);
-            Ok(())
+        Statement::Val(ident, expr, type_anno) | Statement::Let(ident, expr, type_anno) => {
+            let unset = if type_anno.as_deref().is_some_and(|t| t.ends_with('?')) { Value::None } else { Value::Unset };
+            let value = expr.as_ref().map(|e| eval_mutating(e, env, debug)).transpose()?.unwrap_or(unset);
+            if let Some(type_anno) = type_anno {
+                Walker::new(env, debug).check_type(&value, type_anno)?;
+            }
+            let mutability = if matches!(stmt, Statement::Let(..)) { Mutability::Mutable } else { Mutability::Immutable };
+            define(env, ident, value, mutability)
         }
         Statement::Const(ident, expr, type_anno) => {
-            if env.contains_key(ident) {
-                return Err(format!("Const '{}' redefinition", ident));
+            if env.with(ident, |binding| binding.is_some()) {
+                return Err(ErrorValue::new("Error", format!("Const '{}' redefinition", ident)).into());
             }
             let value = eval_expr(expr, env, debug)?;
-            check_type(&value, type_anno)?;
-            env.insert(ident.clone(), value);
-            Ok(())
+            if let Some(type_anno) = type_anno {
+                Walker::new(env, debug).check_type(&value, type_anno)?;
+            }
+            define(env, ident, value, Mutability::Const)
         }
         Statement::Fun(name, params, ret_type, body) => {
-            env.insert(name.clone(), Value::Function(params.clone(), ret_type.clone(), body.clone()));
+            define(env, name, Value::Function(resolver::function(params, ret_type, body)), Mutability::Immutable)
+        }
+        Statement::Type(name, fields) => {
+            let constructor = resolver::function(fields, &None, &[]);
+            STATE.with(|s| s.borrow_mut().constructors.insert(name.clone(), constructor));
+            define(env, name, Value::Type(name.clone(), fields.clone()), Mutability::Immutable)
+        }
+        Statement::Trait(name, signatures, defaults) => {
+            let required = signatures.iter().map(|(method, _, _)| method.clone()).collect();
+            let defaults = method_table(defaults);
+            STATE.with(|s| s.borrow_mut().traits.insert(name.clone(), (required, defaults)));
+            Ok(())
+        }
+        Statement::Impl(type_name, trait_name, methods) => {
+            let declared = matches!(env.get(type_name), Some(Value::Type(..)));
+            if !declared && !BUILTIN_TYPES.contains(&type_name.as_str()) {
+                return Err(ErrorValue::new("NameError", format!("Type '{}' not found", type_name)).into());
+            }
+            let mut table = method_table(methods);
+            if let Some(trait_name) = trait_name {
+                let (required, defaults) = STATE
+                    .with(|s| s.borrow().traits.get(trait_name).cloned())
+                    .ok_or_else(|| ErrorValue::new("NameError", format!("Trait '{}' not found", trait_name)))?;
+                if let Some(missing) = required.iter().find(|method| !table.contains_key(*method)) {
+                    return Err(ErrorValue::new("TypeError", format!("type {} does not implement trait {} (missing method '{}')", type_name, trait_name, missing)).into());
+                }
+                for (method, func) in defaults {
+                    table.entry(method).or_insert(func);
+                }
+                STATE.with(|s| s.borrow_mut().impls.insert((type_name.clone(), trait_name.clone())));
+            }
+            STATE.with(|s| s.borrow_mut().methods.entry(type_name.clone()).or_default().extend(table));
             Ok(())
         }
         Statement::If(condition, then_block, else_block) => {
@@ -46,12 +304,17 @@ fn execute_stmt(stmt: &Statement, env: &mut HashMap<String, Value>, debug: bool)
             }
             Ok(())
         }
-        Statement::For(ident, expr, body) => {
-            for value in eval_expr(expr, env, debug)?.as_list()? {
-                env.insert(ident.clone(), value);
+        Statement::For(target, expr, body) => {
+            let iter = to_iter(eval_expr(expr, env, debug)?)?;
+            while let Some(value) = next_value(&iter, debug)? {
+                let mut bindings = Vec::new();
+                unpack(target, value, &mut bindings)?;
+                for (name, value) in bindings {
+                    define(env, &name, value, Mutability::Immutable)?;
+                }
                 match execute_block(body, env, debug) {
-                    Err(e) if e == "Break" => break,
-                    Err(e) if e == "Continue" => continue,
+                    Err(Signal::Break) => break,
+                    Err(Signal::Continue) => continue,
                     Err(e) => return Err(e),
                     Ok(_) => {}
                 }
@@ -61,88 +324,222 @@ fn execute_stmt(stmt: &Statement, env: &mut HashMap<String, Value>, debug: bool)
         Statement::While(condition, body) => {
             while eval_expr(condition, env, debug)?.as_bool()? {
                 match execute_block(body, env, debug) {
-                    Err(e) if e == "Break" => break,
-                    Err(e) if e == "Continue" => continue,
+                    Err(Signal::Break) => break,
+                    Err(Signal::Continue) => continue,
                     Err(e) => return Err(e),
                     Ok(_) => {}
                 }
             }
             Ok(())
         }
-        Statement::Break => Err("Break".to_string()),
-        Statement::Continue => Err("Continue".to_string()),
-        Statement::Try(error_ident, try_block, catch_block) => {
-            match execute_block(try_block, env, debug) {
-                Err(e) => {
-                    env.insert(error_ident.clone(), Value::String(e));
-                    execute_block(catch_block, env, debug)?;
-                }
-                Ok(_) => {}
+        Statement::Break => Err(Signal::Break),
+        Statement::Continue => Err(Signal::Continue),
+        Statement::Try(try_block, catches, finally_block) => {
+            let mut result = execute_block(try_block, env, debug);
+            if let Err(Signal::Error(error)) = result {
+                result = match catches.iter().find(|(_, kind, _)| kind.as_deref().is_none_or(|k| error.matches(k))) {
+                    Some((error_ident, _, catch_block)) => {
+                        define(env, error_ident, Value::Error(Box::new(error.clone())), Mutability::Immutable)?;
+                        STATE.with(|s| s.borrow_mut().handling.push(error));
+                        let caught = execute_block(catch_block, env, debug);
+                        STATE.with(|s| s.borrow_mut().handling.pop());
+                        caught
+                    }
+                    None => Err(Signal::Error(error)),
+                };
             }
-            Ok(())
+            if let Some(finally_block) = finally_block {
+                execute_block(finally_block, env, debug)?;
+            }
+            result
+        }
+        Statement::Throw(expr, line) => {
+            let error = match expr {
+                Some(expr) => match eval_expr(expr, env, debug)? {
+                    Value::Error(error) if error.location.is_some() => *error,
+                    Value::Error(error) => located(*error, *line),
+                    Value::String(message) => located(ErrorValue::new("Error", &*message), *line),
+                    other => located(ErrorValue::new("Error", other.to_string()), *line),
+                },
+                None => STATE
+                    .with(|s| s.borrow().handling.last().cloned())
+                    .ok_or_else(|| ErrorValue::new("Error", "'throw' without a value outside of 'catch'"))?,
+            };
+            Err(Signal::Error(error))
         }
         Statement::Match(expr, branches) => {
             let value = eval_expr(expr, env, debug)?;
             for (pattern, statements) in branches {
-                if pattern == "_" || pattern == &value.to_string() {
+                let mut bindings = Vec::new();
+                if match_pattern(pattern, &value, &mut bindings) {
+                    for (name, value) in bindings {
+                        define(env, &name, value, Mutability::Immutable)?;
+                    }
                     execute_block(statements, env, debug)?;
                     break;
                 }
             }
             Ok(())
         }
+        Statement::Unpack(target, expr, mutable) => {
+            let mut bindings = Vec::new();
+            unpack(target, eval_mutating(expr, env, debug)?, &mut bindings)?;
+            let mutability = if *mutable { Mutability::Mutable } else { Mutability::Immutable };
+            for (name, value) in bindings {
+                define(env, &name, value, mutability)?;
+            }
+            Ok(())
+        }
+        Statement::Assign(target, expr) => {
+            let mut bindings = Vec::new();
+            unpack(target, eval_mutating(expr, env, debug)?, &mut bindings)?;
+            for (name, value) in bindings {
+                assign(env, &name, value)?;
+            }
+            Ok(())
+        }
         Statement::Expr(expr) => {
-            eval_expr(expr, env, debug)?;
+            eval_mutating(expr, env, debug)?;
             Ok(())
         }
-        Statement::Return(expr) => Err(format!("Return: {}", eval_expr(expr, env, debug)?)),
-        Statement::Import(module, source) => {
+        Statement::Return(expr) => Err(Signal::Return(eval_mutating(expr, env, debug)?)),
+        Statement::Yield(_) => Err(ErrorValue::new("Error", "'yield' is only allowed inside a function").into()),
+        Statement::Import(module, _) => {
             let module_path = format!("{}.velvet", module);
             if !Path::new(&module_path).exists() {
-                return Err(format!("Module '{}' not found", module));
+                return Err(ErrorValue::new("ImportError", format!("Module '{}' not found", module)).into());
             }
-            let module_source = crate::utils::read_file(&module_path)?;
-            let ast = crate::parser::parse(&module_source)?;
-            execute_block(&ast, env, debug)?;
+            let module_source = crate::utils::read_file(&module_path).map_err(|e| ErrorValue::new("Error", e))?;
+            let (ast, scope) = resolver::program(&crate::parser::parse(&module_source).map_err(|e| ErrorValue::new("Error", e))?);
+            execute_block(&ast, &mut Env::new(env.globals.clone(), scope), debug)?;
             Ok(())
         }
         Statement::Test(name, body) => {
             if debug {
                 cli::info(&format!("Test: {}", name));
             }
-            execute_block(body, &mut env.clone(), debug)?;
+            let globals = env.globals.borrow().clone();
+            let result = execute_block(body, &mut env.clone(), debug);
+            *env.globals.borrow_mut() = globals;
+            result
+        }
+        Statement::Attributed(attributes, target) => {
+            execute_stmt(target, env, debug)?;
+            let name = match target.as_ref() {
+                Statement::Fun(name, ..) | Statement::Type(name, _) | Statement::Const(name, ..) => name,
+                _ => return Ok(()),
+            };
+            STATE.with(|s| {
+                let mut state = s.borrow_mut();
+                for attribute in attributes {
+                    match attribute.name.as_str() {
+                        "deprecated" => {
+                            let note = match attribute.args.first() {
+                                Some(Expr::String(note)) => Some(note.clone()),
+                                _ => None,
+                            };
+                            state.deprecated.insert(name.clone(), note);
+                        }
+                        "memoize" => {
+                            state.memos.insert(name.clone(), HashMap::new());
+                        }
+                        _ => {}
+                    }
+                }
+            });
             Ok(())
         }
     }
 }
 
-fn execute_block(stmts: &[Statement], env: &mut HashMap<String, Value>, debug: bool) -> Result<(), String> {
+fn execute_block(stmts: &[Statement], env: &mut Env, debug: bool) -> Result<(), Signal> {
     for stmt in stmts {
         execute_stmt(stmt, env, debug)?;
     }
     Ok(())
 }
 
-fn eval_expr(expr: &Expr, env: &HashMap<String, Value>, debug: bool) -> Result<Value, String> {
+fn mutate(env: &mut Env, ident: &str, method: &str, mut receiver: Value, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Signal> {
+    // Drop the variable's own reference first so an unshared collection is changed in place
+    let mutability = env.update(ident, |binding| {
+        if binding.mutability == Mutability::Mutable {
+            binding.value = Value::Unset;
+        }
+        binding.mutability
+    });
+    let result = call_native_method(&mut receiver, method, args, named);
+    match mutability.ok_or_else(|| ErrorValue::new("NameError", format!("Var '{}' not found", ident)))? {
+        Mutability::Const => {
+            result?;
+            Err(ErrorValue::new("Error", format!("Cannot call '{}' on constant '{}'", method, ident)).into())
+        }
+        Mutability::Immutable => {
+            result?;
+            Err(ErrorValue::new("Error", format!("Cannot call '{}' on immutable variable '{}' (declare it with 'let' to make it mutable)", method, ident)).into())
+        }
+        Mutability::Mutable => {
+            env.update(ident, |binding| binding.value = receiver);
+            result
+        }
+    }
+}
+
+fn eval_mutating(expr: &Expr, env: &mut Env, debug: bool) -> Result<Value, Signal> {
+    if let Expr::MethodCall(target, method, args) = expr {
+        if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+            let receiver = eval_expr(target, env, debug)?;
+            if methods::is_mutating(&receiver, method) && user_method(receiver.type_name(), method).is_none() {
+                let (values, named) = eval_args(args, env, debug)?;
+                return mutate(env, name, method, receiver, values, named);
+            }
+        }
+    }
+    eval_expr(expr, env, debug)
+}
+
+fn eval_expr(expr: &Expr, env: &Env, debug: bool) -> Result<Value, Signal> {
     if debug {
         cli::debug(&format!("Expr: {:?}", expr));
     }
     match expr {
-        Expr::String(s) => Ok(Value::String(s.clone())),
+        Expr::String(s) => Ok(Value::String(s.as_str().into())),
         Expr::Number(n) => Ok(Value::Number(*n)),
         Expr::Bool(b) => Ok(Value::Bool(*b)),
-        Expr::Ident(id) => env.get(id).cloned().ok_or(format!("Var '{}' not found", id)),
+        Expr::Ident(id) if id == "none" && env.with(id, |binding| binding.is_none()) => Ok(Value::None),
+        Expr::Ident(id) => env.with(id, |binding| read(id, binding)),
+        Expr::Local(slot, id) => env.with_slot(*slot, id, |binding| read(id, binding)),
+        Expr::Binary(left, op, right) if op == "??" => match eval_expr(left, env, debug)? {
+            Value::None => eval_expr(right, env, debug),
+            Value::Some(value) => Ok(*value),
+            value => Ok(value),
+        },
+        Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+            let left_val = eval_expr(left, env, debug)?.as_bool()?;
+            if left_val == (op == "or") {
+                return Ok(Value::Bool(left_val));
+            }
+            Ok(Value::Bool(eval_expr(right, env, debug)?.as_bool()?))
+        }
         Expr::Binary(left, op, right) => {
             let left_val = eval_expr(left, env, debug)?;
             let right_val = eval_expr(right, env, debug)?;
+            if let Some(result) = Walker::new(env, debug).call_operator(op, &left_val, &right_val)? {
+                return Ok(result);
+            }
             match op.as_str() {
-                "+" => Ok(Value::Number(left_val.as_number()? + right_val.as_number()?)),
+                "+" => match (&left_val, &right_val) {
+                    (Value::String(_), _) | (_, Value::String(_)) => {
+                        let mut walker = Walker::new(env, debug);
+                        Ok(Value::String(format!("{}{}", walker.display(left_val)?, walker.display(right_val)?).into()))
+                    }
+                    _ => Ok(Value::Number(left_val.as_number()? + right_val.as_number()?)),
+                },
                 "-" => Ok(Value::Number(left_val.as_number()? - right_val.as_number()?)),
                 "*" => Ok(Value::Number(left_val.as_number()? * right_val.as_number()?)),
                 "/" => {
                     let r = right_val.as_number()?;
                     if r == 0.0 {
-                        return Err("Division by zero".to_string());
+                        return Err(ErrorValue::new("ZeroDivisionError", "Division by zero").into());
                     }
                     Ok(Value::Number(left_val.as_number()? / r))
                 }
@@ -152,9 +549,7 @@ fn eval_expr(expr: &Expr, env: &HashMap<String, Value>, debug: bool) -> Result<V
                 ">=" => Ok(Value::Bool(left_val.as_number()? >= right_val.as_number()?)),
                 "<" => Ok(Value::Bool(left_val.as_number()? < right_val.as_number()?)),
                 "<=" => Ok(Value::Bool(left_val.as_number()? <= right_val.as_number()?)),
-                "and" => Ok(Value::Bool(left_val.as_bool()? && right_val.as_bool()?)),
-                "or" => Ok(Value::Bool(left_val.as_bool()? || right_val.as_bool()?)),
-                _ => Err(format!("Unknown operator '{}'", op)),
+                _ => Err(ErrorValue::new("Error", format!("Unknown operator '{}'", op)).into()),
             }
         }
         Expr::Unary(op, expr) => {
@@ -162,46 +557,552 @@ fn eval_expr(expr: &Expr, env: &HashMap<String, Value>, debug: bool) -> Result<V
             match op.as_str() {
                 "-" => Ok(Value::Number(-value.as_number()?)),
                 "!" => Ok(Value::Bool(!value.as_bool()?)),
-                _ => Err(format!("Unknown unary op '{}'", op)),
+                _ => Err(ErrorValue::new("Error", format!("Unknown unary op '{}'", op)).into()),
             }
         }
         Expr::Call(name, args) => {
-            let func = env.get(name).ok_or(format!("Function '{}' not found", name))?;
-            if let Value::Function(params, ret_type, body) = func {
-                if params.len() != args.len() {
-                    return Err(format!("Expected {} args, got {}", params.len(), args.len()));
-                }
-                let mut local_env = env.clone();
-                for ((param, _), arg) in params.iter().zip(args.iter()) {
-                    local_env.insert(param.clone(), eval_expr(arg, env, debug)?);
-                }
-                let result = execute_block(body, &mut local_env, debug)?;
-                if let Some(ret_type) = ret_type {
-                    if ret_type != "void" {
-                        return Err("Missing return value".to_string());
+            let (values, named) = eval_args(args, env, debug)?;
+            match env.get(name) {
+                Some(func) => {
+                    warn_deprecated(name);
+                    if !STATE.with(|s| s.borrow().memos.contains_key(name)) {
+                        return call_function(name, &func, values, named, env, debug);
+                    }
+                    let key = format!("{:?}{:?}", values, named);
+                    if let Some(value) = STATE.with(|s| s.borrow().memos[name].get(&key).cloned()) {
+                        return Ok(value);
                     }
+                    let value = call_function(name, &func, values, named, env, debug)?;
+                    STATE.with(|s| s.borrow_mut().memos.get_mut(name).unwrap().insert(key, value.clone()));
+                    Ok(value)
                 }
-                Ok(Value::None)
+                None if named.is_empty() => Walker::new(env, debug).call_builtin(name, values),
+                None => Err(ErrorValue::new("ArgumentError", format!("Builtin '{}' does not take named argument '{}'", name, named[0].0)).into()),
+            }
+        }
+        Expr::MethodCall(target, method, args) => {
+            let receiver = eval_expr(target, env, debug)?;
+            if let Expr::Ident(name) | Expr::Local(_, name) = target.as_ref() {
+                if methods::is_mutating(&receiver, method) && user_method(receiver.type_name(), method).is_none() {
+                    return Err(ErrorValue::new(
+                        "Error",
+                        format!("Method '{}' changes '{}' and can only be called as a statement or as the value of a declaration or assignment", method, name),
+                    )
+                    .into());
+                }
+            }
+            let (values, named) = eval_args(args, env, debug)?;
+            call_method(receiver, method, values, named, env, debug)
+        }
+        Expr::Named(name, _) => Err(ErrorValue::new("ArgumentError", format!("Named argument '{}' is only allowed in a call", name)).into()),
+        Expr::List(elements) => Ok(Value::List(Rc::new(elements.iter().map(|e| eval_expr(e, env, debug)).collect::<Result<_, _>>()?))),
+        Expr::Map(entries) => {
+            let mut map = Vec::new();
+            for (key, value) in entries {
+                insert_entry(&mut map, eval_expr(key, env, debug)?, eval_expr(value, env, debug)?);
+            }
+            Ok(Value::Map(Rc::new(map)))
+        }
+        Expr::ListComp(element, clauses) => {
+            let mut items = Vec::new();
+            comprehend(clauses, &mut env.clone(), debug, &mut |local_env| {
+                items.push(eval_expr(element, local_env, debug)?);
+                Ok(())
+            })?;
+            Ok(Value::List(Rc::new(items)))
+        }
+        Expr::MapComp(key, value, clauses) => {
+            let mut map = Vec::new();
+            comprehend(clauses, &mut env.clone(), debug, &mut |local_env| {
+                insert_entry(&mut map, eval_expr(key, local_env, debug)?, eval_expr(value, local_env, debug)?);
+                Ok(())
+            })?;
+            Ok(Value::Map(Rc::new(map)))
+        }
+        Expr::Tuple(elements) => Ok(Value::Tuple(elements.iter().map(|e| eval_expr(e, env, debug)).collect::<Result<_, _>>()?)),
+        Expr::Index(target, index) => {
+            let target = eval_expr(target, env, debug)?;
+            let index = eval_expr(index, env, debug)?;
+            if let Some(result) = Walker::new(env, debug).call_operator("[]", &target, &index)? {
+                return Ok(result);
+            }
+            Ok(methods::get_index(&target, &index)?)
+        }
+        Expr::Slice(target, start, end, step) => {
+            let target = eval_expr(target, env, debug)?;
+            let mut bounds = Vec::new();
+            for bound in [start, end, step] {
+                bounds.push(match bound {
+                    Some(bound) => Some(eval_expr(bound, env, debug)?.as_number()?),
+                    None => None,
+                });
+            }
+            Ok(methods::slice(&target, bounds[0], bounds[1], bounds[2])?)
+        }
+        Expr::SafeField(target, field) => match eval_expr(target, env, debug)? {
+            Value::None => Ok(Value::None),
+            Value::Some(value) => Ok(get_field(*value, field)?),
+            value => Ok(get_field(value, field)?),
+        },
+        Expr::Field(target, field) => Ok(get_field(eval_expr(target, env, debug)?, field)?),
+        Expr::Propagate(inner) => match eval_expr(inner, env, debug)? {
+            Value::Ok(value) | Value::Some(value) => Ok(*value),
+            failed @ (Value::Err(_) | Value::None) => {
+                if STATE.with(|s| s.borrow().calls.is_empty()) {
+                    return Err(ErrorValue::new("Error", format!("'?' on {} outside of a function", failed)).into());
+                }
+                Err(Signal::Return(failed))
+            }
+            other => Err(ErrorValue::new("TypeError", format!("Expected result or option for '?', got {}", other)).into()),
+        },
+        Expr::If(condition, then_expr, else_expr) => {
+            if eval_expr(condition, env, debug)?.as_bool()? {
+                eval_expr(then_expr, env, debug)
             } else {
-                Err(format!("'{}' is not a function", name))
+                eval_expr(else_expr, env, debug)
             }
         }
-        Expr::List(elements) => Ok(Value::List(elements.iter().map(|e| eval_expr(e, env, debug)).collect::<Result<_, _>>()?)),
-        Expr::Index(ident, index) => {
-            let list = env.get(ident).ok_or(format!("Var '{}' not found", ident))?.as_list()?;
-            let idx = eval_expr(index, env, debug)?.as_number()? as usize;
-            list.get(idx).cloned().ok_or(format!("Index {} out of bounds", idx))
+        Expr::Match(expr, arms) => {
+            let value = eval_expr(expr, env, debug)?;
+            for (pattern, arm) in arms {
+                let mut bindings = Vec::new();
+                if match_pattern(pattern, &value, &mut bindings) {
+                    let mut local_env = env.clone();
+                    for (name, value) in bindings {
+                        define(&mut local_env, &name, value, Mutability::Immutable)?;
+                    }
+                    return eval_expr(arm, &local_env, debug);
+                }
+            }
+            Err(ErrorValue::new("ValueError", format!("No match arm for value {}", value)).into())
+        }
+    }
+}
+
+pub fn insert_entry(map: &mut Vec<(Value, Value)>, key: Value, value: Value) {
+    match map.iter_mut().find(|(k, _)| k == &key) {
+        Some(entry) => entry.1 = value,
+        None => map.push((key, value)),
+    }
+}
+
+fn comprehend(clauses: &[Clause], env: &mut Env, debug: bool, emit: &mut dyn FnMut(&Env) -> Result<(), Signal>) -> Result<(), Signal> {
+    match clauses.split_first() {
+        None => emit(env),
+        Some((Clause::If(condition), rest)) => {
+            if eval_expr(condition, env, debug)?.as_bool()? {
+                comprehend(rest, env, debug, emit)?;
+            }
+            Ok(())
+        }
+        Some((Clause::For(target, expr), rest)) => {
+            let iter = to_iter(eval_expr(expr, env, debug)?)?;
+            while let Some(value) = next_value(&iter, debug)? {
+                let mut bindings = Vec::new();
+                unpack(target, value, &mut bindings)?;
+                for (name, value) in bindings {
+                    define(env, &name, value, Mutability::Immutable)?;
+                }
+                comprehend(rest, env, debug, emit)?;
+            }
+            Ok(())
         }
     }
 }
 
-fn check_type(value: &Value, type_anno: &Option<String>) -> Result<(), String> {
-    if let Some(type_anno) = type_anno {
-        match (type_anno.as_str(), value) {
-            ("str", Value::String(_)) | ("f64", Value::Number(_)) | ("bool", Value::Bool(_)) | ("list", Value::List(_)) | ("fn", Value::Function(_, _, _)) => Ok(()),
-            _ => Err(format!("Expected {}, got {}", type_anno, value)),
+fn eval_args(args: &[Expr], env: &Env, debug: bool) -> Result<Args, Signal> {
+    let mut values = Vec::new();
+    let mut named = Vec::new();
+    for arg in args {
+        match arg {
+            Expr::Named(arg_name, expr) => named.push((arg_name.clone(), eval_expr(expr, env, debug)?)),
+            expr => values.push(eval_expr(expr, env, debug)?),
         }
-    } else {
-        Ok(())
     }
+    Ok((values, named))
+}
+
+fn method_table(methods: &[Statement]) -> HashMap<String, Value> {
+    methods
+        .iter()
+        .filter_map(|method| match method {
+            Statement::Fun(name, params, ret_type, body) => Some((name.clone(), Value::Function(resolver::function(params, ret_type, body)))),
+            _ => None,
+        })
+        .collect()
+}
+
+fn user_method(type_name: &str, method: &str) -> Option<Value> {
+    STATE.with(|s| s.borrow().methods.get(type_name).and_then(|methods| methods.get(method)).cloned())
+}
+
+// The tree walker as a runtime::Engine: calls made through it see `env`'s globals.
+struct Walker<'a> {
+    env: &'a Env,
+    debug: bool,
+}
+
+impl<'a> Walker<'a> {
+    fn new(env: &'a Env, debug: bool) -> Self {
+        Walker { env, debug }
+    }
+}
+
+impl Engine for Walker<'_> {
+    type Error = Signal;
+
+    fn rethrow(error: ErrorValue) -> Signal {
+        Signal::Error(error)
+    }
+
+    fn call_function(&mut self, name: &str, func: &Value, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Signal> {
+        call_function(name, func, args, named, self.env, self.debug)
+    }
+
+    fn next_value(&mut self, iter: &Iter) -> Result<Option<Value>, Signal> {
+        next_value(iter, self.debug)
+    }
+
+    fn user_method(&self, type_name: &str, method: &str) -> Option<Value> {
+        user_method(type_name, method)
+    }
+
+    fn is_trait(&self, name: &str) -> bool {
+        STATE.with(|s| s.borrow().traits.contains_key(name))
+    }
+
+    fn implements(&self, type_name: &str, trait_name: &str) -> bool {
+        STATE.with(|s| s.borrow().impls.contains(&(type_name.to_string(), trait_name.to_string())))
+    }
+}
+
+fn call_native_method(receiver: &mut Value, method: &str, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Signal> {
+    if let Some((arg_name, _)) = named.first() {
+        return Err(ErrorValue::new("ArgumentError", format!("Method '{}' does not take named argument '{}'", method, arg_name)).into());
+    }
+    Ok(methods::call(receiver, method, args)?)
+}
+
+fn call_method(mut receiver: Value, method: &str, mut args: Vec<Value>, named: Vec<(String, Value)>, env: &Env, debug: bool) -> Result<Value, Signal> {
+    let (type_name, is_static) = match &receiver {
+        Value::Type(name, _) => (name.clone(), true),
+        value => (value.type_name().to_string(), false),
+    };
+    let Some(func) = user_method(&type_name, method) else {
+        if !is_static && methods::available(&type_name).contains(&method) {
+            if let Value::Iterator(_) = receiver {
+                if let Some((arg_name, _)) = named.first() {
+                    return Err(ErrorValue::new("ArgumentError", format!("Method '{}' does not take named argument '{}'", method, arg_name)).into());
+                }
+                args.insert(0, receiver);
+                return Walker::new(env, debug).call_builtin(method, args);
+            }
+            return call_native_method(&mut receiver, method, args, named);
+        }
+        let mut available: Vec<String> = STATE.with(|s| s.borrow().methods.get(&type_name).map(|m| m.keys().cloned().collect()).unwrap_or_default());
+        if !is_static {
+            available.extend(methods::available(&type_name).iter().map(|m| m.to_string()));
+        }
+        available.sort();
+        available.dedup();
+        let available = if available.is_empty() { "none".to_string() } else { available.join(", ") };
+        return Err(ErrorValue::new("NameError", format!("Type '{}' has no method '{}' (available: {})", type_name, method, available)).into());
+    };
+    if !is_static {
+        args.insert(0, receiver);
+    }
+    call_function(&format!("{}.{}", type_name, method), &func, args, named, env, debug)
+}
+
+pub fn get_field(value: Value, field: &str) -> Result<Value, ErrorValue> {
+    match (value, field) {
+        (Value::Record(name, fields), _) => fields
+            .into_iter()
+            .find(|(name, _)| name == field)
+            .map(|(_, value)| value)
+            .ok_or_else(|| ErrorValue::new("NameError", format!("Type '{}' has no field '{}'", name, field))),
+        (Value::Error(e), "kind") => Ok(Value::String(e.kind.into())),
+        (Value::Error(e), "message") => Ok(Value::String(e.message.into())),
+        (Value::Error(e), "location") => Ok(e.location.map_or(Value::None, |location| Value::String(location.into()))),
+        (Value::Error(e), "stack") => Ok(Value::List(Rc::new(e.stack.into_iter().map(|frame| Value::String(frame.into())).collect()))),
+        (Value::None, _) => Err(ErrorValue::new("Error", format!("Cannot read field '{}' of none (use '?.' for optional values)", field))),
+        (value, _) => Err(ErrorValue::new("Error", format!("Value {} has no field '{}'", value, field))),
+    }
+}
+
+fn bind_params(name: &str, params: &[Param], args: Vec<Value>, named: Vec<(String, Value)>, local_env: &mut Env, debug: bool) -> Result<(), Signal> {
+    let values = Walker::new(local_env, debug).bind_args(name, params, args, named)?;
+    for (slot, (param, value)) in params.iter().zip(values).enumerate() {
+        let value = match value {
+            Some(value) => value,
+            None => {
+                let value = eval_expr(param.default.as_ref().unwrap(), local_env, debug)?;
+                let walker = Walker::new(local_env, debug);
+                if walker.is_trait(&param.type_anno) {
+                    walker.check_type(&value, &param.type_anno)?;
+                }
+                value
+            }
+        };
+        local_env.slots[slot] = Some(Binding { value, mutability: Mutability::Immutable });
+    }
+    Ok(())
+}
+
+fn call_function(name: &str, func: &Value, args: Vec<Value>, named: Vec<(String, Value)>, env: &Env, debug: bool) -> Result<Value, Signal> {
+    match func {
+        Value::Function(function) => {
+            #[cfg(feature = "jit")]
+            if named.is_empty() {
+                if let Some(value) = crate::jit::call(function, &args, |name| jit_global(env, name)) {
+                    return Ok(value);
+                }
+            }
+            let mut local_env = Env::new(env.globals.clone(), function.scope.clone());
+            bind_params(name, &function.params, args, named, &mut local_env, debug)?;
+            if function.generator {
+                let generator = Generator { name: name.to_string(), env: local_env, stack: vec![Frame::Block(function.body.clone(), 0)] };
+                return Ok(Value::Iterator(Iter::new(LazyIter::Generator(generator))));
+            }
+            STATE.with(|s| s.borrow_mut().calls.push(name.to_string()));
+            let result = execute_block(&function.body, &mut local_env, debug);
+            STATE.with(|s| s.borrow_mut().calls.pop());
+            match result {
+                Err(Signal::Return(value)) => Ok(value),
+                Err(Signal::Break) | Err(Signal::Continue) => Err(ErrorValue::new("Error", format!("'break' or 'continue' escaped function '{}'", name)).into()),
+                Err(e) => Err(e),
+                Ok(()) => match &function.ret_type {
+                    Some(ret_type) if ret_type != "void" => Err(ErrorValue::new("Error", "Missing return value").into()),
+                    _ => Ok(Value::None),
+                },
+            }
+        }
+        Value::Type(type_name, fields) => {
+            let constructor = STATE
+                .with(|s| s.borrow().constructors.get(type_name).cloned())
+                .unwrap_or_else(|| resolver::function(fields, &None, &[]));
+            let mut local_env = Env::new(env.globals.clone(), constructor.scope.clone());
+            bind_params(type_name, &constructor.params, args, named, &mut local_env, debug)?;
+            let mut values = Vec::new();
+            for (field, binding) in fields.iter().zip(local_env.slots) {
+                let value = binding.unwrap().value;
+                Walker::new(env, debug).check_type(&value, &field.type_anno)?;
+                values.push((field.name.clone(), value));
+            }
+            Ok(Value::Record(type_name.clone(), values))
+        }
+        _ => Err(ErrorValue::new("TypeError", format!("'{}' is not a function", name)).into()),
+    }
+}
+
+#[cfg(feature = "jit")]
+fn jit_global(env: &Env, name: &str) -> crate::jit::Global {
+    let wrapped = STATE.with(|s| s.borrow().memos.contains_key(name) || s.borrow().deprecated.contains_key(name));
+    match env.globals.borrow().get(name) {
+        Some(Binding { mutability: Mutability::Const, .. }) => crate::jit::Global::Const,
+        Some(Binding { value: Value::Function(function), .. }) if !wrapped => crate::jit::Global::Function(function.clone()),
+        _ => crate::jit::Global::Other,
+    }
+}
+
+pub struct Generator {
+    name: String,
+    env: Env,
+    stack: Vec<Frame>,
+}
+
+enum Frame {
+    Block(Vec<Statement>, usize),
+    While(Expr, Vec<Statement>),
+    For(Target, Iter, Vec<Statement>),
+    Try(Vec<(String, Option<String>, Vec<Statement>)>, Option<Vec<Statement>>),
+    Finally(Vec<Statement>),
+}
+
+pub fn contains_yield(stmts: &[Statement]) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Statement::Yield(_) => true,
+        Statement::If(_, then_block, else_block) => contains_yield(then_block) || else_block.as_deref().is_some_and(contains_yield),
+        Statement::For(_, _, body) | Statement::While(_, body) | Statement::Test(_, body) => contains_yield(body),
+        Statement::Try(try_block, catches, finally_block) => {
+            contains_yield(try_block)
+                || catches.iter().any(|(_, _, block)| contains_yield(block))
+                || finally_block.as_deref().is_some_and(contains_yield)
+        }
+        Statement::Match(_, branches) => branches.iter().any(|(_, block)| contains_yield(block)),
+        _ => false,
+    })
+}
+
+fn next_value(iter: &Iter, debug: bool) -> Result<Option<Value>, Signal> {
+    iter.next_with(&mut |lazy| match lazy {
+        LazyIter::Generator(generator) => resume(generator, debug),
+        _ => Err(ErrorValue::new("Error", "Iterator belongs to another engine").into()),
+    })
+}
+
+fn resume(generator: &mut Generator, debug: bool) -> Result<Option<Value>, Signal> {
+    STATE.with(|s| s.borrow_mut().calls.push(generator.name.clone()));
+    let mut result = Ok(None);
+    while !generator.stack.is_empty() {
+        result = match advance(generator, debug) {
+            Ok(None) => continue,
+            Ok(Some(value)) => Ok(Some(value)),
+            Err(signal) => match unwind(generator, signal, debug) {
+                Ok(()) => continue,
+                Err(signal) => Err(signal),
+            },
+        };
+        break;
+    }
+    STATE.with(|s| s.borrow_mut().calls.pop());
+    if !matches!(result, Ok(Some(_))) {
+        generator.stack.clear();
+    }
+    result
+}
+
+fn advance(generator: &mut Generator, debug: bool) -> Result<Option<Value>, Signal> {
+    match generator.stack.last_mut().unwrap() {
+        Frame::Block(stmts, pc) => match stmts.get(*pc).cloned() {
+            Some(stmt) => {
+                *pc += 1;
+                return advance_stmt(&stmt, generator, debug);
+            }
+            None => {
+                generator.stack.pop();
+            }
+        },
+        Frame::While(condition, body) => {
+            if eval_expr(condition, &generator.env, debug)?.as_bool()? {
+                let body = body.clone();
+                generator.stack.push(Frame::Block(body, 0));
+            } else {
+                generator.stack.pop();
+            }
+        }
+        Frame::For(target, iter, body) => match next_value(iter, debug)? {
+            Some(value) => {
+                let mut bindings = Vec::new();
+                unpack(target, value, &mut bindings)?;
+                let body = body.clone();
+                for (name, value) in bindings {
+                    define(&mut generator.env, &name, value, Mutability::Immutable)?;
+                }
+                generator.stack.push(Frame::Block(body, 0));
+            }
+            None => {
+                generator.stack.pop();
+            }
+        },
+        Frame::Try(_, finally_block) => {
+            let finally_block = finally_block.take();
+            generator.stack.pop();
+            if let Some(finally_block) = finally_block {
+                generator.stack.push(Frame::Block(finally_block, 0));
+            }
+        }
+        Frame::Finally(finally_block) => {
+            let finally_block = std::mem::take(finally_block);
+            generator.stack.pop();
+            generator.stack.push(Frame::Block(finally_block, 0));
+        }
+    }
+    Ok(None)
+}
+
+fn advance_stmt(stmt: &Statement, generator: &mut Generator, debug: bool) -> Result<Option<Value>, Signal> {
+    if !contains_yield(std::slice::from_ref(stmt)) {
+        execute_stmt(stmt, &mut generator.env, debug)?;
+        return Ok(None);
+    }
+    match stmt {
+        Statement::Yield(expr) => return Ok(Some(eval_mutating(expr, &mut generator.env, debug)?)),
+        Statement::If(condition, then_block, else_block) => {
+            let block = if eval_expr(condition, &generator.env, debug)?.as_bool()? { Some(then_block) } else { else_block.as_ref() };
+            if let Some(block) = block {
+                generator.stack.push(Frame::Block(block.clone(), 0));
+            }
+        }
+        Statement::While(condition, body) => generator.stack.push(Frame::While(condition.clone(), body.clone())),
+        Statement::For(target, expr, body) => {
+            let iter = to_iter(eval_expr(expr, &generator.env, debug)?)?;
+            generator.stack.push(Frame::For(target.clone(), iter, body.clone()));
+        }
+        Statement::Try(try_block, catches, finally_block) => {
+            generator.stack.push(Frame::Try(catches.clone(), finally_block.clone()));
+            generator.stack.push(Frame::Block(try_block.clone(), 0));
+        }
+        Statement::Match(expr, branches) => {
+            let value = eval_expr(expr, &generator.env, debug)?;
+            for (pattern, statements) in branches {
+                let mut bindings = Vec::new();
+                if match_pattern(pattern, &value, &mut bindings) {
+                    for (name, value) in bindings {
+                        define(&mut generator.env, &name, value, Mutability::Immutable)?;
+                    }
+                    generator.stack.push(Frame::Block(statements.clone(), 0));
+                    break;
+                }
+            }
+        }
+        other => execute_stmt(other, &mut generator.env, debug)?,
+    }
+    Ok(None)
+}
+
+fn unwind(generator: &mut Generator, signal: Signal, debug: bool) -> Result<(), Signal> {
+    while let Some(frame) = generator.stack.pop() {
+        match frame {
+            Frame::While(..) | Frame::For(..) if matches!(signal, Signal::Break) => return Ok(()),
+            frame @ (Frame::While(..) | Frame::For(..)) if matches!(signal, Signal::Continue) => {
+                generator.stack.push(frame);
+                return Ok(());
+            }
+            Frame::Try(catches, finally_block) => {
+                if let Signal::Error(error) = &signal {
+                    if let Some((error_ident, _, catch_block)) = catches.iter().find(|(_, kind, _)| kind.as_deref().is_none_or(|k| error.matches(k))) {
+                        if let Some(finally_block) = finally_block {
+                            generator.stack.push(Frame::Finally(finally_block));
+                        }
+                        define(&mut generator.env, error_ident, Value::Error(Box::new(error.clone())), Mutability::Immutable)?;
+                        generator.stack.push(Frame::Block(catch_block.clone(), 0));
+                        return Ok(());
+                    }
+                }
+                if let Some(finally_block) = finally_block {
+                    execute_block(&finally_block, &mut generator.env, debug)?;
+                }
+            }
+            Frame::Finally(finally_block) => execute_block(&finally_block, &mut generator.env, debug)?,
+            _ => {}
+        }
+    }
+    match signal {
+        Signal::Return(_) => Ok(()),
+        Signal::Break | Signal::Continue => Err(ErrorValue::new("Error", format!("'break' or 'continue' escaped function '{}'", generator.name)).into()),
+        error => Err(error),
+    }
+}
+
+pub fn match_pattern(pattern: &Pattern, value: &Value, bindings: &mut Vec<(String, Value)>) -> bool {
+    match pattern {
+        Pattern::Wildcard => true,
+        Pattern::Bind(name) => {
+            bindings.push((name.clone(), value.clone()));
+            true
+        }
+        Pattern::Literal(literal) => literal == &value.to_string(),
+        Pattern::Variant(name, inner) => match (name.as_str(), value, inner) {
+            ("ok", Value::Ok(v), Some(p)) | ("err", Value::Err(v), Some(p)) | ("some", Value::Some(v), Some(p)) => match_pattern(p, v, bindings),
+            ("none", Value::None, None) => true,
+            _ => false,
+        },
+    }
+}
+
+fn located(mut error: ErrorValue, line: usize) -> ErrorValue {
+    error.location = Some(format!("line {}", line));
+    error.stack = call_stack();
+    error
 }
@@ -0,0 +1,546 @@
+use crate::ast::*;
+use crate::resolver::{Function, Scope};
+use crate::runtime::Value;
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{self, types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Signature, UserFuncName};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+const HOT_CALLS: usize = 50;
+
+pub enum Global {
+    Function(Rc<Function>),
+    Const,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Number,
+    Bool,
+}
+
+impl Ty {
+    fn parse(name: &str) -> Option<Ty> {
+        match name {
+            "f64" => Some(Ty::Number),
+            "bool" => Some(Ty::Bool),
+            _ => None,
+        }
+    }
+
+    fn ir(self) -> ir::Type {
+        match self {
+            Ty::Number => types::F64,
+            Ty::Bool => types::I8,
+        }
+    }
+}
+
+type Entry = unsafe extern "C" fn(*const f64, *mut u8) -> f64;
+type Deps = Vec<(String, Option<Rc<Function>>)>;
+
+enum Status {
+    Counting(usize),
+    Rejected,
+    Compiled(FuncId, Entry, Rc<Deps>),
+}
+
+struct Slot {
+    function: Weak<Function>,
+    status: Status,
+}
+
+struct Jit {
+    module: JITModule,
+    slots: HashMap<*const Function, Slot>,
+    names: usize,
+}
+
+struct Batch<'a> {
+    lookup: &'a dyn Fn(&str) -> Global,
+    queue: Vec<(Rc<Function>, FuncId)>,
+    deps: HashMap<String, Option<Rc<Function>>>,
+}
+
+struct Translator<'a, 'b> {
+    b: FunctionBuilder<'a>,
+    jit: &'a mut Jit,
+    batch: &'a mut Batch<'b>,
+    scope: Rc<Scope>,
+    scopes: Vec<HashMap<String, (Variable, Ty, bool)>>,
+    loops: Vec<(Block, Block)>,
+    refs: HashMap<FuncId, FuncRef>,
+    ret: Ty,
+    bail: ir::Value,
+    bail_block: Block,
+    vars: usize,
+}
+
+thread_local! {
+    static JIT: RefCell<Option<Jit>> = const { RefCell::new(None) };
+}
+
+pub fn enable() -> Result<(), String> {
+    let mut flags = settings::builder();
+    for (name, value) in [("opt_level", "speed"), ("use_colocated_libcalls", "false"), ("is_pic", "false")] {
+        flags.set(name, value).map_err(|e| e.to_string())?;
+    }
+    let isa = cranelift_native::builder()?.finish(settings::Flags::new(flags)).map_err(|e| e.to_string())?;
+    let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+    JIT.with(|jit| *jit.borrow_mut() = Some(Jit { module, slots: HashMap::new(), names: 0 }));
+    Ok(())
+}
+
+pub fn call(function: &Rc<Function>, args: &[Value], lookup: impl Fn(&str) -> Global) -> Option<Value> {
+    JIT.with(|jit| {
+        let mut jit = jit.borrow_mut();
+        let (entry, deps) = jit.as_mut()?.entry(function, &lookup)?;
+        if args.len() != function.params.len() || !deps.iter().all(|(name, expected)| unchanged(lookup(name), expected)) {
+            return None;
+        }
+        let mut values = Vec::with_capacity(args.len());
+        for (arg, param) in args.iter().zip(&function.params) {
+            match (arg, param.type_anno.as_str()) {
+                (Value::Number(n), "f64") => values.push(*n),
+                (Value::Bool(b), "bool") => values.push(if *b { 1.0 } else { 0.0 }),
+                _ => return None,
+            }
+        }
+        let mut bail = 0u8;
+        let result = unsafe { entry(values.as_ptr(), &mut bail) };
+        match (bail, function.ret_type.as_deref()) {
+            (0, Some("bool")) => Some(Value::Bool(result != 0.0)),
+            (0, _) => Some(Value::Number(result)),
+            _ => None,
+        }
+    })
+}
+
+fn unchanged(global: Global, expected: &Option<Rc<Function>>) -> bool {
+    match (global, expected) {
+        (Global::Function(function), Some(expected)) => Rc::ptr_eq(&function, expected),
+        (Global::Const, None) | (_, Some(_)) => false,
+        (_, None) => true,
+    }
+}
+
+fn signature(function: &Function) -> Option<(Vec<Ty>, Ty)> {
+    if function.generator || function.params.iter().any(|p| p.default.is_some() || p.variadic) {
+        return None;
+    }
+    let params = function.params.iter().map(|p| Ty::parse(&p.type_anno)).collect::<Option<Vec<_>>>()?;
+    Some((params, Ty::parse(function.ret_type.as_deref()?)?))
+}
+
+impl Jit {
+    fn slot(&mut self, function: &Rc<Function>) -> &mut Slot {
+        let slot = self.slots.entry(Rc::as_ptr(function)).or_insert_with(|| Slot { function: Weak::new(), status: Status::Counting(0) });
+        if !slot.function.upgrade().is_some_and(|f| Rc::ptr_eq(&f, function)) {
+            *slot = Slot { function: Rc::downgrade(function), status: Status::Counting(0) };
+        }
+        slot
+    }
+
+    fn entry(&mut self, function: &Rc<Function>, lookup: &dyn Fn(&str) -> Global) -> Option<(Entry, Rc<Deps>)> {
+        match &mut self.slot(function).status {
+            Status::Compiled(_, entry, deps) => return Some((*entry, deps.clone())),
+            Status::Rejected => return None,
+            Status::Counting(calls) if *calls + 1 < HOT_CALLS => {
+                *calls += 1;
+                return None;
+            }
+            Status::Counting(_) => {}
+        }
+        if self.compile(function, lookup).is_none() {
+            self.slot(function).status = Status::Rejected;
+            return None;
+        }
+        self.entry(function, lookup)
+    }
+
+    fn name(&mut self) -> String {
+        self.names += 1;
+        format!("velvet_jit_{}", self.names)
+    }
+
+    fn signature(&self, params: &[Ty], ret: Ty) -> Signature {
+        let mut signature = self.module.make_signature();
+        signature.params.extend(params.iter().map(|ty| AbiParam::new(ty.ir())));
+        signature.params.push(AbiParam::new(self.module.target_config().pointer_type()));
+        signature.returns.push(AbiParam::new(ret.ir()));
+        signature
+    }
+
+    fn declare(&mut self, function: &Rc<Function>, batch: &mut Batch) -> Option<FuncId> {
+        let (params, ret) = signature(function)?;
+        let name = self.name();
+        let id = self.module.declare_function(&name, Linkage::Local, &self.signature(&params, ret)).ok()?;
+        batch.queue.push((function.clone(), id));
+        Some(id)
+    }
+
+    fn callee(&mut self, name: &str, batch: &mut Batch) -> Option<(FuncId, Vec<Ty>, Ty)> {
+        let Global::Function(function) = (batch.lookup)(name) else { return None };
+        let (params, ret) = signature(&function)?;
+        batch.deps.insert(name.to_string(), Some(function.clone()));
+        if let Some((_, id)) = batch.queue.iter().find(|(queued, _)| Rc::ptr_eq(queued, &function)) {
+            return Some((*id, params, ret));
+        }
+        let id = match &self.slot(&function).status {
+            Status::Compiled(id, _, deps) => {
+                for (name, expected) in deps.iter() {
+                    batch.deps.entry(name.clone()).or_insert_with(|| expected.clone());
+                }
+                *id
+            }
+            Status::Rejected => return None,
+            Status::Counting(_) => self.declare(&function, batch)?,
+        };
+        Some((id, params, ret))
+    }
+
+    fn compile(&mut self, root: &Rc<Function>, lookup: &dyn Fn(&str) -> Global) -> Option<()> {
+        let mut batch = Batch { lookup, queue: Vec::new(), deps: HashMap::new() };
+        self.declare(root, &mut batch)?;
+        let mut contexts = Vec::new();
+        while let Some((function, id)) = batch.queue.get(contexts.len()).cloned() {
+            match self.translate(&function, id, &mut batch) {
+                Some(context) => contexts.push(context),
+                None => {
+                    self.slot(&function).status = Status::Rejected;
+                    return None;
+                }
+            }
+        }
+        let deps = Rc::new(batch.deps.into_iter().collect::<Deps>());
+        let mut entries = Vec::new();
+        for ((function, id), mut context) in batch.queue.into_iter().zip(contexts) {
+            self.module.define_function(id, &mut context).ok()?;
+            let (params, ret) = signature(&function)?;
+            entries.push((function, id, self.trampoline(&params, ret, id)?));
+        }
+        self.module.finalize_definitions().ok()?;
+        for (function, id, trampoline) in entries {
+            let entry = unsafe { std::mem::transmute::<*const u8, Entry>(self.module.get_finalized_function(trampoline)) };
+            self.slot(&function).status = Status::Compiled(id, entry, deps.clone());
+        }
+        Some(())
+    }
+
+    fn translate(&mut self, function: &Function, id: FuncId, batch: &mut Batch) -> Option<Context> {
+        let (params, ret) = signature(function)?;
+        let mut context = self.module.make_context();
+        context.func.signature = self.signature(&params, ret);
+        context.func.name = UserFuncName::user(0, id.as_u32());
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let values = b.block_params(entry).to_vec();
+        let bail_block = b.create_block();
+        let bail = values[params.len()];
+        let mut t = Translator {
+            b,
+            jit: self,
+            batch,
+            scope: function.scope.clone(),
+            scopes: vec![HashMap::new()],
+            loops: Vec::new(),
+            refs: HashMap::new(),
+            ret,
+            bail,
+            bail_block,
+            vars: 0,
+        };
+        for ((param, ty), value) in function.params.iter().zip(params).zip(values) {
+            let var = t.var(ty);
+            t.b.def_var(var, value);
+            t.scopes[0].insert(param.name.clone(), (var, ty, false));
+        }
+        for stmt in &function.body {
+            t.stmt(stmt)?;
+        }
+        t.b.ins().jump(bail_block, &[]);
+        t.b.switch_to_block(bail_block);
+        let one = t.b.ins().iconst(types::I8, 1);
+        t.b.ins().store(MemFlags::trusted(), one, bail, 0);
+        let zero = match ret {
+            Ty::Number => t.b.ins().f64const(0.0),
+            Ty::Bool => t.b.ins().iconst(types::I8, 0),
+        };
+        t.b.ins().return_(&[zero]);
+        t.b.seal_all_blocks();
+        t.b.finalize();
+        Some(context)
+    }
+
+    fn trampoline(&mut self, params: &[Ty], ret: Ty, inner: FuncId) -> Option<FuncId> {
+        let pointer = self.module.target_config().pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params.extend([AbiParam::new(pointer), AbiParam::new(pointer)]);
+        signature.returns.push(AbiParam::new(types::F64));
+        let name = self.name();
+        let id = self.module.declare_function(&name, Linkage::Local, &signature).ok()?;
+        let mut context = self.module.make_context();
+        context.func.signature = signature;
+        context.func.name = UserFuncName::user(0, id.as_u32());
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut context.func, &mut builder_context);
+        let block = b.create_block();
+        b.append_block_params_for_function_params(block);
+        b.switch_to_block(block);
+        let (args, bail) = (b.block_params(block)[0], b.block_params(block)[1]);
+        let mut values = Vec::new();
+        for (i, ty) in params.iter().enumerate() {
+            let value = b.ins().load(types::F64, MemFlags::trusted(), args, (i * 8) as i32);
+            values.push(match ty {
+                Ty::Number => value,
+                Ty::Bool => {
+                    let zero = b.ins().f64const(0.0);
+                    b.ins().fcmp(FloatCC::NotEqual, value, zero)
+                }
+            });
+        }
+        values.push(bail);
+        let callee = self.module.declare_func_in_func(inner, b.func);
+        let call = b.ins().call(callee, &values);
+        let mut result = b.inst_results(call)[0];
+        if ret == Ty::Bool {
+            let (one, zero) = (b.ins().f64const(1.0), b.ins().f64const(0.0));
+            result = b.ins().select(result, one, zero);
+        }
+        b.ins().return_(&[result]);
+        b.seal_all_blocks();
+        b.finalize();
+        self.module.define_function(id, &mut context).ok()?;
+        Some(id)
+    }
+}
+
+impl Translator<'_, '_> {
+    fn var(&mut self, ty: Ty) -> Variable {
+        let var = Variable::new(self.vars);
+        self.vars += 1;
+        self.b.declare_var(var, ty.ir());
+        var
+    }
+
+    fn lookup(&self, name: &str) -> Option<(Variable, Ty, bool)> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name)).copied()
+    }
+
+    fn unreachable(&mut self) {
+        let block = self.b.create_block();
+        self.b.switch_to_block(block);
+    }
+
+    fn block(&mut self, stmts: &[Statement]) -> Option<()> {
+        self.scopes.push(HashMap::new());
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        self.scopes.pop();
+        Some(())
+    }
+
+    fn stmt(&mut self, stmt: &Statement) -> Option<()> {
+        match stmt {
+            Statement::Val(name, Some(expr), type_anno) | Statement::Let(name, Some(expr), type_anno) => {
+                let (value, ty) = self.expr(expr)?;
+                if type_anno.as_deref().is_some_and(|t| Ty::parse(t) != Some(ty)) || self.lookup(name).is_some() {
+                    return None;
+                }
+                self.batch.deps.entry(name.clone()).or_insert(None);
+                let var = self.var(ty);
+                self.b.def_var(var, value);
+                self.scopes.last_mut().unwrap().insert(name.clone(), (var, ty, matches!(stmt, Statement::Let(..))));
+            }
+            Statement::Assign(Target::Name(name), expr) => {
+                let (value, ty) = self.expr(expr)?;
+                let (var, declared, mutable) = self.lookup(name)?;
+                if !mutable || declared != ty {
+                    return None;
+                }
+                self.b.def_var(var, value);
+            }
+            Statement::If(condition, then_block, else_block) => {
+                let condition = self.condition(condition)?;
+                let (then_start, else_start, merge) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+                self.b.ins().brif(condition, then_start, &[], else_start, &[]);
+                self.b.switch_to_block(then_start);
+                self.block(then_block)?;
+                self.b.ins().jump(merge, &[]);
+                self.b.switch_to_block(else_start);
+                self.block(else_block.as_deref().unwrap_or_default())?;
+                self.b.ins().jump(merge, &[]);
+                self.b.switch_to_block(merge);
+            }
+            Statement::While(condition, body) => {
+                let (header, start, exit) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+                self.b.ins().jump(header, &[]);
+                self.b.switch_to_block(header);
+                let condition = self.condition(condition)?;
+                self.b.ins().brif(condition, start, &[], exit, &[]);
+                self.b.switch_to_block(start);
+                self.loops.push((header, exit));
+                self.block(body)?;
+                self.loops.pop();
+                self.b.ins().jump(header, &[]);
+                self.b.switch_to_block(exit);
+            }
+            Statement::Break | Statement::Continue => {
+                let (header, exit) = *self.loops.last()?;
+                self.b.ins().jump(if matches!(stmt, Statement::Break) { exit } else { header }, &[]);
+                self.unreachable();
+            }
+            Statement::Return(expr) => {
+                let (value, ty) = self.expr(expr)?;
+                if ty != self.ret {
+                    return None;
+                }
+                self.b.ins().return_(&[value]);
+                self.unreachable();
+            }
+            Statement::Expr(expr) => {
+                self.expr(expr)?;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn condition(&mut self, expr: &Expr) -> Option<ir::Value> {
+        match self.expr(expr)? {
+            (value, Ty::Bool) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Option<(ir::Value, Ty)> {
+        Some(match expr {
+            Expr::Number(n) => (self.b.ins().f64const(*n), Ty::Number),
+            Expr::Bool(b) => (self.b.ins().iconst(types::I8, *b as i64), Ty::Bool),
+            Expr::Local(_, name) => {
+                let (var, ty, _) = self.lookup(name)?;
+                (self.b.use_var(var), ty)
+            }
+            Expr::Unary(op, inner) => match (op.as_str(), self.expr(inner)?) {
+                ("-", (value, Ty::Number)) => (self.b.ins().fneg(value), Ty::Number),
+                ("!", (value, Ty::Bool)) => (self.b.ins().bxor_imm(value, 1), Ty::Bool),
+                _ => return None,
+            },
+            Expr::Binary(left, op, right) => self.binary(left, op, right)?,
+            Expr::If(condition, then_expr, else_expr) => {
+                let condition = self.condition(condition)?;
+                let (then_start, else_start, merge) = (self.b.create_block(), self.b.create_block(), self.b.create_block());
+                self.b.ins().brif(condition, then_start, &[], else_start, &[]);
+                self.b.switch_to_block(then_start);
+                let (then_value, ty) = self.expr(then_expr)?;
+                let result = self.b.append_block_param(merge, ty.ir());
+                self.b.ins().jump(merge, &[then_value]);
+                self.b.switch_to_block(else_start);
+                let (else_value, else_ty) = self.expr(else_expr)?;
+                if else_ty != ty {
+                    return None;
+                }
+                self.b.ins().jump(merge, &[else_value]);
+                self.b.switch_to_block(merge);
+                (result, ty)
+            }
+            Expr::Call(name, args) => self.call(name, args)?,
+            _ => return None,
+        })
+    }
+
+    fn binary(&mut self, left: &Expr, op: &str, right: &Expr) -> Option<(ir::Value, Ty)> {
+        if op == "and" || op == "or" {
+            let left = self.condition(left)?;
+            let (rest, merge) = (self.b.create_block(), self.b.create_block());
+            let result = self.b.append_block_param(merge, Ty::Bool.ir());
+            if op == "and" {
+                self.b.ins().brif(left, rest, &[], merge, &[left]);
+            } else {
+                self.b.ins().brif(left, merge, &[left], rest, &[]);
+            }
+            self.b.switch_to_block(rest);
+            let right = self.condition(right)?;
+            self.b.ins().jump(merge, &[right]);
+            self.b.switch_to_block(merge);
+            return Some((result, Ty::Bool));
+        }
+        let (l, ty) = self.expr(left)?;
+        let (r, right_ty) = self.expr(right)?;
+        if ty != right_ty {
+            return None;
+        }
+        let compare = |cc| Some(cc).filter(|_| ty == Ty::Number);
+        if let Some(cc) = match op {
+            "<" => compare(FloatCC::LessThan),
+            "<=" => compare(FloatCC::LessThanOrEqual),
+            ">" => compare(FloatCC::GreaterThan),
+            ">=" => compare(FloatCC::GreaterThanOrEqual),
+            _ => None,
+        } {
+            return Some((self.b.ins().fcmp(cc, l, r), Ty::Bool));
+        }
+        Some(match (op, ty) {
+            ("+", Ty::Number) => (self.b.ins().fadd(l, r), ty),
+            ("-", Ty::Number) => (self.b.ins().fsub(l, r), ty),
+            ("*", Ty::Number) => (self.b.ins().fmul(l, r), ty),
+            ("/", Ty::Number) => {
+                let zero = self.b.ins().f64const(0.0);
+                let is_zero = self.b.ins().fcmp(FloatCC::Equal, r, zero);
+                let next = self.b.create_block();
+                self.b.ins().brif(is_zero, self.bail_block, &[], next, &[]);
+                self.b.switch_to_block(next);
+                (self.b.ins().fdiv(l, r), ty)
+            }
+            ("==", Ty::Number) => (self.b.ins().fcmp(FloatCC::Equal, l, r), Ty::Bool),
+            ("!=", Ty::Number) => (self.b.ins().fcmp(FloatCC::NotEqual, l, r), Ty::Bool),
+            ("==", Ty::Bool) => (self.b.ins().icmp(IntCC::Equal, l, r), ty),
+            ("!=", Ty::Bool) => (self.b.ins().icmp(IntCC::NotEqual, l, r), ty),
+            _ => return None,
+        })
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Option<(ir::Value, Ty)> {
+        if self.scope.names.contains_key(name) {
+            return None;
+        }
+        let (id, params, ret) = self.jit.callee(name, self.batch)?;
+        if args.len() != params.len() {
+            return None;
+        }
+        let mut values = Vec::with_capacity(args.len() + 1);
+        for (arg, ty) in args.iter().zip(params) {
+            match self.expr(arg)? {
+                (value, arg_ty) if arg_ty == ty => values.push(value),
+                _ => return None,
+            }
+        }
+        values.push(self.bail);
+        let callee = match self.refs.get(&id) {
+            Some(callee) => *callee,
+            None => {
+                let callee = self.jit.module.declare_func_in_func(id, self.b.func);
+                *self.refs.entry(id).or_insert(callee)
+            }
+        };
+        let call = self.b.ins().call(callee, &values);
+        let result = self.b.inst_results(call)[0];
+        let failed = self.b.ins().load(types::I8, MemFlags::trusted(), self.bail, 0);
+        let next = self.b.create_block();
+        self.b.ins().brif(failed, self.bail_block, &[], next, &[]);
+        self.b.switch_to_block(next);
+        Some((result, ret))
+    }
+}
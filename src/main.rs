@@ -3,13 +3,25 @@ use std::process;
 
 mod parser;
 mod ast;
+mod runtime;
 mod interpreter;
 mod compiler;
+mod compiler_c;
+mod compiler_js;
+mod compiler_py;
 mod utils;
 mod velvet_config;
 mod cli;
 mod repl;
 mod tester;
+mod checker;
+mod migrate;
+mod methods;
+mod vm;
+mod resolver;
+mod optimizer;
+#[cfg(feature = "jit")]
+mod jit;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -23,16 +35,17 @@ fn main() {
         "start" => run_project(&args),
         "update" => update_libraries(),
         "install" => install_library(&args),
-        "build" => build_project(),
+        "build" => build_project(&args),
         "init" => init_project(),
         "debug" => debug_project(&args),
-        "test" => tester::run_tests(),
+        "test" => run_tests(),
         "clean" => clean_project(),
         "version" => cli::print_version(),
-        "repl" => repl::start(),
+        "repl" => start_repl(),
         "fmt" => format_project(),
         "list-libs" => list_libraries(),
         "check" => check_project(),
+        "migrate" => migrate_project(),
         _ => {
             cli::error(&format!("Unknown command '{}'. Run 'vel help'.", args[1]));
             process::exit(1);
@@ -42,10 +55,50 @@ fn main() {
 
 fn run_project(args: &[String]) {
     velvet_config::check_project().expect("Not a Velvet project directory");
-    let file = args.get(2).cloned().unwrap_or("main.velvet".to_string());
+    let mut file = "main.velvet".to_string();
+    let mut engine = "tree".to_string();
+    let mut optimize = false;
+    let mut jit = false;
+    for arg in &args[2..] {
+        match arg.strip_prefix("--engine=") {
+            Some(name) => engine = name.to_string(),
+            None if arg == "-O" => optimize = true,
+            None if arg == "--jit" => jit = true,
+            None => file = arg.clone(),
+        }
+    }
+    if jit {
+        enable_jit(&engine);
+    }
+    let run: fn(Vec<ast::Statement>) -> Result<(), String> = match engine.as_str() {
+        "tree" => |ast| interpreter::run(ast, false),
+        "vm" => vm::run,
+        _ => {
+            cli::error(&format!("Unknown engine '{}' (expected 'tree' or 'vm')", engine));
+            process::exit(1);
+        }
+    };
     let source = utils::read_file(&file).expect("Cannot read source file");
     let ast = parser::parse(&source).expect("Parse error");
-    interpreter::run(ast, false).expect("Execution error");
+    let ast = if optimize { optimizer::optimize(ast) } else { ast };
+    run(ast).expect("Execution error");
+}
+
+#[cfg(feature = "jit")]
+fn enable_jit(engine: &str) {
+    if engine != "tree" {
+        cli::error("--jit only works with the tree engine");
+        process::exit(1);
+    }
+    if let Err(e) = jit::enable() {
+        cli::warning(&format!("JIT unavailable, interpreting instead: {}", e));
+    }
+}
+
+#[cfg(not(feature = "jit"))]
+fn enable_jit(_engine: &str) {
+    cli::error("This vel was built without JIT support (rebuild with '--features jit')");
+    process::exit(1);
 }
 
 fn update_libraries() {
@@ -65,12 +118,38 @@ fn install_library(args: &[String]) {
     cli::success(&format!("Installed {} via {}", command, manager));
 }
 
-fn build_project() {
+fn build_project(args: &[String]) {
     velvet_config::check_project().expect("Not a Velvet project directory");
+    let mut target = "rust".to_string();
+    let mut optimize = false;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.strip_prefix("--target=") {
+            Some(name) => target = name.to_string(),
+            None if arg == "--target" => target = rest.next().cloned().unwrap_or_default(),
+            None if arg == "-O" => optimize = true,
+            None => {}
+        }
+    }
+    let compile: fn(Vec<ast::Statement>) -> Result<(), String> = match target.as_str() {
+        "rust" => compiler::compile,
+        "c" => compiler_c::compile,
+        "js" => compiler_js::compile,
+        "python" => compiler_py::compile,
+        _ => {
+            cli::error(&format!("Unknown build target '{}' (expected 'rust', 'c', 'js' or 'python')", target));
+            process::exit(1);
+        }
+    };
     let source = utils::read_file("main.velvet").expect("Cannot read main.velvet");
     let ast = parser::parse(&source).expect("Parse error");
-    compiler::compile(ast).expect("Compilation error");
-    cli::success("Compiled to 'velvet_out'.");
+    let ast = if optimize { optimizer::optimize(ast) } else { ast };
+    compile(ast).expect("Compilation error");
+    cli::success(match target.as_str() {
+        "js" => "Compiled to 'velvet_out.mjs'.",
+        "python" => "Compiled to 'velvet_out.py' and 'velvet_runtime.py'.",
+        _ => "Compiled to 'velvet_out'.",
+    });
 }
 
 fn init_project() {
@@ -86,6 +165,20 @@ fn debug_project(args: &[String]) {
     interpreter::run(ast, true).expect("Debug execution error");
 }
 
+fn run_tests() {
+    if let Err(e) = tester::run_tests() {
+        cli::error(&e);
+        process::exit(1);
+    }
+}
+
+fn start_repl() {
+    if let Err(e) = repl::start() {
+        cli::error(&e);
+        process::exit(1);
+    }
+}
+
 fn clean_project() {
     velvet_config::clean_project().expect("Failed to clean project");
     cli::success("Project cleaned.");
@@ -99,6 +192,12 @@ fn format_project() {
     cli::success("Files formatted.");
 }
 
+fn migrate_project() {
+    velvet_config::check_project().expect("Not a Velvet project directory");
+    let changed = migrate::migrate_project().expect("Migration failed");
+    cli::success(&format!("Migrated {} file(s).", changed));
+}
+
 fn list_libraries() {
     let output = utils::run_python_script("lib_manager.py", &["list"]).expect("Failed to list libraries");
     cli::success("Installed libraries:");
@@ -112,6 +211,19 @@ fn check_project() {
         cli::error("Invalid project configuration.");
         process::exit(1);
     }
+    let source = utils::read_file("main.velvet").expect("Cannot read main.velvet");
+    let ast = parser::parse(&source).expect("Parse error");
+    let (errors, warnings) = checker::check(&ast);
+    for warning in &warnings {
+        cli::warning(warning);
+    }
+    for error in &errors {
+        cli::error(error);
+    }
+    if !errors.is_empty() {
+        process::exit(1);
+    }
+    cli::success("No problems found.");
 }
 
 fn format_code(source: &str) -> String {
@@ -0,0 +1,311 @@
+use crate::runtime::{ErrorValue, Value};
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+pub const STRING_METHODS: &[&str] = &[
+    "chars", "contains", "ends_with", "len", "lower", "replace", "repeat", "split", "starts_with", "to_num", "trim", "upper",
+];
+pub const LIST_METHODS: &[&str] = &[
+    "clear", "contains", "extend", "first", "index_of", "insert", "is_empty", "join", "last", "len", "max", "min", "pop", "push",
+    "remove", "reverse", "sort", "sum",
+];
+pub const NUMBER_METHODS: &[&str] = &["abs", "ceil", "floor", "max", "min", "pow", "round", "sqrt", "to_str"];
+pub const ITERATOR_METHODS: &[&str] = &["chain", "collect", "enumerate", "next", "skip", "take", "zip"];
+pub const MAP_METHODS: &[&str] = &["clear", "contains", "get", "is_empty", "items", "keys", "len", "remove", "set", "values"];
+pub const MUTATING: &[&str] = &["clear", "extend", "insert", "pop", "push", "remove", "set"];
+// Built-in methods by what they return, whichever type they are called on
+pub const NUMBER_RESULTS: &[&str] = &["abs", "ceil", "floor", "len", "pow", "round", "sqrt", "sum", "to_num"];
+pub const STRING_RESULTS: &[&str] = &["join", "lower", "repeat", "replace", "to_str", "trim", "upper"];
+pub const BOOL_RESULTS: &[&str] = &["contains", "ends_with", "is_empty", "starts_with"];
+pub const OPTION_RESULTS: &[&str] = &["first", "get", "index_of", "last"];
+pub const OPERATORS: &[(&str, &str)] = &[
+    ("+", "add"),
+    ("-", "sub"),
+    ("*", "mul"),
+    ("/", "div"),
+    ("==", "eq"),
+    ("!=", "eq"),
+    ("<", "cmp"),
+    ("<=", "cmp"),
+    (">", "cmp"),
+    (">=", "cmp"),
+    ("[]", "index"),
+];
+
+pub fn operator_method(op: &str) -> Option<&'static str> {
+    OPERATORS.iter().find(|(symbol, _)| *symbol == op).map(|(_, method)| *method)
+}
+
+pub fn operator_arity(method: &str) -> Option<usize> {
+    match method {
+        "to_str" => Some(1),
+        _ if OPERATORS.iter().any(|(_, name)| *name == method) => Some(2),
+        _ => None,
+    }
+}
+
+pub fn available(type_name: &str) -> &'static [&'static str] {
+    match type_name {
+        "str" => STRING_METHODS,
+        "list" => LIST_METHODS,
+        "f64" => NUMBER_METHODS,
+        "map" => MAP_METHODS,
+        "iterator" => ITERATOR_METHODS,
+        _ => &[],
+    }
+}
+
+pub fn is_mutating(receiver: &Value, method: &str) -> bool {
+    MUTATING.contains(&method) && available(receiver.type_name()).contains(&method)
+}
+
+pub fn call(receiver: &mut Value, method: &str, args: Vec<Value>) -> Result<Value, ErrorValue> {
+    match receiver {
+        Value::String(s) => string_method(s, method, &args),
+        Value::Number(n) => number_method(*n, method, &args),
+        Value::List(items) => list_method(items, method, args),
+        Value::Map(entries) => map_method(entries, method, args),
+        other => Err(ErrorValue::new("NameError", format!("Type '{}' has no method '{}'", other.type_name(), method))),
+    }
+}
+
+pub fn get_index(target: &Value, index: &Value) -> Result<Value, ErrorValue> {
+    match target {
+        Value::Map(entries) => entries.iter().find(|(k, _)| k == index).map(|(_, v)| v.clone()).ok_or_else(|| ErrorValue::new("IndexError", format!("Key '{}' not found", index))),
+        Value::List(items) => Ok(items[position(index, items.len(), "list")?].clone()),
+        Value::Tuple(items) => Ok(items[position(index, items.len(), "tuple")?].clone()),
+        Value::String(s) => {
+            let chars: Vec<char> = s.chars().collect();
+            Ok(Value::String(chars[position(index, chars.len(), "str")?].to_string().into()))
+        }
+        other => Err(ErrorValue::new("TypeError", format!("Cannot index into {}", other.type_name()))),
+    }
+}
+
+pub fn slice(target: &Value, start: Option<f64>, end: Option<f64>, step: Option<f64>) -> Result<Value, ErrorValue> {
+    match target {
+        Value::List(items) => Ok(Value::List(Rc::new(slice_indices(items.len(), start, end, step)?.into_iter().map(|i| items[i].clone()).collect()))),
+        Value::Tuple(items) => Ok(Value::Tuple(slice_indices(items.len(), start, end, step)?.into_iter().map(|i| items[i].clone()).collect())),
+        Value::String(s) => {
+            let chars: Vec<char> = s.chars().collect();
+            Ok(Value::String(slice_indices(chars.len(), start, end, step)?.into_iter().map(|i| chars[i]).collect::<String>().into()))
+        }
+        other => Err(ErrorValue::new("TypeError", format!("Cannot slice {}", other.type_name()))),
+    }
+}
+
+pub fn position(index: &Value, len: usize, container: &str) -> Result<usize, ErrorValue> {
+    let n = index.as_number()?;
+    if n.fract() != 0.0 {
+        return Err(ErrorValue::new("TypeError", format!("Expected integer index, got {}", n)));
+    }
+    let i = if n < 0.0 { n + len as f64 } else { n };
+    if i < 0.0 || i >= len as f64 {
+        return Err(ErrorValue::new("IndexError", format!("Index {} out of bounds for {} of length {}", n, container, len)));
+    }
+    Ok(i as usize)
+}
+
+fn slice_indices(len: usize, start: Option<f64>, end: Option<f64>, step: Option<f64>) -> Result<Vec<usize>, ErrorValue> {
+    let len = len as i64;
+    let step = step.map_or(1, |s| s as i64);
+    if step == 0 {
+        return Err(ErrorValue::new("ValueError", "Slice step cannot be zero"));
+    }
+    let bound = |value: Option<f64>, default: i64| match value {
+        None => default,
+        Some(v) => {
+            let v = if v < 0.0 { v as i64 + len } else { v as i64 };
+            if step > 0 { v.clamp(0, len) } else { v.clamp(-1, len - 1) }
+        }
+    };
+    let (mut i, end) = if step > 0 { (bound(start, 0), bound(end, len)) } else { (bound(start, len - 1), bound(end, -1)) };
+    let mut indices = Vec::new();
+    while (step > 0 && i < end) || (step < 0 && i > end) {
+        indices.push(i as usize);
+        i += step;
+    }
+    Ok(indices)
+}
+
+fn arity(method: &str, args: &[Value], expected: usize) -> Result<(), ErrorValue> {
+    if args.len() != expected {
+        return Err(ErrorValue::new("ArgumentError", format!("Method '{}' expects {} args, got {}", method, expected, args.len())));
+    }
+    Ok(())
+}
+
+fn option(value: Option<Value>) -> Value {
+    value.map_or(Value::None, |v| Value::Some(Box::new(v)))
+}
+
+fn string_method(s: &str, method: &str, args: &[Value]) -> Result<Value, ErrorValue> {
+    let expected = match method {
+        "split" | "contains" | "starts_with" | "ends_with" | "repeat" => 1,
+        "replace" => 2,
+        _ => 0,
+    };
+    arity(method, args, expected)?;
+    Ok(match method {
+        "len" => Value::Number(s.chars().count() as f64),
+        "upper" => Value::String(s.to_uppercase().into()),
+        "lower" => Value::String(s.to_lowercase().into()),
+        "trim" => Value::String(s.trim().into()),
+        "chars" => Value::List(Rc::new(s.chars().map(|c| Value::String(c.to_string().into())).collect())),
+        "split" => Value::List(Rc::new(s.split(&*args[0].as_string()?).map(|p| Value::String(p.into())).collect())),
+        "contains" => Value::Bool(s.contains(&*args[0].as_string()?)),
+        "starts_with" => Value::Bool(s.starts_with(&*args[0].as_string()?)),
+        "ends_with" => Value::Bool(s.ends_with(&*args[0].as_string()?)),
+        "replace" => Value::String(s.replace(&*args[0].as_string()?, &args[1].as_string()?).into()),
+        "repeat" => Value::String(s.repeat(args[0].as_number()?.max(0.0) as usize).into()),
+        "to_num" => Value::Number(s.trim().parse().map_err(|_| ErrorValue::new("ValueError", format!("Cannot convert '{}' to a number", s)))?),
+        _ => return Err(ErrorValue::new("NameError", format!("Type 'str' has no method '{}'", method))),
+    })
+}
+
+fn number_method(n: f64, method: &str, args: &[Value]) -> Result<Value, ErrorValue> {
+    let expected = if matches!(method, "pow" | "min" | "max") { 1 } else { 0 };
+    arity(method, args, expected)?;
+    Ok(match method {
+        "abs" => Value::Number(n.abs()),
+        "ceil" => Value::Number(n.ceil()),
+        "floor" => Value::Number(n.floor()),
+        "round" => Value::Number(n.round()),
+        "sqrt" => Value::Number(n.sqrt()),
+        "pow" => Value::Number(n.powf(args[0].as_number()?)),
+        "min" => Value::Number(n.min(args[0].as_number()?)),
+        "max" => Value::Number(n.max(args[0].as_number()?)),
+        "to_str" => Value::String(n.to_string().into()),
+        _ => return Err(ErrorValue::new("NameError", format!("Type 'f64' has no method '{}'", method))),
+    })
+}
+
+fn compare(a: &Value, b: &Value) -> Result<Ordering, ErrorValue> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => Ok(x.partial_cmp(y).unwrap_or(Ordering::Equal)),
+        (Value::String(x), Value::String(y)) => Ok(x.cmp(y)),
+        _ => Err(ErrorValue::new("TypeError", format!("Cannot compare {} with {}", a.type_name(), b.type_name()))),
+    }
+}
+
+fn extreme(items: &[Value], method: &str, wanted: Ordering) -> Result<Value, ErrorValue> {
+    let mut best = items.first().ok_or_else(|| ErrorValue::new("ValueError", format!("Cannot take {} of an empty list", method)))?;
+    for item in &items[1..] {
+        if compare(item, best)? == wanted {
+            best = item;
+        }
+    }
+    Ok(best.clone())
+}
+
+fn index(value: &Value, len: usize, allow_end: bool) -> Result<usize, ErrorValue> {
+    let i = value.as_number()?;
+    let limit = if allow_end { len + 1 } else { len };
+    if i < 0.0 || i as usize >= limit {
+        return Err(ErrorValue::new("IndexError", format!("Index {} out of bounds for list of length {}", i, len)));
+    }
+    Ok(i as usize)
+}
+
+fn list_method(items: &mut Rc<Vec<Value>>, method: &str, mut args: Vec<Value>) -> Result<Value, ErrorValue> {
+    let expected = match method {
+        "push" | "remove" | "contains" | "index_of" | "extend" | "join" => 1,
+        "insert" => 2,
+        _ => 0,
+    };
+    arity(method, &args, expected)?;
+    let result = match method {
+        "len" => Value::Number(items.len() as f64),
+        "is_empty" => Value::Bool(items.is_empty()),
+        "first" => option(items.first().cloned()),
+        "last" => option(items.last().cloned()),
+        "contains" => Value::Bool(items.contains(&args[0])),
+        "index_of" => option(items.iter().position(|item| item == &args[0]).map(|i| Value::Number(i as f64))),
+        "join" => {
+            let separator = args[0].as_string()?;
+            Value::String(items.iter().map(|item| item.to_string()).collect::<Vec<_>>().join(&separator).into())
+        }
+        "reverse" => Value::List(Rc::new(items.iter().rev().cloned().collect())),
+        "sort" => {
+            let mut sorted = items.to_vec();
+            let mut failure = None;
+            sorted.sort_by(|a, b| {
+                compare(a, b).unwrap_or_else(|e| {
+                    failure = Some(e);
+                    Ordering::Equal
+                })
+            });
+            if let Some(failure) = failure {
+                return Err(failure);
+            }
+            Value::List(Rc::new(sorted))
+        }
+        "sum" => Value::Number(items.iter().map(|item| item.as_number()).sum::<Result<f64, ErrorValue>>()?),
+        "min" => extreme(items, method, Ordering::Less)?,
+        "max" => extreme(items, method, Ordering::Greater)?,
+        "push" => {
+            Rc::make_mut(items).push(args.remove(0));
+            Value::None
+        }
+        "pop" => {
+            if items.is_empty() {
+                return Err(ErrorValue::new("ValueError", "Cannot pop from an empty list"));
+            }
+            Rc::make_mut(items).pop().unwrap()
+        }
+        "insert" => {
+            let at = index(&args[0], items.len(), true)?;
+            Rc::make_mut(items).insert(at, args.remove(1));
+            Value::None
+        }
+        "remove" => {
+            let at = index(&args[0], items.len(), false)?;
+            Rc::make_mut(items).remove(at)
+        }
+        "extend" => {
+            let more = args[0].as_list()?;
+            Rc::make_mut(items).extend(more.iter().cloned());
+            Value::None
+        }
+        "clear" => {
+            *items = Rc::default();
+            Value::None
+        }
+        _ => return Err(ErrorValue::new("NameError", format!("Type 'list' has no method '{}'", method))),
+    };
+    Ok(result)
+}
+
+fn map_method(entries: &mut Rc<Vec<(Value, Value)>>, method: &str, mut args: Vec<Value>) -> Result<Value, ErrorValue> {
+    let expected = match method {
+        "get" | "contains" | "remove" => 1,
+        "set" => 2,
+        _ => 0,
+    };
+    arity(method, &args, expected)?;
+    let position = args.first().and_then(|key| entries.iter().position(|(k, _)| k == key));
+    let result = match method {
+        "len" => Value::Number(entries.len() as f64),
+        "is_empty" => Value::Bool(entries.is_empty()),
+        "keys" => Value::List(Rc::new(entries.iter().map(|(k, _)| k.clone()).collect())),
+        "values" => Value::List(Rc::new(entries.iter().map(|(_, v)| v.clone()).collect())),
+        "items" => Value::List(Rc::new(entries.iter().map(|(k, v)| Value::Tuple(vec![k.clone(), v.clone()])).collect())),
+        "get" => option(position.map(|i| entries[i].1.clone())),
+        "contains" => Value::Bool(position.is_some()),
+        "set" => {
+            let value = args.remove(1);
+            match position {
+                Some(i) => Rc::make_mut(entries)[i].1 = value,
+                None => Rc::make_mut(entries).push((args.remove(0), value)),
+            }
+            Value::None
+        }
+        "remove" => option(position.map(|i| Rc::make_mut(entries).remove(i).1)),
+        "clear" => {
+            *entries = Rc::default();
+            Value::None
+        }
+        _ => return Err(ErrorValue::new("NameError", format!("Type 'map' has no method '{}'", method))),
+    };
+    Ok(result)
+}
@@ -0,0 +1,80 @@
+use crate::cli;
+use crate::utils;
+use std::fs;
+use std::path::Path;
+
+pub fn migrate_project() -> Result<usize, String> {
+    let mut changed = 0;
+    migrate_dir(Path::new("."), &mut changed)?;
+    Ok(changed)
+}
+
+fn migrate_dir(dir: &Path, changed: &mut usize) -> Result<(), String> {
+    for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if path.is_dir() {
+            if !name.starts_with('.') && name != "target" {
+                migrate_dir(&path, changed)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "velvet") {
+            let path = path.to_string_lossy();
+            let source = utils::read_file(&path)?;
+            let migrated = migrate_source(&source);
+            if migrated != source {
+                utils::write_file(&path, &migrated)?;
+                cli::info(&format!("Migrated {}", path));
+                *changed += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn migrate_source(source: &str) -> String {
+    let mut output = String::new();
+    for line in source.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        let (code, comment) = split_comment(body);
+        output.push_str(&migrate_code(code));
+        output.push_str(comment);
+        output.push_str(&line[body.len()..]);
+    }
+    output
+}
+
+fn split_comment(line: &str) -> (&str, &str) {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '@' if !in_string => return line.split_at(i),
+            _ => {}
+        }
+    }
+    (line, "")
+}
+
+fn migrate_code(code: &str) -> String {
+    let content = code.trim_start();
+    let indent = &code[..code.len() - content.len()];
+    if let Some(rest) = content.strip_prefix("var ") {
+        return format!("{}let {}", indent, rest);
+    }
+    if content.starts_with("fun ") {
+        if let Some(close) = content.rfind(')') {
+            let after = &content[close + 1..];
+            let tail = after.trim_end();
+            if let Some(signature) = tail.strip_prefix(':') {
+                let (return_type, colon) = match signature.strip_suffix(':') {
+                    Some(return_type) => (return_type.trim(), ":"),
+                    None => (signature.trim(), ""),
+                };
+                if !return_type.is_empty() {
+                    return format!("{}{} -> {}{}{}", indent, &content[..=close], return_type, colon, &after[tail.len()..]);
+                }
+            }
+        }
+    }
+    code.to_string()
+}
@@ -0,0 +1,452 @@
+use crate::ast::*;
+use crate::interpreter::contains_yield;
+use crate::methods;
+use crate::resolver::{pattern_names, target_names};
+use std::collections::{HashMap, HashSet};
+
+const INLINE_LIMIT: usize = 16;
+
+#[derive(Default)]
+struct Program {
+    bindings: HashMap<String, usize>,
+    traits: HashSet<String>,
+    imports: bool,
+}
+
+#[derive(Default)]
+struct Optimizer {
+    program: Program,
+    consts: HashMap<String, Expr>,
+    inline: HashMap<String, (Vec<String>, Expr)>,
+}
+
+pub fn optimize(statements: Vec<Statement>) -> Vec<Statement> {
+    let mut optimizer = Optimizer::default();
+    optimizer.program.scan(&statements);
+    let mut out = Vec::new();
+    for stmt in statements {
+        if optimizer.push(stmt, &mut out) {
+            break;
+        }
+        if let Some(stmt) = out.last() {
+            optimizer.learn(stmt);
+        }
+    }
+    out
+}
+
+impl Program {
+    fn bind(&mut self, name: &str) {
+        *self.bindings.entry(name.to_string()).or_default() += 1;
+    }
+
+    fn bind_all(&mut self, names: Vec<String>) {
+        names.iter().for_each(|name| self.bind(name));
+    }
+
+    fn unique(&self, name: &str) -> bool {
+        self.bindings.get(name) == Some(&1)
+    }
+
+    fn scan(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            match stmt {
+                Statement::Say(expr) | Statement::Expr(expr) | Statement::Return(expr) | Statement::Yield(expr) => self.scan_expr(expr),
+                Statement::Val(name, expr, _) | Statement::Let(name, expr, _) => {
+                    self.bind(name);
+                    expr.iter().for_each(|e| self.scan_expr(e));
+                }
+                Statement::Const(name, expr, _) => {
+                    self.bind(name);
+                    self.scan_expr(expr);
+                }
+                Statement::Fun(name, params, _, body) => {
+                    self.bind(name);
+                    self.scan_params(params);
+                    self.scan(body);
+                }
+                Statement::Type(name, fields) => {
+                    self.bind(name);
+                    self.scan_params(fields);
+                }
+                Statement::Trait(name, signatures, defaults) => {
+                    self.traits.insert(name.clone());
+                    signatures.iter().for_each(|(_, params, _)| self.scan_params(params));
+                    self.scan(defaults);
+                }
+                Statement::Impl(_, _, methods) => self.scan(methods),
+                Statement::If(condition, then_block, else_block) => {
+                    self.scan_expr(condition);
+                    self.scan(then_block);
+                    self.scan(else_block.as_deref().unwrap_or_default());
+                }
+                Statement::For(target, expr, body) => {
+                    let mut names = Vec::new();
+                    target_names(target, &mut names);
+                    self.bind_all(names);
+                    self.scan_expr(expr);
+                    self.scan(body);
+                }
+                Statement::While(condition, body) => {
+                    self.scan_expr(condition);
+                    self.scan(body);
+                }
+                Statement::Try(try_block, catches, finally_block) => {
+                    self.scan(try_block);
+                    for (ident, _, block) in catches {
+                        self.bind(ident);
+                        self.scan(block);
+                    }
+                    self.scan(finally_block.as_deref().unwrap_or_default());
+                }
+                Statement::Throw(expr, _) => expr.iter().for_each(|e| self.scan_expr(e)),
+                Statement::Match(expr, branches) => {
+                    self.scan_expr(expr);
+                    for (pattern, block) in branches {
+                        let mut names = Vec::new();
+                        pattern_names(pattern, &mut names);
+                        self.bind_all(names);
+                        self.scan(block);
+                    }
+                }
+                Statement::Unpack(target, expr, _) | Statement::Assign(target, expr) => {
+                    let mut names = Vec::new();
+                    target_names(target, &mut names);
+                    self.bind_all(names);
+                    self.scan_expr(expr);
+                }
+                Statement::Import(..) => self.imports = true,
+                Statement::Test(_, body) => self.scan(body),
+                Statement::Attributed(attributes, target) => {
+                    attributes.iter().flat_map(|a| &a.args).for_each(|e| self.scan_expr(e));
+                    self.scan(std::slice::from_ref(target));
+                }
+                Statement::Break | Statement::Continue => {}
+            }
+        }
+    }
+
+    fn scan_params(&mut self, params: &[Param]) {
+        for param in params {
+            self.bind(&param.name);
+            param.default.iter().for_each(|e| self.scan_expr(e));
+        }
+    }
+
+    fn scan_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::ListComp(_, clauses) | Expr::MapComp(_, _, clauses) => {
+                for clause in clauses {
+                    if let Clause::For(target, _) = clause {
+                        let mut names = Vec::new();
+                        target_names(target, &mut names);
+                        self.bind_all(names);
+                    }
+                }
+            }
+            Expr::Match(_, arms) => {
+                for (pattern, _) in arms {
+                    let mut names = Vec::new();
+                    pattern_names(pattern, &mut names);
+                    self.bind_all(names);
+                }
+            }
+            _ => {}
+        }
+        children(expr).into_iter().for_each(|child| self.scan_expr(child));
+    }
+}
+
+fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::String(_) | Expr::Number(_) | Expr::Bool(_) | Expr::Ident(_) | Expr::Local(..) => Vec::new(),
+        Expr::Binary(left, _, right) | Expr::Index(left, right) => vec![left, right],
+        Expr::Unary(_, inner) | Expr::Named(_, inner) | Expr::Field(inner, _) | Expr::SafeField(inner, _) | Expr::Propagate(inner) => vec![inner],
+        Expr::Call(_, args) | Expr::List(args) | Expr::Tuple(args) => args.iter().collect(),
+        Expr::MethodCall(target, _, args) => std::iter::once(target.as_ref()).chain(args).collect(),
+        Expr::Map(entries) => entries.iter().flat_map(|(k, v)| [k, v]).collect(),
+        Expr::ListComp(element, clauses) => std::iter::once(element.as_ref()).chain(clauses.iter().map(clause_expr)).collect(),
+        Expr::MapComp(key, value, clauses) => [key.as_ref(), value.as_ref()].into_iter().chain(clauses.iter().map(clause_expr)).collect(),
+        Expr::Slice(target, start, end, step) => std::iter::once(target.as_ref()).chain([start, end, step].into_iter().flatten().map(|e| e.as_ref())).collect(),
+        Expr::If(condition, then_expr, else_expr) => vec![condition, then_expr, else_expr],
+        Expr::Match(subject, arms) => std::iter::once(subject.as_ref()).chain(arms.iter().map(|(_, arm)| arm)).collect(),
+    }
+}
+
+fn clause_expr(clause: &Clause) -> &Expr {
+    match clause {
+        Clause::For(_, expr) | Clause::If(expr) => expr,
+    }
+}
+
+fn terminates(stmt: &Statement) -> bool {
+    matches!(stmt, Statement::Return(_) | Statement::Break | Statement::Continue | Statement::Throw(..))
+}
+
+fn literal(expr: &Expr) -> bool {
+    matches!(expr, Expr::String(_) | Expr::Number(_) | Expr::Bool(_))
+}
+
+fn size(expr: &Expr) -> usize {
+    1 + children(expr).into_iter().map(size).sum::<usize>()
+}
+
+fn uses(expr: &Expr, name: &str) -> bool {
+    matches!(expr, Expr::Ident(id) if id == name) || children(expr).into_iter().any(|child| uses(child, name))
+}
+
+fn inlinable(expr: &Expr, params: &[String]) -> bool {
+    let own = match expr {
+        Expr::Ident(id) => id == "none" || params.contains(id),
+        Expr::MethodCall(_, method, _) => !methods::MUTATING.contains(&method.as_str()),
+        Expr::Call(..) | Expr::Named(..) | Expr::Propagate(_) | Expr::ListComp(..) | Expr::MapComp(..) | Expr::Match(..) | Expr::Local(..) => false,
+        _ => true,
+    };
+    own && children(expr).into_iter().all(|child| inlinable(child, params))
+}
+
+fn substitute(expr: &Expr, args: &HashMap<&str, &Expr>) -> Expr {
+    let mut expr = expr.clone();
+    replace(&mut expr, args);
+    expr
+}
+
+fn replace(expr: &mut Expr, args: &HashMap<&str, &Expr>) {
+    if let Expr::Ident(id) = expr {
+        if let Some(arg) = args.get(id.as_str()) {
+            *expr = (*arg).clone();
+        }
+        return;
+    }
+    for child in children_mut(expr) {
+        replace(child, args);
+    }
+}
+
+fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::Binary(left, _, right) | Expr::Index(left, right) => vec![left, right],
+        Expr::Unary(_, inner) | Expr::Field(inner, _) | Expr::SafeField(inner, _) => vec![inner],
+        Expr::List(items) | Expr::Tuple(items) => items.iter_mut().collect(),
+        Expr::MethodCall(target, _, args) => std::iter::once(target.as_mut()).chain(args).collect(),
+        Expr::Map(entries) => entries.iter_mut().flat_map(|(k, v)| [k, v]).collect(),
+        Expr::Slice(target, start, end, step) => std::iter::once(target.as_mut()).chain([start, end, step].into_iter().flatten().map(|e| e.as_mut())).collect(),
+        Expr::If(condition, then_expr, else_expr) => vec![condition, then_expr, else_expr],
+        _ => Vec::new(),
+    }
+}
+
+fn fold(left: &Expr, op: &str, right: &Expr) -> Option<Expr> {
+    let folded = match (left, op, right) {
+        (Expr::Number(a), _, Expr::Number(b)) => match op {
+            "+" => Expr::Number(a + b),
+            "-" => Expr::Number(a - b),
+            "*" => Expr::Number(a * b),
+            "/" if *b != 0.0 => Expr::Number(a / b),
+            "<" => Expr::Bool(a < b),
+            "<=" => Expr::Bool(a <= b),
+            ">" => Expr::Bool(a > b),
+            ">=" => Expr::Bool(a >= b),
+            "==" => Expr::Bool(a == b),
+            "!=" => Expr::Bool(a != b),
+            _ => return None,
+        },
+        (Expr::Bool(a), _, Expr::Bool(b)) => match op {
+            "and" => Expr::Bool(*a && *b),
+            "or" => Expr::Bool(*a || *b),
+            "==" => Expr::Bool(a == b),
+            "!=" => Expr::Bool(a != b),
+            _ => return None,
+        },
+        (Expr::String(a), "==", Expr::String(b)) => Expr::Bool(a == b),
+        (Expr::String(a), "!=", Expr::String(b)) => Expr::Bool(a != b),
+        (Expr::String(_), "+", _) | (_, "+", Expr::String(_)) if literal(left) && literal(right) => Expr::String(format!("{}{}", text(left), text(right))),
+        _ => return None,
+    };
+    match folded {
+        Expr::Number(n) if !n.is_finite() => None,
+        folded => Some(folded),
+    }
+}
+
+fn text(expr: &Expr) -> String {
+    match expr {
+        Expr::String(s) => s.clone(),
+        Expr::Number(n) => n.to_string(),
+        Expr::Bool(b) => b.to_string(),
+        _ => unreachable!(),
+    }
+}
+
+impl Optimizer {
+    fn learn(&mut self, stmt: &Statement) {
+        self.learn_attributed(stmt, false);
+    }
+
+    // `#[deprecated]` and `#[memoize]` run code at every call, so those functions stay calls;
+    // `#[inline]` lifts the size limit
+    fn learn_attributed(&mut self, stmt: &Statement, forced: bool) {
+        match stmt {
+            Statement::Attributed(attributes, target) if attributes.iter().all(|a| a.name != "deprecated" && a.name != "memoize") => {
+                self.learn_attributed(target, forced || attributes.iter().any(|a| a.name == "inline"));
+            }
+            Statement::Const(name, value, _) if literal(value) && self.program.unique(name) => {
+                self.consts.insert(name.clone(), value.clone());
+            }
+            Statement::Fun(name, params, _, body) if !self.program.imports && self.program.unique(name) => {
+                let [Statement::Return(expr)] = body.as_slice() else { return };
+                let simple = params.iter().all(|p| p.default.is_none() && !p.variadic && !self.program.traits.contains(&p.type_anno));
+                let names: Vec<String> = params.iter().map(|p| p.name.clone()).collect();
+                if simple && (forced || size(expr) <= INLINE_LIMIT) && inlinable(expr, &names) && names.iter().all(|name| uses(expr, name)) {
+                    self.inline.insert(name.clone(), (names, expr.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, stmt: Statement, out: &mut Vec<Statement>) -> bool {
+        match stmt {
+            Statement::If(condition, then_block, else_block) => {
+                let condition = self.expr(condition);
+                if let Expr::Bool(value) = condition {
+                    let else_stmts = else_block.as_deref().unwrap_or_default();
+                    let (taken, dead) = if value { (&then_block[..], else_stmts) } else { (else_stmts, &then_block[..]) };
+                    if !contains_yield(dead) {
+                        return taken.iter().cloned().any(|stmt| self.push(stmt, out));
+                    }
+                }
+                out.push(Statement::If(condition, self.block(then_block), else_block.map(|b| self.block(b))));
+                false
+            }
+            Statement::While(condition, body) => match self.expr(condition) {
+                Expr::Bool(false) if !contains_yield(&body) => false,
+                condition => {
+                    out.push(Statement::While(condition, self.block(body)));
+                    false
+                }
+            },
+            stmt => {
+                let stmt = self.stmt(stmt);
+                let done = terminates(&stmt);
+                out.push(stmt);
+                done
+            }
+        }
+    }
+
+    fn block(&mut self, stmts: Vec<Statement>) -> Vec<Statement> {
+        let mut out = Vec::new();
+        let mut stmts = stmts.into_iter();
+        while let Some(stmt) = stmts.next() {
+            if self.push(stmt, &mut out) {
+                let rest: Vec<Statement> = stmts.collect();
+                if contains_yield(&rest) {
+                    out.extend(rest);
+                }
+                break;
+            }
+        }
+        out
+    }
+
+    fn stmt(&mut self, stmt: Statement) -> Statement {
+        match stmt {
+            Statement::Say(expr) => Statement::Say(self.expr(expr)),
+            Statement::Val(name, expr, type_anno) => Statement::Val(name, expr.map(|e| self.expr(e)), type_anno),
+            Statement::Let(name, expr, type_anno) => Statement::Let(name, expr.map(|e| self.expr(e)), type_anno),
+            Statement::Const(name, expr, type_anno) => Statement::Const(name, self.expr(expr), type_anno),
+            Statement::Fun(name, params, ret_type, body) => Statement::Fun(name, params, ret_type, self.block(body)),
+            Statement::Trait(name, signatures, defaults) => Statement::Trait(name, signatures, defaults.into_iter().map(|d| self.stmt(d)).collect()),
+            Statement::Impl(type_name, trait_name, methods) => Statement::Impl(type_name, trait_name, methods.into_iter().map(|m| self.stmt(m)).collect()),
+            Statement::If(condition, then_block, else_block) => Statement::If(self.expr(condition), self.block(then_block), else_block.map(|b| self.block(b))),
+            Statement::While(condition, body) => Statement::While(self.expr(condition), self.block(body)),
+            Statement::For(target, expr, body) => Statement::For(target, self.expr(expr), self.block(body)),
+            Statement::Try(try_block, catches, finally_block) => Statement::Try(
+                self.block(try_block),
+                catches.into_iter().map(|(ident, kind, block)| (ident, kind, self.block(block))).collect(),
+                finally_block.map(|b| self.block(b)),
+            ),
+            Statement::Throw(expr, line) => Statement::Throw(expr.map(|e| self.expr(e)), line),
+            Statement::Match(expr, branches) => Statement::Match(self.expr(expr), branches.into_iter().map(|(p, block)| (p, self.block(block))).collect()),
+            Statement::Unpack(target, expr, mutable) => Statement::Unpack(target, self.expr(expr), mutable),
+            Statement::Assign(target, expr) => Statement::Assign(target, self.expr(expr)),
+            Statement::Expr(expr) => Statement::Expr(self.expr(expr)),
+            Statement::Return(expr) => Statement::Return(self.expr(expr)),
+            Statement::Yield(expr) => Statement::Yield(self.expr(expr)),
+            Statement::Test(name, body) => Statement::Test(name, self.block(body)),
+            Statement::Attributed(attributes, target) => Statement::Attributed(attributes, Box::new(self.stmt(*target))),
+            stmt @ (Statement::Type(..) | Statement::Break | Statement::Continue | Statement::Import(..)) => stmt,
+        }
+    }
+
+    fn exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs.into_iter().map(|e| self.expr(e)).collect()
+    }
+
+    fn boxed(&mut self, mut expr: Box<Expr>) -> Box<Expr> {
+        let inner = std::mem::replace(expr.as_mut(), Expr::Bool(false));
+        *expr = self.expr(inner);
+        expr
+    }
+
+    fn expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Ident(id) => self.consts.get(&id).cloned().unwrap_or(Expr::Ident(id)),
+            Expr::Binary(left, op, right) => {
+                let (left, right) = (self.expr(*left), self.expr(*right));
+                fold(&left, &op, &right).unwrap_or_else(|| Expr::Binary(Box::new(left), op, Box::new(right)))
+            }
+            Expr::Unary(op, inner) => match (op.as_str(), self.expr(*inner)) {
+                ("-", Expr::Number(n)) => Expr::Number(-n),
+                ("!", Expr::Bool(b)) => Expr::Bool(!b),
+                (_, inner) => Expr::Unary(op, Box::new(inner)),
+            },
+            Expr::Call(name, args) => {
+                let args = self.exprs(args);
+                let Some((params, body)) = self.inline.get(&name) else { return Expr::Call(name, args) };
+                if args.len() != params.len() || !args.iter().all(|arg| literal(arg) || matches!(arg, Expr::Ident(_))) {
+                    return Expr::Call(name, args);
+                }
+                let bound = params.iter().map(|p| p.as_str()).zip(args.iter()).collect();
+                let inlined = substitute(body, &bound);
+                self.expr(inlined)
+            }
+            Expr::Named(name, inner) => Expr::Named(name, self.boxed(inner)),
+            Expr::MethodCall(target, method, args) => {
+                let target = match *target {
+                    Expr::Ident(id) => Box::new(Expr::Ident(id)),
+                    target => Box::new(self.expr(target)),
+                };
+                Expr::MethodCall(target, method, self.exprs(args))
+            }
+            Expr::List(items) => Expr::List(self.exprs(items)),
+            Expr::Tuple(items) => Expr::Tuple(self.exprs(items)),
+            Expr::Map(entries) => Expr::Map(entries.into_iter().map(|(k, v)| (self.expr(k), self.expr(v))).collect()),
+            Expr::ListComp(element, clauses) => Expr::ListComp(self.boxed(element), self.clauses(clauses)),
+            Expr::MapComp(key, value, clauses) => Expr::MapComp(self.boxed(key), self.boxed(value), self.clauses(clauses)),
+            Expr::Index(target, index) => Expr::Index(self.boxed(target), self.boxed(index)),
+            Expr::Slice(target, start, end, step) => Expr::Slice(self.boxed(target), start.map(|e| self.boxed(e)), end.map(|e| self.boxed(e)), step.map(|e| self.boxed(e))),
+            Expr::Field(target, field) => Expr::Field(self.boxed(target), field),
+            Expr::SafeField(target, field) => Expr::SafeField(self.boxed(target), field),
+            Expr::Propagate(inner) => Expr::Propagate(self.boxed(inner)),
+            Expr::If(condition, then_expr, else_expr) => match self.expr(*condition) {
+                Expr::Bool(true) => self.expr(*then_expr),
+                Expr::Bool(false) => self.expr(*else_expr),
+                condition => Expr::If(Box::new(condition), self.boxed(then_expr), self.boxed(else_expr)),
+            },
+            Expr::Match(subject, arms) => Expr::Match(self.boxed(subject), arms.into_iter().map(|(p, arm)| (p, self.expr(arm))).collect()),
+            expr @ (Expr::String(_) | Expr::Number(_) | Expr::Bool(_) | Expr::Local(..)) => expr,
+        }
+    }
+
+    fn clauses(&mut self, clauses: Vec<Clause>) -> Vec<Clause> {
+        clauses
+            .into_iter()
+            .map(|clause| match clause {
+                Clause::For(target, expr) => Clause::For(target, self.expr(expr)),
+                Clause::If(condition) => Clause::If(self.expr(condition)),
+            })
+            .collect()
+    }
+}
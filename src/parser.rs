@@ -1,14 +1,23 @@
 use pest::Parser;
+use pest::error::LineColLocation;
 use pest_derive::Parser;
 use crate::ast::*;
 
+const INDENT: char = '\u{E000}';
+const DEDENT: char = '\u{E001}';
+
 #[derive(Parser)]
 #[grammar = "velvet.pest"]
 pub struct VelvetParser;
 
 pub fn parse(source: &str) -> Result<Vec<Statement>, String> {
-    let pairs = VelvetParser::parse(Rule::program, source)
-        .map_err(|e| format!("Parse error at {}: {}", e.location, e))?;
+    let source = layout(source)?;
+    let pairs = VelvetParser::parse(Rule::program, &source).map_err(|e| {
+        let (line, col) = match e.line_col {
+            LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+        };
+        format!("Parse error at {}:{}: {}", line, col, e.to_string().replace([INDENT, DEDENT], ""))
+    })?;
     let mut statements = Vec::new();
     for pair in pairs {
         for inner_pair in pair.into_inner() {
@@ -20,40 +29,120 @@ pub fn parse(source: &str) -> Result<Vec<Statement>, String> {
     Ok(statements)
 }
 
+fn layout(source: &str) -> Result<String, String> {
+    let mut output = String::new();
+    let mut levels = vec![0];
+    for (number, line) in source.lines().enumerate() {
+        let content = line.trim_start();
+        if content.is_empty() || content.starts_with('@') {
+            output.push('\n');
+            continue;
+        }
+        let leading = &line[..line.len() - content.len()];
+        let width: usize = leading.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum();
+        output.push_str(leading);
+        if width > *levels.last().unwrap() {
+            levels.push(width);
+            output.push(INDENT);
+        }
+        while width < *levels.last().unwrap() {
+            levels.pop();
+            output.push(DEDENT);
+        }
+        if width != *levels.last().unwrap() {
+            return Err(format!("Inconsistent indentation at line {}", number + 1));
+        }
+        output.push_str(content);
+        output.push('\n');
+    }
+    output.extend(std::iter::repeat_n(DEDENT, levels.len() - 1));
+    Ok(output)
+}
+
 fn parse_statement(pair: pest::iterators::Pair<Rule>) -> Result<Statement, String> {
     match pair.as_rule() {
         Rule::say => Ok(Statement::Say(parse_expr(pair.into_inner().next().unwrap())?)),
-        Rule::val => {
-            let mut inner = pair.into_inner();
+        Rule::val | Rule::let_stmt => {
+            let rule = pair.as_rule();
+            let mut inner = pair.into_inner().peekable();
             let ident = inner.next().unwrap().as_str().to_string();
-            let type_anno = inner.next().map(|p| p.as_str().to_string());
-            let expr = inner.next().map(|p| parse_expr(p).unwrap());
-            Ok(Statement::Val(ident, expr, type_anno))
+            let type_anno = inner.next_if(|p| p.as_rule() == Rule::TYPE).map(|p| p.as_str().to_string());
+            let expr = inner.next().map(parse_expr).transpose()?;
+            if rule == Rule::let_stmt {
+                Ok(Statement::Let(ident, expr, type_anno))
+            } else {
+                Ok(Statement::Val(ident, expr, type_anno))
+            }
         }
-        Rule::const => {
-            let mut inner = pair.into_inner();
+        Rule::unpack_stmt => {
+            let mut inner = pair.into_inner().peekable();
+            let mutable = inner.next_if(|p| p.as_rule() == Rule::mutable_kw).is_some();
+            let target = parse_target(inner.next().unwrap())?;
+            let expr = parse_expr(inner.next().unwrap())?;
+            Ok(Statement::Unpack(target, expr, mutable))
+        }
+        Rule::const_stmt => {
+            let mut inner = pair.into_inner().peekable();
             let ident = inner.next().unwrap().as_str().to_string();
-            let type_anno = inner.next().map(|p| p.as_str().to_string());
+            let type_anno = inner.next_if(|p| p.as_rule() == Rule::TYPE).map(|p| p.as_str().to_string());
             let expr = parse_expr(inner.next().unwrap())?;
             Ok(Statement::Const(ident, expr, type_anno))
         }
         Rule::return_stmt => Ok(Statement::Return(parse_expr(pair.into_inner().next().unwrap())?)),
-        Rule::fn => {
-            let mut inner = pair.into_inner();
+        Rule::yield_stmt => Ok(Statement::Yield(parse_expr(pair.into_inner().next().unwrap())?)),
+        Rule::fn_stmt => {
+            let mut inner = pair.into_inner().peekable();
             let ident = inner.next().unwrap().as_str().to_string();
-            let mut params = Vec::new();
-            let param_pairs = inner.next().unwrap().into_inner();
-            for param in param_pairs {
-                if param.as_rule() == Rule::IDENT {
-                    params.push((param.as_str().to_string(), "f64".to_string()));
-                } else if param.as_rule() == Rule::TYPE {
-                    params.last_mut().unwrap().1 = param.as_str().to_string();
-                }
-            }
-            let return_type = inner.next().map(|p| p.as_str().to_string());
+            let params = parse_params(inner.next().unwrap())?;
+            let return_type = inner.next_if(|p| p.as_rule() == Rule::TYPE).map(|p| p.as_str().to_string());
             let body = parse_block(inner.next().unwrap())?;
             Ok(Statement::Fun(ident, params, return_type, body))
         }
+        Rule::type_stmt => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().unwrap().as_str().to_string();
+            let mut fields = Vec::new();
+            for field in inner.filter(|p| p.as_rule() == Rule::field) {
+                let mut parts = field.into_inner();
+                let field_name = parts.next().unwrap().as_str().to_string();
+                let type_anno = parts.next().unwrap().as_str().to_string();
+                let default = parts.next().map(parse_expr).transpose()?;
+                if fields.iter().any(|f: &Param| f.name == field_name) {
+                    return Err(format!("Duplicate field '{}' in type '{}'", field_name, name));
+                }
+                fields.push(Param { name: field_name, type_anno, default, variadic: false });
+            }
+            Ok(Statement::Type(name, fields))
+        }
+        Rule::trait_stmt => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().unwrap().as_str().to_string();
+            let mut signatures = Vec::new();
+            let mut defaults = Vec::new();
+            for item in inner {
+                match item.as_rule() {
+                    Rule::fn_sig => {
+                        let mut parts = item.into_inner();
+                        let method = parts.next().unwrap().as_str().to_string();
+                        let params = parse_params(parts.next().unwrap())?;
+                        signatures.push((method, params, parts.next().map(|p| p.as_str().to_string())));
+                    }
+                    Rule::fn_stmt => defaults.push(parse_statement(item)?),
+                    _ => {}
+                }
+            }
+            Ok(Statement::Trait(name, signatures, defaults))
+        }
+        Rule::impl_stmt => {
+            let mut inner = pair.into_inner().peekable();
+            let first = inner.next().unwrap().as_str().to_string();
+            let (type_name, trait_name) = match inner.next_if(|p| p.as_rule() == Rule::IDENT) {
+                Some(type_name) => (type_name.as_str().to_string(), Some(first)),
+                None => (first, None),
+            };
+            let methods = inner.filter(|p| p.as_rule() == Rule::fn_stmt).map(parse_statement).collect::<Result<_, _>>()?;
+            Ok(Statement::Impl(type_name, trait_name, methods))
+        }
         Rule::if_stmt => {
             let mut inner = pair.into_inner();
             let condition = parse_expr(inner.next().unwrap())?;
@@ -63,10 +152,10 @@ fn parse_statement(pair: pest::iterators::Pair<Rule>) -> Result<Statement, Strin
         }
         Rule::for_stmt => {
             let mut inner = pair.into_inner();
-            let ident = inner.next().unwrap().as_str().to_string();
+            let target = parse_target(inner.next().unwrap())?;
             let expr = parse_expr(inner.next().unwrap())?;
             let body = parse_block(inner.next().unwrap())?;
-            Ok(Statement::For(ident, expr, body))
+            Ok(Statement::For(target, expr, body))
         }
         Rule::while_stmt => {
             let mut inner = pair.into_inner();
@@ -79,19 +168,47 @@ fn parse_statement(pair: pest::iterators::Pair<Rule>) -> Result<Statement, Strin
         Rule::try_stmt => {
             let mut inner = pair.into_inner();
             let try_block = parse_block(inner.next().unwrap())?;
-            let error_ident = inner.next().unwrap().as_str().to_string();
-            let catch_block = parse_block(inner.next().unwrap())?;
-            Ok(Statement::Try(error_ident, try_block, catch_block))
+            let mut catches = Vec::new();
+            let mut finally_block = None;
+            for clause in inner {
+                match clause.as_rule() {
+                    Rule::catch_clause => {
+                        let mut clause_inner = clause.into_inner();
+                        let error_ident = clause_inner.next().unwrap().as_str().to_string();
+                        let mut next = clause_inner.next().unwrap();
+                        let mut kind = None;
+                        if next.as_rule() == Rule::IDENT {
+                            kind = Some(next.as_str().to_string());
+                            next = clause_inner.next().unwrap();
+                        }
+                        catches.push((error_ident, kind, parse_block(next)?));
+                    }
+                    Rule::finally_clause => {
+                        finally_block = Some(parse_block(clause.into_inner().next().unwrap())?);
+                    }
+                    _ => return Err(format!("Unexpected try clause: {:?}", clause.as_rule())),
+                }
+            }
+            if catches.is_empty() && finally_block.is_none() {
+                return Err("'try' needs at least one 'catch' or 'finally'".to_string());
+            }
+            Ok(Statement::Try(try_block, catches, finally_block))
+        }
+        Rule::throw_stmt => {
+            let line = pair.as_span().start_pos().line_col().0;
+            let expr = pair.into_inner().next().map(parse_expr).transpose()?;
+            Ok(Statement::Throw(expr, line))
         }
         Rule::match_stmt => {
             let mut inner = pair.into_inner();
             let expr = parse_expr(inner.next().unwrap())?;
-            let mut branches = Vec::new();
-            for branch in inner.next().unwrap().into_inner() {
-                let mut branch_inner = branch.into_inner();
-                let pattern = branch_inner.next().unwrap().as_str().to_string();
-                let statements = parse_block(branch_inner.next().unwrap())?;
-                branches.push((pattern, statements));
+            let mut branches: Vec<(Pattern, Vec<Statement>)> = Vec::new();
+            for item in inner.next().unwrap().into_inner() {
+                match item.as_rule() {
+                    Rule::pattern => branches.push((parse_pattern(item, false)?, Vec::new())),
+                    Rule::INDENT | Rule::DEDENT => {}
+                    _ => branches.last_mut().unwrap().1.push(parse_statement(item)?),
+                }
             }
             Ok(Statement::Match(expr, branches))
         }
@@ -107,13 +224,54 @@ fn parse_statement(pair: pest::iterators::Pair<Rule>) -> Result<Statement, Strin
             let body = parse_block(inner.next().unwrap())?;
             Ok(Statement::Test(name, body))
         }
+        Rule::assign_stmt => {
+            let mut inner = pair.into_inner();
+            let target = parse_target(inner.next().unwrap())?;
+            let expr = parse_expr(inner.next().unwrap())?;
+            Ok(Statement::Assign(target, expr))
+        }
         Rule::expr_stmt => Ok(Statement::Expr(parse_expr(pair.into_inner().next().unwrap())?)),
+        Rule::attributed => {
+            let mut inner: Vec<_> = pair.into_inner().collect();
+            let target = parse_statement(inner.pop().unwrap())?;
+            let attributes = inner.into_iter().map(|p| parse_attribute(p, &target)).collect::<Result<_, _>>()?;
+            Ok(Statement::Attributed(attributes, Box::new(target)))
+        }
         _ => Err(format!("Unexpected rule: {:?}", pair.as_rule())),
     }
 }
 
 fn parse_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, String> {
     match pair.as_rule() {
+        Rule::expr => {
+            let mut inner = pair.into_inner();
+            let value = parse_expr(inner.next().unwrap())?;
+            match (inner.next(), inner.next()) {
+                (Some(condition), Some(otherwise)) => {
+                    Ok(Expr::If(Box::new(parse_expr(condition)?), Box::new(value), Box::new(parse_expr(otherwise)?)))
+                }
+                _ => Ok(value),
+            }
+        }
+        Rule::match_expr => {
+            let mut inner = pair.into_inner();
+            let expr = parse_expr(inner.next().unwrap())?;
+            let mut arms = Vec::new();
+            for arm in inner.filter(|p| p.as_rule() == Rule::match_arm) {
+                let mut arm = arm.into_inner();
+                let pattern = parse_pattern(arm.next().unwrap(), false)?;
+                arms.push((pattern, parse_expr(arm.next().unwrap())?));
+            }
+            Ok(Expr::Match(Box::new(expr), arms))
+        }
+        Rule::coalesce => {
+            let mut inner = pair.into_inner();
+            let mut left = parse_expr(inner.next().unwrap())?;
+            for right in inner {
+                left = Expr::Binary(Box::new(left), "??".to_string(), Box::new(parse_expr(right)?));
+            }
+            Ok(left)
+        }
         Rule::logic | Rule::equality | Rule::comparison | Rule::term | Rule::factor => {
             let mut inner = pair.into_inner();
             let mut left = parse_expr(inner.next().unwrap())?;
@@ -125,9 +283,44 @@ fn parse_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, String> {
         }
         Rule::unary => {
             let mut inner = pair.into_inner();
-            let op = inner.next().unwrap().as_str().to_string();
+            let first = inner.next().unwrap();
+            if first.as_rule() != Rule::unary_op {
+                return parse_expr(first);
+            }
             let expr = parse_expr(inner.next().unwrap())?;
-            Ok(Expr::Unary(op, Box::new(expr)))
+            Ok(Expr::Unary(first.as_str().to_string(), Box::new(expr)))
+        }
+        Rule::postfix => {
+            let mut inner = pair.into_inner();
+            let mut expr = parse_expr(inner.next().unwrap())?;
+            for op in inner {
+                expr = match op.as_rule() {
+                    Rule::try_op => Expr::Propagate(Box::new(expr)),
+                    Rule::method_call => {
+                        let mut inner = op.into_inner();
+                        let method = inner.next().unwrap().as_str().to_string();
+                        let args = parse_args(&method, inner)?;
+                        Expr::MethodCall(Box::new(expr), method, args)
+                    }
+                    Rule::index => Expr::Index(Box::new(expr), Box::new(parse_expr(op.into_inner().next().unwrap())?)),
+                    Rule::slice => {
+                        let (mut start, mut end, mut step) = (None, None, None);
+                        for part in op.into_inner() {
+                            let rule = part.as_rule();
+                            let bound = Some(Box::new(parse_expr(part.into_inner().next().unwrap())?));
+                            match rule {
+                                Rule::slice_start => start = bound,
+                                Rule::slice_end => end = bound,
+                                _ => step = bound,
+                            }
+                        }
+                        Expr::Slice(Box::new(expr), start, end, step)
+                    }
+                    Rule::safe_field => Expr::SafeField(Box::new(expr), op.into_inner().next().unwrap().as_str().to_string()),
+                    _ => Expr::Field(Box::new(expr), op.as_str().to_string()),
+                };
+            }
+            Ok(expr)
         }
         Rule::primary => {
             let inner = pair.into_inner().next().unwrap();
@@ -136,13 +329,17 @@ fn parse_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, String> {
                 Rule::NUMBER => Ok(Expr::Number(inner.as_str().parse().unwrap_or(0.0))),
                 Rule::BOOL => Ok(Expr::Bool(inner.as_str() == "true")),
                 Rule::IDENT => Ok(Expr::Ident(inner.as_str().to_string())),
+                Rule::if_expr => {
+                    let mut inner = inner.into_inner();
+                    let condition = parse_expr(inner.next().unwrap())?;
+                    let then_expr = parse_expr(inner.next().unwrap())?;
+                    let else_expr = parse_expr(inner.next().unwrap())?;
+                    Ok(Expr::If(Box::new(condition), Box::new(then_expr), Box::new(else_expr)))
+                }
                 Rule::call => {
                     let mut inner = inner.into_inner();
                     let name = inner.next().unwrap().as_str().to_string();
-                    let mut args = Vec::new();
-                    for arg in inner {
-                        args.push(parse_expr(arg)?);
-                    }
+                    let args = parse_args(&name, inner)?;
                     Ok(Expr::Call(name, args))
                 }
                 Rule::list => {
@@ -152,12 +349,28 @@ fn parse_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, String> {
                     }
                     Ok(Expr::List(elements))
                 }
-                Rule::index => {
+                Rule::list_comp => {
                     let mut inner = inner.into_inner();
-                    let ident = inner.next().unwrap().as_str().to_string();
-                    let index = parse_expr(inner.next().unwrap())?;
-                    Ok(Expr::Index(ident, Box::new(index)))
+                    let element = parse_expr(inner.next().unwrap())?;
+                    Ok(Expr::ListComp(Box::new(element), inner.map(parse_clause).collect::<Result<_, _>>()?))
                 }
+                Rule::map_comp => {
+                    let mut inner = inner.into_inner();
+                    let key = parse_expr(inner.next().unwrap())?;
+                    let value = parse_expr(inner.next().unwrap())?;
+                    Ok(Expr::MapComp(Box::new(key), Box::new(value), inner.map(parse_clause).collect::<Result<_, _>>()?))
+                }
+                Rule::map => {
+                    let mut entries = Vec::new();
+                    for entry in inner.into_inner() {
+                        let mut entry = entry.into_inner();
+                        let key = parse_expr(entry.next().unwrap())?;
+                        let value = parse_expr(entry.next().unwrap())?;
+                        entries.push((key, value));
+                    }
+                    Ok(Expr::Map(entries))
+                }
+                Rule::tuple => Ok(Expr::Tuple(inner.into_inner().map(parse_expr).collect::<Result<_, _>>()?)),
                 Rule::expr => parse_expr(inner),
                 _ => Err(format!("Unexpected primary: {:?}", inner.as_rule())),
             }
@@ -166,10 +379,128 @@ fn parse_expr(pair: pest::iterators::Pair<Rule>) -> Result<Expr, String> {
     }
 }
 
+fn parse_args(name: &str, pairs: pest::iterators::Pairs<Rule>) -> Result<Vec<Expr>, String> {
+    let mut args = Vec::new();
+    for arg in pairs {
+        if arg.as_rule() == Rule::named_arg {
+            let mut named = arg.into_inner();
+            let arg_name = named.next().unwrap().as_str().to_string();
+            args.push(Expr::Named(arg_name, Box::new(parse_expr(named.next().unwrap())?)));
+        } else if matches!(args.last(), Some(Expr::Named(..))) {
+            return Err(format!("Positional argument after named arguments in call to '{}'", name));
+        } else {
+            args.push(parse_expr(arg)?);
+        }
+    }
+    Ok(args)
+}
+
+fn parse_params(pair: pest::iterators::Pair<Rule>) -> Result<Vec<Param>, String> {
+    let mut params: Vec<Param> = Vec::new();
+    for param in pair.into_inner() {
+        let mut parts = param.into_inner().peekable();
+        let variadic = parts.next_if(|p| p.as_rule() == Rule::variadic).is_some();
+        let name = parts.next().unwrap().as_str().to_string();
+        // Empty when the parameter is not annotated
+        let type_anno = parts.next_if(|p| p.as_rule() == Rule::TYPE).map_or(String::new(), |p| p.as_str().to_string());
+        let default = parts.next().map(parse_expr).transpose()?;
+        if let Some(previous) = params.last() {
+            if previous.variadic {
+                return Err(format!("Variadic parameter '...{}' must be the last parameter", previous.name));
+            }
+            if previous.default.is_some() && default.is_none() && !variadic {
+                return Err(format!("Parameter '{}' without a default follows a parameter with one", name));
+            }
+        }
+        if params.iter().any(|p| p.name == name) {
+            return Err(format!("Duplicate parameter '{}'", name));
+        }
+        if variadic && default.is_some() {
+            return Err(format!("Variadic parameter '...{}' cannot have a default", name));
+        }
+        params.push(Param { name, type_anno, default, variadic });
+    }
+    Ok(params)
+}
+
+fn parse_clause(pair: pest::iterators::Pair<Rule>) -> Result<Clause, String> {
+    let rule = pair.as_rule();
+    let mut inner = pair.into_inner();
+    match rule {
+        Rule::comp_for => {
+            let target = parse_target(inner.next().unwrap())?;
+            Ok(Clause::For(target, parse_expr(inner.next().unwrap())?))
+        }
+        _ => Ok(Clause::If(parse_expr(inner.next().unwrap())?)),
+    }
+}
+
+fn parse_target(pair: pest::iterators::Pair<Rule>) -> Result<Target, String> {
+    match pair.as_rule() {
+        Rule::IDENT => Ok(Target::Name(pair.as_str().to_string())),
+        Rule::tuple_target => Ok(Target::Tuple(pair.into_inner().map(parse_target).collect::<Result<_, _>>()?)),
+        Rule::list_target => {
+            let mut targets = Vec::new();
+            let mut rest = None;
+            for item in pair.into_inner() {
+                if item.as_rule() == Rule::rest_target {
+                    rest = Some(item.into_inner().next().unwrap().as_str().to_string());
+                } else {
+                    targets.push(parse_target(item)?);
+                }
+            }
+            Ok(Target::List(targets, rest))
+        }
+        _ => Err(format!("Unexpected target: {:?}", pair.as_rule())),
+    }
+}
+
+fn parse_attribute(pair: pest::iterators::Pair<Rule>, target: &Statement) -> Result<Attribute, String> {
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+    let args: Vec<Expr> = inner.map(parse_expr).collect::<Result<_, _>>()?;
+    match name.as_str() {
+        "test" | "inline" | "memoize" if !matches!(target, Statement::Fun(..)) => {
+            return Err(format!("Attribute '#[{}]' can only be applied to functions", name));
+        }
+        "deprecated" => {
+            if args.len() > 1 || args.iter().any(|a| !matches!(a, Expr::String(_))) {
+                return Err("Attribute '#[deprecated]' takes an optional message string".to_string());
+            }
+        }
+        "test" | "inline" | "memoize" | "export" => {
+            if !args.is_empty() {
+                return Err(format!("Attribute '#[{}]' does not take arguments", name));
+            }
+        }
+        _ => return Err(format!("Unknown attribute '#[{}]'", name)),
+    }
+    Ok(Attribute { name, args })
+}
+
+fn parse_pattern(pair: pest::iterators::Pair<Rule>, nested: bool) -> Result<Pattern, String> {
+    if pair.as_str() == "_" {
+        return Ok(Pattern::Wildcard);
+    }
+    let inner = pair.into_inner().next().unwrap();
+    match inner.as_rule() {
+        Rule::variant_pattern => {
+            let mut variant = inner.into_inner();
+            let name = variant.next().unwrap().as_str().to_string();
+            let binding = parse_pattern(variant.next().unwrap(), true)?;
+            Ok(Pattern::Variant(name, Some(Box::new(binding))))
+        }
+        Rule::none_pattern => Ok(Pattern::Variant("none".to_string(), None)),
+        Rule::IDENT if nested => Ok(Pattern::Bind(inner.as_str().to_string())),
+        Rule::STRING => Ok(Pattern::Literal(inner.as_str().trim_matches('"').to_string())),
+        _ => Ok(Pattern::Literal(inner.as_str().to_string())),
+    }
+}
+
 fn parse_block(pair: pest::iterators::Pair<Rule>) -> Result<Vec<Statement>, String> {
     let mut statements = Vec::new();
     for inner in pair.into_inner() {
-        if inner.as_rule() != Rule::NEWLINE && inner.as_rule() != Rule::INDENT && inner.as_rule() != Rule::DEDENT {
+        if inner.as_rule() != Rule::INDENT && inner.as_rule() != Rule::DEDENT {
             statements.push(parse_statement(inner)?);
         }
     }
@@ -0,0 +1,2133 @@
+/* Runtime emitted at the top of every program built by `vel build --target c`. It mirrors
+   prelude.rs so values print, compare and fail with the same messages as under `vel start`.
+   Strings, lists and wrapped values are reference counted like the Rc values of runtime.rs,
+   and a list with more than one reference is copied before it changes. Runtime functions
+   borrow their arguments and return values the caller owns; the generated code releases its
+   temporaries once they are used. Environments are freed when their function, comprehension
+   or test block ends, also when an error unwinds past it, but temporaries live at that moment
+   and error values themselves are never freed.
+   Case mapping covers Latin, Greek and Cyrillic letters; trimming only knows about ASCII. */
+#include <math.h>
+#include <setjmp.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* String literals are static with a negative count, so release() never frees them, which GCC
+   cannot see once release() is inlined next to one */
+#pragma GCC diagnostic ignored "-Wfree-nonheap-object"
+
+typedef enum { V_UNSET, V_NONE, V_NUMBER, V_BOOL, V_STRING, V_LIST, V_TUPLE, V_FUNCTION, V_ERROR, V_OK, V_ERR, V_SOME } Kind;
+
+typedef struct Value Value;
+typedef struct Str Str;
+typedef struct List List;
+typedef struct Box Box;
+typedef struct ErrorValue ErrorValue;
+typedef struct Function Function;
+typedef struct Env Env;
+
+struct Value {
+    Kind kind;
+    union {
+        double number;
+        int boolean;
+        Str *str;
+        List *list;
+        const Function *function;
+        ErrorValue *error;
+        Box *box;
+    } as;
+};
+
+/* Literals emitted by the compiler are static with refs -1 and never freed */
+struct Str {
+    int refs;
+    const char *chars;
+};
+
+struct List {
+    int len, cap, refs;
+    Value *items;
+};
+
+struct Box {
+    int refs;
+    Value value;
+};
+
+struct ErrorValue {
+    const char *kind, *message, *location;
+    int depth;
+    const char **stack;
+};
+
+typedef enum { CONST, IMMUTABLE, MUTABLE } Mutability;
+
+typedef struct {
+    int bound;
+    Value value;
+    Mutability mutability;
+} Binding;
+
+/* `held` keeps the sources of running for loops alive */
+struct Env {
+    int len;
+    Binding *slots;
+    int nheld, held_cap;
+    Value *held;
+};
+
+typedef struct {
+    const char *name, *type_anno;
+    Value (*fallback)(Env *);
+} Parameter;
+
+struct Function {
+    const Parameter *params;
+    int nparams, slots;
+    const char *ret_type;
+    Value (*body)(Env *);
+    int propagates;
+};
+
+typedef enum { SIGNAL_NONE, SIGNAL_BREAK, SIGNAL_CONTINUE, SIGNAL_RETURN, SIGNAL_ERROR } SignalKind;
+
+typedef struct {
+    SignalKind kind;
+    Value value;
+    ErrorValue *error;
+} Signal;
+
+/* A try region, test block or function that catches '?'; raising jumps to the innermost one */
+typedef struct Handler {
+    jmp_buf buf;
+    struct Handler *prev;
+    int depth, handled, live, held;
+    Env *env;
+} Handler;
+
+typedef enum { P_WILDCARD, P_LITERAL, P_BIND, P_VARIANT } PatternKind;
+
+typedef struct Pattern {
+    PatternKind kind;
+    int slot;
+    const char *text;
+    const struct Pattern *inner;
+} Pattern;
+
+typedef enum { T_NAME, T_TUPLE, T_LIST } TargetKind;
+
+typedef struct Target {
+    TargetKind kind;
+    int slot;
+    const char *name;
+    int count;
+    const struct Target *items;
+    const struct Target *rest;
+} Target;
+
+typedef struct {
+    int slot;
+    const char *name;
+    Value value;
+} Bound;
+
+typedef struct {
+    int len, cap;
+    Bound *items;
+} Bindings;
+
+typedef struct {
+    Value source;
+    int index, mark;
+    size_t offset;
+} Iter;
+
+typedef struct {
+    char *data;
+    size_t len, cap;
+} Buf;
+
+static const char *ERROR_KINDS[] = {"Error", "ValueError", "TypeError", "NameError", "IndexError", "ArgumentError", "ZeroDivisionError", "ImportError", NULL};
+static const char *BUILTINS[] = {"error", "ok", "err", "some", "unwrap", "unwrap_or", "map_err", "is_ok", "is_err", "is_some", "is_none", "divmod", "enumerate", "iter", "next", "collect", "take", "skip", "zip", "chain", NULL};
+static const char *STRING_METHODS[] = {"chars", "contains", "ends_with", "len", "lower", "replace", "repeat", "split", "starts_with", "to_num", "trim", "upper", NULL};
+static const char *LIST_METHODS[] = {"clear", "contains", "extend", "first", "index_of", "insert", "is_empty", "join", "last", "len", "max", "min", "pop", "push", "remove", "reverse", "sort", "sum", NULL};
+static const char *NUMBER_METHODS[] = {"abs", "ceil", "floor", "max", "min", "pow", "round", "sqrt", "to_str", NULL};
+static const char *NO_METHODS[] = {NULL};
+static const char *MUTATING[] = {"clear", "extend", "insert", "pop", "push", "remove", "set", NULL};
+
+static const char **calls;
+static int depth, calls_cap;
+static ErrorValue **handling;
+static int handled, handling_cap;
+static Env **scopes;
+static int live, scopes_cap;
+static Handler *handler;
+static Signal raised;
+
+static void error(const char *kind, const char *format, ...);
+static void env_release_to(Env *env, int mark);
+static void env_free(Env *env);
+
+static void *allocate(size_t size) {
+    void *memory = malloc(size ? size : 1);
+    if (!memory) {
+        fputs("Out of memory\n", stderr);
+        exit(1);
+    }
+    return memory;
+}
+
+static void *grow(void *memory, int *cap, int needed, size_t size) {
+    if (needed <= *cap) {
+        return memory;
+    }
+    *cap = *cap * 2 > needed ? *cap * 2 : needed + 4;
+    memory = realloc(memory, *cap * size);
+    if (!memory) {
+        fputs("Out of memory\n", stderr);
+        exit(1);
+    }
+    return memory;
+}
+
+static int listed(const char **names, const char *name) {
+    for (; *names; names++) {
+        if (strcmp(*names, name) == 0) {
+            return 1;
+        }
+    }
+    return 0;
+}
+
+static void buf_push(Buf *buf, const char *text, size_t len) {
+    if (buf->len + len + 1 > buf->cap) {
+        buf->cap = (buf->len + len + 1) * 2;
+        buf->data = realloc(buf->data, buf->cap);
+        if (!buf->data) {
+            fputs("Out of memory\n", stderr);
+            exit(1);
+        }
+    }
+    memcpy(buf->data + buf->len, text, len);
+    buf->len += len;
+    buf->data[buf->len] = '\0';
+}
+
+static void buf_str(Buf *buf, const char *text) {
+    buf_push(buf, text, strlen(text));
+}
+
+static void buf_vfmt(Buf *buf, const char *format, va_list args) {
+    va_list copy;
+    va_copy(copy, args);
+    int len = vsnprintf(NULL, 0, format, copy);
+    va_end(copy);
+    char *text = allocate(len + 1);
+    vsnprintf(text, len + 1, format, args);
+    buf_push(buf, text, len);
+    free(text);
+}
+
+static void buf_fmt(Buf *buf, const char *format, ...) {
+    va_list args;
+    va_start(args, format);
+    buf_vfmt(buf, format, args);
+    va_end(args);
+}
+
+static char *text(Buf *buf) {
+    if (!buf->data) {
+        buf_push(buf, "", 0);
+    }
+    return buf->data;
+}
+
+static char *format(const char *format, ...) {
+    Buf buf = {0};
+    va_list args;
+    va_start(args, format);
+    buf_vfmt(&buf, format, args);
+    va_end(args);
+    return text(&buf);
+}
+
+/* Shortest digits that read back as the same double, laid out like Rust's Display or Debug */
+static void number_text(Buf *buf, double n, int debug) {
+    if (isnan(n)) {
+        buf_str(buf, "NaN");
+        return;
+    }
+    if (signbit(n)) {
+        buf_str(buf, "-");
+        n = -n;
+    }
+    if (isinf(n)) {
+        buf_str(buf, "inf");
+        return;
+    }
+    if (n == 0) {
+        buf_str(buf, debug ? "0.0" : "0");
+        return;
+    }
+    char scientific[40], digits[24];
+    for (int precision = 0; precision <= 16; precision++) {
+        snprintf(scientific, sizeof scientific, "%.*e", precision, n);
+        if (strtod(scientific, NULL) == n) {
+            break;
+        }
+    }
+    int count = 0;
+    const char *p = scientific;
+    for (; *p != 'e'; p++) {
+        if (*p != '.') {
+            digits[count++] = *p;
+        }
+    }
+    int exponent = atoi(p + 1);
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+    digits[count] = '\0';
+    if (debug && (exponent < -4 || exponent >= 16)) {
+        buf_push(buf, digits, 1);
+        if (count > 1) {
+            buf_fmt(buf, ".%s", digits + 1);
+        }
+        buf_fmt(buf, "e%d", exponent);
+    } else if (exponent < 0) {
+        buf_str(buf, "0.");
+        for (int i = -1; i > exponent; i--) {
+            buf_str(buf, "0");
+        }
+        buf_str(buf, digits);
+    } else {
+        for (int i = 0; i <= exponent; i++) {
+            buf_push(buf, i < count ? digits + i : "0", 1);
+        }
+        if (count > exponent + 1) {
+            buf_fmt(buf, ".%s", digits + exponent + 1);
+        } else if (debug) {
+            buf_str(buf, ".0");
+        }
+    }
+}
+
+static char *number_string(double n) {
+    Buf buf = {0};
+    number_text(&buf, n, 0);
+    return text(&buf);
+}
+
+static Value unset(void) {
+    Value value;
+    value.kind = V_UNSET;
+    value.as.number = 0;
+    return value;
+}
+
+static Value none(void) {
+    Value value = unset();
+    value.kind = V_NONE;
+    return value;
+}
+
+static Value num(double n) {
+    Value value;
+    value.kind = V_NUMBER;
+    value.as.number = n;
+    return value;
+}
+
+static Value boolean(int b) {
+    Value value;
+    value.kind = V_BOOL;
+    value.as.boolean = b != 0;
+    return value;
+}
+
+static Value interned(Str *s) {
+    Value value;
+    value.kind = V_STRING;
+    value.as.str = s;
+    return value;
+}
+
+/* Takes over `s`, which must come from malloc */
+static Value str_take(char *s) {
+    Str *string = allocate(sizeof(Str));
+    string->refs = 1;
+    string->chars = s;
+    return interned(string);
+}
+
+static Value str(const char *s) {
+    return str_take(format("%s", s));
+}
+
+static int *refs(Value value) {
+    switch (value.kind) {
+    case V_STRING: return &value.as.str->refs;
+    case V_LIST: case V_TUPLE: return &value.as.list->refs;
+    case V_OK: case V_ERR: case V_SOME: return &value.as.box->refs;
+    default: return NULL;
+    }
+}
+
+static Value retain(Value value) {
+    int *count = refs(value);
+    if (count && *count >= 0) {
+        (*count)++;
+    }
+    return value;
+}
+
+static void release(Value value) {
+    int *count = refs(value);
+    if (!count || *count < 0 || --*count > 0) {
+        return;
+    }
+    switch (value.kind) {
+    case V_STRING:
+        free((char *)value.as.str->chars);
+        free(value.as.str);
+        return;
+    case V_LIST: case V_TUPLE:
+        for (int i = 0; i < value.as.list->len; i++) {
+            release(value.as.list->items[i]);
+        }
+        free(value.as.list->items);
+        free(value.as.list);
+        return;
+    default:
+        release(value.as.box->value);
+        free(value.as.box);
+    }
+}
+
+static List *list_new(int cap) {
+    List *list = allocate(sizeof(List));
+    list->len = 0;
+    list->cap = cap;
+    list->refs = 1;
+    list->items = allocate(sizeof(Value) * (cap ? cap : 1));
+    return list;
+}
+
+/* Takes over `value` */
+static void list_push(List *list, Value value) {
+    list->items = grow(list->items, &list->cap, list->len + 1, sizeof(Value));
+    list->items[list->len++] = value;
+}
+
+static Value collection(Kind kind, int len, const Value *items) {
+    Value value;
+    value.kind = kind;
+    value.as.list = list_new(len);
+    for (int i = 0; i < len; i++) {
+        list_push(value.as.list, retain(items[i]));
+    }
+    return value;
+}
+
+static Value list_of(int len, const Value *items) {
+    return collection(V_LIST, len, items);
+}
+
+static Value tuple_of(int len, const Value *items) {
+    return collection(V_TUPLE, len, items);
+}
+
+static Value list_value(List *list) {
+    Value value;
+    value.kind = V_LIST;
+    value.as.list = list;
+    return value;
+}
+
+static Value wrap(Kind kind, Value inner) {
+    Value value;
+    value.kind = kind;
+    value.as.box = allocate(sizeof(Box));
+    value.as.box->refs = 1;
+    value.as.box->value = retain(inner);
+    return value;
+}
+
+static Value option(int present, Value inner) {
+    return present ? wrap(V_SOME, inner) : none();
+}
+
+static Value function_value(const Function *function) {
+    Value value;
+    value.kind = V_FUNCTION;
+    value.as.function = function;
+    return value;
+}
+
+static Value error_value(ErrorValue *error) {
+    Value value;
+    value.kind = V_ERROR;
+    value.as.error = error;
+    return value;
+}
+
+static const char *type_name(Value value) {
+    switch (value.kind) {
+    case V_STRING: return "str";
+    case V_NUMBER: return "f64";
+    case V_BOOL: return "bool";
+    case V_LIST: return "list";
+    case V_TUPLE: return "tuple";
+    case V_FUNCTION: return "fn";
+    case V_ERROR: return "error";
+    case V_OK: case V_ERR: return "result";
+    case V_SOME: case V_NONE: return "option";
+    default: return "unset";
+    }
+}
+
+static void show_into(Buf *buf, Value value);
+static void debug_into(Buf *buf, Value value);
+
+static void debug_string(Buf *buf, const char *s) {
+    buf_str(buf, "\"");
+    for (; *s; s++) {
+        unsigned char c = (unsigned char)*s;
+        switch (c) {
+        case '"': buf_str(buf, "\\\""); break;
+        case '\\': buf_str(buf, "\\\\"); break;
+        case '\n': buf_str(buf, "\\n"); break;
+        case '\r': buf_str(buf, "\\r"); break;
+        case '\t': buf_str(buf, "\\t"); break;
+        default:
+            if (c < 0x20 || c == 0x7f) {
+                buf_fmt(buf, "\\u{%x}", c);
+            } else {
+                buf_push(buf, s, 1);
+            }
+        }
+    }
+    buf_str(buf, "\"");
+}
+
+static void debug_items(Buf *buf, const List *list) {
+    buf_str(buf, "[");
+    for (int i = 0; i < list->len; i++) {
+        if (i > 0) {
+            buf_str(buf, ", ");
+        }
+        debug_into(buf, list->items[i]);
+    }
+    buf_str(buf, "]");
+}
+
+static void debug_error(Buf *buf, const ErrorValue *error) {
+    buf_str(buf, "ErrorValue { kind: ");
+    debug_string(buf, error->kind);
+    buf_str(buf, ", message: ");
+    debug_string(buf, error->message);
+    buf_str(buf, ", location: ");
+    if (error->location) {
+        buf_str(buf, "Some(");
+        debug_string(buf, error->location);
+        buf_str(buf, ")");
+    } else {
+        buf_str(buf, "None");
+    }
+    buf_str(buf, ", stack: [");
+    for (int i = 0; i < error->depth; i++) {
+        if (i > 0) {
+            buf_str(buf, ", ");
+        }
+        debug_string(buf, error->stack[i]);
+    }
+    buf_str(buf, "] }");
+}
+
+static void debug_into(Buf *buf, Value value) {
+    switch (value.kind) {
+    case V_STRING:
+        buf_str(buf, "String(");
+        debug_string(buf, value.as.str->chars);
+        buf_str(buf, ")");
+        return;
+    case V_NUMBER:
+        buf_str(buf, "Number(");
+        number_text(buf, value.as.number, 1);
+        buf_str(buf, ")");
+        return;
+    case V_BOOL: buf_str(buf, value.as.boolean ? "Bool(true)" : "Bool(false)"); return;
+    case V_LIST: case V_TUPLE:
+        buf_str(buf, value.kind == V_LIST ? "List(" : "Tuple(");
+        debug_items(buf, value.as.list);
+        buf_str(buf, ")");
+        return;
+    case V_FUNCTION: buf_str(buf, "Function(<fn>)"); return;
+    case V_ERROR:
+        buf_str(buf, "Error(");
+        debug_error(buf, value.as.error);
+        buf_str(buf, ")");
+        return;
+    case V_OK: case V_ERR: case V_SOME:
+        buf_str(buf, value.kind == V_OK ? "Ok(" : value.kind == V_ERR ? "Err(" : "Some(");
+        debug_into(buf, value.as.box->value);
+        buf_str(buf, ")");
+        return;
+    case V_NONE: buf_str(buf, "None"); return;
+    default: buf_str(buf, "Unset");
+    }
+}
+
+static void show_into(Buf *buf, Value value) {
+    switch (value.kind) {
+    case V_STRING: buf_str(buf, value.as.str->chars); return;
+    case V_NUMBER: number_text(buf, value.as.number, 0); return;
+    case V_BOOL: buf_str(buf, value.as.boolean ? "true" : "false"); return;
+    case V_LIST: debug_items(buf, value.as.list); return;
+    case V_TUPLE:
+        buf_str(buf, "(");
+        for (int i = 0; i < value.as.list->len; i++) {
+            if (i > 0) {
+                buf_str(buf, ", ");
+            }
+            show_into(buf, value.as.list->items[i]);
+        }
+        buf_str(buf, value.as.list->len == 1 ? ",)" : ")");
+        return;
+    case V_FUNCTION: buf_str(buf, "<fn>"); return;
+    case V_ERROR: buf_fmt(buf, "%s: %s", value.as.error->kind, value.as.error->message); return;
+    case V_OK: case V_ERR: case V_SOME:
+        buf_str(buf, value.kind == V_OK ? "ok(" : value.kind == V_ERR ? "err(" : "some(");
+        show_into(buf, value.as.box->value);
+        buf_str(buf, ")");
+        return;
+    case V_NONE: buf_str(buf, "none"); return;
+    default: buf_str(buf, "<unset>");
+    }
+}
+
+static char *show(Value value) {
+    Buf buf = {0};
+    show_into(&buf, value);
+    return text(&buf);
+}
+
+static char *debug(Value value) {
+    Buf buf = {0};
+    debug_into(&buf, value);
+    return text(&buf);
+}
+
+static int same_text(const char *a, const char *b) {
+    return a == b || (a && b && strcmp(a, b) == 0);
+}
+
+static int equal(Value a, Value b) {
+    if (a.kind != b.kind) {
+        return 0;
+    }
+    switch (a.kind) {
+    case V_NUMBER: return a.as.number == b.as.number;
+    case V_BOOL: return a.as.boolean == b.as.boolean;
+    case V_STRING: return strcmp(a.as.str->chars, b.as.str->chars) == 0;
+    case V_LIST: case V_TUPLE:
+        if (a.as.list->len != b.as.list->len) {
+            return 0;
+        }
+        for (int i = 0; i < a.as.list->len; i++) {
+            if (!equal(a.as.list->items[i], b.as.list->items[i])) {
+                return 0;
+            }
+        }
+        return 1;
+    case V_FUNCTION: return a.as.function == b.as.function;
+    case V_ERROR:
+        if (!same_text(a.as.error->kind, b.as.error->kind) || !same_text(a.as.error->message, b.as.error->message) || !same_text(a.as.error->location, b.as.error->location) || a.as.error->depth != b.as.error->depth) {
+            return 0;
+        }
+        for (int i = 0; i < a.as.error->depth; i++) {
+            if (strcmp(a.as.error->stack[i], b.as.error->stack[i]) != 0) {
+                return 0;
+            }
+        }
+        return 1;
+    case V_OK: case V_ERR: case V_SOME: return equal(a.as.box->value, b.as.box->value);
+    default: return 1;
+    }
+}
+
+static int starts_with(const char *s, const char *prefix) {
+    return strncmp(s, prefix, strlen(prefix)) == 0;
+}
+
+static ErrorValue *error_new(const char *kind, const char *message) {
+    ErrorValue *error = allocate(sizeof(ErrorValue));
+    error->kind = kind;
+    error->message = message;
+    error->location = NULL;
+    error->depth = 0;
+    error->stack = NULL;
+    return error;
+}
+
+/* Innermost call first, leaving out the `skip` innermost frames */
+static void call_stack(ErrorValue *error, int skip) {
+    error->depth = depth > skip ? depth - skip : 0;
+    error->stack = allocate(sizeof(char *) * (error->depth + 1));
+    for (int i = 0; i < error->depth; i++) {
+        error->stack[i] = calls[depth - 1 - skip - i];
+    }
+}
+
+static char *trace(const ErrorValue *error) {
+    Buf buf = {0};
+    buf_fmt(&buf, "%s: %s", error->kind, error->message);
+    if (error->location) {
+        buf_fmt(&buf, " (%s)", error->location);
+    }
+    for (int i = 0; i < error->depth; i++) {
+        buf_fmt(&buf, "\n    at %s", error->stack[i]);
+    }
+    return text(&buf);
+}
+
+static Signal no_signal(void) {
+    Signal signal;
+    signal.kind = SIGNAL_NONE;
+    signal.value = none();
+    signal.error = NULL;
+    return signal;
+}
+
+static Signal signal_of(SignalKind kind) {
+    Signal signal = no_signal();
+    signal.kind = kind;
+    return signal;
+}
+
+static Signal returned(Value value) {
+    Signal signal = signal_of(SIGNAL_RETURN);
+    signal.value = value;
+    return signal;
+}
+
+static Signal failure(ErrorValue *error) {
+    Signal signal = signal_of(SIGNAL_ERROR);
+    signal.error = error;
+    return signal;
+}
+
+static void finish(Signal signal) {
+    switch (signal.kind) {
+    case SIGNAL_BREAK: fputs("'break' outside of a loop\n", stderr); exit(1);
+    case SIGNAL_CONTINUE: fputs("'continue' outside of a loop\n", stderr); exit(1);
+    case SIGNAL_ERROR: fprintf(stderr, "Uncaught %s\n", trace(signal.error)); exit(1);
+    default: exit(0);
+    }
+}
+
+/* `env` is the environment the handler's code runs in; the loops it holds values for stay held */
+static void push_handler(Handler *h, Env *env) {
+    h->prev = handler;
+    h->depth = depth;
+    h->handled = handled;
+    h->live = live;
+    h->env = env;
+    h->held = env->nheld;
+    handler = h;
+}
+
+static void pop_handler(Handler *h) {
+    handler = h->prev;
+}
+
+static Signal caught(void) {
+    return raised;
+}
+
+/* Hands the signal to the innermost handler, or ends the program when there is none */
+static void resignal(Signal signal) {
+    Handler *h = handler;
+    if (!h) {
+        finish(signal);
+    }
+    handler = h->prev;
+    depth = h->depth;
+    handled = h->handled;
+    while (live > h->live) {
+        env_free(scopes[--live]);
+    }
+    env_release_to(h->env, h->held);
+    raised = signal;
+    longjmp(h->buf, 1);
+}
+
+static void raise_error(ErrorValue *error) {
+    resignal(failure(error));
+}
+
+static void error(const char *kind, const char *format, ...) {
+    Buf buf = {0};
+    va_list args;
+    va_start(args, format);
+    buf_vfmt(&buf, format, args);
+    va_end(args);
+    ErrorValue *e = error_new(kind, text(&buf));
+    call_stack(e, 0);
+    raise_error(e);
+}
+
+static void enter(const char *name) {
+    calls = grow(calls, &calls_cap, depth + 1, sizeof(char *));
+    calls[depth++] = name;
+}
+
+/* Raised as if the call had already returned */
+static void fail_returned(const char *kind, const char *message) {
+    ErrorValue *e = error_new(kind, message);
+    call_stack(e, 1);
+    raise_error(e);
+}
+
+/* A signal leaving a function body outside any of its try regions */
+static Value unwind(Signal signal) {
+    if (signal.kind == SIGNAL_RETURN) {
+        return signal.value;
+    }
+    if (signal.kind == SIGNAL_ERROR) {
+        resignal(signal);
+    }
+    fail_returned("Error", format("'break' or 'continue' escaped function '%s'", calls[depth - 1]));
+    return none();
+}
+
+static ErrorValue *located(ErrorValue *error, int line) {
+    error->location = format("line %d", line);
+    call_stack(error, 0);
+    return error;
+}
+
+static Signal throw_value(Value value, int line) {
+    if (value.kind == V_ERROR && value.as.error->location) {
+        return failure(value.as.error);
+    }
+    if (value.kind == V_ERROR) {
+        ErrorValue *copy = allocate(sizeof(ErrorValue));
+        *copy = *value.as.error;
+        return failure(located(copy, line));
+    }
+    return failure(located(error_new("Error", show(value)), line));
+}
+
+static Signal rethrow(void) {
+    if (handled > 0) {
+        return failure(handling[handled - 1]);
+    }
+    ErrorValue *e = error_new("Error", "'throw' without a value outside of 'catch'");
+    call_stack(e, 0);
+    return failure(e);
+}
+
+static void begin_catch(ErrorValue *error) {
+    handling = grow(handling, &handling_cap, handled + 1, sizeof(ErrorValue *));
+    handling[handled++] = error;
+}
+
+static void end_catch(void) {
+    handled--;
+}
+
+static int error_matches(const ErrorValue *error, const char *kind) {
+    return strcmp(kind, "Error") == 0 || strcmp(error->kind, kind) == 0;
+}
+
+static char *expected(Value value, const char *kind) {
+    if (value.kind == V_NONE) {
+        return format("Expected %s, got none (check optional values with '?\?', '?.' or 'if x != none' first)", kind);
+    }
+    return format("Expected %s, got %s", kind, debug(value));
+}
+
+static double number(Value value) {
+    if (value.kind != V_NUMBER) {
+        error("TypeError", "%s", expected(value, "number"));
+    }
+    return value.as.number;
+}
+
+static int truthy(Value value) {
+    if (value.kind != V_BOOL) {
+        error("TypeError", "%s", expected(value, "bool"));
+    }
+    return value.as.boolean;
+}
+
+static const char *string(Value value) {
+    if (value.kind != V_STRING) {
+        error("TypeError", "%s", expected(value, "string"));
+    }
+    return value.as.str->chars;
+}
+
+static List *as_list(Value value) {
+    if (value.kind != V_LIST) {
+        error("TypeError", "%s", expected(value, "list"));
+    }
+    return value.as.list;
+}
+
+/* Globals live in a chained hash table; tests snapshot and restore all of it */
+#define BUCKETS 1024
+
+typedef struct {
+    const char *name;
+    Binding binding;
+    int next;
+} Global;
+
+typedef struct {
+    int len, cap;
+    Global *entries;
+    int heads[BUCKETS];
+} Globals;
+
+static Globals globals;
+
+static unsigned bucket(const char *name) {
+    unsigned hash = 2166136261u;
+    for (; *name; name++) {
+        hash = (hash ^ (unsigned char)*name) * 16777619u;
+    }
+    return hash % BUCKETS;
+}
+
+static Binding *global(const char *name) {
+    for (int i = globals.heads[bucket(name)]; i; i = globals.entries[i - 1].next) {
+        if (strcmp(globals.entries[i - 1].name, name) == 0) {
+            return &globals.entries[i - 1].binding;
+        }
+    }
+    return NULL;
+}
+
+static void set_global(const char *name, Binding binding) {
+    Binding *existing = global(name);
+    if (existing) {
+        release(existing->value);
+        *existing = binding;
+        return;
+    }
+    unsigned b = bucket(name);
+    globals.entries = grow(globals.entries, &globals.cap, globals.len + 1, sizeof(Global));
+    globals.entries[globals.len].name = name;
+    globals.entries[globals.len].binding = binding;
+    globals.entries[globals.len].next = globals.heads[b];
+    globals.heads[b] = ++globals.len;
+}
+
+static Globals *snapshot(void) {
+    Globals *copy = allocate(sizeof(Globals));
+    *copy = globals;
+    copy->entries = allocate(sizeof(Global) * (globals.cap ? globals.cap : 1));
+    if (globals.len) {
+        memcpy(copy->entries, globals.entries, sizeof(Global) * globals.len);
+    }
+    for (int i = 0; i < globals.len; i++) {
+        retain(globals.entries[i].binding.value);
+    }
+    return copy;
+}
+
+static void restore(Globals *saved) {
+    for (int i = 0; i < globals.len; i++) {
+        release(globals.entries[i].binding.value);
+    }
+    free(globals.entries);
+    globals = *saved;
+    free(saved);
+}
+
+/* Every environment is on the live stack until env_leave, so unwinding can free it */
+static Env *env_new(int len) {
+    Env *env = allocate(sizeof(Env));
+    env->len = len;
+    env->slots = calloc(len ? len : 1, sizeof(Binding));
+    env->nheld = env->held_cap = 0;
+    env->held = NULL;
+    scopes = grow(scopes, &scopes_cap, live + 1, sizeof(Env *));
+    scopes[live++] = env;
+    return env;
+}
+
+static void env_release_to(Env *env, int mark) {
+    while (env->nheld > mark) {
+        release(env->held[--env->nheld]);
+    }
+}
+
+static void env_free(Env *env) {
+    for (int i = 0; i < env->len; i++) {
+        release(env->slots[i].value);
+    }
+    env_release_to(env, 0);
+    free(env->held);
+    free(env->slots);
+    free(env);
+}
+
+static void env_leave(Env *env) {
+    live--;
+    env_free(env);
+}
+
+static Env *env_clone(Env *env) {
+    Env *copy = env_new(env->len);
+    for (int i = 0; i < env->len; i++) {
+        copy->slots[i] = env->slots[i];
+        retain(copy->slots[i].value);
+    }
+    return copy;
+}
+
+static Binding *env_with(Env *env, int slot, const char *name) {
+    if (slot >= 0 && env->slots[slot].bound) {
+        return &env->slots[slot];
+    }
+    return global(name);
+}
+
+static Binding *env_update(Env *env, int slot, const char *name) {
+    if (slot < 0) {
+        return global(name);
+    }
+    if (!env->slots[slot].bound) {
+        Binding *outer = global(name);
+        if (!outer) {
+            return NULL;
+        }
+        env->slots[slot] = *outer;
+        retain(outer->value);
+    }
+    return &env->slots[slot];
+}
+
+static int env_bound(Env *env, int slot, const char *name) {
+    return env_with(env, slot, name) != NULL;
+}
+
+static Value env_read(Env *env, int slot, const char *name) {
+    Binding *binding = env_with(env, slot, name);
+    if (!binding) {
+        error("NameError", "Var '%s' not found", name);
+    }
+    if (binding->value.kind == V_UNSET) {
+        error("NameError", "Variable '%s' is used before it is assigned", name);
+    }
+    return retain(binding->value);
+}
+
+static Value env_read_none(Env *env) {
+    if (env_bound(env, -1, "none")) {
+        return env_read(env, -1, "none");
+    }
+    return none();
+}
+
+static void env_define(Env *env, int slot, const char *name, Value value, Mutability mutability) {
+    Binding *existing = slot >= 0 ? (env->slots[slot].bound ? &env->slots[slot] : NULL) : global(name);
+    if (existing && existing->mutability == CONST) {
+        error("Error", "Cannot redeclare constant '%s'", name);
+    }
+    Binding binding;
+    binding.bound = 1;
+    binding.value = retain(value);
+    binding.mutability = mutability;
+    if (slot >= 0) {
+        release(env->slots[slot].value);
+        env->slots[slot] = binding;
+    } else {
+        set_global(name, binding);
+    }
+}
+
+static void env_assign(Env *env, int slot, const char *name, Value value) {
+    Binding *binding = env_update(env, slot, name);
+    if (!binding) {
+        error("NameError", "Var '%s' not found", name);
+    }
+    if (binding->mutability == CONST) {
+        error("Error", "Cannot assign to constant '%s'", name);
+    }
+    if (binding->mutability == IMMUTABLE && binding->value.kind != V_UNSET) {
+        error("Error", "Cannot assign twice to immutable variable '%s' (declare it with 'let' to make it mutable)", name);
+    }
+    Value old = binding->value;
+    binding->value = retain(value);
+    release(old);
+}
+
+static void bind(Bindings *bindings, int slot, const char *name, Value value) {
+    bindings->items = grow(bindings->items, &bindings->cap, bindings->len + 1, sizeof(Bound));
+    bindings->items[bindings->len].slot = slot;
+    bindings->items[bindings->len].name = name;
+    bindings->items[bindings->len].value = retain(value);
+    bindings->len++;
+}
+
+static void unbind(Bindings *bindings, int len) {
+    for (int i = 0; i < len; i++) {
+        release(bindings->items[i].value);
+    }
+    free(bindings->items);
+    bindings->items = NULL;
+    bindings->cap = 0;
+}
+
+static void define_all(Env *env, Bindings *bindings, Mutability mutability) {
+    int len = bindings->len;
+    bindings->len = 0;
+    for (int i = 0; i < len; i++) {
+        env_define(env, bindings->items[i].slot, bindings->items[i].name, bindings->items[i].value, mutability);
+    }
+    unbind(bindings, len);
+}
+
+static void assign_all(Env *env, Bindings *bindings) {
+    int len = bindings->len;
+    bindings->len = 0;
+    for (int i = 0; i < len; i++) {
+        env_assign(env, bindings->items[i].slot, bindings->items[i].name, bindings->items[i].value);
+    }
+    unbind(bindings, len);
+}
+
+static void unpack(const Target *target, Value value, Bindings *bindings) {
+    if (target->kind == T_NAME) {
+        if (strcmp(target->name, "_") != 0) {
+            bind(bindings, target->slot, target->name, value);
+        }
+        return;
+    }
+    if (target->kind == T_TUPLE) {
+        if (value.kind != V_TUPLE) {
+            error("TypeError", "Expected tuple to unpack, got %s", show(value));
+        }
+        if (value.as.list->len != target->count) {
+            error("ValueError", "Cannot unpack tuple of length %d into %d names", value.as.list->len, target->count);
+        }
+        for (int i = 0; i < target->count; i++) {
+            unpack(&target->items[i], value.as.list->items[i], bindings);
+        }
+        return;
+    }
+    List *values = as_list(value);
+    if (values->len < target->count || (!target->rest && values->len != target->count)) {
+        if (target->rest) {
+            error("ValueError", "Cannot unpack list of length %d into at least %d names", values->len, target->count);
+        }
+        error("ValueError", "Cannot unpack list of length %d into %d names", values->len, target->count);
+    }
+    for (int i = 0; i < target->count; i++) {
+        unpack(&target->items[i], values->items[i], bindings);
+    }
+    if (target->rest) {
+        Value rest = list_of(values->len - target->count, values->items + target->count);
+        unpack(target->rest, rest, bindings);
+        release(rest);
+    }
+}
+
+static int match_pattern(const Pattern *pattern, Value value, Bindings *bindings) {
+    switch (pattern->kind) {
+    case P_WILDCARD: return 1;
+    case P_BIND:
+        bind(bindings, pattern->slot, pattern->text, value);
+        return 1;
+    case P_LITERAL: {
+        char *shown = show(value);
+        int same = strcmp(pattern->text, shown) == 0;
+        free(shown);
+        return same;
+    }
+    default:
+        if (pattern->inner) {
+            int wanted = strcmp(pattern->text, "ok") == 0 ? V_OK : strcmp(pattern->text, "err") == 0 ? V_ERR : strcmp(pattern->text, "some") == 0 ? V_SOME : -1;
+            return (int)value.kind == wanted && match_pattern(pattern->inner, value.as.box->value, bindings);
+        }
+        return strcmp(pattern->text, "none") == 0 && value.kind == V_NONE;
+    }
+}
+
+static Value no_match(Value value) {
+    error("ValueError", "No match arm for value %s", show(value));
+    return none();
+}
+
+static void check_type(Value value, const char *type_anno) {
+    size_t len = strlen(type_anno);
+    if (len > 0 && type_anno[len - 1] == '?') {
+        if (value.kind == V_NONE || value.kind == V_UNSET) {
+            return;
+        }
+        char *inner = format("%.*s", (int)(len - 1), type_anno);
+        check_type(value, inner);
+        free(inner);
+        return;
+    }
+    const char *name = type_name(value);
+    if (value.kind == V_UNSET || strcmp(name, type_anno) == 0) {
+        return;
+    }
+    error("TypeError", "Expected %s, got %s", type_anno, show(value));
+}
+
+static void say(Value value) {
+    char *shown = show(value);
+    printf("%s\n", shown);
+    free(shown);
+}
+
+static Value binary(const char *op, Value left, Value right) {
+    switch (op[0]) {
+    case '+':
+        if (left.kind == V_STRING || right.kind == V_STRING) {
+            Buf buf = {0};
+            show_into(&buf, left);
+            show_into(&buf, right);
+            return str_take(text(&buf));
+        }
+        return num(number(left) + number(right));
+    case '-': return num(number(left) - number(right));
+    case '*': return num(number(left) * number(right));
+    case '/': {
+        double r = number(right);
+        if (r == 0) {
+            error("ZeroDivisionError", "Division by zero");
+        }
+        return num(number(left) / r);
+    }
+    case '=': return boolean(equal(left, right));
+    case '!': return boolean(!equal(left, right));
+    case '>': {
+        double l = number(left);
+        return boolean(op[1] ? l >= number(right) : l > number(right));
+    }
+    case '<': {
+        double l = number(left);
+        return boolean(op[1] ? l <= number(right) : l < number(right));
+    }
+    }
+    error("Error", "Unknown operator '%s'", op);
+    return none();
+}
+
+static Value unary(const char *op, Value value) {
+    if (strcmp(op, "-") == 0) {
+        return num(-number(value));
+    }
+    if (strcmp(op, "!") == 0) {
+        return boolean(!truthy(value));
+    }
+    error("Error", "Unknown unary op '%s'", op);
+    return none();
+}
+
+/* Unwraps a `some` in place; true when the value is none and the fallback is wanted */
+static int coalesce(Value *value) {
+    if (value->kind == V_NONE) {
+        return 1;
+    }
+    if (value->kind == V_SOME) {
+        Value inner = retain(value->as.box->value);
+        release(*value);
+        *value = inner;
+    }
+    return 0;
+}
+
+static int char_count(const char *s) {
+    int count = 0;
+    for (; *s; s++) {
+        count += ((unsigned char)*s & 0xC0) != 0x80;
+    }
+    return count;
+}
+
+static size_t char_len(const char *s) {
+    size_t len = 1;
+    while (s[len] && ((unsigned char)s[len] & 0xC0) == 0x80) {
+        len++;
+    }
+    return len;
+}
+
+static char *char_at(const char *s, int index) {
+    for (; index > 0; index--) {
+        s += char_len(s);
+    }
+    return format("%.*s", (int)char_len(s), s);
+}
+
+static int position(Value index, int len, const char *container) {
+    double n = number(index);
+    if (n - trunc(n) != 0) {
+        error("TypeError", "Expected integer index, got %s", number_string(n));
+    }
+    double i = n < 0 ? n + len : n;
+    if (i < 0 || i >= len) {
+        error("IndexError", "Index %s out of bounds for %s of length %d", number_string(n), container, len);
+    }
+    return (int)i;
+}
+
+static Value index_value(Value target, Value index) {
+    switch (target.kind) {
+    case V_LIST: case V_TUPLE:
+        return retain(target.as.list->items[position(index, target.as.list->len, target.kind == V_LIST ? "list" : "tuple")]);
+    case V_STRING:
+        return str_take(char_at(target.as.str->chars, position(index, char_count(target.as.str->chars), "str")));
+    default:
+        error("TypeError", "Cannot index into %s", type_name(target));
+        return none();
+    }
+}
+
+static long long whole(double n) {
+    if (isnan(n)) {
+        return 0;
+    }
+    if (n >= 9.2e18) {
+        return 9200000000000000000LL;
+    }
+    if (n <= -9.2e18) {
+        return -9200000000000000000LL;
+    }
+    return (long long)n;
+}
+
+static long long bound(const Value *value, long long fallback, long long len, long long step) {
+    if (!value) {
+        return fallback;
+    }
+    double n = value->as.number;
+    long long v = n < 0 ? whole(n) + len : whole(n);
+    long long low = step > 0 ? 0 : -1, high = step > 0 ? len : len - 1;
+    return v < low ? low : v > high ? high : v;
+}
+
+static Value slice(Value target, const Value *start, const Value *end, const Value *step) {
+    long long len;
+    if (target.kind == V_LIST || target.kind == V_TUPLE) {
+        len = target.as.list->len;
+    } else if (target.kind == V_STRING) {
+        len = char_count(target.as.str->chars);
+    } else {
+        error("TypeError", "Cannot slice %s", type_name(target));
+        return none();
+    }
+    long long by = step ? whole(step->as.number) : 1;
+    if (by == 0) {
+        error("ValueError", "Slice step cannot be zero");
+    }
+    long long i = by > 0 ? bound(start, 0, len, by) : bound(start, len - 1, len, by);
+    long long stop = by > 0 ? bound(end, len, len, by) : bound(end, -1, len, by);
+    if (target.kind == V_STRING) {
+        const char **chars = allocate(sizeof(char *) * (len + 1));
+        const char *s = target.as.str->chars;
+        for (long long c = 0; c < len; c++, s += char_len(s)) {
+            chars[c] = s;
+        }
+        Buf buf = {0};
+        for (; (by > 0 && i < stop) || (by < 0 && i > stop); i += by) {
+            buf_push(&buf, chars[i], char_len(chars[i]));
+        }
+        free(chars);
+        return str_take(text(&buf));
+    }
+    Value result;
+    result.kind = target.kind;
+    result.as.list = list_new(0);
+    for (; (by > 0 && i < stop) || (by < 0 && i > stop); i += by) {
+        list_push(result.as.list, retain(target.as.list->items[i]));
+    }
+    return result;
+}
+
+/* Hands the failed value to the innermost handler, which returns it from the function */
+static Value propagate(Value value) {
+    if (value.kind == V_OK || value.kind == V_SOME) {
+        return retain(value.as.box->value);
+    }
+    if (value.kind == V_ERR || value.kind == V_NONE) {
+        if (depth == 0) {
+            error("Error", "'?' on %s outside of a function", show(value));
+        }
+        resignal(returned(retain(value)));
+    }
+    error("TypeError", "Expected result or option for '?', got %s", show(value));
+    return none();
+}
+
+static Value get_field(Value value, const char *field) {
+    if (value.kind == V_ERROR) {
+        ErrorValue *e = value.as.error;
+        if (strcmp(field, "kind") == 0) {
+            return str(e->kind);
+        }
+        if (strcmp(field, "message") == 0) {
+            return str(e->message);
+        }
+        if (strcmp(field, "location") == 0) {
+            return e->location ? str(e->location) : none();
+        }
+        if (strcmp(field, "stack") == 0) {
+            List *frames = list_new(e->depth);
+            for (int i = 0; i < e->depth; i++) {
+                list_push(frames, str(e->stack[i]));
+            }
+            return list_value(frames);
+        }
+    }
+    if (value.kind == V_NONE) {
+        error("Error", "Cannot read field '%s' of none (use '?.' for optional values)", field);
+    }
+    error("Error", "Value %s has no field '%s'", show(value), field);
+    return none();
+}
+
+static Value safe_field(Value value, const char *field) {
+    if (value.kind == V_NONE) {
+        return none();
+    }
+    if (value.kind == V_SOME) {
+        return get_field(value.as.box->value, field);
+    }
+    return get_field(value, field);
+}
+
+/* Takes over `value` and holds it in `env` until iter_end, or until `env` is freed */
+static Iter iter_of(Env *env, Value value) {
+    Iter iter;
+    if (value.kind != V_LIST && value.kind != V_TUPLE && value.kind != V_STRING) {
+        error("TypeError", "Expected list, str, map or iterator to iterate over, got %s", show(value));
+    }
+    env->held = grow(env->held, &env->held_cap, env->nheld + 1, sizeof(Value));
+    iter.mark = env->nheld;
+    env->held[env->nheld++] = value;
+    iter.source = value;
+    iter.index = 0;
+    iter.offset = 0;
+    return iter;
+}
+
+static void iter_end(Env *env, const Iter *iter) {
+    env_release_to(env, iter->mark);
+}
+
+static int iter_next(Iter *iter, Value *value) {
+    if (iter->source.kind == V_STRING) {
+        const char *s = iter->source.as.str->chars + iter->offset;
+        if (!*s) {
+            return 0;
+        }
+        size_t len = char_len(s);
+        *value = str_take(format("%.*s", (int)len, s));
+        iter->offset += len;
+        return 1;
+    }
+    if (iter->index >= iter->source.as.list->len) {
+        return 0;
+    }
+    *value = retain(iter->source.as.list->items[iter->index++]);
+    return 1;
+}
+
+static const char **available(const char *type) {
+    if (strcmp(type, "str") == 0) {
+        return STRING_METHODS;
+    }
+    if (strcmp(type, "list") == 0) {
+        return LIST_METHODS;
+    }
+    if (strcmp(type, "f64") == 0) {
+        return NUMBER_METHODS;
+    }
+    return NO_METHODS;
+}
+
+static void arity(const char *method, int argc, int expected) {
+    if (argc != expected) {
+        error("ArgumentError", "Method '%s' expects %d args, got %d", method, expected, argc);
+    }
+}
+
+static int parse_number(const char *s, double *out) {
+    const char *p = s;
+    if (*p == '+' || *p == '-') {
+        p++;
+    }
+    const char *words[] = {"inf", "infinity", "nan", NULL};
+    for (int i = 0; words[i]; i++) {
+        if (strlen(p) == strlen(words[i])) {
+            int same = 1;
+            for (size_t j = 0; p[j]; j++) {
+                same &= (p[j] | 0x20) == words[i][j];
+            }
+            if (same) {
+                *out = strtod(s, NULL);
+                return 1;
+            }
+        }
+    }
+    int digits = 0;
+    while (*p >= '0' && *p <= '9') {
+        p++, digits++;
+    }
+    if (*p == '.') {
+        p++;
+        while (*p >= '0' && *p <= '9') {
+            p++, digits++;
+        }
+    }
+    if (!digits) {
+        return 0;
+    }
+    if (*p == 'e' || *p == 'E') {
+        p++;
+        if (*p == '+' || *p == '-') {
+            p++;
+        }
+        if (!(*p >= '0' && *p <= '9')) {
+            return 0;
+        }
+        while (*p >= '0' && *p <= '9') {
+            p++;
+        }
+    }
+    if (*p) {
+        return 0;
+    }
+    *out = strtod(s, NULL);
+    return 1;
+}
+
+static int is_space(char c) {
+    return c == ' ' || c == '\t' || c == '\n' || c == '\r' || c == '\v' || c == '\f';
+}
+
+static char *trim(const char *s) {
+    while (is_space(*s)) {
+        s++;
+    }
+    size_t len = strlen(s);
+    while (len > 0 && is_space(s[len - 1])) {
+        len--;
+    }
+    return format("%.*s", (int)len, s);
+}
+
+static Value chars(const char *s) {
+    List *items = list_new(0);
+    for (; *s; s += char_len(s)) {
+        list_push(items, str_take(format("%.*s", (int)char_len(s), s)));
+    }
+    return list_value(items);
+}
+
+static unsigned decode(const char **s) {
+    const unsigned char *c = (const unsigned char *)*s;
+    size_t len = char_len(*s);
+    unsigned code = len == 1 ? c[0] : c[0] & (0x7F >> len);
+    for (size_t i = 1; i < len; i++) {
+        code = (code << 6) | (c[i] & 0x3F);
+    }
+    *s += len;
+    return code;
+}
+
+static void encode(Buf *buf, unsigned code) {
+    if (code < 0x80) {
+        buf_fmt(buf, "%c", code);
+    } else if (code < 0x800) {
+        buf_fmt(buf, "%c%c", 0xC0 | code >> 6, 0x80 | (code & 0x3F));
+    } else if (code < 0x10000) {
+        buf_fmt(buf, "%c%c%c", 0xE0 | code >> 12, 0x80 | (code >> 6 & 0x3F), 0x80 | (code & 0x3F));
+    } else {
+        buf_fmt(buf, "%c%c%c%c", 0xF0 | code >> 18, 0x80 | (code >> 12 & 0x3F), 0x80 | (code >> 6 & 0x3F), 0x80 | (code & 0x3F));
+    }
+}
+
+/* Case mapping for ASCII, Latin-1, Latin Extended-A, Greek and Cyrillic */
+static unsigned upper_case(unsigned c) {
+    if ((c >= 'a' && c <= 'z') || (c >= 0xE0 && c <= 0xFE && c != 0xF7) || (c >= 0x3B1 && c <= 0x3C9 && c != 0x3C2) || (c >= 0x430 && c <= 0x44F)) {
+        return c - 32;
+    }
+    if (c == 0xFF) {
+        return 0x178;
+    }
+    if (c == 0x3C2) {
+        return 0x3A3;
+    }
+    if (c >= 0x450 && c <= 0x45F) {
+        return c - 80;
+    }
+    if (((c >= 0x101 && c <= 0x137) || (c >= 0x14B && c <= 0x177)) && c % 2 == 1) {
+        return c - 1;
+    }
+    if (((c >= 0x13A && c <= 0x148) || (c >= 0x17A && c <= 0x17E)) && c % 2 == 0) {
+        return c - 1;
+    }
+    return c == 0x131 ? 'I' : c;
+}
+
+static unsigned lower_case(unsigned c) {
+    if ((c >= 'A' && c <= 'Z') || (c >= 0xC0 && c <= 0xDE && c != 0xD7) || (c >= 0x391 && c <= 0x3A9 && c != 0x3A2) || (c >= 0x410 && c <= 0x42F)) {
+        return c + 32;
+    }
+    if (c == 0x178) {
+        return 0xFF;
+    }
+    if (c >= 0x400 && c <= 0x40F) {
+        return c + 80;
+    }
+    if (((c >= 0x100 && c <= 0x136) || (c >= 0x14A && c <= 0x176)) && c % 2 == 0 && c != 0x130) {
+        return c + 1;
+    }
+    if (((c >= 0x139 && c <= 0x147) || (c >= 0x179 && c <= 0x17D)) && c % 2 == 1) {
+        return c + 1;
+    }
+    return c;
+}
+
+static int cased(unsigned c) {
+    return upper_case(c) != c || lower_case(c) != c;
+}
+
+static char *change_case(const char *s, int upper) {
+    Buf buf = {0};
+    unsigned previous = 0;
+    while (*s) {
+        unsigned c = decode(&s);
+        if (upper && c == 0xDF) {
+            buf_str(&buf, "SS");
+        } else if (!upper && c == 0x130) {
+            buf_str(&buf, "i\xCC\x87");
+        } else if (!upper && c == 0x3A3 && cased(previous) && !(*s && cased(decode(&(const char *){s})))) {
+            encode(&buf, 0x3C2);
+        } else {
+            encode(&buf, upper ? upper_case(c) : lower_case(c));
+        }
+        previous = c;
+    }
+    return text(&buf);
+}
+
+static Value string_method(const char *s, const char *method, int argc, Value *args) {
+    int expected = 0;
+    if (listed((const char *[]){"split", "contains", "starts_with", "ends_with", "repeat", NULL}, method)) {
+        expected = 1;
+    } else if (strcmp(method, "replace") == 0) {
+        expected = 2;
+    }
+    arity(method, argc, expected);
+    if (strcmp(method, "len") == 0) {
+        return num(char_count(s));
+    }
+    if (strcmp(method, "upper") == 0 || strcmp(method, "lower") == 0) {
+        return str_take(change_case(s, method[0] == 'u'));
+    }
+    if (strcmp(method, "trim") == 0) {
+        return str_take(trim(s));
+    }
+    if (strcmp(method, "chars") == 0) {
+        return chars(s);
+    }
+    if (strcmp(method, "split") == 0) {
+        const char *separator = string(args[0]);
+        if (!*separator) {
+            Value items = chars(s);
+            List *parts = list_new(0);
+            list_push(parts, str(""));
+            for (int i = 0; i < items.as.list->len; i++) {
+                list_push(parts, retain(items.as.list->items[i]));
+            }
+            list_push(parts, str(""));
+            release(items);
+            return list_value(parts);
+        }
+        List *parts = list_new(0);
+        size_t len = strlen(separator);
+        const char *found;
+        while ((found = strstr(s, separator))) {
+            list_push(parts, str_take(format("%.*s", (int)(found - s), s)));
+            s = found + len;
+        }
+        list_push(parts, str(s));
+        return list_value(parts);
+    }
+    if (strcmp(method, "contains") == 0) {
+        return boolean(strstr(s, string(args[0])) != NULL);
+    }
+    if (strcmp(method, "starts_with") == 0) {
+        return boolean(starts_with(s, string(args[0])));
+    }
+    if (strcmp(method, "ends_with") == 0) {
+        const char *suffix = string(args[0]);
+        size_t len = strlen(s), n = strlen(suffix);
+        return boolean(n <= len && strcmp(s + len - n, suffix) == 0);
+    }
+    if (strcmp(method, "replace") == 0) {
+        const char *from = string(args[0]), *to = string(args[1]);
+        Buf buf = {0};
+        if (!*from) {
+            buf_str(&buf, to);
+            for (; *s; s += char_len(s)) {
+                buf_push(&buf, s, char_len(s));
+                buf_str(&buf, to);
+            }
+            return str_take(text(&buf));
+        }
+        const char *found;
+        while ((found = strstr(s, from))) {
+            buf_push(&buf, s, found - s);
+            buf_str(&buf, to);
+            s = found + strlen(from);
+        }
+        buf_str(&buf, s);
+        return str_take(text(&buf));
+    }
+    if (strcmp(method, "repeat") == 0) {
+        double times = number(args[0]);
+        Buf buf = {0};
+        for (long long i = 0; i < whole(times); i++) {
+            buf_str(&buf, s);
+        }
+        return str_take(text(&buf));
+    }
+    if (strcmp(method, "to_num") == 0) {
+        double n;
+        char *trimmed = trim(s);
+        int parsed = parse_number(trimmed, &n);
+        free(trimmed);
+        if (!parsed) {
+            error("ValueError", "Cannot convert '%s' to a number", s);
+        }
+        return num(n);
+    }
+    error("NameError", "Type 'str' has no method '%s'", method);
+    return none();
+}
+
+static Value number_method(double n, const char *method, int argc, Value *args) {
+    arity(method, argc, listed((const char *[]){"pow", "min", "max", NULL}, method) ? 1 : 0);
+    if (strcmp(method, "abs") == 0) {
+        return num(fabs(n));
+    }
+    if (strcmp(method, "ceil") == 0) {
+        return num(ceil(n));
+    }
+    if (strcmp(method, "floor") == 0) {
+        return num(floor(n));
+    }
+    if (strcmp(method, "round") == 0) {
+        return num(round(n));
+    }
+    if (strcmp(method, "sqrt") == 0) {
+        return num(sqrt(n));
+    }
+    if (strcmp(method, "pow") == 0) {
+        return num(pow(n, number(args[0])));
+    }
+    if (strcmp(method, "min") == 0) {
+        return num(fmin(n, number(args[0])));
+    }
+    if (strcmp(method, "max") == 0) {
+        return num(fmax(n, number(args[0])));
+    }
+    if (strcmp(method, "to_str") == 0) {
+        return str_take(number_string(n));
+    }
+    error("NameError", "Type 'f64' has no method '%s'", method);
+    return none();
+}
+
+static const char *compare_failure;
+
+static int compare(Value a, Value b) {
+    if (a.kind == V_NUMBER && b.kind == V_NUMBER) {
+        return a.as.number < b.as.number ? -1 : a.as.number > b.as.number ? 1 : 0;
+    }
+    if (a.kind == V_STRING && b.kind == V_STRING) {
+        int order = strcmp(a.as.str->chars, b.as.str->chars);
+        return order < 0 ? -1 : order > 0;
+    }
+    compare_failure = format("Cannot compare %s with %s", type_name(a), type_name(b));
+    return 0;
+}
+
+static void merge_sort(Value *items, Value *scratch, int len) {
+    if (len < 2) {
+        return;
+    }
+    int half = len / 2;
+    merge_sort(items, scratch, half);
+    merge_sort(items + half, scratch, len - half);
+    int i = 0, j = half, k = 0;
+    while (i < half && j < len) {
+        scratch[k++] = compare(items[j], items[i]) < 0 ? items[j++] : items[i++];
+    }
+    while (i < half) {
+        scratch[k++] = items[i++];
+    }
+    while (j < len) {
+        scratch[k++] = items[j++];
+    }
+    memcpy(items, scratch, sizeof(Value) * len);
+}
+
+static Value extreme(List *items, const char *method, int wanted) {
+    if (items->len == 0) {
+        error("ValueError", "Cannot take %s of an empty list", method);
+    }
+    Value best = items->items[0];
+    for (int i = 1; i < items->len; i++) {
+        compare_failure = NULL;
+        int order = compare(items->items[i], best);
+        if (compare_failure) {
+            error("TypeError", "%s", compare_failure);
+        }
+        if (order == wanted) {
+            best = items->items[i];
+        }
+    }
+    return best;
+}
+
+static int list_index(Value value, int len, int allow_end) {
+    double i = number(value);
+    long long limit = allow_end ? len + 1 : len;
+    if (i < 0 || whole(i) >= limit) {
+        error("IndexError", "Index %s out of bounds for list of length %d", number_string(i), len);
+    }
+    return (int)whole(i);
+}
+
+static Value list_method(Value *receiver, const char *method, int argc, Value *args) {
+    List *items = receiver->as.list;
+    int expected = 0;
+    if (listed((const char *[]){"push", "remove", "contains", "index_of", "extend", "join", NULL}, method)) {
+        expected = 1;
+    } else if (strcmp(method, "insert") == 0) {
+        expected = 2;
+    }
+    arity(method, argc, expected);
+    if (strcmp(method, "len") == 0) {
+        return num(items->len);
+    }
+    if (strcmp(method, "is_empty") == 0) {
+        return boolean(items->len == 0);
+    }
+    if (strcmp(method, "first") == 0) {
+        return option(items->len > 0, items->len > 0 ? items->items[0] : none());
+    }
+    if (strcmp(method, "last") == 0) {
+        return option(items->len > 0, items->len > 0 ? items->items[items->len - 1] : none());
+    }
+    if (strcmp(method, "contains") == 0 || strcmp(method, "index_of") == 0) {
+        int found = -1;
+        for (int i = 0; i < items->len && found < 0; i++) {
+            if (equal(items->items[i], args[0])) {
+                found = i;
+            }
+        }
+        return method[0] == 'c' ? boolean(found >= 0) : option(found >= 0, num(found));
+    }
+    if (strcmp(method, "join") == 0) {
+        const char *separator = string(args[0]);
+        Buf buf = {0};
+        for (int i = 0; i < items->len; i++) {
+            if (i > 0) {
+                buf_str(&buf, separator);
+            }
+            show_into(&buf, items->items[i]);
+        }
+        return str_take(text(&buf));
+    }
+    if (strcmp(method, "reverse") == 0) {
+        List *reversed = list_new(items->len);
+        for (int i = items->len - 1; i >= 0; i--) {
+            list_push(reversed, retain(items->items[i]));
+        }
+        return list_value(reversed);
+    }
+    if (strcmp(method, "sort") == 0) {
+        Value sorted = list_of(items->len, items->items);
+        Value *scratch = allocate(sizeof(Value) * (items->len + 1));
+        compare_failure = NULL;
+        merge_sort(sorted.as.list->items, scratch, items->len);
+        free(scratch);
+        if (compare_failure) {
+            error("TypeError", "%s", compare_failure);
+        }
+        return sorted;
+    }
+    if (strcmp(method, "sum") == 0) {
+        double sum = -0.0;
+        for (int i = 0; i < items->len; i++) {
+            sum += number(items->items[i]);
+        }
+        return num(sum);
+    }
+    if (strcmp(method, "min") == 0) {
+        return retain(extreme(items, method, -1));
+    }
+    if (strcmp(method, "max") == 0) {
+        return retain(extreme(items, method, 1));
+    }
+    if (strcmp(method, "push") == 0) {
+        list_push(items, retain(args[0]));
+        return none();
+    }
+    if (strcmp(method, "pop") == 0) {
+        if (items->len == 0) {
+            error("ValueError", "Cannot pop from an empty list");
+        }
+        return items->items[--items->len];
+    }
+    if (strcmp(method, "insert") == 0) {
+        int at = list_index(args[0], items->len, 1);
+        list_push(items, retain(args[1]));
+        memmove(items->items + at + 1, items->items + at, sizeof(Value) * (items->len - 1 - at));
+        items->items[at] = args[1];
+        return none();
+    }
+    if (strcmp(method, "remove") == 0) {
+        int at = list_index(args[0], items->len, 0);
+        Value removed = items->items[at];
+        memmove(items->items + at, items->items + at + 1, sizeof(Value) * (items->len - 1 - at));
+        items->len--;
+        return removed;
+    }
+    if (strcmp(method, "extend") == 0) {
+        List *more = as_list(args[0]);
+        int len = more->len;
+        for (int i = 0; i < len; i++) {
+            list_push(items, retain(more->items[i]));
+        }
+        return none();
+    }
+    if (strcmp(method, "clear") == 0) {
+        release(*receiver);
+        *receiver = list_of(0, NULL);
+        return none();
+    }
+    error("NameError", "Type 'list' has no method '%s'", method);
+    return none();
+}
+
+static Value native_method(Value *receiver, const char *method, int argc, Value *args) {
+    switch (receiver->kind) {
+    case V_STRING: return string_method(receiver->as.str->chars, method, argc, args);
+    case V_NUMBER: return number_method(receiver->as.number, method, argc, args);
+    case V_LIST: return list_method(receiver, method, argc, args);
+    default:
+        error("NameError", "Type '%s' has no method '%s'", type_name(*receiver), method);
+        return none();
+    }
+}
+
+static Value own(Value value) {
+    if (value.kind == V_LIST) {
+        return list_of(value.as.list->len, value.as.list->items);
+    }
+    return retain(value);
+}
+
+static int mutates(Value receiver, const char *method) {
+    return listed(MUTATING, method) && listed(available(type_name(receiver)), method);
+}
+
+static void forbid_mutation(Value receiver, const char *method, const char *name) {
+    if (mutates(receiver, method)) {
+        error("Error", "Method '%s' changes '%s' and can only be called as a statement or as the value of a declaration or assignment", method, name);
+    }
+}
+
+static Value call_method(Value receiver, const char *method, int argc, Value *args) {
+    const char *type = type_name(receiver);
+    const char **methods = available(type);
+    if (listed(methods, method)) {
+        if (!mutates(receiver, method)) {
+            return native_method(&receiver, method, argc, args);
+        }
+        Value copy = own(receiver);
+        Value result = native_method(&copy, method, argc, args);
+        release(copy);
+        return result;
+    }
+    Buf names = {0};
+    for (; *methods; methods++) {
+        buf_fmt(&names, names.len ? ", %s" : "%s", *methods);
+    }
+    error("NameError", "Type '%s' has no method '%s' (available: %s)", type, method, names.len ? text(&names) : "none");
+    return none();
+}
+
+/* Statement-level method call on a variable; the binding's list is copied first if shared */
+static Value env_mutate(Env *env, int slot, const char *name, const char *method, int argc, Value *args) {
+    Binding *binding = env_update(env, slot, name);
+    if (!binding) {
+        error("NameError", "Var '%s' not found", name);
+    }
+    if (binding->mutability != MUTABLE) {
+        Value copy = own(binding->value);
+        native_method(&copy, method, argc, args);
+        if (binding->mutability == CONST) {
+            error("Error", "Cannot call '%s' on constant '%s'", method, name);
+        }
+        error("Error", "Cannot call '%s' on immutable variable '%s' (declare it with 'let' to make it mutable)", method, name);
+    }
+    if (binding->value.kind == V_LIST && binding->value.as.list->refs > 1) {
+        Value copy = own(binding->value);
+        release(binding->value);
+        binding->value = copy;
+    }
+    return native_method(&binding->value, method, argc, args);
+}
+
+static Value call_function(const char *name, Value func, int argc, Value *args);
+
+static void bind_args(const char *name, const Function *function, int argc, Value *args, Env *local) {
+    if (argc > function->nparams) {
+        error("ArgumentError", "Expected at most %d args, got %d", function->nparams, argc);
+    }
+    for (int slot = 0; slot < function->nparams; slot++) {
+        const Parameter *param = &function->params[slot];
+        Value value;
+        if (slot < argc) {
+            value = retain(args[slot]);
+        } else if (param->fallback) {
+            value = param->fallback(local);
+        } else {
+            error("ArgumentError", "Missing argument '%s' in call to '%s'", param->name, name);
+        }
+        local->slots[slot].bound = 1;
+        local->slots[slot].value = value;
+        local->slots[slot].mutability = IMMUTABLE;
+    }
+}
+
+static Value call_function(const char *name, Value func, int argc, Value *args) {
+    if (func.kind != V_FUNCTION) {
+        error("TypeError", "'%s' is not a function", name);
+    }
+    const Function *function = func.as.function;
+    Env *local = env_new(function->slots);
+    bind_args(name, function, argc, args, local);
+    enter(name);
+    Value result;
+    if (function->propagates) {
+        Handler h;
+        if (setjmp(h.buf) == 0) {
+            push_handler(&h, local);
+            result = function->body(local);
+            pop_handler(&h);
+        } else {
+            Signal signal = caught();
+            if (signal.kind != SIGNAL_RETURN) {
+                resignal(signal);
+            }
+            result = signal.value;
+        }
+    } else {
+        result = function->body(local);
+    }
+    if (result.kind == V_UNSET && function->ret_type && strcmp(function->ret_type, "void") != 0) {
+        fail_returned("Error", "Missing return value");
+    }
+    env_leave(local);
+    depth--;
+    return result.kind == V_UNSET ? none() : result;
+}
+
+static Value call_builtin(const char *name, int argc, Value *args) {
+    if (strcmp(name, "error") == 0 && argc == 1) {
+        return error_value(error_new("Error", show(args[0])));
+    }
+    if (strcmp(name, "error") == 0 && argc == 2) {
+        return error_value(error_new(show(args[0]), show(args[1])));
+    }
+    if (listed(ERROR_KINDS, name) && argc == 1) {
+        return error_value(error_new(name, show(args[0])));
+    }
+    if (argc == 1 && (strcmp(name, "ok") == 0 || strcmp(name, "err") == 0 || strcmp(name, "some") == 0)) {
+        return wrap(name[0] == 'o' ? V_OK : name[0] == 'e' ? V_ERR : V_SOME, args[0]);
+    }
+    if (strcmp(name, "unwrap") == 0 && argc == 1) {
+        Value value = args[0];
+        if (value.kind == V_OK || value.kind == V_SOME) {
+            return retain(value.as.box->value);
+        }
+        if (value.kind == V_ERR && value.as.box->value.kind == V_ERROR) {
+            raise_error(value.as.box->value.as.error);
+        }
+        if (value.kind == V_ERR) {
+            error("Error", "unwrap called on err(%s)", show(value.as.box->value));
+        }
+        if (value.kind == V_NONE) {
+            error("Error", "unwrap called on none");
+        }
+        error("TypeError", "Expected result or option, got %s", show(value));
+    }
+    if (strcmp(name, "unwrap_or") == 0 && argc == 2) {
+        Value value = args[0];
+        if (value.kind == V_OK || value.kind == V_SOME) {
+            return retain(value.as.box->value);
+        }
+        if (value.kind == V_ERR || value.kind == V_NONE) {
+            return retain(args[1]);
+        }
+        error("TypeError", "Expected result or option, got %s", show(value));
+    }
+    if (strcmp(name, "map_err") == 0 && argc == 2) {
+        if (args[0].kind == V_ERR) {
+            Value mapped = call_function(name, args[1], 1, &args[0].as.box->value);
+            Value result = wrap(V_ERR, mapped);
+            release(mapped);
+            return result;
+        }
+        if (args[0].kind == V_OK) {
+            return retain(args[0]);
+        }
+        error("TypeError", "Expected result, got %s", show(args[0]));
+    }
+    if (argc == 1 && starts_with(name, "is_") && listed(BUILTINS, name)) {
+        Kind kind = strcmp(name, "is_ok") == 0 ? V_OK : strcmp(name, "is_err") == 0 ? V_ERR : strcmp(name, "is_some") == 0 ? V_SOME : V_NONE;
+        return boolean(args[0].kind == kind);
+    }
+    if (strcmp(name, "divmod") == 0 && argc == 2) {
+        double a = number(args[0]), b = number(args[1]);
+        if (b == 0) {
+            error("ZeroDivisionError", "Division by zero");
+        }
+        double quotient = floor(a / b);
+        Value parts[] = {num(quotient), num(a - b * quotient)};
+        return tuple_of(2, parts);
+    }
+    if (listed(BUILTINS, name) || listed(ERROR_KINDS, name)) {
+        error("ArgumentError", "Builtin '%s' called with the wrong number of args, got %d", name, argc);
+    }
+    error("NameError", "Function '%s' not found", name);
+    return none();
+}
+
+static Value call(Env *env, int slot, const char *name, int argc, Value *args) {
+    Binding *binding = env_with(env, slot, name);
+    if (binding) {
+        return call_function(name, binding->value, argc, args);
+    }
+    return call_builtin(name, argc, args);
+}
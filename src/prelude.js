@@ -0,0 +1,1324 @@
+// Runtime emitted at the top of every module built by `vel build --target js`. It mirrors
+// prelude.rs so values print, compare and fail with the same messages as under `vel start`.
+// Lists are plain arrays and none is null; a list or map held by more than one owner is
+// copied before it changes, so arrays handed to exported functions are never modified.
+
+const UNSET = undefined;
+const CONST = 0;
+const IMMUTABLE = 1;
+const MUTABLE = 2;
+const BREAK = "break";
+const CONTINUE = "continue";
+const RETURN = "return";
+const ERROR = "error";
+
+const ERROR_KINDS = ["Error", "ValueError", "TypeError", "NameError", "IndexError", "ArgumentError", "ZeroDivisionError", "ImportError"];
+const BUILTINS = ["error", "ok", "err", "some", "unwrap", "unwrap_or", "map_err", "is_ok", "is_err", "is_some", "is_none", "divmod", "enumerate", "iter", "next", "collect", "take", "skip", "zip", "chain"];
+const STRING_METHODS = ["chars", "contains", "ends_with", "len", "lower", "replace", "repeat", "split", "starts_with", "to_num", "trim", "upper"];
+const LIST_METHODS = ["clear", "contains", "extend", "first", "index_of", "insert", "is_empty", "join", "last", "len", "max", "min", "pop", "push", "remove", "reverse", "sort", "sum"];
+const NUMBER_METHODS = ["abs", "ceil", "floor", "max", "min", "pow", "round", "sqrt", "to_str"];
+const ITERATOR_METHODS = ["chain", "collect", "enumerate", "next", "skip", "take", "zip"];
+const MAP_METHODS = ["clear", "contains", "get", "is_empty", "items", "keys", "len", "remove", "set", "values"];
+const MUTATING = ["clear", "extend", "insert", "pop", "push", "remove", "set"];
+
+class Tuple {
+    constructor(items) {
+        this.items = items;
+    }
+}
+
+// ok(..), err(..) or some(..)
+class Variant {
+    constructor(tag, inner) {
+        this.tag = tag;
+        this.inner = inner;
+    }
+}
+
+// Entries are [key, value] pairs in insertion order
+class MapValue {
+    constructor(entries) {
+        this.entries = entries;
+    }
+}
+
+class ErrorValue {
+    constructor(kind, message) {
+        this.kind = kind;
+        this.message = message;
+        this.location = null;
+        this.stack = [];
+    }
+}
+
+class Func {
+    constructor(params, slots, retType, body, generator) {
+        this.params = params;
+        this.slots = slots;
+        this.retType = retType;
+        this.body = body;
+        this.generator = generator;
+    }
+}
+
+// A lazy iterator over a JS iterator; a generator's runs under its function's name
+class Iter {
+    constructor(source, name) {
+        this.source = source;
+        this.name = name;
+        this.running = false;
+    }
+
+    // The next value, or UNSET once the iterator is done
+    next() {
+        if (this.running) {
+            error("Error", "Generator is already running");
+        }
+        this.running = true;
+        const depth = calls.length;
+        if (this.name !== null) {
+            calls.push(this.name);
+        }
+        try {
+            const step = this.source.next();
+            return step.done ? UNSET : step.value;
+        } finally {
+            this.running = false;
+            calls.length = depth;
+        }
+    }
+
+    // Leaving a `for` loop early keeps the rest for later, so there is no `return()`
+    [Symbol.iterator]() {
+        return {
+            next: () => {
+                const value = this.next();
+                return value === UNSET ? { done: true } : { value, done: false };
+            },
+        };
+    }
+}
+
+// Thrown for errors, and for break, continue and return once they leave the code that can handle them
+class Signal {
+    constructor(kind, value, error) {
+        this.kind = kind;
+        this.value = value;
+        this.error = error;
+    }
+}
+
+// A named argument, until `split` separates it from the positional ones
+class Named {
+    constructor(name, value) {
+        this.name = name;
+        this.value = value;
+    }
+}
+
+const calls = [];
+const owners = new WeakMap();
+const handling = [];
+let globals = new Map();
+
+function numberText(n, debug) {
+    if (Number.isNaN(n)) {
+        return "NaN";
+    }
+    const sign = n < 0 || Object.is(n, -0) ? "-" : "";
+    n = Math.abs(n);
+    if (n === Infinity) {
+        return sign + "inf";
+    }
+    if (n === 0) {
+        return sign + (debug ? "0.0" : "0");
+    }
+    const [mantissa, power] = n.toExponential().split("e");
+    const digits = mantissa.replace(".", "");
+    const exponent = Number(power);
+    if (debug && (exponent < -4 || exponent >= 16)) {
+        return sign + digits[0] + (digits.length > 1 ? "." + digits.slice(1) : "") + "e" + exponent;
+    }
+    if (exponent < 0) {
+        return sign + "0." + "0".repeat(-exponent - 1) + digits;
+    }
+    if (digits.length > exponent + 1) {
+        return sign + digits.slice(0, exponent + 1) + "." + digits.slice(exponent + 1);
+    }
+    return sign + digits + "0".repeat(exponent + 1 - digits.length) + (debug ? ".0" : "");
+}
+
+function retain(value) {
+    if (Array.isArray(value) || value instanceof MapValue) {
+        owners.set(value, (owners.get(value) || 0) + 1);
+    }
+    return value;
+}
+
+function listOf(items) {
+    items.forEach(retain);
+    return items;
+}
+
+function tupleOf(items) {
+    return new Tuple(listOf(items));
+}
+
+function insertEntry(entries, key, value) {
+    const entry = entries.find(([k]) => equal(k, key));
+    if (entry) {
+        entry[1] = retain(value);
+    } else {
+        entries.push([retain(key), retain(value)]);
+    }
+}
+
+function mapOf(pairs) {
+    const entries = [];
+    for (const [key, value] of pairs) {
+        insertEntry(entries, key, value);
+    }
+    return new MapValue(entries);
+}
+
+function shared(value) {
+    return (Array.isArray(value) || value instanceof MapValue) && owners.get(value) > 1;
+}
+
+function wrap(tag, inner) {
+    return new Variant(tag, retain(inner));
+}
+
+function option(present, inner) {
+    return present ? wrap("some", inner) : null;
+}
+
+function kindOf(value) {
+    if (value === UNSET) {
+        return "unset";
+    }
+    if (value === null) {
+        return "none";
+    }
+    if (Array.isArray(value)) {
+        return "list";
+    }
+    if (value instanceof Tuple) {
+        return "tuple";
+    }
+    if (value instanceof MapValue) {
+        return "map";
+    }
+    if (value instanceof Iter) {
+        return "iterator";
+    }
+    if (value instanceof Func) {
+        return "fn";
+    }
+    if (value instanceof ErrorValue) {
+        return "error";
+    }
+    if (value instanceof Variant) {
+        return value.tag;
+    }
+    return typeof value;
+}
+
+function typeName(value) {
+    switch (kindOf(value)) {
+        case "string": return "str";
+        case "number": return "f64";
+        case "boolean": return "bool";
+        case "list": return "list";
+        case "tuple": return "tuple";
+        case "map": return "map";
+        case "iterator": return "iterator";
+        case "fn": return "fn";
+        case "error": return "error";
+        case "ok": case "err": return "result";
+        case "some": case "none": return "option";
+        default: return "unset";
+    }
+}
+
+function debugString(s) {
+    let out = "\"";
+    for (const c of s) {
+        const code = c.codePointAt(0);
+        if (c === "\"" || c === "\\") {
+            out += "\\" + c;
+        } else if (c === "\n") {
+            out += "\\n";
+        } else if (c === "\r") {
+            out += "\\r";
+        } else if (c === "\t") {
+            out += "\\t";
+        } else if (code < 0x20 || code === 0x7f) {
+            out += "\\u{" + code.toString(16) + "}";
+        } else {
+            out += c;
+        }
+    }
+    return out + "\"";
+}
+
+function debugError(error) {
+    const location = error.location === null ? "None" : "Some(" + debugString(error.location) + ")";
+    const stack = error.stack.map(debugString).join(", ");
+    return `ErrorValue { kind: ${debugString(error.kind)}, message: ${debugString(error.message)}, location: ${location}, stack: [${stack}] }`;
+}
+
+function debug(value) {
+    switch (kindOf(value)) {
+        case "string": return "String(" + debugString(value) + ")";
+        case "number": return "Number(" + numberText(value, true) + ")";
+        case "boolean": return `Bool(${value})`;
+        case "list": return "List([" + value.map(debug).join(", ") + "])";
+        case "tuple": return "Tuple([" + value.items.map(debug).join(", ") + "])";
+        case "map": return "Map([" + value.entries.map(([key, item]) => `(${debug(key)}, ${debug(item)})`).join(", ") + "])";
+        case "iterator": return "Iterator(<iterator>)";
+        case "fn": return "Function(<fn>)";
+        case "error": return "Error(" + debugError(value) + ")";
+        case "ok": return "Ok(" + debug(value.inner) + ")";
+        case "err": return "Err(" + debug(value.inner) + ")";
+        case "some": return "Some(" + debug(value.inner) + ")";
+        case "none": return "None";
+        default: return "Unset";
+    }
+}
+
+function show(value) {
+    switch (kindOf(value)) {
+        case "string": return value;
+        case "number": return numberText(value, false);
+        case "boolean": return String(value);
+        case "list": return "[" + value.map(debug).join(", ") + "]";
+        case "tuple": return "(" + value.items.map(show).join(", ") + (value.items.length === 1 ? ",)" : ")");
+        case "map": return "{" + value.entries.map(([key, item]) => `${show(key)}: ${show(item)}`).join(", ") + "}";
+        case "iterator": return "<iterator>";
+        case "fn": return "<fn>";
+        case "error": return `${value.kind}: ${value.message}`;
+        case "ok": case "err": case "some": return `${value.tag}(${show(value.inner)})`;
+        case "none": return "none";
+        default: return "<unset>";
+    }
+}
+
+function equal(a, b) {
+    const kind = kindOf(a);
+    if (kind !== kindOf(b)) {
+        return false;
+    }
+    switch (kind) {
+        case "list": return a.length === b.length && a.every((item, i) => equal(item, b[i]));
+        case "tuple": return equal(a.items, b.items);
+        case "map":
+            return a.entries.length === b.entries.length && a.entries.every(([key, item], i) => equal(key, b.entries[i][0]) && equal(item, b.entries[i][1]));
+        case "error":
+            return a.kind === b.kind && a.message === b.message && a.location === b.location && equal(a.stack, b.stack);
+        case "ok": case "err": case "some": return equal(a.inner, b.inner);
+        case "none": case "unset": return true;
+        default: return a === b;
+    }
+}
+
+// Innermost call first, leaving out the `skip` innermost frames
+function callStack(error, skip) {
+    error.stack = calls.slice(0, Math.max(calls.length - skip, 0)).reverse();
+    return error;
+}
+
+function trace(error) {
+    let text = `${error.kind}: ${error.message}`;
+    if (error.location !== null) {
+        text += ` (${error.location})`;
+    }
+    return text + error.stack.map((frame) => "\n    at " + frame).join("");
+}
+
+function failure(error) {
+    return new Signal(ERROR, UNSET, error);
+}
+
+function returned(value) {
+    return new Signal(RETURN, value);
+}
+
+function error(kind, message) {
+    throw failure(callStack(new ErrorValue(kind, message), 0));
+}
+
+// Raised as if the call had already returned
+function failReturned(kind, message) {
+    throw failure(callStack(new ErrorValue(kind, message), 1));
+}
+
+function report(message) {
+    console.error(message);
+    if (typeof process !== "undefined") {
+        process.exitCode = 1;
+    }
+}
+
+// Runs the program body; a signal nothing handled ends it the way `vel start` would
+function start(body) {
+    try {
+        body();
+    } catch (signal) {
+        if (!(signal instanceof Signal)) {
+            throw signal;
+        }
+        if (signal.kind === BREAK) {
+            report("'break' outside of a loop");
+        } else if (signal.kind === CONTINUE) {
+            report("'continue' outside of a loop");
+        } else if (signal.kind === ERROR) {
+            report(`Uncaught ${trace(signal.error)}`);
+        }
+    }
+}
+
+function throwValue(value, line) {
+    if (value instanceof ErrorValue && value.location !== null) {
+        return failure(value);
+    }
+    const error = value instanceof ErrorValue ? Object.assign(new ErrorValue(), value) : new ErrorValue("Error", show(value));
+    error.location = `line ${line}`;
+    return failure(callStack(error, 0));
+}
+
+function rethrow() {
+    if (handling.length > 0) {
+        return failure(handling[handling.length - 1]);
+    }
+    return failure(callStack(new ErrorValue("Error", "'throw' without a value outside of 'catch'"), 0));
+}
+
+// The error a catch clause sees; anything else keeps unwinding
+function caught(signal) {
+    if (signal instanceof Signal && signal.kind === ERROR) {
+        return signal.error;
+    }
+    throw signal;
+}
+
+function errorMatches(error, kind) {
+    return kind === "Error" || error.kind === kind;
+}
+
+function expected(value, kind) {
+    if (value === null) {
+        return `Expected ${kind}, got none (check optional values with '??', '?.' or 'if x != none' first)`;
+    }
+    return `Expected ${kind}, got ${debug(value)}`;
+}
+
+function number(value) {
+    if (typeof value !== "number") {
+        error("TypeError", expected(value, "number"));
+    }
+    return value;
+}
+
+function truthy(value) {
+    if (typeof value !== "boolean") {
+        error("TypeError", expected(value, "bool"));
+    }
+    return value;
+}
+
+function string(value) {
+    if (typeof value !== "string") {
+        error("TypeError", expected(value, "string"));
+    }
+    return value;
+}
+
+function asList(value) {
+    if (!Array.isArray(value)) {
+        error("TypeError", expected(value, "list"));
+    }
+    return value;
+}
+
+// Tests run against a copy of the globals and put the originals back afterwards
+function snapshot() {
+    const saved = new Map();
+    for (const [name, binding] of globals) {
+        saved.set(name, { ...binding, value: retain(binding.value) });
+    }
+    return saved;
+}
+
+function restore(saved) {
+    globals = saved;
+}
+
+class Env {
+    constructor(len) {
+        this.slots = new Array(len).fill(null);
+    }
+
+    clone() {
+        const copy = new Env(0);
+        copy.slots = this.slots.map((binding) => binding && { ...binding, value: retain(binding.value) });
+        return copy;
+    }
+
+    with(slot, name) {
+        if (slot >= 0 && this.slots[slot]) {
+            return this.slots[slot];
+        }
+        return globals.get(name);
+    }
+
+    update(slot, name) {
+        if (slot < 0) {
+            return globals.get(name);
+        }
+        if (!this.slots[slot]) {
+            const outer = globals.get(name);
+            if (!outer) {
+                return UNSET;
+            }
+            this.slots[slot] = { ...outer, value: retain(outer.value) };
+        }
+        return this.slots[slot];
+    }
+
+    bound(slot, name) {
+        return this.with(slot, name) !== UNSET;
+    }
+
+    read(slot, name) {
+        const binding = this.with(slot, name);
+        if (!binding) {
+            error("NameError", `Var '${name}' not found`);
+        }
+        if (binding.value === UNSET) {
+            error("NameError", `Variable '${name}' is used before it is assigned`);
+        }
+        return binding.value;
+    }
+
+    readNone() {
+        return this.bound(-1, "none") ? this.read(-1, "none") : null;
+    }
+
+    define(slot, name, value, mutability) {
+        const existing = slot >= 0 ? this.slots[slot] : globals.get(name);
+        if (existing && existing.mutability === CONST) {
+            error("Error", `Cannot redeclare constant '${name}'`);
+        }
+        const binding = { value: retain(value), mutability };
+        if (slot >= 0) {
+            this.slots[slot] = binding;
+        } else {
+            globals.set(name, binding);
+        }
+    }
+
+    assign(slot, name, value) {
+        const binding = this.update(slot, name);
+        if (!binding) {
+            error("NameError", `Var '${name}' not found`);
+        }
+        if (binding.mutability === CONST) {
+            error("Error", `Cannot assign to constant '${name}'`);
+        }
+        if (binding.mutability === IMMUTABLE && binding.value !== UNSET) {
+            error("Error", `Cannot assign twice to immutable variable '${name}' (declare it with 'let' to make it mutable)`);
+        }
+        binding.value = retain(value);
+    }
+
+    defineAll(bindings, mutability) {
+        for (const { slot, name, value } of bindings.splice(0)) {
+            this.define(slot, name, value, mutability);
+        }
+    }
+
+    assignAll(bindings) {
+        for (const { slot, name, value } of bindings.splice(0)) {
+            this.assign(slot, name, value);
+        }
+    }
+
+    // Statement-level method call on a variable; the binding's list or map is copied first if shared
+    mutate(slot, name, receiver, method, args, named = []) {
+        if (!mutates(receiver, method)) {
+            return callMethod(receiver, method, args, named);
+        }
+        const binding = this.update(slot, name);
+        if (!binding) {
+            error("NameError", `Var '${name}' not found`);
+        }
+        if (binding.mutability !== MUTABLE) {
+            nativeMethod({ value: own(binding.value) }, method, args, named);
+            if (binding.mutability === CONST) {
+                error("Error", `Cannot call '${method}' on constant '${name}'`);
+            }
+            error("Error", `Cannot call '${method}' on immutable variable '${name}' (declare it with 'let' to make it mutable)`);
+        }
+        if (shared(binding.value)) {
+            binding.value = retain(own(binding.value));
+        }
+        return nativeMethod(binding, method, args, named);
+    }
+}
+
+function unpack(target, value, bindings) {
+    if (target.kind === "name") {
+        if (target.name !== "_") {
+            bindings.push({ slot: target.slot, name: target.name, value });
+        }
+        return;
+    }
+    const count = target.items.length;
+    if (target.kind === "tuple") {
+        if (!(value instanceof Tuple)) {
+            error("TypeError", `Expected tuple to unpack, got ${show(value)}`);
+        }
+        if (value.items.length !== count) {
+            error("ValueError", `Cannot unpack tuple of length ${value.items.length} into ${count} names`);
+        }
+        target.items.forEach((item, i) => unpack(item, value.items[i], bindings));
+        return;
+    }
+    const values = asList(value);
+    if (values.length < count || (!target.rest && values.length !== count)) {
+        error("ValueError", `Cannot unpack list of length ${values.length} into ${target.rest ? "at least " : ""}${count} names`);
+    }
+    target.items.forEach((item, i) => unpack(item, values[i], bindings));
+    if (target.rest) {
+        unpack(target.rest, listOf(values.slice(count)), bindings);
+    }
+}
+
+function matchPattern(pattern, value, bindings) {
+    switch (pattern.kind) {
+        case "wildcard": return true;
+        case "bind":
+            bindings.push({ slot: pattern.slot, name: pattern.name, value });
+            return true;
+        case "literal": return pattern.text === show(value);
+        default:
+            if (pattern.inner) {
+                return value instanceof Variant && value.tag === pattern.name && matchPattern(pattern.inner, value.inner, bindings);
+            }
+            return pattern.name === "none" && value === null;
+    }
+}
+
+function noMatch(value) {
+    error("ValueError", `No match arm for value ${show(value)}`);
+}
+
+function checkType(value, type) {
+    if (type.endsWith("?")) {
+        if (value !== null && value !== UNSET) {
+            checkType(value, type.slice(0, -1));
+        }
+        return;
+    }
+    if (value === UNSET || typeName(value) === type) {
+        return;
+    }
+    error("TypeError", `Expected ${type}, got ${show(value)}`);
+}
+
+function say(value) {
+    console.log(show(value));
+}
+
+function binary(op, left, right) {
+    switch (op) {
+        case "+":
+            if (typeof left === "string" || typeof right === "string") {
+                return show(left) + show(right);
+            }
+            return number(left) + number(right);
+        case "-": return number(left) - number(right);
+        case "*": return number(left) * number(right);
+        case "/": {
+            const divisor = number(right);
+            if (divisor === 0) {
+                error("ZeroDivisionError", "Division by zero");
+            }
+            return number(left) / divisor;
+        }
+        case "==": return equal(left, right);
+        case "!=": return !equal(left, right);
+        case ">": return number(left) > number(right);
+        case ">=": return number(left) >= number(right);
+        case "<": return number(left) < number(right);
+        case "<=": return number(left) <= number(right);
+    }
+    error("Error", `Unknown operator '${op}'`);
+}
+
+function unary(op, value) {
+    if (op === "-") {
+        return -number(value);
+    }
+    if (op === "!") {
+        return !truthy(value);
+    }
+    error("Error", `Unknown unary op '${op}'`);
+}
+
+function coalesce(value, fallback) {
+    if (value === null) {
+        return fallback();
+    }
+    return value instanceof Variant && value.tag === "some" ? value.inner : value;
+}
+
+function position(index, len, container) {
+    const n = number(index);
+    if (n - Math.trunc(n) !== 0) {
+        error("TypeError", `Expected integer index, got ${numberText(n, false)}`);
+    }
+    const i = n < 0 ? n + len : n;
+    if (i < 0 || i >= len) {
+        error("IndexError", `Index ${numberText(n, false)} out of bounds for ${container} of length ${len}`);
+    }
+    return i;
+}
+
+function indexValue(target, index) {
+    if (target instanceof MapValue) {
+        const entry = target.entries.find(([key]) => equal(key, index));
+        if (!entry) {
+            error("IndexError", `Key '${show(index)}' not found`);
+        }
+        return entry[1];
+    }
+    if (Array.isArray(target)) {
+        return target[position(index, target.length, "list")];
+    }
+    if (target instanceof Tuple) {
+        return target.items[position(index, target.items.length, "tuple")];
+    }
+    if (typeof target === "string") {
+        const chars = Array.from(target);
+        return chars[position(index, chars.length, "str")];
+    }
+    error("TypeError", `Cannot index into ${typeName(target)}`);
+}
+
+function whole(n) {
+    if (Number.isNaN(n)) {
+        return 0;
+    }
+    return Math.trunc(Math.min(Math.max(n, -9.2e18), 9.2e18));
+}
+
+function bound(value, fallback, len, step) {
+    if (value === UNSET) {
+        return fallback;
+    }
+    const v = value < 0 ? whole(value) + len : whole(value);
+    const low = step > 0 ? 0 : -1;
+    const high = step > 0 ? len : len - 1;
+    return Math.min(Math.max(v, low), high);
+}
+
+// Bounds arrive already checked to be numbers, or undefined when left out
+function slice(target, start, end, step) {
+    let items;
+    if (Array.isArray(target)) {
+        items = target;
+    } else if (target instanceof Tuple) {
+        items = target.items;
+    } else if (typeof target === "string") {
+        items = Array.from(target);
+    } else {
+        error("TypeError", `Cannot slice ${typeName(target)}`);
+    }
+    const by = step === UNSET ? 1 : whole(step);
+    if (by === 0) {
+        error("ValueError", "Slice step cannot be zero");
+    }
+    const len = items.length;
+    const picked = [];
+    let i = by > 0 ? bound(start, 0, len, by) : bound(start, len - 1, len, by);
+    const stop = by > 0 ? bound(end, len, len, by) : bound(end, -1, len, by);
+    for (; (by > 0 && i < stop) || (by < 0 && i > stop); i += by) {
+        picked.push(items[i]);
+    }
+    if (typeof target === "string") {
+        return picked.join("");
+    }
+    return target instanceof Tuple ? tupleOf(picked) : listOf(picked);
+}
+
+// Returns the failed value from the innermost function call
+function propagate(value) {
+    if (value instanceof Variant && value.tag !== "err") {
+        return value.inner;
+    }
+    if (value === null || value instanceof Variant) {
+        if (calls.length === 0) {
+            error("Error", `'?' on ${show(value)} outside of a function`);
+        }
+        throw returned(value);
+    }
+    error("TypeError", `Expected result or option for '?', got ${show(value)}`);
+}
+
+function getField(value, field) {
+    if (value instanceof ErrorValue) {
+        switch (field) {
+            case "kind": return value.kind;
+            case "message": return value.message;
+            case "location": return value.location;
+            case "stack": return listOf(value.stack.slice());
+        }
+    }
+    if (value === null) {
+        error("Error", `Cannot read field '${field}' of none (use '?.' for optional values)`);
+    }
+    error("Error", `Value ${show(value)} has no field '${field}'`);
+}
+
+function safeField(value, field) {
+    if (value === null) {
+        return null;
+    }
+    return getField(value instanceof Variant && value.tag === "some" ? value.inner : value, field);
+}
+
+function iterate(value) {
+    if (Array.isArray(value)) {
+        return retain(value);
+    }
+    if (value instanceof Tuple) {
+        return value.items;
+    }
+    if (typeof value === "string") {
+        return Array.from(value);
+    }
+    if (value instanceof MapValue) {
+        return value.entries.map(([key]) => key);
+    }
+    if (value instanceof Iter) {
+        return value;
+    }
+    error("TypeError", `Expected list, str, map or iterator to iterate over, got ${show(value)}`);
+}
+
+function toIter(value) {
+    return value instanceof Iter ? value : new Iter(iterate(value)[Symbol.iterator](), null);
+}
+
+function* taking(inner, count) {
+    for (; count > 0; count--) {
+        const value = inner.next();
+        if (value === UNSET) {
+            return;
+        }
+        yield value;
+    }
+}
+
+function* skipping(inner, count) {
+    for (; count > 0; count--) {
+        if (inner.next() === UNSET) {
+            return;
+        }
+    }
+    yield* inner;
+}
+
+function* zipping(first, second) {
+    for (;;) {
+        const a = first.next();
+        const b = a === UNSET ? UNSET : second.next();
+        if (b === UNSET) {
+            return;
+        }
+        yield tupleOf([a, b]);
+    }
+}
+
+function* chaining(first, second) {
+    yield* first;
+    yield* second;
+}
+
+function* enumerating(inner) {
+    let index = 0;
+    for (const value of inner) {
+        yield tupleOf([index++, value]);
+    }
+}
+
+function available(type) {
+    switch (type) {
+        case "str": return STRING_METHODS;
+        case "list": return LIST_METHODS;
+        case "f64": return NUMBER_METHODS;
+        case "map": return MAP_METHODS;
+        case "iterator": return ITERATOR_METHODS;
+        default: return [];
+    }
+}
+
+function arity(method, args, expected) {
+    if (args.length !== expected) {
+        error("ArgumentError", `Method '${method}' expects ${expected} args, got ${args.length}`);
+    }
+}
+
+function parseNumber(text) {
+    const special = /^([+-]?)(inf|infinity|nan)$/i.exec(text);
+    if (special) {
+        const n = special[2].toLowerCase() === "nan" ? NaN : Infinity;
+        return special[1] === "-" ? -n : n;
+    }
+    return /^[+-]?(\d+\.?\d*|\.\d+)([eE][+-]?\d+)?$/.test(text) ? Number(text) : UNSET;
+}
+
+function stringMethod(s, method, args) {
+    let expected = 0;
+    if (["split", "contains", "starts_with", "ends_with", "repeat"].includes(method)) {
+        expected = 1;
+    } else if (method === "replace") {
+        expected = 2;
+    }
+    arity(method, args, expected);
+    switch (method) {
+        case "len": return Array.from(s).length;
+        case "upper": return s.toUpperCase();
+        case "lower": return s.toLowerCase();
+        case "trim": return s.trim();
+        case "chars": return listOf(Array.from(s));
+        case "split": {
+            const separator = string(args[0]);
+            return listOf(separator === "" ? ["", ...Array.from(s), ""] : s.split(separator));
+        }
+        case "contains": return s.includes(string(args[0]));
+        case "starts_with": return s.startsWith(string(args[0]));
+        case "ends_with": return s.endsWith(string(args[0]));
+        case "replace": {
+            const from = string(args[0]);
+            const to = string(args[1]);
+            return from === "" ? to + Array.from(s).map((c) => c + to).join("") : s.split(from).join(to);
+        }
+        case "repeat": return s.repeat(Math.max(whole(number(args[0])), 0));
+        case "to_num": {
+            const n = parseNumber(s.trim());
+            if (n === UNSET) {
+                error("ValueError", `Cannot convert '${s}' to a number`);
+            }
+            return n;
+        }
+    }
+    error("NameError", `Type 'str' has no method '${method}'`);
+}
+
+function numberMethod(n, method, args) {
+    arity(method, args, ["pow", "min", "max"].includes(method) ? 1 : 0);
+    switch (method) {
+        case "abs": return Math.abs(n);
+        case "ceil": return Math.ceil(n);
+        case "floor": return Math.floor(n);
+        case "round": return Math.sign(n) * Math.round(Math.abs(n));
+        case "sqrt": return Math.sqrt(n);
+        case "pow": return n ** number(args[0]);
+        case "min": case "max": {
+            const other = number(args[0]);
+            if (Number.isNaN(n) || Number.isNaN(other)) {
+                return Number.isNaN(n) ? other : n;
+            }
+            return method === "min" ? Math.min(n, other) : Math.max(n, other);
+        }
+        case "to_str": return numberText(n, false);
+    }
+    error("NameError", `Type 'f64' has no method '${method}'`);
+}
+
+let compareFailure = null;
+
+function compare(a, b) {
+    if (typeof a === "number" && typeof b === "number") {
+        return a < b ? -1 : a > b ? 1 : 0;
+    }
+    if (typeof a === "string" && typeof b === "string") {
+        return a < b ? -1 : a > b ? 1 : 0;
+    }
+    compareFailure = `Cannot compare ${typeName(a)} with ${typeName(b)}`;
+    return 0;
+}
+
+function extreme(items, method, wanted) {
+    if (items.length === 0) {
+        error("ValueError", `Cannot take ${method} of an empty list`);
+    }
+    let best = items[0];
+    for (const item of items.slice(1)) {
+        compareFailure = null;
+        const order = compare(item, best);
+        if (compareFailure) {
+            error("TypeError", compareFailure);
+        }
+        if (order === wanted) {
+            best = item;
+        }
+    }
+    return best;
+}
+
+function listIndex(value, len, allowEnd) {
+    const i = number(value);
+    if (i < 0 || whole(i) >= (allowEnd ? len + 1 : len)) {
+        error("IndexError", `Index ${numberText(i, false)} out of bounds for list of length ${len}`);
+    }
+    return whole(i);
+}
+
+// `holder.value` is the list, replaced rather than changed when cleared
+function listMethod(holder, method, args) {
+    const items = holder.value;
+    let expected = 0;
+    if (["push", "remove", "contains", "index_of", "extend", "join"].includes(method)) {
+        expected = 1;
+    } else if (method === "insert") {
+        expected = 2;
+    }
+    arity(method, args, expected);
+    switch (method) {
+        case "len": return items.length;
+        case "is_empty": return items.length === 0;
+        case "first": return option(items.length > 0, items[0]);
+        case "last": return option(items.length > 0, items[items.length - 1]);
+        case "contains": return items.some((item) => equal(item, args[0]));
+        case "index_of": {
+            const found = items.findIndex((item) => equal(item, args[0]));
+            return option(found >= 0, found);
+        }
+        case "join": {
+            const separator = string(args[0]);
+            return items.map(show).join(separator);
+        }
+        case "reverse": return listOf(items.slice().reverse());
+        case "sort": {
+            compareFailure = null;
+            const sorted = listOf(items.slice().sort(compare));
+            if (compareFailure) {
+                error("TypeError", compareFailure);
+            }
+            return sorted;
+        }
+        case "sum": return items.reduce((sum, item) => sum + number(item), -0);
+        case "min": return extreme(items, method, -1);
+        case "max": return extreme(items, method, 1);
+        case "push":
+            items.push(retain(args[0]));
+            return null;
+        case "pop":
+            if (items.length === 0) {
+                error("ValueError", "Cannot pop from an empty list");
+            }
+            return items.pop();
+        case "insert":
+            items.splice(listIndex(args[0], items.length, true), 0, retain(args[1]));
+            return null;
+        case "remove": return items.splice(listIndex(args[0], items.length, false), 1)[0];
+        case "extend":
+            items.push(...listOf(asList(args[0]).slice()));
+            return null;
+        case "clear":
+            holder.value = listOf([]);
+            return null;
+    }
+    error("NameError", `Type 'list' has no method '${method}'`);
+}
+
+// `holder.value` is the map, replaced rather than changed when cleared
+function mapMethod(holder, method, args) {
+    const entries = holder.value.entries;
+    let expected = 0;
+    if (["get", "contains", "remove"].includes(method)) {
+        expected = 1;
+    } else if (method === "set") {
+        expected = 2;
+    }
+    arity(method, args, expected);
+    const position = args.length > 0 ? entries.findIndex(([key]) => equal(key, args[0])) : -1;
+    switch (method) {
+        case "len": return entries.length;
+        case "is_empty": return entries.length === 0;
+        case "keys": return listOf(entries.map(([key]) => key));
+        case "values": return listOf(entries.map(([, value]) => value));
+        case "items": return listOf(entries.map((entry) => tupleOf(entry.slice())));
+        case "get": return option(position >= 0, position >= 0 ? entries[position][1] : UNSET);
+        case "contains": return position >= 0;
+        case "set":
+            insertEntry(entries, args[0], args[1]);
+            return null;
+        case "remove": return option(position >= 0, position >= 0 ? entries.splice(position, 1)[0][1] : UNSET);
+        case "clear":
+            holder.value = new MapValue([]);
+            return null;
+    }
+    error("NameError", `Type 'map' has no method '${method}'`);
+}
+
+function nativeMethod(holder, method, args, named) {
+    if (named.length > 0) {
+        error("ArgumentError", `Method '${method}' does not take named argument '${named[0][0]}'`);
+    }
+    const receiver = holder.value;
+    switch (typeof receiver) {
+        case "string": return stringMethod(receiver, method, args);
+        case "number": return numberMethod(receiver, method, args);
+    }
+    if (Array.isArray(receiver)) {
+        return listMethod(holder, method, args);
+    }
+    if (receiver instanceof MapValue) {
+        return mapMethod(holder, method, args);
+    }
+    error("NameError", `Type '${typeName(receiver)}' has no method '${method}'`);
+}
+
+function own(value) {
+    if (value instanceof MapValue) {
+        return mapOf(value.entries);
+    }
+    return Array.isArray(value) ? listOf(value.slice()) : value;
+}
+
+function mutates(receiver, method) {
+    return MUTATING.includes(method) && available(typeName(receiver)).includes(method);
+}
+
+function forbidMutation(receiver, method, name) {
+    if (mutates(receiver, method)) {
+        error("Error", `Method '${method}' changes '${name}' and can only be called as a statement or as the value of a declaration or assignment`);
+    }
+    return receiver;
+}
+
+function callMethod(receiver, method, args, named = []) {
+    const type = typeName(receiver);
+    const methods = available(type);
+    if (methods.includes(method) && receiver instanceof Iter) {
+        if (named.length > 0) {
+            error("ArgumentError", `Method '${method}' does not take named argument '${named[0][0]}'`);
+        }
+        return callBuiltin(method, [receiver, ...args]);
+    }
+    if (methods.includes(method)) {
+        return nativeMethod({ value: mutates(receiver, method) ? own(receiver) : receiver }, method, args, named);
+    }
+    error("NameError", `Type '${type}' has no method '${method}' (available: ${methods.length ? methods.join(", ") : "none"})`);
+}
+
+// Splits call arguments built with `named` into positional values and [name, value] pairs
+function split(args) {
+    const positional = args.filter((arg) => !(arg instanceof Named));
+    return [positional, args.filter((arg) => arg instanceof Named).map((arg) => [arg.name, arg.value])];
+}
+
+function bindArgs(name, func, args, named, local) {
+    const params = func.params;
+    const variadic = params.length > 0 && params[params.length - 1].variadic;
+    if (!variadic && args.length > params.length) {
+        error("ArgumentError", `Expected at most ${params.length} args, got ${args.length}`);
+    }
+    named.forEach(([argName], i) => {
+        if (!params.some((param) => param.name === argName && !param.variadic)) {
+            error("ArgumentError", `Unknown named argument '${argName}' in call to '${name}'`);
+        }
+        if (named.slice(0, i).some(([other]) => other === argName)) {
+            error("ArgumentError", `Duplicate named argument '${argName}' in call to '${name}'`);
+        }
+    });
+    params.forEach((param, slot) => {
+        const given = named.findIndex(([argName]) => argName === param.name);
+        let value;
+        if (param.variadic) {
+            value = listOf(args.slice(slot));
+        } else if (slot < args.length) {
+            if (given >= 0) {
+                error("ArgumentError", `Named argument '${param.name}' was already given by position in call to '${name}'`);
+            }
+            value = args[slot];
+        } else if (given >= 0) {
+            value = named.splice(given, 1)[0][1];
+        } else if (param.fallback) {
+            value = param.fallback(local);
+        } else {
+            error("ArgumentError", `Missing argument '${param.name}' in call to '${name}'`);
+        }
+        local.slots[slot] = { value: retain(value), mutability: IMMUTABLE };
+    });
+}
+
+function runBody(func, local) {
+    try {
+        return func.body(local);
+    } catch (signal) {
+        if (signal instanceof Signal && signal.kind === RETURN) {
+            return signal.value;
+        }
+        if (signal instanceof Signal && signal.kind !== ERROR) {
+            failReturned("Error", `'break' or 'continue' escaped function '${calls[calls.length - 1]}'`);
+        }
+        throw signal;
+    }
+}
+
+// A generator's body runs as the iterator is advanced; what it returns is dropped
+function* generate(func, local) {
+    try {
+        yield* func.body(local);
+    } catch (signal) {
+        if (signal instanceof Signal && signal.kind === RETURN) {
+            return;
+        }
+        if (signal instanceof Signal && signal.kind !== ERROR) {
+            error("Error", `'break' or 'continue' escaped function '${calls[calls.length - 1]}'`);
+        }
+        throw signal;
+    }
+}
+
+function callFunction(name, func, args, named = []) {
+    if (!(func instanceof Func)) {
+        error("TypeError", `'${name}' is not a function`);
+    }
+    const local = new Env(func.slots);
+    bindArgs(name, func, args, named, local);
+    if (func.generator) {
+        return new Iter(generate(func, local), name);
+    }
+    const depth = calls.length;
+    calls.push(name);
+    try {
+        const result = runBody(func, local);
+        if (result === UNSET && func.retType !== null && func.retType !== "void") {
+            failReturned("Error", "Missing return value");
+        }
+        return result === UNSET ? null : result;
+    } finally {
+        calls.length = depth;
+    }
+}
+
+function callBuiltin(name, args) {
+    const [value, other] = args;
+    if (name === "error" && args.length === 1) {
+        return new ErrorValue("Error", show(value));
+    }
+    if (name === "error" && args.length === 2) {
+        return new ErrorValue(show(value), show(other));
+    }
+    if (ERROR_KINDS.includes(name) && args.length === 1) {
+        return new ErrorValue(name, show(value));
+    }
+    if (["ok", "err", "some"].includes(name) && args.length === 1) {
+        return wrap(name, value);
+    }
+    if (name === "unwrap" && args.length === 1) {
+        if (value instanceof Variant && value.tag !== "err") {
+            return value.inner;
+        }
+        if (value instanceof Variant && value.inner instanceof ErrorValue) {
+            throw failure(value.inner);
+        }
+        if (value instanceof Variant) {
+            error("Error", `unwrap called on err(${show(value.inner)})`);
+        }
+        if (value === null) {
+            error("Error", "unwrap called on none");
+        }
+        error("TypeError", `Expected result or option, got ${show(value)}`);
+    }
+    if (name === "unwrap_or" && args.length === 2) {
+        if (value instanceof Variant) {
+            return value.tag === "err" ? other : value.inner;
+        }
+        if (value === null) {
+            return other;
+        }
+        error("TypeError", `Expected result or option, got ${show(value)}`);
+    }
+    if (name === "map_err" && args.length === 2) {
+        if (value instanceof Variant && value.tag === "err") {
+            return wrap("err", callFunction(name, other, [value.inner]));
+        }
+        if (value instanceof Variant && value.tag === "ok") {
+            return value;
+        }
+        error("TypeError", `Expected result, got ${show(value)}`);
+    }
+    if (name.startsWith("is_") && BUILTINS.includes(name) && args.length === 1) {
+        return kindOf(value) === name.slice(3);
+    }
+    if (name === "iter" && args.length === 1) {
+        return toIter(value);
+    }
+    if (name === "next" && args.length === 1) {
+        const item = toIter(value).next();
+        return item === UNSET ? null : wrap("some", item);
+    }
+    if (name === "collect" && args.length === 1) {
+        return listOf(Array.from(toIter(value)));
+    }
+    if ((name === "take" || name === "skip") && args.length === 2) {
+        const count = whole(Math.max(number(other), 0));
+        const inner = toIter(value);
+        return new Iter(name === "take" ? taking(inner, count) : skipping(inner, count), null);
+    }
+    if ((name === "zip" || name === "chain") && args.length === 2) {
+        const second = toIter(other);
+        const first = toIter(value);
+        return new Iter(name === "zip" ? zipping(first, second) : chaining(first, second), null);
+    }
+    if (name === "enumerate" && args.length === 1) {
+        return new Iter(enumerating(toIter(value)), null);
+    }
+    if (name === "divmod" && args.length === 2) {
+        const a = number(value);
+        const b = number(other);
+        if (b === 0) {
+            error("ZeroDivisionError", "Division by zero");
+        }
+        const quotient = Math.floor(a / b);
+        return tupleOf([quotient, a - b * quotient]);
+    }
+    if (BUILTINS.includes(name) || ERROR_KINDS.includes(name)) {
+        error("ArgumentError", `Builtin '${name}' called with the wrong number of args, got ${args.length}`);
+    }
+    error("NameError", `Function '${name}' not found`);
+}
+
+function call(env, slot, name, args, named = []) {
+    const binding = env.with(slot, name);
+    if (binding) {
+        return callFunction(name, binding.value, args, named);
+    }
+    if (named.length > 0) {
+        error("ArgumentError", `Builtin '${name}' does not take named argument '${named[0][0]}'`);
+    }
+    return callBuiltin(name, args);
+}
+
+// Entry point for exported functions; Velvet errors reach JavaScript callers as `Error`s
+function exported(name, args) {
+    try {
+        return call(new Env(0), -1, name, args);
+    } catch (signal) {
+        if (signal instanceof Signal && signal.kind === ERROR) {
+            const thrown = new Error(signal.error.message);
+            thrown.name = signal.error.kind;
+            thrown.stack = trace(signal.error);
+            throw thrown;
+        }
+        throw signal;
+    }
+}
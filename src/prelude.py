@@ -0,0 +1,1169 @@
+# Runtime written next to every module built by `vel build --target python` as velvet_runtime.py,
+# which the module imports as `_rt`. It mirrors prelude.rs so values print, compare and fail with
+# the same messages as under `vel start`. Velvet values are plain Python ones: numbers are floats,
+# lists are lists, tuples are tuples, maps are dicts and none is None; ok(..), err(..) and some(..)
+# are Ok, Err and Some, and errors are Error exceptions. Map keys must be hashable, and true and 1
+# are the same key. Generated code only changes a list or map in place while no other value refers
+# to it. Velvet match statements become Python match statements, so modules need Python 3.10+.
+
+import builtins
+import functools
+import inspect
+import keyword
+import math
+import re
+import sys
+
+
+class Unset:
+    def __repr__(self):
+        return "UNSET"
+
+
+UNSET = Unset()
+ANY = object()
+BIND = object()
+
+ERROR_KINDS = ("Error", "ValueError", "TypeError", "NameError", "IndexError", "ArgumentError", "ZeroDivisionError", "ImportError")
+STRING_METHODS = ("chars", "contains", "ends_with", "len", "lower", "replace", "repeat", "split", "starts_with", "to_num", "trim", "upper")
+LIST_METHODS = ("clear", "contains", "extend", "first", "index_of", "insert", "is_empty", "join", "last", "len", "max", "min", "pop", "push", "remove", "reverse", "sort", "sum")
+NUMBER_METHODS = ("abs", "ceil", "floor", "max", "min", "pow", "round", "sqrt", "to_str")
+ITERATOR_METHODS = ("chain", "collect", "enumerate", "next", "skip", "take", "zip")
+MAP_METHODS = ("clear", "contains", "get", "is_empty", "items", "keys", "len", "remove", "set", "values")
+MUTATING = ("clear", "extend", "insert", "pop", "push", "remove", "set")
+
+
+class Variant:
+    __match_args__ = ("value",)
+    tag = ""
+
+    def __init__(self, value):
+        self.value = value
+
+    def __repr__(self):
+        return show(self)
+
+
+class Ok(Variant):
+    tag = "ok"
+
+
+class Err(Variant):
+    tag = "err"
+
+
+class Some(Variant):
+    tag = "some"
+
+
+class Error(Exception):
+    def __init__(self, kind, message):
+        super().__init__(message)
+        self.kind = kind
+        self.message = message
+        self.location = None
+        self.stack = []
+
+    def __str__(self):
+        return _trace(self)
+
+
+# Carries the value of a failed '?' out of the current function
+class Return(BaseException):
+    def __init__(self, value):
+        self.value = value
+
+
+# A 'break' or 'continue' with no loop around it
+class Escape(BaseException):
+    def __init__(self, keyword):
+        self.keyword = keyword
+
+
+# What a catch clause can see; Return and Escape pass through
+Catchable = Exception
+
+
+# A named argument passed through `invoke`, or to something that takes none
+class Named:
+    def __init__(self, name, value):
+        self.name = name
+        self.value = value
+
+
+# A lazy iterator over a Python iterator; a generator's runs under its function's name
+class Iter:
+    def __init__(self, source, name=None):
+        self.source = source
+        self.name = name
+        self.running = False
+
+    def __iter__(self):
+        return self
+
+    def __next__(self):
+        if self.running:
+            fail("Error", "Generator is already running")
+        self.running = True
+        depth = len(_calls)
+        if self.name is not None:
+            _calls.append(self.name)
+        try:
+            return builtins.next(self.source)
+        except Return:
+            raise StopIteration
+        except Escape:
+            fail("Error", f"'break' or 'continue' escaped function '{self.name}'")
+        finally:
+            self.running = False
+            del _calls[depth:]
+
+
+_calls = []
+nan = math.nan
+inf = math.inf
+
+
+# The Velvet name of a Python one; the compiler adds a '_' to keywords and names starting with '_rt'
+def _velvet_name(name):
+    base = name[:-1]
+    if name.endswith("_") and (keyword.iskeyword(base.rstrip("_")) or base.startswith("_rt")):
+        return base
+    return name
+
+
+def _unnamed(what, name, args):
+    for arg in args:
+        if isinstance(arg, Named):
+            fail("ArgumentError", f"{what} '{name}' does not take named argument '{_velvet_name(arg.name)}'")
+
+
+def _digits(n):
+    text = repr(n)
+    mantissa, _, power = text.partition("e")
+    whole, _, fraction = mantissa.partition(".")
+    digits = whole + fraction
+    point = len(whole) + int(power or 0)
+    stripped = digits.lstrip("0")
+    point -= len(digits) - len(stripped)
+    return stripped.rstrip("0") or "0", point - 1
+
+
+def _number_text(n, debug=False):
+    n = float(n)
+    if math.isnan(n):
+        return "NaN"
+    sign = "-" if math.copysign(1.0, n) < 0 else ""
+    n = abs(n)
+    if math.isinf(n):
+        return sign + "inf"
+    if n == 0:
+        return sign + ("0.0" if debug else "0")
+    digits, exponent = _digits(n)
+    if debug and (exponent < -4 or exponent >= 16):
+        return sign + digits[0] + ("." + digits[1:] if len(digits) > 1 else "") + "e" + str(exponent)
+    if exponent < 0:
+        return sign + "0." + "0" * (-exponent - 1) + digits
+    if len(digits) > exponent + 1:
+        return sign + digits[: exponent + 1] + "." + digits[exponent + 1 :]
+    return sign + digits + "0" * (exponent + 1 - len(digits)) + (".0" if debug else "")
+
+
+def _is_number(value):
+    return type(value) in (int, float)
+
+
+def _kind(value):
+    if value is UNSET:
+        return "unset"
+    if value is None:
+        return "none"
+    if type(value) is bool:
+        return "bool"
+    if _is_number(value):
+        return "number"
+    if type(value) is str:
+        return "string"
+    if type(value) is list:
+        return "list"
+    if type(value) is tuple:
+        return "tuple"
+    if type(value) is dict:
+        return "map"
+    if isinstance(value, Iter):
+        return "iterator"
+    if isinstance(value, Error):
+        return "error"
+    if isinstance(value, Variant):
+        return value.tag
+    if callable(value):
+        return "fn"
+    return "unset"
+
+
+def _type_name(value):
+    return {
+        "string": "str",
+        "number": "f64",
+        "bool": "bool",
+        "list": "list",
+        "tuple": "tuple",
+        "map": "map",
+        "iterator": "iterator",
+        "fn": "fn",
+        "error": "error",
+        "ok": "result",
+        "err": "result",
+        "some": "option",
+        "none": "option",
+    }.get(_kind(value), "unset")
+
+
+def _debug_string(s):
+    out = '"'
+    for c in s:
+        if c in '"\\':
+            out += "\\" + c
+        elif c == "\n":
+            out += "\\n"
+        elif c == "\r":
+            out += "\\r"
+        elif c == "\t":
+            out += "\\t"
+        elif ord(c) < 0x20 or ord(c) == 0x7F:
+            out += "\\u{%x}" % ord(c)
+        else:
+            out += c
+    return out + '"'
+
+
+def _debug_error(error):
+    location = "None" if error.location is None else "Some(" + _debug_string(error.location) + ")"
+    stack = ", ".join(_debug_string(frame) for frame in error.stack)
+    return f"ErrorValue {{ kind: {_debug_string(error.kind)}, message: {_debug_string(error.message)}, location: {location}, stack: [{stack}] }}"
+
+
+def debug(value):
+    kind = _kind(value)
+    if kind == "string":
+        return "String(" + _debug_string(value) + ")"
+    if kind == "number":
+        return "Number(" + _number_text(value, True) + ")"
+    if kind == "bool":
+        return "Bool(" + ("true" if value else "false") + ")"
+    if kind == "list":
+        return "List([" + ", ".join(debug(item) for item in value) + "])"
+    if kind == "tuple":
+        return "Tuple([" + ", ".join(debug(item) for item in value) + "])"
+    if kind == "map":
+        return "Map([" + ", ".join(f"({debug(key)}, {debug(item)})" for key, item in value.items()) + "])"
+    if kind == "iterator":
+        return "Iterator(<iterator>)"
+    if kind == "fn":
+        return "Function(<fn>)"
+    if kind == "error":
+        return "Error(" + _debug_error(value) + ")"
+    if kind in ("ok", "err", "some"):
+        return kind.capitalize() + "(" + debug(value.value) + ")"
+    if kind == "none":
+        return "None"
+    return "Unset"
+
+
+def show(value):
+    kind = _kind(value)
+    if kind == "string":
+        return value
+    if kind == "number":
+        return _number_text(value)
+    if kind == "bool":
+        return "true" if value else "false"
+    if kind == "list":
+        return "[" + ", ".join(debug(item) for item in value) + "]"
+    if kind == "tuple":
+        return "(" + ", ".join(show(item) for item in value) + (",)" if len(value) == 1 else ")")
+    if kind == "map":
+        return "{" + ", ".join(f"{show(key)}: {show(item)}" for key, item in value.items()) + "}"
+    if kind == "iterator":
+        return "<iterator>"
+    if kind == "fn":
+        return "<fn>"
+    if kind == "error":
+        return f"{value.kind}: {value.message}"
+    if kind in ("ok", "err", "some"):
+        return f"{kind}({show(value.value)})"
+    if kind == "none":
+        return "none"
+    return "<unset>"
+
+
+def say(value):
+    print(show(value))
+
+
+def _equal(a, b):
+    kind = _kind(a)
+    if kind != _kind(b):
+        return False
+    if kind in ("list", "tuple"):
+        return len(a) == len(b) and all(_equal(x, y) for x, y in builtins.zip(a, b))
+    if kind == "map":
+        return len(a) == len(b) and all(_equal(x, y) for x, y in builtins.zip(a.items(), b.items()))
+    if kind == "error":
+        return a.kind == b.kind and a.message == b.message and a.location == b.location and _equal(a.stack, b.stack)
+    if kind in ("ok", "err", "some"):
+        return _equal(a.value, b.value)
+    if kind in ("none", "unset"):
+        return True
+    if kind == "fn":
+        return a is b
+    return a == b
+
+
+# Innermost call first, leaving out the `skip` innermost frames
+def _stacked(error, skip=0):
+    error.stack = _calls[: max(len(_calls) - skip, 0)][::-1]
+    return error
+
+
+def _trace(error):
+    text = f"{error.kind}: {error.message}"
+    if error.location is not None:
+        text += f" ({error.location})"
+    return text + "".join("\n    at " + frame for frame in error.stack)
+
+
+def fail(kind, message, skip=0):
+    raise _stacked(Error(kind, message), skip)
+
+
+# The Velvet error for anything a catch clause can see, including Python's own exceptions
+def caught(exception):
+    if isinstance(exception, Error):
+        return exception
+    if isinstance(exception, UnboundLocalError):
+        found = re.search(r"'(\w+)'", str(exception))
+        return _stacked(Error("NameError", f"Variable '{_velvet_name(found.group(1)) if found else '?'}' is used before it is assigned"))
+    if isinstance(exception, NameError):
+        frame = exception.__traceback__
+        while frame.tb_next is not None:
+            frame = frame.tb_next
+        name = exception.name or "?"
+        what = "Function" if name in frame.tb_frame.f_globals.get("__all__", ()) else "Var"
+        return _stacked(Error("NameError", f"{what} '{_velvet_name(name)}' not found"))
+    if isinstance(exception, RecursionError):
+        return _stacked(Error("Error", "Stack overflow"))
+    kind = type(exception).__name__
+    return _stacked(Error(kind if kind in ERROR_KINDS else "Error", str(exception)))
+
+
+def matches(exception, kind):
+    return kind == "Error" or caught(exception).kind == kind
+
+
+def thrown(value, line):
+    if isinstance(value, Error) and value.location is not None:
+        return value
+    error = Error(value.kind, value.message) if isinstance(value, Error) else Error("Error", show(value))
+    error.location = f"line {line}"
+    return _stacked(error)
+
+
+def rethrow():
+    current = sys.exc_info()[1]
+    if current is None:
+        return _stacked(Error("Error", "'throw' without a value outside of 'catch'"))
+    return caught(current)
+
+
+def _report(kind, value, traceback):
+    if isinstance(value, Escape):
+        print(f"'{value.keyword}' outside of a loop", file=sys.stderr)
+    elif isinstance(value, Exception):
+        print("Uncaught " + _trace(caught(value)), file=sys.stderr)
+    else:
+        sys.__excepthook__(kind, value, traceback)
+
+
+# Run by every generated module; one started as a script reports errors the way `vel start` does
+def start(module):
+    if module == "__main__":
+        sys.excepthook = _report
+        sys.setrecursionlimit(20000)
+
+
+# A 'return' outside of a function ends the program
+def exit():
+    raise SystemExit
+
+
+def _expected(value, kind):
+    if value is None:
+        return f"Expected {kind}, got none (check optional values with '??', '?.' or 'if x != none' first)"
+    return f"Expected {kind}, got {debug(value)}"
+
+
+def _number(value):
+    if not _is_number(value):
+        fail("TypeError", _expected(value, "number"))
+    return float(value)
+
+
+def truthy(value):
+    if type(value) is not bool:
+        fail("TypeError", _expected(value, "bool"))
+    return value
+
+
+def _string(value):
+    if type(value) is not str:
+        fail("TypeError", _expected(value, "string"))
+    return value
+
+
+def _list(value):
+    if type(value) is not list:
+        fail("TypeError", _expected(value, "list"))
+    return value
+
+
+def copy(value):
+    if type(value) is dict:
+        return dict(value)
+    return list(value) if type(value) is list else value
+
+
+def check(value, type_name):
+    if type_name.endswith("?"):
+        if value is not None and value is not UNSET:
+            check(value, type_name[:-1])
+        return value
+    if value is UNSET or _type_name(value) == type_name:
+        return value
+    fail("TypeError", f"Expected {type_name}, got {show(value)}")
+
+
+# A function that assigns to a global works on its own copy of it, read from its caller's module
+def inherit(name):
+    return copy(sys._getframe(1).f_globals.get(name, UNSET))
+
+
+# Tests run against a copy of their module's globals and put the originals back afterwards
+class isolated:
+    def __enter__(self):
+        self.namespace = sys._getframe(1).f_globals
+        self.saved = {name: copy(value) for name, value in self.namespace.items()}
+
+    def __exit__(self, *exception):
+        self.namespace.clear()
+        self.namespace.update(self.saved)
+
+
+def redeclared(name, value):
+    fail("Error", f"Cannot redeclare constant '{name}'")
+
+
+def immutable(name, value):
+    fail("Error", f"Cannot assign twice to immutable variable '{name}' (declare it with 'let' to make it mutable)")
+
+
+def constant(name, value):
+    fail("Error", f"Cannot assign to constant '{name}'")
+
+
+def undeclared(name, value):
+    fail("NameError", f"Var '{name}' not found")
+
+
+def missing(name, *args):
+    _unnamed("Builtin", name, args)
+    fail("NameError", f"Function '{name}' not found")
+
+
+def add(left, right):
+    if type(left) is str or type(right) is str:
+        return show(left) + show(right)
+    return _number(left) + _number(right)
+
+
+def sub(left, right):
+    return _number(left) - _number(right)
+
+
+def mul(left, right):
+    return _number(left) * _number(right)
+
+
+def div(left, right):
+    left, right = _number(left), _number(right)
+    if right == 0:
+        fail("ZeroDivisionError", "Division by zero")
+    return left / right
+
+
+def eq(left, right):
+    return _equal(left, right)
+
+
+def ne(left, right):
+    return not _equal(left, right)
+
+
+def lt(left, right):
+    return _number(left) < _number(right)
+
+
+def le(left, right):
+    return _number(left) <= _number(right)
+
+
+def gt(left, right):
+    return _number(left) > _number(right)
+
+
+def ge(left, right):
+    return _number(left) >= _number(right)
+
+
+def neg(value):
+    return -_number(value)
+
+
+def not_(value):
+    return not truthy(value)
+
+
+def coalesce(value, fallback):
+    if value is None:
+        return fallback()
+    return value.value if isinstance(value, Some) else value
+
+
+def _position(index, length, container):
+    n = _number(index)
+    if not n.is_integer():
+        fail("TypeError", f"Expected integer index, got {_number_text(n)}")
+    i = int(n + length if n < 0 else n)
+    if i < 0 or i >= length:
+        fail("IndexError", f"Index {_number_text(n)} out of bounds for {container} of length {length}")
+    return i
+
+
+def at(target, index):
+    if type(target) is dict:
+        try:
+            return target[index]
+        except (KeyError, TypeError):
+            fail("IndexError", f"Key '{show(index)}' not found")
+    if type(target) is list:
+        return target[_position(index, len(target), "list")]
+    if type(target) is tuple:
+        return target[_position(index, len(target), "tuple")]
+    if type(target) is str:
+        return target[_position(index, len(target), "str")]
+    fail("TypeError", f"Cannot index into {_type_name(target)}")
+
+
+def _whole(n):
+    if math.isnan(n):
+        return 0
+    return int(min(max(n, -9.2e18), 9.2e18))
+
+
+def _bound(value, fallback, length, step):
+    if value is None:
+        return fallback
+    v = _whole(value) + length if value < 0 else _whole(value)
+    low, high = (0, length) if step > 0 else (-1, length - 1)
+    return min(max(v, low), high)
+
+
+# Omitted bounds are None
+def slice_of(target, start, end, step):
+    if type(target) not in (list, tuple, str):
+        fail("TypeError", f"Cannot slice {_type_name(target)}")
+    start, end, step = (None if bound is None else _number(bound) for bound in (start, end, step))
+    by = 1 if step is None else _whole(step)
+    if by == 0:
+        fail("ValueError", "Slice step cannot be zero")
+    length = len(target)
+    i = _bound(start, 0, length, by) if by > 0 else _bound(start, length - 1, length, by)
+    stop = _bound(end, length, length, by) if by > 0 else _bound(end, -1, length, by)
+    picked = []
+    while (by > 0 and i < stop) or (by < 0 and i > stop):
+        picked.append(target[i])
+        i += by
+    if type(target) is str:
+        return "".join(picked)
+    return tuple(picked) if type(target) is tuple else picked
+
+
+def propagate(value):
+    if isinstance(value, Variant) and value.tag != "err":
+        return value.value
+    if value is None or isinstance(value, Variant):
+        if not _calls:
+            fail("Error", f"'?' on {show(value)} outside of a function")
+        raise Return(value)
+    fail("TypeError", f"Expected result or option for '?', got {show(value)}")
+
+
+def field(value, name):
+    if isinstance(value, Error) and name in ("kind", "message", "location", "stack"):
+        return list(value.stack) if name == "stack" else getattr(value, name)
+    if value is None:
+        fail("Error", f"Cannot read field '{name}' of none (use '?.' for optional values)")
+    fail("Error", f"Value {show(value)} has no field '{name}'")
+
+
+def safe_field(value, name):
+    if value is None:
+        return None
+    return field(value.value if isinstance(value, Some) else value, name)
+
+
+def _to_iter(value):
+    return value if isinstance(value, Iter) else Iter(builtins.iter(iterate(value)))
+
+
+def iterate(value):
+    if type(value) in (list, dict):
+        return list(value)
+    if type(value) in (tuple, str) or isinstance(value, Iter):
+        return value
+    fail("TypeError", f"Expected list, str, map or iterator to iterate over, got {show(value)}")
+
+
+def _available(type_name):
+    methods = {"str": STRING_METHODS, "list": LIST_METHODS, "f64": NUMBER_METHODS, "map": MAP_METHODS, "iterator": ITERATOR_METHODS}
+    return methods.get(type_name, ())
+
+
+def _arity(name, args, expected):
+    if len(args) != expected:
+        fail("ArgumentError", f"Method '{name}' expects {expected} args, got {len(args)}")
+
+
+def _parse_number(text):
+    if re.fullmatch(r"[+-]?(inf|infinity|nan)", text, re.IGNORECASE) or re.fullmatch(r"[+-]?(\d+\.?\d*|\.\d+)([eE][+-]?\d+)?", text):
+        return float(text)
+    return None
+
+
+def _string_method(s, name, args):
+    expected = 0
+    if name in ("split", "contains", "starts_with", "ends_with", "repeat"):
+        expected = 1
+    elif name == "replace":
+        expected = 2
+    _arity(name, args, expected)
+    if name == "len":
+        return float(len(s))
+    if name == "upper":
+        return s.upper()
+    if name == "lower":
+        return s.lower()
+    if name == "trim":
+        return s.strip()
+    if name == "chars":
+        return list(s)
+    if name == "split":
+        separator = _string(args[0])
+        return ["", *s, ""] if separator == "" else s.split(separator)
+    if name == "contains":
+        return _string(args[0]) in s
+    if name == "starts_with":
+        return s.startswith(_string(args[0]))
+    if name == "ends_with":
+        return s.endswith(_string(args[0]))
+    if name == "replace":
+        return s.replace(_string(args[0]), _string(args[1]))
+    if name == "repeat":
+        return s * max(_whole(_number(args[0])), 0)
+    if name == "to_num":
+        n = _parse_number(s.strip())
+        if n is None:
+            fail("ValueError", f"Cannot convert '{s}' to a number")
+        return n
+    fail("NameError", f"Type 'str' has no method '{name}'")
+
+
+def _round(n):
+    if not math.isfinite(n):
+        return n
+    whole = math.floor(abs(n))
+    if abs(n) - whole >= 0.5:
+        whole += 1
+    return math.copysign(whole, n)
+
+
+def _pow(n, power):
+    try:
+        return math.pow(n, power)
+    except OverflowError:
+        odd = power.is_integer() and power % 2 == 1
+        return math.copysign(math.inf, n) if odd else math.inf
+    except ValueError:
+        return math.nan
+
+
+def _number_method(n, name, args):
+    _arity(name, args, 1 if name in ("pow", "min", "max") else 0)
+    n = float(n)
+    if name == "abs":
+        return abs(n)
+    if name == "ceil":
+        return float(math.ceil(n)) if math.isfinite(n) else n
+    if name == "floor":
+        return float(math.floor(n)) if math.isfinite(n) else n
+    if name == "round":
+        return _round(n)
+    if name == "sqrt":
+        return math.sqrt(n) if n >= 0 else math.nan
+    if name == "pow":
+        return _pow(n, _number(args[0]))
+    if name in ("min", "max"):
+        other = _number(args[0])
+        if math.isnan(n) or math.isnan(other):
+            return other if math.isnan(n) else n
+        return min(n, other) if name == "min" else max(n, other)
+    if name == "to_str":
+        return _number_text(n)
+    fail("NameError", f"Type 'f64' has no method '{name}'")
+
+
+_compare_failure = None
+
+
+def _compare(a, b):
+    global _compare_failure
+    if (_is_number(a) and _is_number(b)) or (type(a) is str and type(b) is str):
+        return -1 if a < b else 1 if a > b else 0
+    _compare_failure = f"Cannot compare {_type_name(a)} with {_type_name(b)}"
+    return 0
+
+
+def _extreme(items, name, wanted):
+    global _compare_failure
+    if not items:
+        fail("ValueError", f"Cannot take {name} of an empty list")
+    best = items[0]
+    for item in items[1:]:
+        _compare_failure = None
+        order = _compare(item, best)
+        if _compare_failure:
+            fail("TypeError", _compare_failure)
+        if order == wanted:
+            best = item
+    return best
+
+
+def _list_index(value, length, allow_end):
+    i = _number(value)
+    if i < 0 or _whole(i) >= (length + 1 if allow_end else length):
+        fail("IndexError", f"Index {_number_text(i)} out of bounds for list of length {length}")
+    return _whole(i)
+
+
+def _option(present, value):
+    return Some(value) if present else None
+
+
+def _list_method(items, name, args):
+    global _compare_failure
+    expected = 0
+    if name in ("push", "remove", "contains", "index_of", "extend", "join"):
+        expected = 1
+    elif name == "insert":
+        expected = 2
+    _arity(name, args, expected)
+    if name == "len":
+        return float(len(items))
+    if name == "is_empty":
+        return not items
+    if name == "first":
+        return _option(items, items[0] if items else None)
+    if name == "last":
+        return _option(items, items[-1] if items else None)
+    if name == "contains":
+        return any(_equal(item, args[0]) for item in items)
+    if name == "index_of":
+        found = builtins.next((i for i, item in builtins.enumerate(items) if _equal(item, args[0])), None)
+        return _option(found is not None, None if found is None else float(found))
+    if name == "join":
+        return _string(args[0]).join(show(item) for item in items)
+    if name == "reverse":
+        return items[::-1]
+    if name == "sort":
+        _compare_failure = None
+        result = sorted(items, key=functools.cmp_to_key(_compare))
+        if _compare_failure:
+            fail("TypeError", _compare_failure)
+        return result
+    if name == "sum":
+        total = -0.0
+        for item in items:
+            total += _number(item)
+        return total
+    if name in ("min", "max"):
+        return _extreme(items, name, -1 if name == "min" else 1)
+    if name == "push":
+        items.append(args[0])
+        return None
+    if name == "pop":
+        if not items:
+            fail("ValueError", "Cannot pop from an empty list")
+        return items.pop()
+    if name == "insert":
+        items.insert(_list_index(args[0], len(items), True), args[1])
+        return None
+    if name == "remove":
+        return items.pop(_list_index(args[0], len(items), False))
+    if name == "extend":
+        items.extend(_list(args[0]))
+        return None
+    if name == "clear":
+        items.clear()
+        return None
+    fail("NameError", f"Type 'list' has no method '{name}'")
+
+
+def _map_method(entries, name, args):
+    expected = 0
+    if name in ("get", "contains", "remove"):
+        expected = 1
+    elif name == "set":
+        expected = 2
+    _arity(name, args, expected)
+    if name == "len":
+        return float(len(entries))
+    if name == "is_empty":
+        return not entries
+    if name == "keys":
+        return list(entries)
+    if name == "values":
+        return list(entries.values())
+    if name == "items":
+        return list(entries.items())
+    if name == "get":
+        return _option(args[0] in entries, entries.get(args[0]))
+    if name == "contains":
+        return args[0] in entries
+    if name == "set":
+        entries[args[0]] = args[1]
+        return None
+    if name == "remove":
+        return _option(args[0] in entries, entries.pop(args[0], None))
+    if name == "clear":
+        entries.clear()
+        return None
+    fail("NameError", f"Type 'map' has no method '{name}'")
+
+
+def _native_method(receiver, name, args):
+    _unnamed("Method", name, args)
+    if type(receiver) is str:
+        return _string_method(receiver, name, args)
+    if _is_number(receiver):
+        return _number_method(receiver, name, args)
+    if type(receiver) is list:
+        return _list_method(receiver, name, args)
+    if type(receiver) is dict:
+        return _map_method(receiver, name, args)
+    fail("NameError", f"Type '{_type_name(receiver)}' has no method '{name}'")
+
+
+def _mutates(receiver, name):
+    return name in MUTATING and name in _available(_type_name(receiver))
+
+
+# `changes` names the variable a method call inside a larger expression must leave alone
+def method(receiver, name, *args, changes=None):
+    type_name = _type_name(receiver)
+    methods = _available(type_name)
+    if name not in methods:
+        fail("NameError", f"Type '{type_name}' has no method '{name}' (available: {', '.join(methods) if methods else 'none'})")
+    if type_name == "iterator":
+        _unnamed("Method", name, args)
+        return globals()[name](receiver, *args)
+    if _mutates(receiver, name):
+        if changes is not None:
+            fail("Error", f"Method '{name}' changes '{changes}' and can only be called as a statement or as the value of a declaration or assignment")
+        receiver = copy(receiver)
+    return _native_method(receiver, name, args)
+
+
+# A statement-level method call on a variable, which may change its list in place
+def update(receiver, name, *args, immutable=None, constant=None):
+    if not _mutates(receiver, name):
+        return method(receiver, name, *args)
+    if immutable is None and constant is None:
+        return _native_method(receiver, name, args)
+    _native_method(copy(receiver), name, args)
+    if constant is not None:
+        fail("Error", f"Cannot call '{name}' on constant '{constant}'")
+    fail("Error", f"Cannot call '{name}' on immutable variable '{immutable}' (declare it with 'let' to make it mutable)")
+
+
+def _match(pattern, value, bindings):
+    if pattern is ANY:
+        return True
+    if pattern is BIND:
+        bindings.append(value)
+        return True
+    if pattern is None:
+        return value is None
+    if isinstance(pattern, Variant):
+        return type(value) is type(pattern) and _match(pattern.value, value.value, bindings)
+    return pattern == show(value)
+
+
+# A match expression: each arm is a pattern and a function taking what the pattern binds
+def choose(subject, *arms):
+    for pattern, arm in arms:
+        bindings = []
+        if _match(pattern, subject, bindings):
+            return arm(*bindings)
+    fail("ValueError", f"No match arm for value {show(subject)}")
+
+
+# The values for a function's parameters, in order, from positional args and (name, value) pairs
+def _bind(name, params, args, pairs):
+    variadic = bool(params) and params[-1].kind is inspect.Parameter.VAR_POSITIONAL
+    if not variadic and len(args) > len(params):
+        fail("ArgumentError", f"Expected at most {len(params)} args, got {len(args)}")
+    for i, (given, _) in builtins.enumerate(pairs):
+        if not any(param.name == given and param.kind is not inspect.Parameter.VAR_POSITIONAL for param in params):
+            fail("ArgumentError", f"Unknown named argument '{_velvet_name(given)}' in call to '{name}'")
+        if any(other == given for other, _ in pairs[:i]):
+            fail("ArgumentError", f"Duplicate named argument '{_velvet_name(given)}' in call to '{name}'")
+    values = []
+    for slot, param in builtins.enumerate(params):
+        named = [value for given, value in pairs if given == param.name]
+        if param.kind is inspect.Parameter.VAR_POSITIONAL:
+            values.extend(args[slot:])
+        elif slot < len(args):
+            if named:
+                fail("ArgumentError", f"Named argument '{_velvet_name(param.name)}' was already given by position in call to '{name}'")
+            values.append(args[slot])
+        elif named:
+            values.append(named[0])
+        elif param.default is not inspect.Parameter.empty:
+            values.append(param.default)
+        else:
+            fail("ArgumentError", f"Missing argument '{_velvet_name(param.name)}' in call to '{name}'")
+    return values
+
+
+# Gives a Velvet function its call stack entry, argument checks and return checks; calling a
+# generator binds its args and returns an iterator that runs the body as it is advanced
+def function(name, returns=False):
+    def decorate(body):
+        params = list(inspect.signature(body).parameters.values())
+        generator = inspect.isgeneratorfunction(body)
+
+        def run(args, pairs):
+            values = _bind(name, params, args, pairs)
+            if generator:
+                return Iter(body(*values), name)
+            depth = len(_calls)
+            _calls.append(name)
+            try:
+                try:
+                    result = body(*values)
+                except Return as signal:
+                    return signal.value
+                except Escape:
+                    fail("Error", f"'break' or 'continue' escaped function '{name}'", 1)
+                if result is UNSET:
+                    if returns:
+                        fail("Error", "Missing return value", 1)
+                    return None
+                return result
+            finally:
+                del _calls[depth:]
+
+        @functools.wraps(body)
+        def call(*args, **named):
+            return run(args, list(named.items()))
+
+        call.invoke = run
+        return call
+
+    return decorate
+
+
+# A call whose named args come before positional ones or repeat a name, which Python syntax can't express
+def invoke(name, function, *args):
+    if not callable(getattr(function, "invoke", None)):
+        fail("TypeError", f"'{name}' is not a function")
+    positional = [arg for arg in args if not isinstance(arg, Named)]
+    return function.invoke(positional, [(arg.name, arg.value) for arg in args if isinstance(arg, Named)])
+
+
+def _wrong(name, args):
+    fail("ArgumentError", f"Builtin '{name}' called with the wrong number of args, got {len(args)}")
+
+
+def _args(name, args, count):
+    if len(args) != count:
+        _wrong(name, args)
+    return args
+
+
+def error(*args):
+    if len(args) == 1:
+        return Error("Error", show(args[0]))
+    if len(args) == 2:
+        return Error(show(args[0]), show(args[1]))
+    _wrong("error", args)
+
+
+def ok(*args):
+    return Ok(*_args("ok", args, 1))
+
+
+def err(*args):
+    return Err(*_args("err", args, 1))
+
+
+def some(*args):
+    return Some(*_args("some", args, 1))
+
+
+def unwrap(*args):
+    (value,) = _args("unwrap", args, 1)
+    if isinstance(value, Variant) and value.tag != "err":
+        return value.value
+    if isinstance(value, Err) and isinstance(value.value, Error):
+        raise value.value
+    if isinstance(value, Err):
+        fail("Error", f"unwrap called on err({show(value.value)})")
+    if value is None:
+        fail("Error", "unwrap called on none")
+    fail("TypeError", f"Expected result or option, got {show(value)}")
+
+
+def unwrap_or(*args):
+    value, other = _args("unwrap_or", args, 2)
+    if isinstance(value, Variant):
+        return other if value.tag == "err" else value.value
+    if value is None:
+        return other
+    fail("TypeError", f"Expected result or option, got {show(value)}")
+
+
+def map_err(*args):
+    value, change = _args("map_err", args, 2)
+    if isinstance(value, Err):
+        if not callable(change):
+            fail("TypeError", "'map_err' is not a function")
+        return Err(change(value.value))
+    if isinstance(value, Ok):
+        return value
+    fail("TypeError", f"Expected result, got {show(value)}")
+
+
+def is_ok(*args):
+    return _kind(*_args("is_ok", args, 1)) == "ok"
+
+
+def is_err(*args):
+    return _kind(*_args("is_err", args, 1)) == "err"
+
+
+def is_some(*args):
+    return _kind(*_args("is_some", args, 1)) == "some"
+
+
+def is_none(*args):
+    return _kind(*_args("is_none", args, 1)) == "none"
+
+
+def iter(*args):
+    return _to_iter(*_args("iter", args, 1))
+
+
+def next(*args):
+    item = builtins.next(_to_iter(*_args("next", args, 1)), UNSET)
+    return None if item is UNSET else Some(item)
+
+
+def collect(*args):
+    return list(_to_iter(*_args("collect", args, 1)))
+
+
+def _taking(inner, count):
+    for _ in range(count):
+        value = builtins.next(inner, UNSET)
+        if value is UNSET:
+            return
+        yield value
+
+
+def _skipping(inner, count):
+    for _ in range(count):
+        if builtins.next(inner, UNSET) is UNSET:
+            return
+    yield from inner
+
+
+def take(*args):
+    value, count = _args("take", args, 2)
+    count = _whole(max(_number(count), 0))
+    return Iter(_taking(_to_iter(value), count))
+
+
+def skip(*args):
+    value, count = _args("skip", args, 2)
+    count = _whole(max(_number(count), 0))
+    return Iter(_skipping(_to_iter(value), count))
+
+
+def _zipping(first, second):
+    while True:
+        a = builtins.next(first, UNSET)
+        b = UNSET if a is UNSET else builtins.next(second, UNSET)
+        if b is UNSET:
+            return
+        yield (a, b)
+
+
+def zip(*args):
+    first, second = _args("zip", args, 2)
+    second = _to_iter(second)
+    return Iter(_zipping(_to_iter(first), second))
+
+
+def _chaining(first, second):
+    yield from first
+    yield from second
+
+
+def chain(*args):
+    first, second = _args("chain", args, 2)
+    second = _to_iter(second)
+    return Iter(_chaining(_to_iter(first), second))
+
+
+def enumerate(*args):
+    inner = _to_iter(*_args("enumerate", args, 1))
+    return Iter((float(index), value) for index, value in builtins.enumerate(inner))
+
+
+def divmod(*args):
+    a, b = (_number(value) for value in _args("divmod", args, 2))
+    if b == 0:
+        fail("ZeroDivisionError", "Division by zero")
+    quotient = a / b
+    if math.isfinite(quotient):
+        quotient = float(math.floor(quotient))
+    return (quotient, a - b * quotient)
+
+
+# The other builtins, for calls with the wrong number of args or with named args
+def builtin(name, *args):
+    _unnamed("Builtin", name, args)
+    if name in ERROR_KINDS and len(args) == 1:
+        return Error(name, show(args[0]))
+    _wrong(name, args)
@@ -0,0 +1,1200 @@
+// Runtime emitted at the top of every program built by `vel build`. The compiler appends
+// runtime.rs and methods.rs as modules, so values print, compare and iterate exactly as
+// they do under `vel start`; the helpers below mirror the interpreter's.
+#![allow(warnings)]
+use runtime::{ErrorValue, Iter, LazyIter, Value, BUILTINS, BUILTIN_TYPES, ERROR_KINDS};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+mod ast {
+    #[derive(Clone, PartialEq)]
+    pub struct Expr(pub &'static str);
+
+    impl std::fmt::Debug for Expr {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Param {
+        pub name: String,
+        pub type_anno: String,
+        pub default: Option<Expr>,
+        pub variadic: bool,
+    }
+}
+
+mod resolver {
+    pub use crate::Function;
+}
+
+mod interpreter {
+    pub use crate::Generator;
+}
+
+mod vm {
+    #[derive(Debug, PartialEq)]
+    pub struct Function;
+
+    pub struct Coroutine;
+}
+
+macro_rules! check {
+    ($label:lifetime, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(signal) => break $label Err(signal),
+        }
+    };
+}
+
+#[derive(Debug)]
+pub enum Signal {
+    Break,
+    Continue,
+    Return(Value),
+    Error(ErrorValue),
+}
+
+impl From<ErrorValue> for Signal {
+    fn from(mut error: ErrorValue) -> Self {
+        error.stack = call_stack();
+        Signal::Error(error)
+    }
+}
+
+type R<T> = Result<T, Signal>;
+type Args = (Vec<Value>, Vec<(String, Value)>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mutability {
+    Const,
+    Immutable,
+    Mutable,
+}
+
+#[derive(Debug, Clone)]
+pub struct Binding {
+    value: Value,
+    mutability: Mutability,
+}
+
+pub struct Parameter {
+    name: &'static str,
+    type_anno: &'static str,
+    variadic: bool,
+    default: Option<fn(&mut Env) -> R<Value>>,
+}
+
+pub enum Code {
+    Body(fn(&mut Env) -> R<()>),
+    Generator(&'static [Step]),
+}
+
+pub struct Function {
+    params: &'static [Parameter],
+    slots: usize,
+    ret_type: Option<&'static str>,
+    code: Code,
+}
+
+impl std::fmt::Debug for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<fn>")
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+pub enum Pattern {
+    Wildcard,
+    Literal(&'static str),
+    Bind(Option<usize>, &'static str),
+    Variant(&'static str, Option<&'static Pattern>),
+}
+
+pub enum Target {
+    Name(Option<usize>, &'static str),
+    Tuple(&'static [Target]),
+    List(&'static [Target], Option<&'static Target>),
+}
+
+type Bindings = Vec<(Option<usize>, &'static str, Value)>;
+
+#[derive(Clone)]
+pub struct Env {
+    slots: Vec<Option<Binding>>,
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<String>,
+    handling: Vec<ErrorValue>,
+    methods: HashMap<String, HashMap<String, Value>>,
+    traits: HashMap<String, (Vec<String>, HashMap<String, Value>)>,
+    impls: HashSet<(String, String)>,
+    deprecated: HashMap<String, Option<String>>,
+    memos: HashMap<String, HashMap<String, Value>>,
+    constructors: HashMap<String, Rc<Function>>,
+}
+
+thread_local! {
+    static GLOBALS: RefCell<HashMap<String, Binding>> = RefCell::new(HashMap::new());
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+fn call_stack() -> Vec<String> {
+    STATE.with(|s| s.borrow().calls.iter().rev().cloned().collect())
+}
+
+fn error<T>(kind: &str, message: impl Into<String>) -> R<T> {
+    Err(ErrorValue::new(kind, message).into())
+}
+
+fn outcome(result: R<()>) -> Result<(), String> {
+    match result {
+        Ok(()) | Err(Signal::Return(_)) => Ok(()),
+        Err(Signal::Break) => Err("'break' outside of a loop".to_string()),
+        Err(Signal::Continue) => Err("'continue' outside of a loop".to_string()),
+        Err(Signal::Error(e)) => Err(format!("Uncaught {}", e.trace())),
+    }
+}
+
+fn warn_deprecated(name: &str) {
+    let note = STATE.with(|s| s.borrow_mut().deprecated.remove(name));
+    match note {
+        Some(Some(note)) => eprintln!("\x1b[1;33mWARN:\x1b[0m '{}' is deprecated: {}", name, note),
+        Some(None) => eprintln!("\x1b[1;33mWARN:\x1b[0m '{}' is deprecated", name),
+        None => {}
+    }
+}
+
+fn read(id: &str, binding: Option<&Binding>) -> R<Value> {
+    match binding {
+        Some(Binding { value: Value::Unset, .. }) => error("NameError", format!("Variable '{}' is used before it is assigned", id)),
+        Some(binding) => {
+            if binding.mutability == Mutability::Const {
+                warn_deprecated(id);
+            }
+            Ok(binding.value.clone())
+        }
+        None => error("NameError", format!("Var '{}' not found", id)),
+    }
+}
+
+impl Env {
+    fn new(len: usize) -> Self {
+        Env { slots: vec![None; len] }
+    }
+
+    fn with<T>(&self, slot: Option<usize>, name: &str, f: impl FnOnce(Option<&Binding>) -> T) -> T {
+        match slot.and_then(|slot| self.slots[slot].as_ref()) {
+            Some(binding) => f(Some(binding)),
+            None => {
+                let binding = GLOBALS.with(|g| g.borrow().get(name).cloned());
+                f(binding.as_ref())
+            }
+        }
+    }
+
+    fn update<T>(&mut self, slot: Option<usize>, name: &str, f: impl FnOnce(&mut Binding) -> T) -> Option<T> {
+        match slot {
+            Some(slot) => {
+                if self.slots[slot].is_none() {
+                    self.slots[slot] = GLOBALS.with(|g| g.borrow().get(name).cloned());
+                }
+                self.slots[slot].as_mut().map(f)
+            }
+            None => GLOBALS.with(|g| g.borrow_mut().get_mut(name).map(f)),
+        }
+    }
+
+    fn get(&self, slot: Option<usize>, name: &str) -> Option<Value> {
+        self.with(slot, name, |binding| binding.map(|b| b.value.clone()))
+    }
+
+    fn bound(&self, slot: Option<usize>, name: &str) -> bool {
+        self.with(slot, name, |binding| binding.is_some())
+    }
+
+    fn read(&self, slot: Option<usize>, name: &str) -> R<Value> {
+        self.with(slot, name, |binding| read(name, binding))
+    }
+
+    fn read_none(&self) -> R<Value> {
+        if self.bound(None, "none") {
+            return self.read(None, "none");
+        }
+        Ok(Value::None)
+    }
+
+    fn define(&mut self, slot: Option<usize>, name: &str, value: Value, mutability: Mutability) -> R<()> {
+        let existing = match slot {
+            Some(slot) => self.slots[slot].as_ref().map(|b| b.mutability),
+            None => GLOBALS.with(|g| g.borrow().get(name).map(|b| b.mutability)),
+        };
+        if existing == Some(Mutability::Const) {
+            return error("Error", format!("Cannot redeclare constant '{}'", name));
+        }
+        let binding = Binding { value, mutability };
+        match slot {
+            Some(slot) => self.slots[slot] = Some(binding),
+            None => {
+                GLOBALS.with(|g| g.borrow_mut().insert(name.to_string(), binding));
+            }
+        }
+        Ok(())
+    }
+
+    fn define_all(&mut self, bindings: Bindings, mutability: Mutability) -> R<()> {
+        for (slot, name, value) in bindings {
+            self.define(slot, name, value, mutability)?;
+        }
+        Ok(())
+    }
+
+    fn assign(&mut self, slot: Option<usize>, ident: &str, value: Value) -> R<()> {
+        self.update(slot, ident, |binding| match binding.mutability {
+            Mutability::Const => error("Error", format!("Cannot assign to constant '{}'", ident)),
+            Mutability::Immutable if binding.value != Value::Unset => error("Error", format!(
+                "Cannot assign twice to immutable variable '{}' (declare it with 'let' to make it mutable)",
+                ident
+            )),
+            _ => {
+                binding.value = value;
+                Ok(())
+            }
+        })
+        .ok_or_else(|| ErrorValue::new("NameError", format!("Var '{}' not found", ident)))?
+    }
+
+    fn assign_all(&mut self, bindings: Bindings) -> R<()> {
+        for (slot, name, value) in bindings {
+            self.assign(slot, name, value)?;
+        }
+        Ok(())
+    }
+
+    fn mutate(&mut self, slot: Option<usize>, ident: &str, method: &str, mut receiver: Value, (args, named): Args) -> R<Value> {
+        // Drop the variable's own reference first so an unshared collection is changed in place
+        let mutability = self.update(slot, ident, |binding| {
+            if binding.mutability == Mutability::Mutable {
+                binding.value = Value::Unset;
+            }
+            binding.mutability
+        });
+        let result = call_native_method(&mut receiver, method, args, named);
+        match mutability.ok_or_else(|| ErrorValue::new("NameError", format!("Var '{}' not found", ident)))? {
+            Mutability::Const => {
+                result?;
+                error("Error", format!("Cannot call '{}' on constant '{}'", method, ident))
+            }
+            Mutability::Immutable => {
+                result?;
+                error("Error", format!("Cannot call '{}' on immutable variable '{}' (declare it with 'let' to make it mutable)", method, ident))
+            }
+            Mutability::Mutable => {
+                self.update(slot, ident, |binding| binding.value = receiver);
+                result
+            }
+        }
+    }
+}
+
+fn snapshot() -> HashMap<String, Binding> {
+    GLOBALS.with(|g| g.borrow().clone())
+}
+
+fn restore(globals: HashMap<String, Binding>) {
+    GLOBALS.with(|g| *g.borrow_mut() = globals);
+}
+
+fn unpack(target: &Target, value: Value, bindings: &mut Bindings) -> R<()> {
+    match target {
+        Target::Name(_, "_") => Ok(()),
+        Target::Name(slot, name) => {
+            bindings.push((*slot, name, value));
+            Ok(())
+        }
+        Target::Tuple(targets) => match value {
+            Value::Tuple(values) if values.len() == targets.len() => {
+                for (target, value) in targets.iter().zip(values) {
+                    unpack(target, value, bindings)?;
+                }
+                Ok(())
+            }
+            Value::Tuple(values) => error("ValueError", format!("Cannot unpack tuple of length {} into {} names", values.len(), targets.len())),
+            other => error("TypeError", format!("Expected tuple to unpack, got {}", other)),
+        },
+        Target::List(targets, rest) => {
+            let values = value.as_list()?;
+            if values.len() < targets.len() || (rest.is_none() && values.len() != targets.len()) {
+                let names = if rest.is_some() { format!("at least {}", targets.len()) } else { targets.len().to_string() };
+                return error("ValueError", format!("Cannot unpack list of length {} into {} names", values.len(), names));
+            }
+            for (target, value) in targets.iter().zip(values.iter()) {
+                unpack(target, value.clone(), bindings)?;
+            }
+            if let Some(rest) = rest {
+                unpack(rest, Value::List(Rc::new(values[targets.len()..].to_vec())), bindings)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn match_pattern(pattern: &Pattern, value: &Value, bindings: &mut Bindings) -> bool {
+    match pattern {
+        Pattern::Wildcard => true,
+        Pattern::Bind(slot, name) => {
+            bindings.push((*slot, name, value.clone()));
+            true
+        }
+        Pattern::Literal(literal) => *literal == value.to_string(),
+        Pattern::Variant(name, inner) => match (*name, value, inner) {
+            ("ok", Value::Ok(v), Some(p)) | ("err", Value::Err(v), Some(p)) | ("some", Value::Some(v), Some(p)) => match_pattern(p, v, bindings),
+            ("none", Value::None, None) => true,
+            _ => false,
+        },
+    }
+}
+
+fn no_match(value: &Value) -> R<Value> {
+    error("ValueError", format!("No match arm for value {}", value))
+}
+
+fn located(mut error: ErrorValue, line: usize) -> ErrorValue {
+    error.location = Some(format!("line {}", line));
+    error.stack = call_stack();
+    error
+}
+
+fn throw(value: Value, line: usize) -> Signal {
+    Signal::Error(match value {
+        Value::Error(error) if error.location.is_some() => *error,
+        Value::Error(error) => located(*error, line),
+        Value::String(message) => located(ErrorValue::new("Error", &*message), line),
+        other => located(ErrorValue::new("Error", other.to_string()), line),
+    })
+}
+
+fn rethrow() -> Signal {
+    match STATE.with(|s| s.borrow().handling.last().cloned()) {
+        Some(error) => Signal::Error(error),
+        None => ErrorValue::new("Error", "'throw' without a value outside of 'catch'").into(),
+    }
+}
+
+fn begin_catch(error: ErrorValue) {
+    STATE.with(|s| s.borrow_mut().handling.push(error));
+}
+
+fn end_catch() {
+    STATE.with(|s| s.borrow_mut().handling.pop());
+}
+
+fn is_trait(name: &str) -> bool {
+    STATE.with(|s| s.borrow().traits.contains_key(name))
+}
+
+fn check_type(value: &Value, type_anno: &str) -> R<()> {
+    if let Some(inner) = type_anno.strip_suffix('?') {
+        return match value {
+            Value::None | Value::Unset => Ok(()),
+            _ => check_type(value, inner),
+        };
+    }
+    match (type_anno, value) {
+        (_, Value::Unset) | ("str", Value::String(_)) | ("f64", Value::Number(_)) | ("bool", Value::Bool(_)) | ("list", Value::List(_)) | ("tuple", Value::Tuple(_)) | ("map", Value::Map(_)) | ("iterator", Value::Iterator(_)) | ("fn", Value::Function(_)) | ("error", Value::Error(_)) | ("result", Value::Ok(_) | Value::Err(_)) | ("option", Value::Some(_) | Value::None) => Ok(()),
+        (expected, Value::Record(name, _)) if name == expected => Ok(()),
+        (expected, value) if is_trait(expected) => {
+            if STATE.with(|s| s.borrow().impls.contains(&(value.type_name().to_string(), expected.to_string()))) {
+                Ok(())
+            } else {
+                error("TypeError", format!("type {} does not implement trait {}", value.type_name(), expected))
+            }
+        }
+        _ => error("TypeError", format!("Expected {}, got {}", type_anno, value)),
+    }
+}
+
+fn truthy(value: Value) -> R<bool> {
+    Ok(value.as_bool()?)
+}
+
+fn number(value: Value) -> R<f64> {
+    Ok(value.as_number()?)
+}
+
+fn insert_entry(map: &mut Vec<(Value, Value)>, key: Value, value: Value) {
+    match map.iter_mut().find(|(k, _)| k == &key) {
+        Some(entry) => entry.1 = value,
+        None => map.push((key, value)),
+    }
+}
+
+fn say(value: Value) -> R<()> {
+    println!("{}", display(value)?);
+    Ok(())
+}
+
+fn binary(op: &str, left: Value, right: Value) -> R<Value> {
+    if let Some(result) = call_operator(op, &left, &right)? {
+        return Ok(result);
+    }
+    match op {
+        "+" => match (&left, &right) {
+            (Value::String(_), _) | (_, Value::String(_)) => Ok(Value::String(format!("{}{}", display(left)?, display(right)?).into())),
+            _ => Ok(Value::Number(left.as_number()? + right.as_number()?)),
+        },
+        "-" => Ok(Value::Number(left.as_number()? - right.as_number()?)),
+        "*" => Ok(Value::Number(left.as_number()? * right.as_number()?)),
+        "/" => {
+            let r = right.as_number()?;
+            if r == 0.0 {
+                return error("ZeroDivisionError", "Division by zero");
+            }
+            Ok(Value::Number(left.as_number()? / r))
+        }
+        "==" => Ok(Value::Bool(left == right)),
+        "!=" => Ok(Value::Bool(left != right)),
+        ">" => Ok(Value::Bool(left.as_number()? > right.as_number()?)),
+        ">=" => Ok(Value::Bool(left.as_number()? >= right.as_number()?)),
+        "<" => Ok(Value::Bool(left.as_number()? < right.as_number()?)),
+        "<=" => Ok(Value::Bool(left.as_number()? <= right.as_number()?)),
+        _ => error("Error", format!("Unknown operator '{}'", op)),
+    }
+}
+
+fn unary(op: &str, value: Value) -> R<Value> {
+    match op {
+        "-" => Ok(Value::Number(-value.as_number()?)),
+        "!" => Ok(Value::Bool(!value.as_bool()?)),
+        _ => error("Error", format!("Unknown unary op '{}'", op)),
+    }
+}
+
+fn index(target: Value, index: Value) -> R<Value> {
+    if let Some(result) = call_operator("[]", &target, &index)? {
+        return Ok(result);
+    }
+    Ok(methods::get_index(&target, &index)?)
+}
+
+fn slice(target: Value, start: Option<f64>, end: Option<f64>, step: Option<f64>) -> R<Value> {
+    Ok(methods::slice(&target, start, end, step)?)
+}
+
+fn propagate(value: Value) -> R<Result<Value, Value>> {
+    match value {
+        Value::Ok(value) | Value::Some(value) => Ok(Ok(*value)),
+        failed @ (Value::Err(_) | Value::None) => {
+            if STATE.with(|s| s.borrow().calls.is_empty()) {
+                return error("Error", format!("'?' on {} outside of a function", failed));
+            }
+            Ok(Err(failed))
+        }
+        other => error("TypeError", format!("Expected result or option for '?', got {}", other)),
+    }
+}
+
+fn get_field(value: Value, field: &str) -> R<Value> {
+    match (value, field) {
+        (Value::Record(name, fields), _) => fields
+            .into_iter()
+            .find(|(name, _)| name == field)
+            .map(|(_, value)| value)
+            .ok_or_else(|| ErrorValue::new("NameError", format!("Type '{}' has no field '{}'", name, field)).into()),
+        (Value::Error(e), "kind") => Ok(Value::String(e.kind.into())),
+        (Value::Error(e), "message") => Ok(Value::String(e.message.into())),
+        (Value::Error(e), "location") => Ok(e.location.map_or(Value::None, |location| Value::String(location.into()))),
+        (Value::Error(e), "stack") => Ok(Value::List(Rc::new(e.stack.into_iter().map(|frame| Value::String(frame.into())).collect()))),
+        (Value::None, _) => error("Error", format!("Cannot read field '{}' of none (use '?.' for optional values)", field)),
+        (value, _) => error("Error", format!("Value {} has no field '{}'", value, field)),
+    }
+}
+
+fn safe_field(value: Value, field: &str) -> R<Value> {
+    match value {
+        Value::None => Ok(Value::None),
+        Value::Some(value) => get_field(*value, field),
+        value => get_field(value, field),
+    }
+}
+
+fn function_value(function: Function) -> Value {
+    Value::Function(Rc::new(function))
+}
+
+fn define_type(name: &str, constructor: Function, fields: Vec<ast::Param>) -> Value {
+    STATE.with(|s| s.borrow_mut().constructors.insert(name.to_string(), Rc::new(constructor)));
+    Value::Type(name.to_string(), fields)
+}
+
+fn define_trait(name: &str, required: &[&str], defaults: Vec<(&str, Function)>) {
+    let required = required.iter().map(|method| method.to_string()).collect();
+    let defaults = defaults.into_iter().map(|(method, function)| (method.to_string(), function_value(function))).collect();
+    STATE.with(|s| s.borrow_mut().traits.insert(name.to_string(), (required, defaults)));
+}
+
+fn define_impl(declared: Option<Value>, type_name: &str, trait_name: Option<&str>, methods: Vec<(&str, Function)>) -> R<()> {
+    if !matches!(declared, Some(Value::Type(..))) && !BUILTIN_TYPES.contains(&type_name) {
+        return error("NameError", format!("Type '{}' not found", type_name));
+    }
+    let mut table: HashMap<String, Value> = methods.into_iter().map(|(method, function)| (method.to_string(), function_value(function))).collect();
+    if let Some(trait_name) = trait_name {
+        let (required, defaults) = STATE
+            .with(|s| s.borrow().traits.get(trait_name).cloned())
+            .ok_or_else(|| ErrorValue::new("NameError", format!("Trait '{}' not found", trait_name)))?;
+        if let Some(missing) = required.iter().find(|method| !table.contains_key(*method)) {
+            return error("TypeError", format!("type {} does not implement trait {} (missing method '{}')", type_name, trait_name, missing));
+        }
+        for (method, func) in defaults {
+            table.entry(method).or_insert(func);
+        }
+        STATE.with(|s| s.borrow_mut().impls.insert((type_name.to_string(), trait_name.to_string())));
+    }
+    STATE.with(|s| s.borrow_mut().methods.entry(type_name.to_string()).or_default().extend(table));
+    Ok(())
+}
+
+fn deprecate(name: &str, note: Option<&str>) {
+    STATE.with(|s| s.borrow_mut().deprecated.insert(name.to_string(), note.map(str::to_string)));
+}
+
+fn memoize(name: &str) {
+    STATE.with(|s| s.borrow_mut().memos.insert(name.to_string(), HashMap::new()));
+}
+
+fn user_method(type_name: &str, method: &str) -> Option<Value> {
+    STATE.with(|s| s.borrow().methods.get(type_name).and_then(|methods| methods.get(method)).cloned())
+}
+
+fn mutates(receiver: &Value, method: &str) -> bool {
+    methods::is_mutating(receiver, method) && user_method(receiver.type_name(), method).is_none()
+}
+
+fn forbid_mutation(receiver: &Value, method: &str, name: &str) -> R<()> {
+    if mutates(receiver, method) {
+        return error("Error", format!(
+            "Method '{}' changes '{}' and can only be called as a statement or as the value of a declaration or assignment",
+            method, name
+        ));
+    }
+    Ok(())
+}
+
+fn call_native_method(receiver: &mut Value, method: &str, args: Vec<Value>, named: Vec<(String, Value)>) -> R<Value> {
+    if let Some((arg_name, _)) = named.first() {
+        return error("ArgumentError", format!("Method '{}' does not take named argument '{}'", method, arg_name));
+    }
+    Ok(methods::call(receiver, method, args)?)
+}
+
+fn call_method(mut receiver: Value, method: &str, (mut args, named): Args) -> R<Value> {
+    let (type_name, is_static) = match &receiver {
+        Value::Type(name, _) => (name.clone(), true),
+        value => (value.type_name().to_string(), false),
+    };
+    let Some(func) = user_method(&type_name, method) else {
+        if !is_static && methods::available(&type_name).contains(&method) {
+            if let Value::Iterator(_) = receiver {
+                if let Some((arg_name, _)) = named.first() {
+                    return error("ArgumentError", format!("Method '{}' does not take named argument '{}'", method, arg_name));
+                }
+                args.insert(0, receiver);
+                return call_builtin(method, args);
+            }
+            return call_native_method(&mut receiver, method, args, named);
+        }
+        let mut available: Vec<String> = STATE.with(|s| s.borrow().methods.get(&type_name).map(|m| m.keys().cloned().collect()).unwrap_or_default());
+        if !is_static {
+            available.extend(methods::available(&type_name).iter().map(|m| m.to_string()));
+        }
+        available.sort();
+        available.dedup();
+        let available = if available.is_empty() { "none".to_string() } else { available.join(", ") };
+        return error("NameError", format!("Type '{}' has no method '{}' (available: {})", type_name, method, available));
+    };
+    if !is_static {
+        args.insert(0, receiver);
+    }
+    call_function(&format!("{}.{}", type_name, method), &func, args, named)
+}
+
+fn call_operator(op: &str, left: &Value, right: &Value) -> R<Option<Value>> {
+    let (Value::Record(type_name, _), Some(method)) = (left, methods::operator_method(op)) else {
+        return Ok(None);
+    };
+    let Some(func) = user_method(type_name, method) else {
+        if matches!(op, "==" | "!=") || (op == "+" && matches!(right, Value::String(_))) {
+            return Ok(None);
+        }
+        return error("TypeError", format!("Operator '{}' is not defined for type '{}' (define '{}' in an impl block)", op, type_name, method));
+    };
+    let result = call_function(&format!("{}.{}", type_name, method), &func, vec![left.clone(), right.clone()], Vec::new())?;
+    Ok(Some(match op {
+        "==" => Value::Bool(result.as_bool()?),
+        "!=" => Value::Bool(!result.as_bool()?),
+        "<" => Value::Bool(result.as_number()? < 0.0),
+        "<=" => Value::Bool(result.as_number()? <= 0.0),
+        ">" => Value::Bool(result.as_number()? > 0.0),
+        ">=" => Value::Bool(result.as_number()? >= 0.0),
+        _ => result,
+    }))
+}
+
+fn display(value: Value) -> R<String> {
+    if let Value::Record(type_name, _) = &value {
+        if let Some(func) = user_method(type_name, "to_str") {
+            let name = format!("{}.to_str", type_name);
+            return Ok(call_function(&name, &func, vec![value], Vec::new())?.to_string());
+        }
+    }
+    Ok(value.to_string())
+}
+
+fn call(env: &Env, slot: Option<usize>, name: &str, (values, named): Args) -> R<Value> {
+    match env.get(slot, name) {
+        Some(func) => {
+            warn_deprecated(name);
+            if !STATE.with(|s| s.borrow().memos.contains_key(name)) {
+                return call_function(name, &func, values, named);
+            }
+            let key = format!("{:?}{:?}", values, named);
+            if let Some(value) = STATE.with(|s| s.borrow().memos[name].get(&key).cloned()) {
+                return Ok(value);
+            }
+            let value = call_function(name, &func, values, named)?;
+            STATE.with(|s| s.borrow_mut().memos.get_mut(name).unwrap().insert(key, value.clone()));
+            Ok(value)
+        }
+        None if named.is_empty() => call_builtin(name, values),
+        None => error("ArgumentError", format!("Builtin '{}' does not take named argument '{}'", name, named[0].0)),
+    }
+}
+
+fn bind_args(name: &str, params: &[Parameter], args: Vec<Value>, mut named: Vec<(String, Value)>, local_env: &mut Env) -> R<()> {
+    let variadic = params.last().map_or(false, |p| p.variadic);
+    if !variadic && args.len() > params.len() {
+        return error("ArgumentError", format!("Expected at most {} args, got {}", params.len(), args.len()));
+    }
+    for (i, (arg_name, _)) in named.iter().enumerate() {
+        if !params.iter().any(|p| p.name == arg_name && !p.variadic) {
+            return error("ArgumentError", format!("Unknown named argument '{}' in call to '{}'", arg_name, name));
+        }
+        if named[..i].iter().any(|(other, _)| other == arg_name) {
+            return error("ArgumentError", format!("Duplicate named argument '{}' in call to '{}'", arg_name, name));
+        }
+    }
+    let mut args = args.into_iter();
+    for (slot, param) in params.iter().enumerate() {
+        let value = if param.variadic {
+            Value::List(Rc::new(args.by_ref().collect()))
+        } else if let Some(value) = args.next() {
+            if named.iter().any(|(arg_name, _)| arg_name == param.name) {
+                return error("ArgumentError", format!("Named argument '{}' was already given by position in call to '{}'", param.name, name));
+            }
+            value
+        } else if let Some(position) = named.iter().position(|(arg_name, _)| arg_name == param.name) {
+            named.remove(position).1
+        } else if let Some(default) = param.default {
+            default(local_env)?
+        } else {
+            return error("ArgumentError", format!("Missing argument '{}' in call to '{}'", param.name, name));
+        };
+        if !param.variadic && is_trait(param.type_anno) {
+            check_type(&value, param.type_anno)?;
+        }
+        local_env.slots[slot] = Some(Binding { value, mutability: Mutability::Immutable });
+    }
+    Ok(())
+}
+
+fn call_function(name: &str, func: &Value, args: Vec<Value>, named: Vec<(String, Value)>) -> R<Value> {
+    match func {
+        Value::Function(function) => {
+            let mut local_env = Env::new(function.slots);
+            bind_args(name, function.params, args, named, &mut local_env)?;
+            let body = match function.code {
+                Code::Generator(steps) => {
+                    let generator = Generator { name: name.to_string(), env: local_env, stack: vec![Frame::Block(steps, 0)] };
+                    return Ok(Value::Iterator(Iter::new(LazyIter::Generator(generator))));
+                }
+                Code::Body(body) => body,
+            };
+            STATE.with(|s| s.borrow_mut().calls.push(name.to_string()));
+            let result = body(&mut local_env);
+            STATE.with(|s| s.borrow_mut().calls.pop());
+            match result {
+                Err(Signal::Return(value)) => Ok(value),
+                Err(Signal::Break) | Err(Signal::Continue) => error("Error", format!("'break' or 'continue' escaped function '{}'", name)),
+                Err(e) => Err(e),
+                Ok(()) => match function.ret_type {
+                    Some(ret_type) if ret_type != "void" => error("Error", "Missing return value"),
+                    _ => Ok(Value::None),
+                },
+            }
+        }
+        Value::Type(type_name, fields) => {
+            let constructor = STATE
+                .with(|s| s.borrow().constructors.get(type_name).cloned())
+                .ok_or_else(|| ErrorValue::new("NameError", format!("Type '{}' not found", type_name)))?;
+            let mut local_env = Env::new(constructor.slots);
+            bind_args(type_name, constructor.params, args, named, &mut local_env)?;
+            let mut values = Vec::new();
+            for (field, binding) in fields.iter().zip(local_env.slots) {
+                let value = binding.unwrap().value;
+                check_type(&value, &field.type_anno)?;
+                values.push((field.name.clone(), value));
+            }
+            Ok(Value::Record(type_name.clone(), values))
+        }
+        _ => error("TypeError", format!("'{}' is not a function", name)),
+    }
+}
+
+fn call_builtin(name: &str, mut args: Vec<Value>) -> R<Value> {
+    match (name, args.len()) {
+        ("error", 1) => Ok(Value::Error(Box::new(ErrorValue::new("Error", args[0].to_string())))),
+        ("error", 2) => Ok(Value::Error(Box::new(ErrorValue::new(&args[0].to_string(), args[1].to_string())))),
+        (kind, 1) if ERROR_KINDS.contains(&kind) => Ok(Value::Error(Box::new(ErrorValue::new(kind, args[0].to_string())))),
+        ("ok", 1) => Ok(Value::Ok(Box::new(args.remove(0)))),
+        ("err", 1) => Ok(Value::Err(Box::new(args.remove(0)))),
+        ("some", 1) => Ok(Value::Some(Box::new(args.remove(0)))),
+        ("unwrap", 1) => match args.remove(0) {
+            Value::Ok(value) | Value::Some(value) => Ok(*value),
+            Value::Err(failed) => match *failed {
+                Value::Error(failed) => Err(Signal::Error(*failed)),
+                other => error("Error", format!("unwrap called on err({})", other)),
+            },
+            Value::None => error("Error", "unwrap called on none"),
+            other => error("TypeError", format!("Expected result or option, got {}", other)),
+        },
+        ("unwrap_or", 2) => {
+            let default = args.pop().unwrap();
+            match args.remove(0) {
+                Value::Ok(value) | Value::Some(value) => Ok(*value),
+                Value::Err(_) | Value::None => Ok(default),
+                other => error("TypeError", format!("Expected result or option, got {}", other)),
+            }
+        }
+        ("map_err", 2) => {
+            let func = args.pop().unwrap();
+            match args.remove(0) {
+                Value::Err(error) => Ok(Value::Err(Box::new(call_function(name, &func, vec![*error], Vec::new())?))),
+                ok @ Value::Ok(_) => Ok(ok),
+                other => error("TypeError", format!("Expected result, got {}", other)),
+            }
+        }
+        ("is_ok", 1) => Ok(Value::Bool(matches!(args[0], Value::Ok(_)))),
+        ("is_err", 1) => Ok(Value::Bool(matches!(args[0], Value::Err(_)))),
+        ("is_some", 1) => Ok(Value::Bool(matches!(args[0], Value::Some(_)))),
+        ("is_none", 1) => Ok(Value::Bool(matches!(args[0], Value::None))),
+        ("divmod", 2) => {
+            let (a, b) = (args[0].as_number()?, args[1].as_number()?);
+            if b == 0.0 {
+                return error("ZeroDivisionError", "Division by zero");
+            }
+            let quotient = (a / b).floor();
+            Ok(Value::Tuple(vec![Value::Number(quotient), Value::Number(a - b * quotient)]))
+        }
+        ("iter", 1) => Ok(Value::Iterator(to_iter(args.remove(0))?)),
+        ("next", 1) => {
+            let iter = to_iter(args.remove(0))?;
+            Ok(next_value(&iter)?.map_or(Value::None, |value| Value::Some(Box::new(value))))
+        }
+        ("collect", 1) => {
+            let iter = to_iter(args.remove(0))?;
+            let mut items = Vec::new();
+            while let Some(value) = next_value(&iter)? {
+                items.push(value);
+            }
+            Ok(Value::List(Rc::new(items)))
+        }
+        ("take", 2) | ("skip", 2) => {
+            let count = args[1].as_number()?.max(0.0) as usize;
+            let inner = to_iter(args.remove(0))?;
+            let lazy = if name == "take" { LazyIter::Take(inner, count) } else { LazyIter::Skip(inner, count) };
+            Ok(Value::Iterator(Iter::new(lazy)))
+        }
+        ("zip", 2) | ("chain", 2) => {
+            let second = to_iter(args.pop().unwrap())?;
+            let first = to_iter(args.pop().unwrap())?;
+            let lazy = if name == "zip" { LazyIter::Zip(first, second) } else { LazyIter::Chain(first, second) };
+            Ok(Value::Iterator(Iter::new(lazy)))
+        }
+        ("enumerate", 1) => Ok(Value::Iterator(Iter::new(LazyIter::Enumerate(to_iter(args.remove(0))?, 0)))),
+        (_, n) if BUILTINS.contains(&name) || ERROR_KINDS.contains(&name) => error("ArgumentError", format!("Builtin '{}' called with the wrong number of args, got {}", name, n)),
+        _ => error("NameError", format!("Function '{}' not found", name)),
+    }
+}
+
+fn to_iter(value: Value) -> R<Iter> {
+    let lazy = match value {
+        Value::Iterator(iter) => return Ok(iter),
+        Value::List(items) => LazyIter::List(items, 0),
+        Value::Tuple(items) => LazyIter::Items(items.into_iter()),
+        Value::String(s) => LazyIter::Chars(s, 0),
+        Value::Map(entries) => LazyIter::Keys(entries, 0),
+        other => return error("TypeError", format!("Expected list, str, map or iterator to iterate over, got {}", other)),
+    };
+    Ok(Iter::new(lazy))
+}
+
+fn next_value(iter: &Iter) -> R<Option<Value>> {
+    iter.next_with(&mut |lazy| match lazy {
+        LazyIter::Generator(generator) => resume(generator),
+        _ => error("Error", "Iterator belongs to another engine"),
+    })
+}
+
+// Types a fully annotated function works on unboxed; `unbox` fails on any other value so
+// the caller can fall back to the dynamic body.
+trait Native: Sized {
+    fn unbox(value: &Value) -> Option<Self>;
+    fn boxed(self) -> Value;
+}
+
+impl Native for f64 {
+    fn unbox(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn boxed(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl Native for bool {
+    fn unbox(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn boxed(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl Native for String {
+    fn unbox(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+
+    fn boxed(self) -> Value {
+        Value::String(self.into())
+    }
+}
+
+impl Native for Value {
+    fn unbox(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+
+    fn boxed(self) -> Value {
+        self
+    }
+}
+
+impl<T: Native> Native for Vec<T> {
+    fn unbox(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(items) => items.iter().map(T::unbox).collect(),
+            _ => None,
+        }
+    }
+
+    fn boxed(self) -> Value {
+        Value::List(Rc::new(self.into_iter().map(T::boxed).collect()))
+    }
+}
+
+fn native_arg<T: Native>(env: &Env, slot: usize) -> Option<T> {
+    env.slots[slot].as_ref().and_then(|binding| T::unbox(&binding.value))
+}
+
+fn unboxed<T: Native>(value: Value, type_name: &str) -> R<T> {
+    match T::unbox(&value) {
+        Some(native) => Ok(native),
+        None => error("TypeError", format!("Expected {}, got {}", type_name, value)),
+    }
+}
+
+// True while the global `name` is still the function whose dynamic entry is `body`
+fn defines(name: &str, body: fn(&mut Env) -> R<()>) -> bool {
+    GLOBALS.with(|g| match g.borrow().get(name) {
+        Some(Binding { value: Value::Function(function), .. }) => matches!(function.code, Code::Body(code) if code as usize == body as usize),
+        _ => false,
+    })
+}
+
+fn enter<T>(name: &str, body: impl FnOnce() -> R<T>) -> R<T> {
+    STATE.with(|s| s.borrow_mut().calls.push(name.to_string()));
+    let result = body();
+    STATE.with(|s| s.borrow_mut().calls.pop());
+    result
+}
+
+// Raised as if the call had already returned, like the dynamic body's missing value
+fn missing_return<T>() -> R<T> {
+    let mut error = ErrorValue::new("Error", "Missing return value");
+    error.stack = call_stack().into_iter().skip(1).collect();
+    Err(Signal::Error(error))
+}
+
+fn divide(left: f64, right: f64) -> R<f64> {
+    if right == 0.0 {
+        return error("ZeroDivisionError", "Division by zero");
+    }
+    Ok(left / right)
+}
+
+fn at(index: f64, len: usize) -> R<usize> {
+    Ok(methods::position(&Value::Number(index), len, "list")?)
+}
+
+// A generator body is a tree of steps: statements without a yield run as one compiled
+// function, the rest are unfolded so the frame stack can suspend between them.
+pub enum Step {
+    Run(fn(&mut Env) -> R<()>),
+    Yield(fn(&mut Env) -> R<Value>),
+    If(fn(&mut Env) -> R<bool>, &'static [Step], Option<&'static [Step]>),
+    While(fn(&mut Env) -> R<bool>, &'static [Step]),
+    For(fn(&mut Env) -> R<Value>, fn(&mut Env, Value) -> R<()>, &'static [Step]),
+    Try(&'static [Step], &'static [Catch], Option<&'static [Step]>),
+    Match(fn(&mut Env) -> R<Option<usize>>, &'static [&'static [Step]]),
+}
+
+pub struct Catch {
+    slot: Option<usize>,
+    ident: &'static str,
+    kind: Option<&'static str>,
+    body: &'static [Step],
+}
+
+pub struct Generator {
+    name: String,
+    env: Env,
+    stack: Vec<Frame>,
+}
+
+enum Frame {
+    Block(&'static [Step], usize),
+    While(fn(&mut Env) -> R<bool>, &'static [Step]),
+    For(fn(&mut Env, Value) -> R<()>, Iter, &'static [Step]),
+    Try(&'static [Catch], Option<&'static [Step]>),
+    Finally(&'static [Step]),
+}
+
+fn find_catch<'a>(catches: &'a [Catch], error: &ErrorValue) -> Option<&'a Catch> {
+    catches.iter().find(|catch| catch.kind.map_or(true, |k| error.matches(k)))
+}
+
+fn run_steps(steps: &[Step], env: &mut Env) -> R<()> {
+    for step in steps {
+        match step {
+            Step::Run(run) => run(env)?,
+            Step::Yield(_) => return error("Error", "'yield' is only allowed inside a function"),
+            Step::If(condition, then_block, else_block) => {
+                if condition(env)? {
+                    run_steps(then_block, env)?;
+                } else if let Some(else_block) = else_block {
+                    run_steps(else_block, env)?;
+                }
+            }
+            Step::While(condition, body) => {
+                while condition(env)? {
+                    match run_steps(body, env) {
+                        Err(Signal::Break) => break,
+                        Err(Signal::Continue) => continue,
+                        Err(e) => return Err(e),
+                        Ok(_) => {}
+                    }
+                }
+            }
+            Step::For(iterable, bind, body) => {
+                let iter = to_iter(iterable(env)?)?;
+                while let Some(value) = next_value(&iter)? {
+                    bind(env, value)?;
+                    match run_steps(body, env) {
+                        Err(Signal::Break) => break,
+                        Err(Signal::Continue) => continue,
+                        Err(e) => return Err(e),
+                        Ok(_) => {}
+                    }
+                }
+            }
+            Step::Try(try_block, catches, finally_block) => {
+                let mut result = run_steps(try_block, env);
+                if let Err(Signal::Error(error)) = result {
+                    result = match find_catch(catches, &error) {
+                        Some(catch) => {
+                            env.define(catch.slot, catch.ident, Value::Error(Box::new(error.clone())), Mutability::Immutable)?;
+                            begin_catch(error);
+                            let caught = run_steps(catch.body, env);
+                            end_catch();
+                            caught
+                        }
+                        None => Err(Signal::Error(error)),
+                    };
+                }
+                if let Some(finally_block) = finally_block {
+                    run_steps(finally_block, env)?;
+                }
+                result?;
+            }
+            Step::Match(select, arms) => {
+                if let Some(arm) = select(env)? {
+                    run_steps(arms[arm], env)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resume(generator: &mut Generator) -> R<Option<Value>> {
+    STATE.with(|s| s.borrow_mut().calls.push(generator.name.clone()));
+    let mut result = Ok(None);
+    while !generator.stack.is_empty() {
+        result = match advance(generator) {
+            Ok(None) => continue,
+            Ok(Some(value)) => Ok(Some(value)),
+            Err(signal) => match unwind(generator, signal) {
+                Ok(()) => continue,
+                Err(signal) => Err(signal),
+            },
+        };
+        break;
+    }
+    STATE.with(|s| s.borrow_mut().calls.pop());
+    if !matches!(result, Ok(Some(_))) {
+        generator.stack.clear();
+    }
+    result
+}
+
+fn advance(generator: &mut Generator) -> R<Option<Value>> {
+    match generator.stack.last_mut().unwrap() {
+        Frame::Block(steps, pc) => match (*steps).get(*pc) {
+            Some(step) => {
+                *pc += 1;
+                return advance_step(step, generator);
+            }
+            None => {
+                generator.stack.pop();
+            }
+        },
+        Frame::While(condition, body) => {
+            let (condition, body) = (*condition, *body);
+            if condition(&mut generator.env)? {
+                generator.stack.push(Frame::Block(body, 0));
+            } else {
+                generator.stack.pop();
+            }
+        }
+        Frame::For(bind, iter, body) => {
+            let (bind, iter, body) = (*bind, iter.clone(), *body);
+            match next_value(&iter)? {
+                Some(value) => {
+                    bind(&mut generator.env, value)?;
+                    generator.stack.push(Frame::Block(body, 0));
+                }
+                None => {
+                    generator.stack.pop();
+                }
+            }
+        }
+        Frame::Try(_, finally_block) => {
+            let finally_block = finally_block.take();
+            generator.stack.pop();
+            if let Some(finally_block) = finally_block {
+                generator.stack.push(Frame::Block(finally_block, 0));
+            }
+        }
+        Frame::Finally(finally_block) => {
+            let finally_block = std::mem::take(finally_block);
+            generator.stack.pop();
+            generator.stack.push(Frame::Block(finally_block, 0));
+        }
+    }
+    Ok(None)
+}
+
+fn advance_step(step: &'static Step, generator: &mut Generator) -> R<Option<Value>> {
+    let env = &mut generator.env;
+    match step {
+        Step::Run(run) => run(env)?,
+        Step::Yield(value) => return Ok(Some(value(env)?)),
+        Step::If(condition, then_block, else_block) => {
+            let block = if condition(env)? { Some(*then_block) } else { *else_block };
+            if let Some(block) = block {
+                generator.stack.push(Frame::Block(block, 0));
+            }
+        }
+        Step::While(condition, body) => generator.stack.push(Frame::While(*condition, body)),
+        Step::For(iterable, bind, body) => {
+            let iter = to_iter(iterable(env)?)?;
+            generator.stack.push(Frame::For(*bind, iter, body));
+        }
+        Step::Try(try_block, catches, finally_block) => {
+            generator.stack.push(Frame::Try(catches, *finally_block));
+            generator.stack.push(Frame::Block(try_block, 0));
+        }
+        Step::Match(select, arms) => {
+            if let Some(arm) = select(env)? {
+                generator.stack.push(Frame::Block(arms[arm], 0));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn unwind(generator: &mut Generator, signal: Signal) -> R<()> {
+    while let Some(frame) = generator.stack.pop() {
+        match frame {
+            Frame::While(..) | Frame::For(..) if matches!(signal, Signal::Break) => return Ok(()),
+            frame @ (Frame::While(..) | Frame::For(..)) if matches!(signal, Signal::Continue) => {
+                generator.stack.push(frame);
+                return Ok(());
+            }
+            Frame::Try(catches, finally_block) => {
+                if let Signal::Error(error) = &signal {
+                    if let Some(catch) = find_catch(catches, error) {
+                        if let Some(finally_block) = finally_block {
+                            generator.stack.push(Frame::Finally(finally_block));
+                        }
+                        generator.env.define(catch.slot, catch.ident, Value::Error(Box::new(error.clone())), Mutability::Immutable)?;
+                        generator.stack.push(Frame::Block(catch.body, 0));
+                        return Ok(());
+                    }
+                }
+                if let Some(finally_block) = finally_block {
+                    run_steps(finally_block, &mut generator.env)?;
+                }
+            }
+            Frame::Finally(finally_block) => run_steps(finally_block, &mut generator.env)?,
+            _ => {}
+        }
+    }
+    match signal {
+        Signal::Return(_) => Ok(()),
+        Signal::Break | Signal::Continue => error("Error", format!("'break' or 'continue' escaped function '{}'", generator.name)),
+        error => Err(error),
+    }
+}
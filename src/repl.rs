@@ -5,8 +5,7 @@ use std::collections::VecDeque;
 use std::io::{self, Write};
 use std::fs;
 
-pub fn start() {
-    let mut env = std::collections::HashMap::new();
+pub fn start() -> Result<(), String> {
     let mut history = VecDeque::new();
     let history_file = ".velvet_history";
     if let Ok(content) = fs::read_to_string(history_file) {
@@ -16,13 +15,13 @@ pub fn start() {
     println!("\x1b[1;34mVelvet REPL v1.4 (exit, clear)\x1b[0m");
     loop {
         print!("\x1b[1;36m>> \x1b[0m");
-        io::stdout().flush()?;
+        io::stdout().flush().map_err(|e| e.to_string())?;
         let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        io::stdin().read_line(&mut input).map_err(|e| e.to_string())?;
         let input = input.trim();
 
         if input == "exit" {
-            fs::write(history_file, history.iter().collect::<Vec<_>>().join("\n"))?;
+            fs::write(history_file, history.iter().cloned().collect::<Vec<_>>().join("\n")).map_err(|e| e.to_string())?;
             break;
         }
         if input == "clear" {
@@ -43,4 +42,5 @@ pub fn start() {
             Err(e) => cli::error(&e),
         }
     }
+    Ok(())
 }
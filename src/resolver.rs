@@ -0,0 +1,314 @@
+use crate::ast::*;
+use crate::interpreter::contains_yield;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Default)]
+pub struct Scope {
+    pub names: HashMap<String, usize>,
+    pub len: usize,
+}
+
+pub struct Function {
+    pub params: Vec<Param>,
+    pub ret_type: Option<String>,
+    pub body: Vec<Statement>,
+    pub scope: Rc<Scope>,
+    pub generator: bool,
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<fn>")
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[derive(Default)]
+struct Resolver {
+    scope: Scope,
+    shadows: Vec<HashMap<String, usize>>,
+}
+
+pub fn program(statements: &[Statement]) -> (Vec<Statement>, Rc<Scope>) {
+    let mut resolver = Resolver::default();
+    let statements = resolver.block(statements);
+    (statements, Rc::new(resolver.scope))
+}
+
+pub fn function(params: &[Param], ret_type: &Option<String>, body: &[Statement]) -> Rc<Function> {
+    let mut resolver = Resolver::default();
+    for (slot, param) in params.iter().enumerate() {
+        resolver.scope.names.insert(param.name.clone(), slot);
+    }
+    resolver.scope.len = params.len();
+    let mut names = Vec::new();
+    declarations(body, &mut names);
+    for name in names {
+        resolver.local(&name);
+    }
+    let params = params
+        .iter()
+        .map(|param| Param { default: param.default.as_ref().map(|d| resolver.expr(d)), ..param.clone() })
+        .collect();
+    let body = resolver.block(body);
+    Rc::new(Function { params, ret_type: ret_type.clone(), generator: contains_yield(&body), body, scope: Rc::new(resolver.scope) })
+}
+
+pub fn declarations(stmts: &[Statement], names: &mut Vec<String>) {
+    for stmt in stmts {
+        match stmt {
+            Statement::Val(name, expr, _) | Statement::Let(name, expr, _) => {
+                names.push(name.clone());
+                if let Some(expr) = expr {
+                    receiver(expr, names);
+                }
+            }
+            Statement::Const(name, ..) | Statement::Fun(name, ..) | Statement::Type(name, _) => names.push(name.clone()),
+            Statement::Unpack(target, expr, _) | Statement::Assign(target, expr) => {
+                target_names(target, names);
+                receiver(expr, names);
+            }
+            Statement::Say(expr) | Statement::Expr(expr) | Statement::Return(expr) | Statement::Yield(expr) => receiver(expr, names),
+            Statement::If(_, then_block, else_block) => {
+                declarations(then_block, names);
+                declarations(else_block.as_deref().unwrap_or_default(), names);
+            }
+            Statement::For(target, _, body) => {
+                target_names(target, names);
+                declarations(body, names);
+            }
+            Statement::While(_, body) | Statement::Test(_, body) => declarations(body, names),
+            Statement::Try(try_block, catches, finally_block) => {
+                declarations(try_block, names);
+                for (ident, _, block) in catches {
+                    names.push(ident.clone());
+                    declarations(block, names);
+                }
+                declarations(finally_block.as_deref().unwrap_or_default(), names);
+            }
+            Statement::Match(_, branches) => {
+                for (pattern, block) in branches {
+                    pattern_names(pattern, names);
+                    declarations(block, names);
+                }
+            }
+            Statement::Attributed(_, target) => declarations(std::slice::from_ref(target), names),
+            _ => {}
+        }
+    }
+}
+
+// The top-level functions a compiled module exports: those marked `#[export]`, or all of them
+// when none is, each once
+pub fn exports(stmts: &[Statement]) -> Vec<String> {
+    let mut all = Vec::new();
+    let mut marked = Vec::new();
+    for stmt in stmts {
+        let (attributes, target) = match stmt {
+            Statement::Attributed(attributes, target) => (&attributes[..], target.as_ref()),
+            stmt => (&[][..], stmt),
+        };
+        let Statement::Fun(name, ..) = target else { continue };
+        if !all.contains(name) {
+            all.push(name.clone());
+        }
+        if attributes.iter().any(|a| a.name == "export") && !marked.contains(name) {
+            marked.push(name.clone());
+        }
+    }
+    if marked.is_empty() { all } else { marked }
+}
+
+fn receiver(expr: &Expr, names: &mut Vec<String>) {
+    if let Expr::MethodCall(target, _, _) = expr {
+        if let Expr::Ident(name) = target.as_ref() {
+            names.push(name.clone());
+        }
+    }
+}
+
+pub fn target_names(target: &Target, names: &mut Vec<String>) {
+    match target {
+        Target::Name(name) if name == "_" => {}
+        Target::Name(name) => names.push(name.clone()),
+        Target::Tuple(targets) => targets.iter().for_each(|t| target_names(t, names)),
+        Target::List(targets, rest) => {
+            targets.iter().for_each(|t| target_names(t, names));
+            if let Some(rest) = rest.as_ref().filter(|rest| *rest != "_") {
+                names.push(rest.clone());
+            }
+        }
+    }
+}
+
+pub fn pattern_names(pattern: &Pattern, names: &mut Vec<String>) {
+    match pattern {
+        Pattern::Bind(name) => names.push(name.clone()),
+        Pattern::Variant(_, Some(inner)) => pattern_names(inner, names),
+        _ => {}
+    }
+}
+
+impl Resolver {
+    fn local(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.scope.names.get(name) {
+            return slot;
+        }
+        self.scope.names.insert(name.to_string(), self.scope.len);
+        self.scope.len += 1;
+        self.scope.len - 1
+    }
+
+    fn shadow(&mut self, name: &str) -> String {
+        let slot = self.scope.len;
+        let renamed = format!("{}#{}", name, slot);
+        self.local(&renamed);
+        self.shadows.last_mut().unwrap().insert(name.to_string(), slot);
+        renamed
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.shadows.iter().rev().find_map(|shadow| shadow.get(name)).or_else(|| self.scope.names.get(name)).copied()
+    }
+
+    fn target(&mut self, target: &Target) -> Target {
+        match target {
+            Target::Name(name) if name == "_" => target.clone(),
+            Target::Name(name) => Target::Name(self.shadow(name)),
+            Target::Tuple(targets) => Target::Tuple(targets.iter().map(|t| self.target(t)).collect()),
+            Target::List(targets, rest) => {
+                let targets = targets.iter().map(|t| self.target(t)).collect();
+                let rest = rest.as_ref().map(|rest| if rest == "_" { rest.clone() } else { self.shadow(rest) });
+                Target::List(targets, rest)
+            }
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) -> Pattern {
+        match pattern {
+            Pattern::Bind(name) => Pattern::Bind(self.shadow(name)),
+            Pattern::Variant(name, Some(inner)) => Pattern::Variant(name.clone(), Some(Box::new(self.pattern(inner)))),
+            _ => pattern.clone(),
+        }
+    }
+
+    fn block(&mut self, stmts: &[Statement]) -> Vec<Statement> {
+        stmts.iter().map(|stmt| self.stmt(stmt)).collect()
+    }
+
+    fn stmt(&mut self, stmt: &Statement) -> Statement {
+        match stmt {
+            Statement::Say(expr) => Statement::Say(self.expr(expr)),
+            Statement::Val(name, expr, type_anno) => Statement::Val(name.clone(), expr.as_ref().map(|e| self.expr(e)), type_anno.clone()),
+            Statement::Let(name, expr, type_anno) => Statement::Let(name.clone(), expr.as_ref().map(|e| self.expr(e)), type_anno.clone()),
+            Statement::Const(name, expr, type_anno) => Statement::Const(name.clone(), self.expr(expr), type_anno.clone()),
+            Statement::If(condition, then_block, else_block) => {
+                Statement::If(self.expr(condition), self.block(then_block), else_block.as_ref().map(|b| self.block(b)))
+            }
+            Statement::For(target, expr, body) => Statement::For(target.clone(), self.expr(expr), self.block(body)),
+            Statement::While(condition, body) => Statement::While(self.expr(condition), self.block(body)),
+            Statement::Try(try_block, catches, finally_block) => Statement::Try(
+                self.block(try_block),
+                catches.iter().map(|(ident, kind, block)| (ident.clone(), kind.clone(), self.block(block))).collect(),
+                finally_block.as_ref().map(|b| self.block(b)),
+            ),
+            Statement::Throw(expr, line) => Statement::Throw(expr.as_ref().map(|e| self.expr(e)), *line),
+            Statement::Match(expr, branches) => {
+                Statement::Match(self.expr(expr), branches.iter().map(|(pattern, block)| (pattern.clone(), self.block(block))).collect())
+            }
+            Statement::Unpack(target, expr, mutable) => Statement::Unpack(target.clone(), self.expr(expr), *mutable),
+            Statement::Assign(target, expr) => Statement::Assign(target.clone(), self.expr(expr)),
+            Statement::Expr(expr) => Statement::Expr(self.expr(expr)),
+            Statement::Return(expr) => Statement::Return(self.expr(expr)),
+            Statement::Yield(expr) => Statement::Yield(self.expr(expr)),
+            Statement::Test(name, body) => Statement::Test(name.clone(), self.block(body)),
+            Statement::Attributed(attributes, target) => Statement::Attributed(attributes.clone(), Box::new(self.stmt(target))),
+            Statement::Fun(..) | Statement::Type(..) | Statement::Trait(..) | Statement::Impl(..) | Statement::Break | Statement::Continue | Statement::Import(..) => stmt.clone(),
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) -> Vec<Expr> {
+        exprs.iter().map(|e| self.expr(e)).collect()
+    }
+
+    fn boxed(&mut self, expr: &Expr) -> Box<Expr> {
+        Box::new(self.expr(expr))
+    }
+
+    fn clauses(&mut self, clauses: &[Clause]) -> Vec<Clause> {
+        clauses
+            .iter()
+            .map(|clause| match clause {
+                Clause::For(target, iterable) => {
+                    let iterable = self.expr(iterable);
+                    Clause::For(self.target(target), iterable)
+                }
+                Clause::If(condition) => Clause::If(self.expr(condition)),
+            })
+            .collect()
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Ident(name) if name != "none" => match self.lookup(name) {
+                Some(slot) => Expr::Local(slot, name.clone()),
+                None => expr.clone(),
+            },
+            Expr::String(_) | Expr::Number(_) | Expr::Bool(_) | Expr::Ident(_) | Expr::Local(..) => expr.clone(),
+            Expr::Binary(left, op, right) => Expr::Binary(self.boxed(left), op.clone(), self.boxed(right)),
+            Expr::Unary(op, inner) => Expr::Unary(op.clone(), self.boxed(inner)),
+            Expr::Call(name, args) => Expr::Call(name.clone(), self.exprs(args)),
+            Expr::Named(name, inner) => Expr::Named(name.clone(), self.boxed(inner)),
+            Expr::MethodCall(target, method, args) => Expr::MethodCall(self.boxed(target), method.clone(), self.exprs(args)),
+            Expr::List(elements) => Expr::List(self.exprs(elements)),
+            Expr::Tuple(elements) => Expr::Tuple(self.exprs(elements)),
+            Expr::Map(entries) => Expr::Map(entries.iter().map(|(k, v)| (self.expr(k), self.expr(v))).collect()),
+            Expr::ListComp(element, clauses) => {
+                self.shadows.push(HashMap::new());
+                let clauses = self.clauses(clauses);
+                let element = self.boxed(element);
+                self.shadows.pop();
+                Expr::ListComp(element, clauses)
+            }
+            Expr::MapComp(key, value, clauses) => {
+                self.shadows.push(HashMap::new());
+                let clauses = self.clauses(clauses);
+                let (key, value) = (self.boxed(key), self.boxed(value));
+                self.shadows.pop();
+                Expr::MapComp(key, value, clauses)
+            }
+            Expr::Index(target, index) => Expr::Index(self.boxed(target), self.boxed(index)),
+            Expr::Slice(target, start, end, step) => Expr::Slice(
+                self.boxed(target),
+                start.as_ref().map(|e| self.boxed(e)),
+                end.as_ref().map(|e| self.boxed(e)),
+                step.as_ref().map(|e| self.boxed(e)),
+            ),
+            Expr::Field(target, field) => Expr::Field(self.boxed(target), field.clone()),
+            Expr::SafeField(target, field) => Expr::SafeField(self.boxed(target), field.clone()),
+            Expr::Propagate(inner) => Expr::Propagate(self.boxed(inner)),
+            Expr::If(condition, then_expr, else_expr) => Expr::If(self.boxed(condition), self.boxed(then_expr), self.boxed(else_expr)),
+            Expr::Match(subject, arms) => {
+                let subject = self.boxed(subject);
+                let arms = arms
+                    .iter()
+                    .map(|(pattern, arm)| {
+                        self.shadows.push(HashMap::new());
+                        let pattern = self.pattern(pattern);
+                        let arm = self.expr(arm);
+                        self.shadows.pop();
+                        (pattern, arm)
+                    })
+                    .collect();
+                Expr::Match(subject, arms)
+            }
+        }
+    }
+}
@@ -1,41 +1,223 @@
+use std::cell::RefCell;
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
-    String(String),
+    String(Rc<str>),
     Number(f64),
     Bool(bool),
-    List(Vec<Value>),
-    Function(Vec<(String, String)>, Option<String>, Vec<super::ast::Statement>),
+    List(Rc<Vec<Value>>),
+    Tuple(Vec<Value>),
+    Map(Rc<Vec<(Value, Value)>>),
+    Iterator(Iter),
+    Function(Rc<crate::resolver::Function>),
+    Compiled(Rc<crate::vm::Function>),
+    Type(String, Vec<super::ast::Param>),
+    Record(String, Vec<(String, Value)>),
+    Error(Box<ErrorValue>),
+    Ok(Box<Value>),
+    Err(Box<Value>),
+    Some(Box<Value>),
     None,
+    Unset,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorValue {
+    pub kind: String,
+    pub message: String,
+    pub location: Option<String>,
+    pub stack: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Iter(pub Rc<RefCell<LazyIter>>);
+
+pub enum LazyIter {
+    Items(std::vec::IntoIter<Value>),
+    List(Rc<Vec<Value>>, usize),
+    Keys(Rc<Vec<(Value, Value)>>, usize),
+    Chars(Rc<str>, usize),
+    Generator(crate::interpreter::Generator),
+    Coroutine(crate::vm::Coroutine),
+    Take(Iter, usize),
+    Skip(Iter, usize),
+    Zip(Iter, Iter),
+    Enumerate(Iter, usize),
+    Chain(Iter, Iter),
+}
+
+impl Iter {
+    pub fn new(lazy: LazyIter) -> Self {
+        Iter(Rc::new(RefCell::new(lazy)))
+    }
+
+    pub fn next_with<E: From<ErrorValue>>(&self, resume: &mut dyn FnMut(&mut LazyIter) -> Result<Option<Value>, E>) -> Result<Option<Value>, E> {
+        let mut lazy = self.0.try_borrow_mut().map_err(|_| ErrorValue::new("Error", "Generator is already running"))?;
+        match &mut *lazy {
+            LazyIter::Items(items) => Ok(items.next()),
+            LazyIter::List(items, index) => {
+                *index += 1;
+                Ok(items.get(*index - 1).cloned())
+            }
+            LazyIter::Keys(entries, index) => {
+                *index += 1;
+                Ok(entries.get(*index - 1).map(|(key, _)| key.clone()))
+            }
+            LazyIter::Chars(s, offset) => {
+                let Some(c) = s[*offset..].chars().next() else { return Ok(None) };
+                *offset += c.len_utf8();
+                Ok(Some(Value::String(c.to_string().into())))
+            }
+            LazyIter::Take(inner, remaining) => {
+                if *remaining == 0 {
+                    return Ok(None);
+                }
+                *remaining -= 1;
+                inner.next_with(resume)
+            }
+            LazyIter::Skip(inner, remaining) => {
+                while *remaining > 0 {
+                    *remaining -= 1;
+                    if inner.next_with(resume)?.is_none() {
+                        return Ok(None);
+                    }
+                }
+                inner.next_with(resume)
+            }
+            LazyIter::Zip(first, second) => match first.next_with(resume)? {
+                Some(a) => Ok(second.next_with(resume)?.map(|b| Value::Tuple(vec![a, b]))),
+                None => Ok(None),
+            },
+            LazyIter::Enumerate(inner, index) => {
+                let Some(value) = inner.next_with(resume)? else { return Ok(None) };
+                *index += 1;
+                Ok(Some(Value::Tuple(vec![Value::Number((*index - 1) as f64), value])))
+            }
+            LazyIter::Chain(first, second) => match first.next_with(resume)? {
+                Some(value) => Ok(Some(value)),
+                None => second.next_with(resume),
+            },
+            generator => resume(generator),
+        }
+    }
+}
+
+impl PartialEq for Iter {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Iter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<iterator>")
+    }
+}
+
+pub const ERROR_KINDS: &[&str] = &[
+    "Error",
+    "ValueError",
+    "TypeError",
+    "NameError",
+    "IndexError",
+    "ArgumentError",
+    "ZeroDivisionError",
+    "ImportError",
+];
+
+pub const BUILTIN_TYPES: &[&str] = &["str", "f64", "bool", "list", "tuple", "map", "iterator", "fn", "error", "result", "option"];
+// The built-in types whose values can be changed in place
+pub const CONTAINER_TYPES: &[&str] = &["list", "map"];
+
+pub const BUILTINS: &[&str] = &["error", "ok", "err", "some", "unwrap", "unwrap_or", "map_err", "is_ok", "is_err", "is_some", "is_none", "divmod", "enumerate", "iter", "next", "collect", "take", "skip", "zip", "chain"];
+
+impl ErrorValue {
+    pub fn new(kind: &str, message: impl Into<String>) -> Self {
+        ErrorValue {
+            kind: kind.to_string(),
+            message: message.into(),
+            location: None,
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn matches(&self, kind: &str) -> bool {
+        kind == "Error" || self.kind == kind
+    }
+
+    pub fn trace(&self) -> String {
+        let mut out = self.to_string();
+        if let Some(location) = &self.location {
+            out.push_str(&format!(" ({})", location));
+        }
+        for frame in &self.stack {
+            out.push_str(&format!("\n    at {}", frame));
+        }
+        out
+    }
+}
+
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
 }
 
 impl Value {
-    pub fn as_number(&self) -> Result<f64, String> {
+    fn expected(&self, kind: &str) -> ErrorValue {
+        let message = match self {
+            Value::None => format!("Expected {}, got none (check optional values with '??', '?.' or 'if x != none' first)", kind),
+            _ => format!("Expected {}, got {:?}", kind, self),
+        };
+        ErrorValue::new("TypeError", message)
+    }
+
+    pub fn type_name(&self) -> &str {
+        match self {
+            Value::String(_) => "str",
+            Value::Number(_) => "f64",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Tuple(_) => "tuple",
+            Value::Map(_) => "map",
+            Value::Iterator(_) => "iterator",
+            Value::Function(_) | Value::Compiled(_) => "fn",
+            Value::Type(..) => "type",
+            Value::Record(name, _) => name,
+            Value::Error(_) => "error",
+            Value::Ok(_) | Value::Err(_) => "result",
+            Value::Some(_) | Value::None => "option",
+            Value::Unset => "unset",
+        }
+    }
+
+    pub fn as_number(&self) -> Result<f64, ErrorValue> {
         match self {
             Value::Number(n) => Ok(*n),
-            _ => Err(format!("Expected number, got {:?}", self)),
+            _ => Err(self.expected("number")),
         }
     }
 
-    pub fn as_bool(&self) -> Result<bool, String> {
+    pub fn as_bool(&self) -> Result<bool, ErrorValue> {
         match self {
             Value::Bool(b) => Ok(*b),
-            _ => Err(format!("Expected bool, got {:?}", self)),
+            _ => Err(self.expected("bool")),
         }
     }
 
-    pub fn as_list(&self) -> Result<Vec<Value>, String> {
+    pub fn as_list(&self) -> Result<Rc<Vec<Value>>, ErrorValue> {
         match self {
             Value::List(l) => Ok(l.clone()),
-            _ => Err(format!("Expected list, got {:?}", self)),
+            _ => Err(self.expected("list")),
         }
     }
 
-    pub fn as_string(&self) -> Result<String, String> {
+    pub fn as_string(&self) -> Result<Rc<str>, ErrorValue> {
         match self {
             Value::String(s) => Ok(s.clone()),
-            _ => Err(format!("Expected string, got {:?}", self)),
+            _ => Err(self.expected("string")),
         }
     }
 }
@@ -47,8 +229,226 @@ impl fmt::Display for Value {
             Value::Number(n) => write!(f, "{}", n),
             Value::Bool(b) => write!(f, "{}", b),
             Value::List(l) => write!(f, "{:?}", l),
-            Value::Function(_, _, _) => write!(f, "<fn>"),
+            Value::Tuple(values) if values.len() == 1 => write!(f, "({},)", values[0]),
+            Value::Tuple(values) => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "({})", values.join(", "))
+            }
+            Value::Map(entries) => {
+                let entries: Vec<String> = entries.iter().map(|(key, value)| format!("{}: {}", key, value)).collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+            Value::Iterator(_) => write!(f, "<iterator>"),
+            Value::Function(_) | Value::Compiled(_) => write!(f, "<fn>"),
+            Value::Type(name, _) => write!(f, "<type {}>", name),
+            Value::Record(name, fields) => {
+                let fields: Vec<String> = fields.iter().map(|(field, value)| format!("{}={}", field, value)).collect();
+                write!(f, "{}({})", name, fields.join(", "))
+            }
+            Value::Error(e) => write!(f, "{}", e),
+            Value::Ok(v) => write!(f, "ok({})", v),
+            Value::Err(e) => write!(f, "err({})", e),
+            Value::Some(v) => write!(f, "some({})", v),
             Value::None => write!(f, "none"),
+            Value::Unset => write!(f, "<unset>"),
+        }
+    }
+}
+
+pub fn to_iter(value: Value) -> Result<Iter, ErrorValue> {
+    let lazy = match value {
+        Value::Iterator(iter) => return Ok(iter),
+        Value::List(items) => LazyIter::List(items, 0),
+        Value::Tuple(items) => LazyIter::Items(items.into_iter()),
+        Value::String(s) => LazyIter::Chars(s, 0),
+        Value::Map(entries) => LazyIter::Keys(entries, 0),
+        other => return Err(ErrorValue::new("TypeError", format!("Expected list, str, map or iterator to iterate over, got {}", other))),
+    };
+    Ok(Iter::new(lazy))
+}
+
+/// What the tree walker and the VM share: argument binding, operator overloading,
+/// builtins and annotation checks. Each engine supplies how a function runs, how
+/// its generators resume, and where user methods and trait impls are registered.
+pub trait Engine {
+    type Error: From<ErrorValue>;
+
+    /// Raises an error value that was already raised once, keeping its stack.
+    fn rethrow(error: ErrorValue) -> Self::Error;
+
+    fn call_function(&mut self, name: &str, func: &Value, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Self::Error>;
+    fn next_value(&mut self, iter: &Iter) -> Result<Option<Value>, Self::Error>;
+    fn user_method(&self, type_name: &str, method: &str) -> Option<Value>;
+    fn is_trait(&self, name: &str) -> bool;
+    fn implements(&self, type_name: &str, trait_name: &str) -> bool;
+
+    fn call_operator(&mut self, op: &str, left: &Value, right: &Value) -> Result<Option<Value>, Self::Error> {
+        let (Value::Record(type_name, _), Some(method)) = (left, crate::methods::operator_method(op)) else {
+            return Ok(None);
+        };
+        let Some(func) = self.user_method(type_name, method) else {
+            if matches!(op, "==" | "!=") || (op == "+" && matches!(right, Value::String(_))) {
+                return Ok(None);
+            }
+            return Err(ErrorValue::new("TypeError", format!("Operator '{}' is not defined for type '{}' (define '{}' in an impl block)", op, type_name, method)).into());
+        };
+        let result = self.call_function(&format!("{}.{}", type_name, method), &func, vec![left.clone(), right.clone()], Vec::new())?;
+        Ok(Some(match op {
+            "==" => Value::Bool(result.as_bool()?),
+            "!=" => Value::Bool(!result.as_bool()?),
+            "<" => Value::Bool(result.as_number()? < 0.0),
+            "<=" => Value::Bool(result.as_number()? <= 0.0),
+            ">" => Value::Bool(result.as_number()? > 0.0),
+            ">=" => Value::Bool(result.as_number()? >= 0.0),
+            _ => result,
+        }))
+    }
+
+    fn display(&mut self, value: Value) -> Result<String, Self::Error> {
+        if let Value::Record(type_name, _) = &value {
+            if let Some(func) = self.user_method(type_name, "to_str") {
+                let name = format!("{}.to_str", type_name);
+                return Ok(self.call_function(&name, &func, vec![value], Vec::new())?.to_string());
+            }
+        }
+        Ok(value.to_string())
+    }
+
+    /// The value of each parameter, in order; `None` where the caller left a
+    /// defaulted parameter out, for the engine to evaluate its default.
+    fn bind_args(&self, name: &str, params: &[super::ast::Param], args: Vec<Value>, mut named: Vec<(String, Value)>) -> Result<Vec<Option<Value>>, Self::Error> {
+        let variadic = params.last().is_some_and(|p| p.variadic);
+        if !variadic && args.len() > params.len() {
+            return Err(ErrorValue::new("ArgumentError", format!("Expected at most {} args, got {}", params.len(), args.len())).into());
+        }
+        for (i, (arg_name, _)) in named.iter().enumerate() {
+            if !params.iter().any(|p| &p.name == arg_name && !p.variadic) {
+                return Err(ErrorValue::new("ArgumentError", format!("Unknown named argument '{}' in call to '{}'", arg_name, name)).into());
+            }
+            if named[..i].iter().any(|(other, _)| other == arg_name) {
+                return Err(ErrorValue::new("ArgumentError", format!("Duplicate named argument '{}' in call to '{}'", arg_name, name)).into());
+            }
+        }
+        let mut args = args.into_iter();
+        let mut values = Vec::with_capacity(params.len());
+        for param in params {
+            let value = if param.variadic {
+                Value::List(Rc::new(args.by_ref().collect()))
+            } else if let Some(value) = args.next() {
+                if named.iter().any(|(arg_name, _)| arg_name == &param.name) {
+                    return Err(ErrorValue::new("ArgumentError", format!("Named argument '{}' was already given by position in call to '{}'", param.name, name)).into());
+                }
+                value
+            } else if let Some(position) = named.iter().position(|(arg_name, _)| arg_name == &param.name) {
+                named.remove(position).1
+            } else if param.default.is_some() {
+                values.push(None);
+                continue;
+            } else {
+                return Err(ErrorValue::new("ArgumentError", format!("Missing argument '{}' in call to '{}'", param.name, name)).into());
+            };
+            if !param.variadic && self.is_trait(&param.type_anno) {
+                self.check_type(&value, &param.type_anno)?;
+            }
+            values.push(Some(value));
+        }
+        Ok(values)
+    }
+
+    fn call_builtin(&mut self, name: &str, mut args: Vec<Value>) -> Result<Value, Self::Error> {
+        match (name, args.len()) {
+            ("error", 1) => Ok(Value::Error(Box::new(ErrorValue::new("Error", args[0].to_string())))),
+            ("error", 2) => Ok(Value::Error(Box::new(ErrorValue::new(&args[0].to_string(), args[1].to_string())))),
+            (kind, 1) if ERROR_KINDS.contains(&kind) => Ok(Value::Error(Box::new(ErrorValue::new(kind, args[0].to_string())))),
+            ("ok", 1) => Ok(Value::Ok(Box::new(args.remove(0)))),
+            ("err", 1) => Ok(Value::Err(Box::new(args.remove(0)))),
+            ("some", 1) => Ok(Value::Some(Box::new(args.remove(0)))),
+            ("unwrap", 1) => match args.remove(0) {
+                Value::Ok(value) | Value::Some(value) => Ok(*value),
+                Value::Err(error) => match *error {
+                    Value::Error(error) => Err(Self::rethrow(*error)),
+                    other => Err(ErrorValue::new("Error", format!("unwrap called on err({})", other)).into()),
+                },
+                Value::None => Err(ErrorValue::new("Error", "unwrap called on none").into()),
+                other => Err(ErrorValue::new("TypeError", format!("Expected result or option, got {}", other)).into()),
+            },
+            ("unwrap_or", 2) => {
+                let default = args.pop().unwrap();
+                match args.remove(0) {
+                    Value::Ok(value) | Value::Some(value) => Ok(*value),
+                    Value::Err(_) | Value::None => Ok(default),
+                    other => Err(ErrorValue::new("TypeError", format!("Expected result or option, got {}", other)).into()),
+                }
+            }
+            ("map_err", 2) => {
+                let func = args.pop().unwrap();
+                match args.remove(0) {
+                    Value::Err(error) => Ok(Value::Err(Box::new(self.call_function(name, &func, vec![*error], Vec::new())?))),
+                    ok @ Value::Ok(_) => Ok(ok),
+                    other => Err(ErrorValue::new("TypeError", format!("Expected result, got {}", other)).into()),
+                }
+            }
+            ("is_ok", 1) => Ok(Value::Bool(matches!(args[0], Value::Ok(_)))),
+            ("is_err", 1) => Ok(Value::Bool(matches!(args[0], Value::Err(_)))),
+            ("is_some", 1) => Ok(Value::Bool(matches!(args[0], Value::Some(_)))),
+            ("is_none", 1) => Ok(Value::Bool(matches!(args[0], Value::None))),
+            ("divmod", 2) => {
+                let (a, b) = (args[0].as_number()?, args[1].as_number()?);
+                if b == 0.0 {
+                    return Err(ErrorValue::new("ZeroDivisionError", "Division by zero").into());
+                }
+                let quotient = (a / b).floor();
+                Ok(Value::Tuple(vec![Value::Number(quotient), Value::Number(a - b * quotient)]))
+            }
+            ("iter", 1) => Ok(Value::Iterator(to_iter(args.remove(0))?)),
+            ("next", 1) => {
+                let iter = to_iter(args.remove(0))?;
+                Ok(self.next_value(&iter)?.map_or(Value::None, |value| Value::Some(Box::new(value))))
+            }
+            ("collect", 1) => {
+                let iter = to_iter(args.remove(0))?;
+                let mut items = Vec::new();
+                while let Some(value) = self.next_value(&iter)? {
+                    items.push(value);
+                }
+                Ok(Value::List(Rc::new(items)))
+            }
+            ("take", 2) | ("skip", 2) => {
+                let count = args[1].as_number()?.max(0.0) as usize;
+                let inner = to_iter(args.remove(0))?;
+                let lazy = if name == "take" { LazyIter::Take(inner, count) } else { LazyIter::Skip(inner, count) };
+                Ok(Value::Iterator(Iter::new(lazy)))
+            }
+            ("zip", 2) | ("chain", 2) => {
+                let second = to_iter(args.pop().unwrap())?;
+                let first = to_iter(args.pop().unwrap())?;
+                let lazy = if name == "zip" { LazyIter::Zip(first, second) } else { LazyIter::Chain(first, second) };
+                Ok(Value::Iterator(Iter::new(lazy)))
+            }
+            ("enumerate", 1) => Ok(Value::Iterator(Iter::new(LazyIter::Enumerate(to_iter(args.remove(0))?, 0)))),
+            (_, n) if BUILTINS.contains(&name) || ERROR_KINDS.contains(&name) => Err(ErrorValue::new("ArgumentError", format!("Builtin '{}' called with the wrong number of args, got {}", name, n)).into()),
+            _ => Err(ErrorValue::new("NameError", format!("Function '{}' not found", name)).into()),
+        }
+    }
+
+    fn check_type(&self, value: &Value, type_anno: &str) -> Result<(), Self::Error> {
+        if let Some(inner) = type_anno.strip_suffix('?') {
+            return match value {
+                Value::None | Value::Unset => Ok(()),
+                _ => self.check_type(value, inner),
+            };
+        }
+        match (type_anno, value) {
+            (_, Value::Unset) | ("str", Value::String(_)) | ("f64", Value::Number(_)) | ("bool", Value::Bool(_)) | ("list", Value::List(_)) | ("tuple", Value::Tuple(_)) | ("map", Value::Map(_)) | ("iterator", Value::Iterator(_)) | ("fn", Value::Function(_) | Value::Compiled(_)) | ("error", Value::Error(_)) | ("result", Value::Ok(_) | Value::Err(_)) | ("option", Value::Some(_) | Value::None) => Ok(()),
+            (expected, Value::Record(name, _)) if name == expected => Ok(()),
+            (expected, value) if self.is_trait(expected) => {
+                if self.implements(value.type_name(), expected) {
+                    Ok(())
+                } else {
+                    Err(ErrorValue::new("TypeError", format!("type {} does not implement trait {}", value.type_name(), expected)).into())
+                }
+            }
+            _ => Err(ErrorValue::new("TypeError", format!("Expected {}, got {}", type_anno, value)).into()),
         }
     }
 }
@@ -5,24 +5,35 @@ use std::fs;
 
 pub fn run_tests() -> Result<(), String> {
     let test_dir = "tests";
-    if !fs::metadata(test_dir).is_ok() {
+    if fs::metadata(test_dir).is_err() {
         return Err("No tests directory".to_string());
     }
 
     let mut test_count = 0;
     let mut passed = 0;
-    for entry in fs::read_dir(test_dir)? {
-        let path = entry?.path();
-        if path.extension().map_or(false, |ext| ext == "velvet") {
+    for entry in fs::read_dir(test_dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().is_some_and(|ext| ext == "velvet") {
             test_count += 1;
             cli::info(&format!("Running {}", path.display()));
-            let source = fs::read_to_string(&path)?;
+            let source = fs::read_to_string(&path).map_err(|e| e.to_string())?;
             let ast = parser::parse(&source)?;
-            if interpreter::run(ast, false).is_ok() {
-                passed += 1;
-                cli::success(&format!("{} passed", path.display()));
-            } else {
-                cli::error(&format!("{} failed", path.display()));
+            match interpreter::run_tests(ast) {
+                Ok(results) => {
+                    passed += 1;
+                    cli::success(&format!("{} passed", path.display()));
+                    for (name, result) in results {
+                        test_count += 1;
+                        match result {
+                            Ok(()) => {
+                                passed += 1;
+                                cli::success(&format!("{}::{} passed", path.display(), name));
+                            }
+                            Err(e) => cli::error(&format!("{}::{} failed: {}", path.display(), name, e)),
+                        }
+                    }
+                }
+                Err(_) => cli::error(&format!("{} failed", path.display())),
             }
         }
     }
@@ -1,3 +1,4 @@
+use crate::{compiler, compiler_c, compiler_js, compiler_py};
 use std::fs;
 use std::path::Path;
 
@@ -9,21 +10,22 @@ pub fn check_project() -> Result<(), String> {
 }
 
 pub fn init_project() -> Result<(), String> {
-    fs::create_dir_all("lib/.velvet_library")?;
-    fs::create_dir_all("tests")?;
-    fs::write("main.velvet", "@ Simple Velvet program\nsay \"Hello, Velvet!\"\n")?;
-    fs::write("velvet.json", r#"{"name":"new-project","version":"0.1.0","dependencies":{},"modules":[]}"#)?;
+    fs::create_dir_all("lib/.velvet_library").map_err(|e| e.to_string())?;
+    fs::create_dir_all("tests").map_err(|e| e.to_string())?;
+    fs::write("main.velvet", "@ Simple Velvet program\nsay \"Hello, Velvet!\"\n").map_err(|e| e.to_string())?;
+    fs::write("velvet.json", r#"{"name":"new-project","version":"0.1.0","dependencies":{},"modules":[]}"#).map_err(|e| e.to_string())?;
     fs::write("tests/test_basic.velvet", r#"@ Basic test
 test "basic test":
     say "Test passed"
-"#)?;
+"#).map_err(|e| e.to_string())?;
     Ok(())
 }
 
 pub fn clean_project() -> Result<(), String> {
-    for file in &["velvet_out", "velvet_out.rs"] {
+    let outputs = [compiler::OUTPUTS, compiler_c::OUTPUTS, compiler_js::OUTPUTS, compiler_py::OUTPUTS];
+    for file in outputs.concat() {
         if Path::new(file).exists() {
-            fs::remove_file(file)?;
+            fs::remove_file(file).map_err(|e| e.to_string())?;
         }
     }
     Ok(())
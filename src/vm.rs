@@ -0,0 +1,1573 @@
+use crate::ast::*;
+use crate::cli;
+use crate::interpreter::{contains_yield, get_field, insert_entry, match_pattern, unpack};
+use crate::methods;
+use crate::resolver::{declarations, pattern_names, target_names};
+use crate::runtime::{to_iter, Engine, ErrorValue, Iter, LazyIter, Value, BUILTIN_TYPES};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mutability {
+    Const,
+    Immutable,
+    Mutable,
+}
+
+#[derive(Debug, Clone)]
+struct Binding {
+    value: Value,
+    mutability: Mutability,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Var {
+    Local(usize),
+    Global(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl BinOp {
+    fn parse(op: &str) -> Option<BinOp> {
+        Some(match op {
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            ">" => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            "<" => BinOp::Lt,
+            "<=" => BinOp::Le,
+            _ => return None,
+        })
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+        }
+    }
+}
+
+enum Op {
+    Constant(usize),
+    None,
+    Pop,
+    Get(Var),
+    Define(Var, Mutability),
+    DefineConst(Var),
+    Assign(Var),
+    Unpack(Target),
+    CheckType(String),
+    Binary(BinOp),
+    Negate,
+    Not,
+    Jump(usize),
+    JumpIfFalse(usize),
+    ShortCircuit(bool, usize),
+    Truth,
+    Coalesce(usize),
+    Call(Var, Vec<Option<String>>),
+    Method(String, Vec<Option<String>>, Option<String>),
+    MutMethod(String, Vec<Option<String>>, Var),
+    Index,
+    Slice(bool, bool, bool),
+    Field(String),
+    SafeField(String),
+    Propagate(usize),
+    List(usize),
+    Tuple(usize),
+    Map(usize),
+    Append(usize),
+    Insert(usize),
+    Iter,
+    ForNext(usize),
+    MatchPattern(Pattern, Vec<Var>, usize),
+    NoMatch,
+    Say,
+    Return,
+    ReturnDefault,
+    Yield,
+    Escaped,
+    Fail(ErrorValue),
+    Abort(String),
+    Throw(usize),
+    ThrowCaught,
+    Rethrow,
+    PushHandler(usize),
+    PopHandler,
+    CatchKind(String, usize),
+    Catch(Var),
+    PopHandling,
+    JumpIfBound(usize, usize),
+    BindParam(usize),
+    MakeRecord(String),
+    DefineConstructor(Rc<Function>),
+    DefineTrait(String, Vec<String>, Vec<(String, Value)>),
+    DefineImpl(String, Var, Option<String>, Vec<(String, Value)>),
+    Deprecate(String, Option<String>),
+    Memoize(String),
+    Import(String),
+    EnterTest,
+    ExitTest,
+}
+
+pub struct Function {
+    name: String,
+    params: Vec<Param>,
+    ret_type: Option<String>,
+    code: Vec<Op>,
+    constants: Vec<Value>,
+    slots: Vec<(String, Option<usize>)>,
+    generator: bool,
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<fn {}>", self.name)
+    }
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[derive(Default)]
+struct Names {
+    index: HashMap<String, usize>,
+    names: Vec<String>,
+}
+
+enum Block {
+    Loop(Vec<usize>, usize),
+    Try(Option<Vec<Statement>>, bool),
+    Test,
+}
+
+struct Compiler<'a> {
+    names: &'a mut Names,
+    code: Vec<Op>,
+    constants: Vec<Value>,
+    slots: Vec<(String, Option<usize>)>,
+    locals: HashMap<String, usize>,
+    scopes: Vec<HashMap<String, usize>>,
+    blocks: Vec<Block>,
+    in_function: bool,
+    generator: bool,
+}
+
+fn compile(names: &mut Names, statements: &[Statement]) -> Rc<Function> {
+    let mut compiler = Compiler::new(names, false);
+    compiler.block(statements);
+    compiler.emit(Op::None);
+    compiler.emit(Op::Return);
+    compiler.finish("<main>", Vec::new(), None)
+}
+
+fn compile_function(names: &mut Names, name: &str, params: &[Param], ret_type: &Option<String>, body: &[Statement]) -> Rc<Function> {
+    let mut compiler = Compiler::new(names, true);
+    let mut declared = params.iter().map(|p| p.name.clone()).collect();
+    declarations(body, &mut declared);
+    for name in declared {
+        compiler.local(&name);
+    }
+    compiler.generator = contains_yield(body);
+    compiler.defaults(params);
+    compiler.block(body);
+    compiler.emit(Op::ReturnDefault);
+    compiler.finish(name, params.to_vec(), ret_type.clone())
+}
+
+fn compile_constructor(names: &mut Names, name: &str, fields: &[Param]) -> Rc<Function> {
+    let mut compiler = Compiler::new(names, true);
+    for field in fields {
+        compiler.local(&field.name);
+    }
+    compiler.defaults(fields);
+    compiler.emit(Op::MakeRecord(name.to_string()));
+    compiler.emit(Op::Return);
+    compiler.finish(name, fields.to_vec(), None)
+}
+
+impl<'a> Compiler<'a> {
+    fn new(names: &'a mut Names, in_function: bool) -> Self {
+        Compiler {
+            names,
+            code: Vec::new(),
+            constants: Vec::new(),
+            slots: Vec::new(),
+            locals: HashMap::new(),
+            scopes: Vec::new(),
+            blocks: Vec::new(),
+            in_function,
+            generator: false,
+        }
+    }
+
+    fn finish(self, name: &str, params: Vec<Param>, ret_type: Option<String>) -> Rc<Function> {
+        Rc::new(Function {
+            name: name.to_string(),
+            params,
+            ret_type,
+            code: self.code,
+            constants: self.constants,
+            slots: self.slots,
+            generator: self.generator,
+        })
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    fn constant(&mut self, value: Value) {
+        self.constants.push(value);
+        self.emit(Op::Constant(self.constants.len() - 1));
+    }
+
+    fn patch(&mut self, at: usize) {
+        let here = self.code.len();
+        match &mut self.code[at] {
+            Op::Jump(target)
+            | Op::JumpIfFalse(target)
+            | Op::ShortCircuit(_, target)
+            | Op::Coalesce(target)
+            | Op::Propagate(target)
+            | Op::ForNext(target)
+            | Op::MatchPattern(_, _, target)
+            | Op::PushHandler(target)
+            | Op::CatchKind(_, target)
+            | Op::JumpIfBound(_, target) => *target = here,
+            _ => unreachable!("patching a non-jump instruction"),
+        }
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.names.index.get(name) {
+            return index;
+        }
+        self.names.names.push(name.to_string());
+        self.names.index.insert(name.to_string(), self.names.names.len() - 1);
+        self.names.names.len() - 1
+    }
+
+    fn local(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.locals.get(name) {
+            return slot;
+        }
+        let slot = self.fresh(name);
+        self.locals.insert(name.to_string(), slot);
+        slot
+    }
+
+    fn fresh(&mut self, name: &str) -> usize {
+        let global = self.global(name);
+        self.slots.push((name.to_string(), Some(global)));
+        self.slots.len() - 1
+    }
+
+    fn resolve(&mut self, name: &str) -> Var {
+        if let Some(&slot) = self.scopes.iter().rev().find_map(|scope| scope.get(name)) {
+            return Var::Local(slot);
+        }
+        match self.locals.get(name) {
+            Some(&slot) => Var::Local(slot),
+            None => Var::Global(self.global(name)),
+        }
+    }
+
+    fn scope(&mut self, names: Vec<String>) {
+        let scope = names.into_iter().map(|name| (name.clone(), self.fresh(&name))).collect();
+        self.scopes.push(scope);
+    }
+
+    fn defaults(&mut self, params: &[Param]) {
+        for (slot, param) in params.iter().enumerate() {
+            if let Some(default) = &param.default {
+                let skip = self.emit(Op::JumpIfBound(slot, 0));
+                self.expr(default);
+                self.emit(Op::BindParam(slot));
+                self.patch(skip);
+            }
+        }
+    }
+
+    fn block(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn bind(&mut self, target: &Target, store: &dyn Fn(Var) -> Op) {
+        match target {
+            Target::Name(name) if name == "_" => {
+                self.emit(Op::Pop);
+            }
+            Target::Name(name) => {
+                let var = self.resolve(name);
+                self.emit(store(var));
+            }
+            _ => {
+                self.emit(Op::Unpack(target.clone()));
+                let mut names = Vec::new();
+                target_names(target, &mut names);
+                for name in names {
+                    let var = self.resolve(&name);
+                    self.emit(store(var));
+                }
+            }
+        }
+    }
+
+    fn unwind(&mut self, level: usize) {
+        for index in (level..self.blocks.len()).rev() {
+            let (finally_block, catching) = match &self.blocks[index] {
+                Block::Loop(..) => continue,
+                Block::Test => {
+                    self.emit(Op::ExitTest);
+                    continue;
+                }
+                Block::Try(finally_block, catching) => (finally_block.clone(), *catching),
+            };
+            if catching {
+                self.emit(Op::PopHandling);
+            }
+            if !catching || finally_block.is_some() {
+                self.emit(Op::PopHandler);
+            }
+            if let Some(finally_block) = finally_block {
+                let inner = self.blocks.split_off(index);
+                self.block(&finally_block);
+                self.blocks.extend(inner);
+            }
+        }
+    }
+
+    fn jump_out(&mut self, is_break: bool) {
+        let Some(index) = self.blocks.iter().rposition(|block| matches!(block, Block::Loop(..))) else {
+            self.unwind(0);
+            if self.in_function {
+                self.emit(Op::Escaped);
+            } else {
+                let keyword = if is_break { "break" } else { "continue" };
+                self.emit(Op::Abort(format!("'{}' outside of a loop", keyword)));
+            }
+            return;
+        };
+        self.unwind(index + 1);
+        let jump = self.emit(Op::Jump(0));
+        if let Block::Loop(breaks, continue_at) = &mut self.blocks[index] {
+            if is_break {
+                breaks.push(jump);
+            } else {
+                let continue_at = *continue_at;
+                self.code[jump] = Op::Jump(continue_at);
+            }
+        }
+    }
+
+    fn end_loop(&mut self) {
+        let Some(Block::Loop(breaks, _)) = self.blocks.pop() else { unreachable!("loop block expected") };
+        for jump in breaks {
+            self.patch(jump);
+        }
+    }
+
+    fn methods(&mut self, stmts: &[Statement]) -> Vec<(String, Value)> {
+        stmts
+            .iter()
+            .filter_map(|method| match method {
+                Statement::Fun(name, params, ret_type, body) => {
+                    Some((name.clone(), Value::Compiled(compile_function(self.names, name, params, ret_type, body))))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Say(expr) => {
+                self.mutating(expr);
+                self.emit(Op::Say);
+            }
+            Statement::Val(ident, expr, type_anno) | Statement::Let(ident, expr, type_anno) => {
+                match expr {
+                    Some(expr) => self.mutating(expr),
+                    None if type_anno.as_deref().is_some_and(|t| t.ends_with('?')) => {
+                        self.emit(Op::None);
+                    }
+                    None => self.constant(Value::Unset),
+                }
+                if let Some(type_anno) = type_anno {
+                    self.emit(Op::CheckType(type_anno.clone()));
+                }
+                let mutability = if matches!(stmt, Statement::Let(..)) { Mutability::Mutable } else { Mutability::Immutable };
+                let var = self.resolve(ident);
+                self.emit(Op::Define(var, mutability));
+            }
+            Statement::Const(ident, expr, type_anno) => {
+                self.expr(expr);
+                if let Some(type_anno) = type_anno {
+                    self.emit(Op::CheckType(type_anno.clone()));
+                }
+                let var = self.resolve(ident);
+                self.emit(Op::DefineConst(var));
+            }
+            Statement::Fun(name, params, ret_type, body) => {
+                let function = compile_function(self.names, name, params, ret_type, body);
+                self.constant(Value::Compiled(function));
+                let var = self.resolve(name);
+                self.emit(Op::Define(var, Mutability::Immutable));
+            }
+            Statement::Type(name, fields) => {
+                let constructor = compile_constructor(self.names, name, fields);
+                self.emit(Op::DefineConstructor(constructor));
+                self.constant(Value::Type(name.clone(), fields.clone()));
+                let var = self.resolve(name);
+                self.emit(Op::Define(var, Mutability::Immutable));
+            }
+            Statement::Trait(name, signatures, defaults) => {
+                let required = signatures.iter().map(|(method, _, _)| method.clone()).collect();
+                let defaults = self.methods(defaults);
+                self.emit(Op::DefineTrait(name.clone(), required, defaults));
+            }
+            Statement::Impl(type_name, trait_name, methods) => {
+                let var = self.resolve(type_name);
+                let methods = self.methods(methods);
+                self.emit(Op::DefineImpl(type_name.clone(), var, trait_name.clone(), methods));
+            }
+            Statement::If(condition, then_block, else_block) => {
+                self.expr(condition);
+                let skip = self.emit(Op::JumpIfFalse(0));
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    let end = self.emit(Op::Jump(0));
+                    self.patch(skip);
+                    self.block(else_block);
+                    self.patch(end);
+                } else {
+                    self.patch(skip);
+                }
+            }
+            Statement::For(target, expr, body) => {
+                self.expr(expr);
+                self.emit(Op::Iter);
+                let top = self.code.len();
+                let next = self.emit(Op::ForNext(0));
+                self.bind(target, &|var| Op::Define(var, Mutability::Immutable));
+                self.blocks.push(Block::Loop(Vec::new(), top));
+                self.block(body);
+                self.emit(Op::Jump(top));
+                self.patch(next);
+                self.end_loop();
+                self.emit(Op::Pop);
+            }
+            Statement::While(condition, body) => {
+                let top = self.code.len();
+                self.expr(condition);
+                let exit = self.emit(Op::JumpIfFalse(0));
+                self.blocks.push(Block::Loop(Vec::new(), top));
+                self.block(body);
+                self.emit(Op::Jump(top));
+                self.patch(exit);
+                self.end_loop();
+            }
+            Statement::Break => self.jump_out(true),
+            Statement::Continue => self.jump_out(false),
+            Statement::Try(try_block, catches, finally_block) => self.try_stmt(try_block, catches, finally_block),
+            Statement::Throw(Some(expr), line) => {
+                self.expr(expr);
+                self.emit(Op::Throw(*line));
+            }
+            Statement::Throw(None, _) => {
+                self.emit(Op::ThrowCaught);
+            }
+            Statement::Match(expr, branches) => {
+                self.expr(expr);
+                let mut ends = Vec::new();
+                for (pattern, block) in branches {
+                    let mut names = Vec::new();
+                    pattern_names(pattern, &mut names);
+                    let vars = names.iter().map(|name| self.resolve(name)).collect();
+                    let next = self.emit(Op::MatchPattern(pattern.clone(), vars, 0));
+                    self.block(block);
+                    ends.push(self.emit(Op::Jump(0)));
+                    self.patch(next);
+                }
+                self.emit(Op::Pop);
+                for end in ends {
+                    self.patch(end);
+                }
+            }
+            Statement::Unpack(target, expr, mutable) => {
+                self.mutating(expr);
+                let mutability = if *mutable { Mutability::Mutable } else { Mutability::Immutable };
+                self.bind(target, &|var| Op::Define(var, mutability));
+            }
+            Statement::Assign(target, expr) => {
+                self.mutating(expr);
+                self.bind(target, &Op::Assign);
+            }
+            Statement::Expr(expr) => {
+                self.mutating(expr);
+                self.emit(Op::Pop);
+            }
+            Statement::Return(expr) => {
+                self.mutating(expr);
+                self.unwind(0);
+                self.emit(Op::Return);
+            }
+            Statement::Yield(expr) if self.generator => {
+                self.mutating(expr);
+                self.emit(Op::Yield);
+            }
+            Statement::Yield(_) => {
+                self.emit(Op::Fail(ErrorValue::new("Error", "'yield' is only allowed inside a function")));
+            }
+            Statement::Import(module, _) => {
+                self.emit(Op::Import(module.clone()));
+            }
+            Statement::Test(_, body) => {
+                self.emit(Op::EnterTest);
+                self.blocks.push(Block::Test);
+                self.block(body);
+                self.blocks.pop();
+                self.emit(Op::ExitTest);
+            }
+            Statement::Attributed(attributes, target) => {
+                self.stmt(target);
+                let name = match target.as_ref() {
+                    Statement::Fun(name, ..) | Statement::Type(name, _) | Statement::Const(name, ..) => name,
+                    _ => return,
+                };
+                for attribute in attributes {
+                    match attribute.name.as_str() {
+                        "deprecated" => {
+                            let note = match attribute.args.first() {
+                                Some(Expr::String(note)) => Some(note.clone()),
+                                _ => None,
+                            };
+                            self.emit(Op::Deprecate(name.clone(), note));
+                        }
+                        "memoize" => {
+                            self.emit(Op::Memoize(name.clone()));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn try_stmt(&mut self, try_block: &[Statement], catches: &[(String, Option<String>, Vec<Statement>)], finally_block: &Option<Vec<Statement>>) {
+        let handler = self.emit(Op::PushHandler(0));
+        self.blocks.push(Block::Try(finally_block.clone(), false));
+        self.block(try_block);
+        self.blocks.pop();
+        self.emit(Op::PopHandler);
+        let mut ends = vec![self.emit(Op::Jump(0))];
+        self.patch(handler);
+        let mut guards = Vec::new();
+        for (ident, kind, catch_block) in catches {
+            let next = kind.as_ref().map(|kind| self.emit(Op::CatchKind(kind.clone(), 0)));
+            let var = self.resolve(ident);
+            self.emit(Op::Catch(var));
+            if finally_block.is_some() {
+                guards.push(self.emit(Op::PushHandler(0)));
+            }
+            self.blocks.push(Block::Try(finally_block.clone(), true));
+            self.block(catch_block);
+            self.blocks.pop();
+            if finally_block.is_some() {
+                self.emit(Op::PopHandler);
+            }
+            self.emit(Op::PopHandling);
+            ends.push(self.emit(Op::Jump(0)));
+            if let Some(next) = next {
+                self.patch(next);
+            }
+        }
+        if let Some(finally_block) = finally_block {
+            self.block(finally_block);
+        }
+        self.emit(Op::Rethrow);
+        if let Some(finally_block) = finally_block.as_ref().filter(|_| !guards.is_empty()) {
+            for guard in guards {
+                self.patch(guard);
+            }
+            self.emit(Op::PopHandling);
+            self.block(finally_block);
+            self.emit(Op::Rethrow);
+        }
+        for end in ends {
+            self.patch(end);
+        }
+        if let Some(finally_block) = finally_block {
+            self.block(finally_block);
+        }
+    }
+
+    fn mutating(&mut self, expr: &Expr) {
+        if let Expr::MethodCall(target, method, args) = expr {
+            if let Expr::Ident(name) = target.as_ref() {
+                let var = self.resolve(name);
+                self.emit(Op::Get(var));
+                let names = self.args(args);
+                self.emit(Op::MutMethod(method.clone(), names, var));
+                return;
+            }
+        }
+        self.expr(expr);
+    }
+
+    fn args(&mut self, args: &[Expr]) -> Vec<Option<String>> {
+        args.iter()
+            .map(|arg| match arg {
+                Expr::Named(name, expr) => {
+                    self.expr(expr);
+                    Some(name.clone())
+                }
+                expr => {
+                    self.expr(expr);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn comprehension(&mut self, clauses: &[Clause], depth: usize, emit: &dyn Fn(&mut Self, usize)) {
+        match clauses.split_first() {
+            None => emit(self, depth),
+            Some((Clause::If(condition), rest)) => {
+                self.expr(condition);
+                let skip = self.emit(Op::JumpIfFalse(0));
+                self.comprehension(rest, depth, emit);
+                self.patch(skip);
+            }
+            Some((Clause::For(target, iterable), rest)) => {
+                self.expr(iterable);
+                self.emit(Op::Iter);
+                let top = self.code.len();
+                let next = self.emit(Op::ForNext(0));
+                self.bind(target, &|var| Op::Define(var, Mutability::Immutable));
+                self.comprehension(rest, depth + 1, emit);
+                self.emit(Op::Jump(top));
+                self.patch(next);
+                self.emit(Op::Pop);
+            }
+        }
+    }
+
+    fn clause_scope(&mut self, clauses: &[Clause]) {
+        let mut names = Vec::new();
+        for clause in clauses {
+            if let Clause::For(target, _) = clause {
+                target_names(target, &mut names);
+            }
+        }
+        self.scope(names);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::String(s) => self.constant(Value::String(s.as_str().into())),
+            Expr::Number(n) => self.constant(Value::Number(*n)),
+            Expr::Bool(b) => self.constant(Value::Bool(*b)),
+            Expr::Ident(id) if id == "none" => {
+                self.emit(Op::None);
+            }
+            Expr::Ident(id) | Expr::Local(_, id) => {
+                let var = self.resolve(id);
+                self.emit(Op::Get(var));
+            }
+            Expr::Binary(left, op, right) if op == "??" => {
+                self.expr(left);
+                let end = self.emit(Op::Coalesce(0));
+                self.expr(right);
+                self.patch(end);
+            }
+            // `and` stops at the first false operand and `or` at the first true one
+            Expr::Binary(left, op, right) if op == "and" || op == "or" => {
+                self.expr(left);
+                let end = self.emit(Op::ShortCircuit(op == "or", 0));
+                self.expr(right);
+                self.emit(Op::Truth);
+                self.patch(end);
+            }
+            Expr::Binary(left, op, right) => {
+                self.expr(left);
+                self.expr(right);
+                match BinOp::parse(op) {
+                    Some(op) => self.emit(Op::Binary(op)),
+                    None => self.emit(Op::Fail(ErrorValue::new("Error", format!("Unknown operator '{}'", op)))),
+                };
+            }
+            Expr::Unary(op, inner) => {
+                self.expr(inner);
+                match op.as_str() {
+                    "-" => self.emit(Op::Negate),
+                    "!" => self.emit(Op::Not),
+                    _ => self.emit(Op::Fail(ErrorValue::new("Error", format!("Unknown unary op '{}'", op)))),
+                };
+            }
+            Expr::Call(name, args) => {
+                let names = self.args(args);
+                let var = self.resolve(name);
+                self.emit(Op::Call(var, names));
+            }
+            Expr::Named(name, _) => {
+                self.emit(Op::Fail(ErrorValue::new("ArgumentError", format!("Named argument '{}' is only allowed in a call", name))));
+            }
+            Expr::MethodCall(target, method, args) => {
+                self.expr(target);
+                let guard = match target.as_ref() {
+                    Expr::Ident(name) => Some(name.clone()),
+                    _ => None,
+                };
+                let names = self.args(args);
+                self.emit(Op::Method(method.clone(), names, guard));
+            }
+            Expr::List(elements) => {
+                elements.iter().for_each(|e| self.expr(e));
+                self.emit(Op::List(elements.len()));
+            }
+            Expr::Tuple(elements) => {
+                elements.iter().for_each(|e| self.expr(e));
+                self.emit(Op::Tuple(elements.len()));
+            }
+            Expr::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                self.emit(Op::Map(entries.len()));
+            }
+            Expr::ListComp(element, clauses) => {
+                self.emit(Op::List(0));
+                self.clause_scope(clauses);
+                self.comprehension(clauses, 0, &|compiler, depth| {
+                    compiler.expr(element);
+                    compiler.emit(Op::Append(depth));
+                });
+                self.scopes.pop();
+            }
+            Expr::MapComp(key, value, clauses) => {
+                self.emit(Op::Map(0));
+                self.clause_scope(clauses);
+                self.comprehension(clauses, 0, &|compiler, depth| {
+                    compiler.expr(key);
+                    compiler.expr(value);
+                    compiler.emit(Op::Insert(depth));
+                });
+                self.scopes.pop();
+            }
+            Expr::Index(target, index) => {
+                self.expr(target);
+                self.expr(index);
+                self.emit(Op::Index);
+            }
+            Expr::Slice(target, start, end, step) => {
+                self.expr(target);
+                for bound in [start, end, step].into_iter().flatten() {
+                    self.expr(bound);
+                }
+                self.emit(Op::Slice(start.is_some(), end.is_some(), step.is_some()));
+            }
+            Expr::Field(target, field) => {
+                self.expr(target);
+                self.emit(Op::Field(field.clone()));
+            }
+            Expr::SafeField(target, field) => {
+                self.expr(target);
+                self.emit(Op::SafeField(field.clone()));
+            }
+            Expr::Propagate(inner) => {
+                self.expr(inner);
+                let unwrapped = self.emit(Op::Propagate(0));
+                self.unwind(0);
+                self.emit(Op::Return);
+                self.patch(unwrapped);
+            }
+            Expr::If(condition, then_expr, else_expr) => {
+                self.expr(condition);
+                let skip = self.emit(Op::JumpIfFalse(0));
+                self.expr(then_expr);
+                let end = self.emit(Op::Jump(0));
+                self.patch(skip);
+                self.expr(else_expr);
+                self.patch(end);
+            }
+            Expr::Match(subject, arms) => {
+                self.expr(subject);
+                let mut ends = Vec::new();
+                for (pattern, arm) in arms {
+                    let mut names = Vec::new();
+                    pattern_names(pattern, &mut names);
+                    self.scope(names.clone());
+                    let vars = names.iter().map(|name| self.resolve(name)).collect();
+                    let next = self.emit(Op::MatchPattern(pattern.clone(), vars, 0));
+                    self.expr(arm);
+                    self.scopes.pop();
+                    ends.push(self.emit(Op::Jump(0)));
+                    self.patch(next);
+                }
+                self.emit(Op::NoMatch);
+                for end in ends {
+                    self.patch(end);
+                }
+            }
+        }
+    }
+}
+
+struct Handler {
+    target: usize,
+    depth: usize,
+    handling: usize,
+}
+
+type Snapshot = (Vec<Option<Binding>>, Vec<Option<Binding>>);
+
+struct Frame {
+    function: Rc<Function>,
+    ip: usize,
+    slots: Vec<Option<Binding>>,
+    stack: Vec<Value>,
+    handlers: Vec<Handler>,
+    tests: Vec<Snapshot>,
+}
+
+impl Frame {
+    fn new(function: Rc<Function>) -> Self {
+        let slots = vec![None; function.slots.len()];
+        Frame { function, ip: 0, slots, stack: Vec::new(), handlers: Vec::new(), tests: Vec::new() }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn pop_args(&mut self, names: &[Option<String>]) -> (Vec<Value>, Vec<(String, Value)>) {
+        let args = self.stack.split_off(self.stack.len() - names.len());
+        let mut values = Vec::new();
+        let mut named = Vec::new();
+        for (name, value) in names.iter().zip(args) {
+            match name {
+                Some(name) => named.push((name.clone(), value)),
+                None => values.push(value),
+            }
+        }
+        (values, named)
+    }
+}
+
+pub struct Coroutine {
+    name: String,
+    frame: Option<Frame>,
+}
+
+enum Flow {
+    Return(Value),
+    Yield(Value),
+}
+
+enum Fault {
+    Raise(ErrorValue),
+    Error(ErrorValue),
+    Abort(String),
+}
+
+impl From<ErrorValue> for Fault {
+    fn from(error: ErrorValue) -> Self {
+        Fault::Raise(error)
+    }
+}
+
+#[derive(Default)]
+struct Vm {
+    names: Names,
+    globals: Vec<Option<Binding>>,
+    calls: Vec<String>,
+    handling: Vec<ErrorValue>,
+    methods: HashMap<String, HashMap<String, Value>>,
+    traits: HashMap<String, (Vec<String>, HashMap<String, Value>)>,
+    impls: HashSet<(String, String)>,
+    constructors: HashMap<String, Rc<Function>>,
+    deprecated: HashMap<String, Option<String>>,
+    memos: HashMap<String, HashMap<String, Value>>,
+}
+
+pub fn run(statements: Vec<Statement>) -> Result<(), String> {
+    let mut vm = Vm::default();
+    let main = compile(&mut vm.names, &statements);
+    match vm.execute(main) {
+        Ok(()) => Ok(()),
+        Err(Fault::Abort(message)) => Err(message),
+        Err(fault) => Err(format!("Uncaught {}", vm.error(fault).trace())),
+    }
+}
+
+fn binding<'a>(globals: &'a [Option<Binding>], frame: &'a Frame, var: Var) -> Option<&'a Binding> {
+    match var {
+        Var::Local(slot) => match &frame.slots[slot] {
+            Some(binding) => Some(binding),
+            None => frame.function.slots[slot].1.and_then(|global| globals.get(global)?.as_ref()),
+        },
+        Var::Global(global) => globals.get(global)?.as_ref(),
+    }
+}
+
+impl Vm {
+    fn execute(&mut self, function: Rc<Function>) -> Result<(), Fault> {
+        self.globals.resize(self.names.names.len(), None);
+        self.run(&mut Frame::new(function)).map(|_| ())
+    }
+
+    fn error(&self, fault: Fault) -> ErrorValue {
+        match fault {
+            Fault::Raise(mut error) => {
+                error.stack = self.call_stack();
+                error
+            }
+            Fault::Error(error) => error,
+            Fault::Abort(message) => ErrorValue::new("Error", message),
+        }
+    }
+
+    fn call_stack(&self) -> Vec<String> {
+        self.calls.iter().rev().cloned().collect()
+    }
+
+    fn name(&self, frame: &Frame, var: Var) -> String {
+        match var {
+            Var::Local(slot) => frame.function.slots[slot].0.clone(),
+            Var::Global(global) => self.names.names[global].clone(),
+        }
+    }
+
+    fn store(&mut self, frame: &mut Frame, var: Var, value: Value, mutability: Mutability) {
+        let binding = Some(Binding { value, mutability });
+        match var {
+            Var::Local(slot) => frame.slots[slot] = binding,
+            Var::Global(global) => self.globals[global] = binding,
+        }
+    }
+
+    fn define(&mut self, frame: &mut Frame, var: Var, value: Value, mutability: Mutability) -> Result<(), Fault> {
+        let existing = match var {
+            Var::Local(slot) => frame.slots[slot].as_ref(),
+            Var::Global(global) => self.globals[global].as_ref(),
+        };
+        if let Some(Binding { mutability: Mutability::Const, .. }) = existing {
+            return Err(ErrorValue::new("Error", format!("Cannot redeclare constant '{}'", self.name(frame, var))).into());
+        }
+        self.store(frame, var, value, mutability);
+        Ok(())
+    }
+
+    fn warn_deprecated(&mut self, name: &str) {
+        match self.deprecated.remove(name) {
+            Some(Some(note)) => cli::warning(&format!("'{}' is deprecated: {}", name, note)),
+            Some(None) => cli::warning(&format!("'{}' is deprecated", name)),
+            None => {}
+        }
+    }
+
+    fn run(&mut self, frame: &mut Frame) -> Result<Flow, Fault> {
+        loop {
+            match self.dispatch(frame) {
+                Err(Fault::Abort(message)) => return Err(Fault::Abort(message)),
+                Err(fault) => {
+                    let error = self.error(fault);
+                    let Some(handler) = frame.handlers.pop() else { return Err(Fault::Error(error)) };
+                    frame.stack.truncate(handler.depth);
+                    self.handling.truncate(handler.handling);
+                    frame.stack.push(Value::Error(Box::new(error)));
+                    frame.ip = handler.target;
+                }
+                flow => return flow,
+            }
+        }
+    }
+
+    fn dispatch(&mut self, frame: &mut Frame) -> Result<Flow, Fault> {
+        let function = frame.function.clone();
+        loop {
+            let op = &function.code[frame.ip];
+            frame.ip += 1;
+            match op {
+                Op::Constant(index) => frame.stack.push(function.constants[*index].clone()),
+                Op::None => frame.stack.push(Value::None),
+                Op::Pop => {
+                    frame.pop();
+                }
+                Op::Get(var) => {
+                    let value = match binding(&self.globals, frame, *var) {
+                        Some(Binding { value: Value::Unset, .. }) => {
+                            return Err(ErrorValue::new("NameError", format!("Variable '{}' is used before it is assigned", self.name(frame, *var))).into())
+                        }
+                        Some(Binding { value, mutability }) => {
+                            let (value, mutability) = (value.clone(), *mutability);
+                            if mutability == Mutability::Const {
+                                let name = self.name(frame, *var);
+                                self.warn_deprecated(&name);
+                            }
+                            value
+                        }
+                        None => return Err(ErrorValue::new("NameError", format!("Var '{}' not found", self.name(frame, *var))).into()),
+                    };
+                    frame.stack.push(value);
+                }
+                Op::Define(var, mutability) => {
+                    let value = frame.pop();
+                    self.define(frame, *var, value, *mutability)?;
+                }
+                Op::DefineConst(var) => {
+                    if binding(&self.globals, frame, *var).is_some() {
+                        return Err(ErrorValue::new("Error", format!("Const '{}' redefinition", self.name(frame, *var))).into());
+                    }
+                    let value = frame.pop();
+                    self.store(frame, *var, value, Mutability::Const);
+                }
+                Op::Assign(var) => {
+                    let value = frame.pop();
+                    let name = self.name(frame, *var);
+                    let mutability = match binding(&self.globals, frame, *var) {
+                        None => return Err(ErrorValue::new("NameError", format!("Var '{}' not found", name)).into()),
+                        Some(Binding { mutability: Mutability::Const, .. }) => return Err(ErrorValue::new("Error", format!("Cannot assign to constant '{}'", name)).into()),
+                        Some(Binding { mutability: Mutability::Immutable, value }) if *value != Value::Unset => {
+                            return Err(ErrorValue::new("Error", format!("Cannot assign twice to immutable variable '{}' (declare it with 'let' to make it mutable)", name)).into())
+                        }
+                        Some(binding) => binding.mutability,
+                    };
+                    self.store(frame, *var, value, mutability);
+                }
+                Op::Unpack(target) => {
+                    let mut bindings = Vec::new();
+                    unpack(target, frame.pop(), &mut bindings)?;
+                    frame.stack.extend(bindings.into_iter().rev().map(|(_, value)| value));
+                }
+                Op::CheckType(type_anno) => self.check_type(frame.stack.last().unwrap(), type_anno)?,
+                Op::Binary(op) => {
+                    let right = frame.pop();
+                    let left = frame.pop();
+                    let result = self.binary(*op, left, right)?;
+                    frame.stack.push(result);
+                }
+                Op::Negate => {
+                    let value = frame.pop().as_number()?;
+                    frame.stack.push(Value::Number(-value));
+                }
+                Op::Not => {
+                    let value = frame.pop().as_bool()?;
+                    frame.stack.push(Value::Bool(!value));
+                }
+                Op::Jump(target) => frame.ip = *target,
+                Op::JumpIfFalse(target) => {
+                    if !frame.pop().as_bool()? {
+                        frame.ip = *target;
+                    }
+                }
+                Op::ShortCircuit(stop, target) => {
+                    let value = frame.pop().as_bool()?;
+                    if value == *stop {
+                        frame.stack.push(Value::Bool(value));
+                        frame.ip = *target;
+                    }
+                }
+                Op::Truth => {
+                    let value = frame.pop().as_bool()?;
+                    frame.stack.push(Value::Bool(value));
+                }
+                Op::Coalesce(target) => match frame.pop() {
+                    Value::None => {}
+                    Value::Some(value) => {
+                        frame.stack.push(*value);
+                        frame.ip = *target;
+                    }
+                    value => {
+                        frame.stack.push(value);
+                        frame.ip = *target;
+                    }
+                },
+                Op::Call(var, names) => {
+                    let (values, named) = frame.pop_args(names);
+                    let name = self.name(frame, *var);
+                    let func = binding(&self.globals, frame, *var).map(|binding| binding.value.clone());
+                    let result = match func {
+                        Some(func) => self.call_named(&name, &func, values, named)?,
+                        None if named.is_empty() => self.call_builtin(&name, values)?,
+                        None => return Err(ErrorValue::new("ArgumentError", format!("Builtin '{}' does not take named argument '{}'", name, named[0].0)).into()),
+                    };
+                    frame.stack.push(result);
+                }
+                Op::Method(method, names, guard) => {
+                    let (values, named) = frame.pop_args(names);
+                    let receiver = frame.pop();
+                    if let Some(name) = guard {
+                        if methods::is_mutating(&receiver, method) && self.user_method(receiver.type_name(), method).is_none() {
+                            return Err(ErrorValue::new(
+                                "Error",
+                                format!("Method '{}' changes '{}' and can only be called as a statement or as the value of a declaration or assignment", method, name),
+                            )
+                            .into());
+                        }
+                    }
+                    let result = self.call_method(receiver, method, values, named)?;
+                    frame.stack.push(result);
+                }
+                Op::MutMethod(method, names, var) => {
+                    let (values, named) = frame.pop_args(names);
+                    let mut receiver = frame.pop();
+                    if !methods::is_mutating(&receiver, method) || self.user_method(receiver.type_name(), method).is_some() {
+                        let result = self.call_method(receiver, method, values, named)?;
+                        frame.stack.push(result);
+                        continue;
+                    }
+                    let name = self.name(frame, *var);
+                    let mutability = binding(&self.globals, frame, *var).map(|binding| binding.mutability);
+                    if mutability == Some(Mutability::Mutable) {
+                        self.store(frame, *var, Value::Unset, Mutability::Mutable);
+                    }
+                    let result = call_native_method(&mut receiver, method, values, named);
+                    match mutability {
+                        None => return Err(ErrorValue::new("NameError", format!("Var '{}' not found", name)).into()),
+                        Some(Mutability::Const) => {
+                            result?;
+                            return Err(ErrorValue::new("Error", format!("Cannot call '{}' on constant '{}'", method, name)).into());
+                        }
+                        Some(Mutability::Immutable) => {
+                            result?;
+                            return Err(ErrorValue::new(
+                                "Error",
+                                format!("Cannot call '{}' on immutable variable '{}' (declare it with 'let' to make it mutable)", method, name),
+                            )
+                            .into());
+                        }
+                        Some(Mutability::Mutable) => self.store(frame, *var, receiver, Mutability::Mutable),
+                    }
+                    frame.stack.push(result?);
+                }
+                Op::Index => {
+                    let index = frame.pop();
+                    let target = frame.pop();
+                    let result = match self.call_operator("[]", &target, &index)? {
+                        Some(result) => result,
+                        None => methods::get_index(&target, &index)?,
+                    };
+                    frame.stack.push(result);
+                }
+                Op::Slice(start, end, step) => {
+                    let mut bounds = [None, None, None];
+                    for (bound, present) in bounds.iter_mut().zip([start, end, step]).rev() {
+                        if *present {
+                            *bound = Some(frame.pop().as_number()?);
+                        }
+                    }
+                    let target = frame.pop();
+                    frame.stack.push(methods::slice(&target, bounds[0], bounds[1], bounds[2])?);
+                }
+                Op::Field(field) => {
+                    let value = get_field(frame.pop(), field)?;
+                    frame.stack.push(value);
+                }
+                Op::SafeField(field) => {
+                    let value = match frame.pop() {
+                        Value::None => Value::None,
+                        Value::Some(value) => get_field(*value, field)?,
+                        value => get_field(value, field)?,
+                    };
+                    frame.stack.push(value);
+                }
+                Op::Propagate(target) => match frame.pop() {
+                    Value::Ok(value) | Value::Some(value) => {
+                        frame.stack.push(*value);
+                        frame.ip = *target;
+                    }
+                    failed @ (Value::Err(_) | Value::None) => {
+                        if self.calls.is_empty() {
+                            return Err(ErrorValue::new("Error", format!("'?' on {} outside of a function", failed)).into());
+                        }
+                        frame.stack.push(failed);
+                    }
+                    other => return Err(ErrorValue::new("TypeError", format!("Expected result or option for '?', got {}", other)).into()),
+                },
+                Op::List(count) => {
+                    let items = frame.stack.split_off(frame.stack.len() - count);
+                    frame.stack.push(Value::List(Rc::new(items)));
+                }
+                Op::Tuple(count) => {
+                    let items = frame.stack.split_off(frame.stack.len() - count);
+                    frame.stack.push(Value::Tuple(items));
+                }
+                Op::Map(count) => {
+                    let items = frame.stack.split_off(frame.stack.len() - count * 2);
+                    let mut map = Vec::new();
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        insert_entry(&mut map, key, value);
+                    }
+                    frame.stack.push(Value::Map(Rc::new(map)));
+                }
+                Op::Append(depth) => {
+                    let value = frame.pop();
+                    let at = frame.stack.len() - 1 - depth;
+                    if let Value::List(items) = &mut frame.stack[at] {
+                        Rc::make_mut(items).push(value);
+                    }
+                }
+                Op::Insert(depth) => {
+                    let value = frame.pop();
+                    let key = frame.pop();
+                    let at = frame.stack.len() - 1 - depth;
+                    if let Value::Map(map) = &mut frame.stack[at] {
+                        insert_entry(Rc::make_mut(map), key, value);
+                    }
+                }
+                Op::Iter => {
+                    let iter = to_iter(frame.pop())?;
+                    frame.stack.push(Value::Iterator(iter));
+                }
+                Op::ForNext(target) => {
+                    let Some(Value::Iterator(iter)) = frame.stack.last() else { unreachable!("iterator expected") };
+                    let iter = iter.clone();
+                    match self.next_value(&iter)? {
+                        Some(value) => frame.stack.push(value),
+                        None => frame.ip = *target,
+                    }
+                }
+                Op::MatchPattern(pattern, vars, target) => {
+                    let mut bindings = Vec::new();
+                    if !match_pattern(pattern, frame.stack.last().unwrap(), &mut bindings) {
+                        frame.ip = *target;
+                        continue;
+                    }
+                    frame.pop();
+                    for (var, (_, value)) in vars.iter().zip(bindings) {
+                        self.define(frame, *var, value, Mutability::Immutable)?;
+                    }
+                }
+                Op::NoMatch => return Err(ErrorValue::new("ValueError", format!("No match arm for value {}", frame.pop())).into()),
+                Op::Say => {
+                    let value = frame.pop();
+                    println!("{}", self.display(value)?);
+                }
+                Op::Return => return Ok(Flow::Return(frame.pop())),
+                Op::ReturnDefault => match &function.ret_type {
+                    Some(ret_type) if ret_type != "void" && !function.generator => return Err(ErrorValue::new("Error", "Missing return value").into()),
+                    _ => return Ok(Flow::Return(Value::None)),
+                },
+                Op::Yield => return Ok(Flow::Yield(frame.pop())),
+                Op::Escaped => {
+                    let name = self.calls.last().cloned().unwrap_or_default();
+                    return Err(ErrorValue::new("Error", format!("'break' or 'continue' escaped function '{}'", name)).into());
+                }
+                Op::Fail(error) => return Err(error.clone().into()),
+                Op::Abort(message) => return Err(Fault::Abort(message.clone())),
+                Op::Throw(line) => {
+                    let error = match frame.pop() {
+                        Value::Error(error) if error.location.is_some() => *error,
+                        Value::Error(error) => self.located(*error, *line),
+                        Value::String(message) => self.located(ErrorValue::new("Error", &*message), *line),
+                        other => self.located(ErrorValue::new("Error", other.to_string()), *line),
+                    };
+                    return Err(Fault::Error(error));
+                }
+                Op::ThrowCaught => {
+                    let error = self.handling.last().cloned().ok_or_else(|| ErrorValue::new("Error", "'throw' without a value outside of 'catch'"))?;
+                    return Err(Fault::Error(error));
+                }
+                Op::Rethrow => {
+                    let Value::Error(error) = frame.pop() else { unreachable!("error expected") };
+                    return Err(Fault::Error(*error));
+                }
+                Op::PushHandler(target) => {
+                    frame.handlers.push(Handler { target: *target, depth: frame.stack.len(), handling: self.handling.len() });
+                }
+                Op::PopHandler => {
+                    frame.handlers.pop();
+                }
+                Op::CatchKind(kind, target) => {
+                    if let Some(Value::Error(error)) = frame.stack.last() {
+                        if !error.matches(kind) {
+                            frame.ip = *target;
+                        }
+                    }
+                }
+                Op::Catch(var) => {
+                    let Value::Error(error) = frame.pop() else { unreachable!("error expected") };
+                    self.define(frame, *var, Value::Error(error.clone()), Mutability::Immutable)?;
+                    self.handling.push(*error);
+                }
+                Op::PopHandling => {
+                    self.handling.pop();
+                }
+                Op::JumpIfBound(slot, target) => {
+                    if frame.slots[*slot].is_some() {
+                        frame.ip = *target;
+                    }
+                }
+                Op::BindParam(slot) => {
+                    let value = frame.pop();
+                    let param = &function.params[*slot];
+                    if !param.variadic && self.is_trait(&param.type_anno) {
+                        self.check_type(&value, &param.type_anno)?;
+                    }
+                    frame.slots[*slot] = Some(Binding { value, mutability: Mutability::Immutable });
+                }
+                Op::MakeRecord(type_name) => {
+                    let mut values = Vec::new();
+                    for (field, slot) in function.params.iter().zip(frame.slots.iter_mut()) {
+                        let value = slot.take().map_or(Value::None, |binding| binding.value);
+                        self.check_type(&value, &field.type_anno)?;
+                        values.push((field.name.clone(), value));
+                    }
+                    frame.stack.push(Value::Record(type_name.clone(), values));
+                }
+                Op::DefineConstructor(constructor) => {
+                    self.constructors.insert(constructor.name.clone(), constructor.clone());
+                }
+                Op::DefineTrait(name, required, defaults) => {
+                    self.traits.insert(name.clone(), (required.clone(), defaults.iter().cloned().collect()));
+                }
+                Op::DefineImpl(type_name, var, trait_name, methods) => {
+                    let declared = matches!(binding(&self.globals, frame, *var).map(|b| &b.value), Some(Value::Type(..)));
+                    if !declared && !BUILTIN_TYPES.contains(&type_name.as_str()) {
+                        return Err(ErrorValue::new("NameError", format!("Type '{}' not found", type_name)).into());
+                    }
+                    let mut table: HashMap<String, Value> = methods.iter().cloned().collect();
+                    if let Some(trait_name) = trait_name {
+                        let (required, defaults) = self.traits.get(trait_name).cloned().ok_or_else(|| ErrorValue::new("NameError", format!("Trait '{}' not found", trait_name)))?;
+                        if let Some(missing) = required.iter().find(|method| !table.contains_key(*method)) {
+                            return Err(ErrorValue::new("TypeError", format!("type {} does not implement trait {} (missing method '{}')", type_name, trait_name, missing)).into());
+                        }
+                        for (method, func) in defaults {
+                            table.entry(method).or_insert(func);
+                        }
+                        self.impls.insert((type_name.clone(), trait_name.clone()));
+                    }
+                    self.methods.entry(type_name.clone()).or_default().extend(table);
+                }
+                Op::Deprecate(name, note) => {
+                    self.deprecated.insert(name.clone(), note.clone());
+                }
+                Op::Memoize(name) => {
+                    self.memos.insert(name.clone(), HashMap::new());
+                }
+                Op::Import(module) => {
+                    let module_path = format!("{}.velvet", module);
+                    if !Path::new(&module_path).exists() {
+                        return Err(ErrorValue::new("ImportError", format!("Module '{}' not found", module)).into());
+                    }
+                    let module_source = crate::utils::read_file(&module_path).map_err(|e| ErrorValue::new("Error", e))?;
+                    let ast = crate::parser::parse(&module_source).map_err(|e| ErrorValue::new("Error", e))?;
+                    let main = compile(&mut self.names, &ast);
+                    self.execute(main)?;
+                }
+                Op::EnterTest => frame.tests.push((frame.slots.clone(), self.globals.clone())),
+                Op::ExitTest => {
+                    let (slots, mut globals) = frame.tests.pop().unwrap();
+                    globals.resize(self.globals.len(), None);
+                    frame.slots = slots;
+                    self.globals = globals;
+                }
+            }
+        }
+    }
+
+    fn located(&self, mut error: ErrorValue, line: usize) -> ErrorValue {
+        error.location = Some(format!("line {}", line));
+        error.stack = self.call_stack();
+        error
+    }
+
+    fn binary(&mut self, op: BinOp, left: Value, right: Value) -> Result<Value, Fault> {
+        if let Some(result) = self.call_operator(op.symbol(), &left, &right)? {
+            return Ok(result);
+        }
+        Ok(match op {
+            BinOp::Add => match (&left, &right) {
+                (Value::String(_), _) | (_, Value::String(_)) => Value::String(format!("{}{}", self.display(left)?, self.display(right)?).into()),
+                _ => Value::Number(left.as_number()? + right.as_number()?),
+            },
+            BinOp::Sub => Value::Number(left.as_number()? - right.as_number()?),
+            BinOp::Mul => Value::Number(left.as_number()? * right.as_number()?),
+            BinOp::Div => {
+                let r = right.as_number()?;
+                if r == 0.0 {
+                    return Err(ErrorValue::new("ZeroDivisionError", "Division by zero").into());
+                }
+                Value::Number(left.as_number()? / r)
+            }
+            BinOp::Eq => Value::Bool(left == right),
+            BinOp::Ne => Value::Bool(left != right),
+            BinOp::Gt => Value::Bool(left.as_number()? > right.as_number()?),
+            BinOp::Ge => Value::Bool(left.as_number()? >= right.as_number()?),
+            BinOp::Lt => Value::Bool(left.as_number()? < right.as_number()?),
+            BinOp::Le => Value::Bool(left.as_number()? <= right.as_number()?),
+        })
+    }
+
+    fn call_method(&mut self, mut receiver: Value, method: &str, mut args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Fault> {
+        let (type_name, is_static) = match &receiver {
+            Value::Type(name, _) => (name.clone(), true),
+            value => (value.type_name().to_string(), false),
+        };
+        let Some(func) = self.user_method(&type_name, method) else {
+            if !is_static && methods::available(&type_name).contains(&method) {
+                if let Value::Iterator(_) = receiver {
+                    if let Some((arg_name, _)) = named.first() {
+                        return Err(ErrorValue::new("ArgumentError", format!("Method '{}' does not take named argument '{}'", method, arg_name)).into());
+                    }
+                    args.insert(0, receiver);
+                    return self.call_builtin(method, args);
+                }
+                return Ok(call_native_method(&mut receiver, method, args, named)?);
+            }
+            let mut available: Vec<String> = self.methods.get(&type_name).map(|m| m.keys().cloned().collect()).unwrap_or_default();
+            if !is_static {
+                available.extend(methods::available(&type_name).iter().map(|m| m.to_string()));
+            }
+            available.sort();
+            available.dedup();
+            let available = if available.is_empty() { "none".to_string() } else { available.join(", ") };
+            return Err(ErrorValue::new("NameError", format!("Type '{}' has no method '{}' (available: {})", type_name, method, available)).into());
+        };
+        if !is_static {
+            args.insert(0, receiver);
+        }
+        self.call_function(&format!("{}.{}", type_name, method), &func, args, named)
+    }
+
+    fn call_named(&mut self, name: &str, func: &Value, values: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Fault> {
+        self.warn_deprecated(name);
+        if !self.memos.contains_key(name) {
+            return self.call_function(name, func, values, named);
+        }
+        let key = format!("{:?}{:?}", values, named);
+        if let Some(value) = self.memos[name].get(&key).cloned() {
+            return Ok(value);
+        }
+        let value = self.call_function(name, func, values, named)?;
+        self.memos.get_mut(name).unwrap().insert(key, value.clone());
+        Ok(value)
+    }
+
+    fn resume(&mut self, coroutine: &mut Coroutine) -> Result<Option<Value>, Fault> {
+        let Some(frame) = coroutine.frame.as_mut() else { return Ok(None) };
+        self.calls.push(coroutine.name.clone());
+        let depth = self.handling.len();
+        let result = self.run(frame);
+        self.calls.pop();
+        self.handling.truncate(depth);
+        match result {
+            Ok(Flow::Yield(value)) => Ok(Some(value)),
+            Ok(Flow::Return(_)) => {
+                coroutine.frame = None;
+                Ok(None)
+            }
+            Err(fault) => {
+                coroutine.frame = None;
+                Err(fault)
+            }
+        }
+    }
+
+}
+
+impl Engine for Vm {
+    type Error = Fault;
+
+    fn rethrow(error: ErrorValue) -> Fault {
+        Fault::Error(error)
+    }
+
+    fn call_function(&mut self, name: &str, func: &Value, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, Fault> {
+        let (function, params, traced) = match func {
+            Value::Compiled(function) => (function.clone(), &function.params, true),
+            Value::Type(type_name, fields) => {
+                let constructor = self.constructors.get(type_name).cloned().ok_or_else(|| ErrorValue::new("NameError", format!("Type '{}' not found", type_name)))?;
+                (constructor, fields, false)
+            }
+            _ => return Err(ErrorValue::new("TypeError", format!("'{}' is not a function", name)).into()),
+        };
+        let mut frame = Frame::new(function.clone());
+        for (slot, value) in self.bind_args(name, params, args, named)?.into_iter().enumerate() {
+            if let Some(value) = value {
+                frame.slots[slot] = Some(Binding { value, mutability: Mutability::Immutable });
+            }
+        }
+        if function.generator {
+            let coroutine = Coroutine { name: name.to_string(), frame: Some(frame) };
+            return Ok(Value::Iterator(Iter::new(LazyIter::Coroutine(coroutine))));
+        }
+        if traced {
+            self.calls.push(name.to_string());
+        }
+        let depth = self.handling.len();
+        let result = self.run(&mut frame);
+        if traced {
+            self.calls.pop();
+        }
+        self.handling.truncate(depth);
+        match result? {
+            Flow::Return(value) | Flow::Yield(value) => Ok(value),
+        }
+    }
+
+    fn next_value(&mut self, iter: &Iter) -> Result<Option<Value>, Fault> {
+        iter.next_with(&mut |lazy| match lazy {
+            LazyIter::Coroutine(coroutine) => self.resume(coroutine),
+            _ => Err(ErrorValue::new("Error", "Iterator belongs to another engine").into()),
+        })
+    }
+
+    fn user_method(&self, type_name: &str, method: &str) -> Option<Value> {
+        self.methods.get(type_name).and_then(|methods| methods.get(method)).cloned()
+    }
+
+    fn is_trait(&self, name: &str) -> bool {
+        self.traits.contains_key(name)
+    }
+
+    fn implements(&self, type_name: &str, trait_name: &str) -> bool {
+        self.impls.contains(&(type_name.to_string(), trait_name.to_string()))
+    }
+}
+
+fn call_native_method(receiver: &mut Value, method: &str, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Value, ErrorValue> {
+    if let Some((arg_name, _)) = named.first() {
+        return Err(ErrorValue::new("ArgumentError", format!("Method '{}' does not take named argument '{}'", method, arg_name)));
+    }
+    methods::call(receiver, method, args)
+}
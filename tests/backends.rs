@@ -0,0 +1,180 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Builds every example and test with `vel build --target <target>` and
+/// checks that each command in `runs` prints what `vel start` prints. Files in
+/// `unsupported` must be rejected as unsupported by the backend; rejecting any
+/// other file, or accepting a listed one, fails the test.
+///
+/// What `vel start` prints must also match the file's snapshot in
+/// tests/snapshots, so a target whose programs cannot run here is still held
+/// to output that was checked when the snapshots were recorded. Run with
+/// `UPDATE_SNAPSHOTS=1` to record them again. `vel clean` must remove
+/// everything the build wrote.
+fn check(target: &str, runs: &[&[&str]], unsupported: &[&str]) {
+    let (dir, files) = common::workspace(&format!("build-{}", target), &["examples", "tests"]);
+    let mut failures = Vec::new();
+    let mut same = 0;
+    let mut skipped = Vec::new();
+    for file in &files {
+        fs::copy(dir.join(file), dir.join("main.velvet")).unwrap();
+        common::vel(&dir, &["clean"]);
+        let expected = common::stdout(&common::vel(&dir, &["start", file]));
+        let snapshot = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots").join(format!("{}.out", file));
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(&snapshot, &expected).unwrap();
+        }
+        match fs::read_to_string(&snapshot) {
+            Ok(recorded) if recorded == expected => {}
+            Ok(recorded) => failures.push(common::difference(&format!("{} (snapshot)", file), &recorded, &expected)),
+            Err(_) => failures.push(format!("{} has no snapshot; record one with UPDATE_SNAPSHOTS=1", file)),
+        }
+        let build = common::vel(&dir, &["build", "--target", target]);
+        let listed = unsupported.contains(&file.as_str());
+        if !build.status.success() {
+            let log = common::transcript(&build);
+            if listed && log.contains("not supported by the") {
+                skipped.push(file.as_str());
+            } else {
+                failures.push(format!("{} did not build:\n{}", file, log));
+            }
+            continue;
+        }
+        if listed {
+            failures.push(format!("{} now builds; remove it from the unsupported list", file));
+        }
+        let mut differs = false;
+        for run in runs {
+            let actual = common::stdout(&Command::new(run[0]).args(&run[1..]).current_dir(&dir).output().unwrap());
+            if actual != expected {
+                failures.push(common::difference(&format!("{} ({})", file, run.join(" ")), &expected, &actual));
+                differs = true;
+            }
+        }
+        if !differs && !runs.is_empty() {
+            same += 1;
+        }
+        common::vel(&dir, &["clean"]);
+        let left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("velvet_"))
+            .collect();
+        if !left.is_empty() {
+            failures.push(format!("{} left {:?} behind after vel clean", file, left));
+        }
+    }
+    eprintln!("{}: {} same, {} unsupported {:?}", target, same, skipped.len(), skipped);
+    fs::remove_dir_all(dir).unwrap();
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
+
+fn available(program: &str) -> bool {
+    Command::new(program).arg("--version").output().is_ok()
+}
+
+#[test]
+fn rust_backend_matches_the_interpreter() {
+    check("rust", &[&["./velvet_out"]], &[]);
+}
+
+#[test]
+fn c_backend_matches_the_interpreter() {
+    if !available("cc") {
+        eprintln!("cc not found; skipping the c target");
+        return;
+    }
+    let unsupported = [
+        "test_attributes.velvet",
+        "test_builtin_methods.velvet",
+        "test_comprehensions.velvet",
+        "test_destructuring.velvet",
+        "test_generators.velvet",
+        "test_methods.velvet",
+        "test_operators.velvet",
+        "test_optimizer.velvet",
+        "test_params.velvet",
+        "test_value_semantics.velvet",
+    ];
+    check("c", &[&["./velvet_out"]], &unsupported);
+}
+
+/// Without node the generated modules cannot run, so the test falls back to
+/// building every file and checking the interpreter against its snapshots.
+#[test]
+fn js_backend_matches_the_interpreter() {
+    let unsupported = ["test_attributes.velvet", "test_methods.velvet", "test_operators.velvet"];
+    if !available("node") {
+        eprintln!("node not found; checking the js target against snapshots only");
+        check("js", &[], &unsupported);
+        return;
+    }
+    check("js", &[&["node", "velvet_out.mjs"]], &unsupported);
+}
+
+/// Once any function is marked `#[export]`, the module exports only those.
+#[test]
+fn js_module_exports_marked_functions() {
+    if !available("node") {
+        eprintln!("node not found; skipping the js import check");
+        return;
+    }
+    let dir = common::project("js-exports");
+    fs::write(dir.join("main.velvet"), "#[export]\n#[inline]\nfun add(a, b):\n    return a + b\n\nfun helper(x):\n    return x\n").unwrap();
+    let build = common::vel(&dir, &["build", "--target", "js"]);
+    assert!(build.status.success(), "{}", common::transcript(&build));
+    let script = "import('./velvet_out.mjs').then((m) => console.log(Object.keys(m).join(','), m.add(2, 3)))";
+    let run = Command::new("node").args(["-e", script]).current_dir(&dir).output().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+    assert_eq!(common::stdout(&run), "add 5\n", "{}", common::transcript(&run));
+}
+
+/// Like the js target, without python3 this only checks the snapshots.
+#[test]
+fn python_backend_matches_the_interpreter() {
+    let unsupported = ["test_attributes.velvet", "test_methods.velvet", "test_operators.velvet"];
+    if !available("python3") {
+        eprintln!("python3 not found; checking the python target against snapshots only");
+        check("python", &[], &unsupported);
+        return;
+    }
+    check("python", &[&["python3", "velvet_out.py"]], &unsupported);
+}
+
+/// Exported functions keep their Velvet names, even ones Python code would
+/// otherwise reach for, and can be called from Python with plain values.
+#[test]
+fn python_module_exports_its_functions() {
+    if !available("python3") {
+        eprintln!("python3 not found; skipping the python import check");
+        return;
+    }
+    let dir = common::project("python-exports");
+    fs::write(dir.join("main.velvet"), "fun add(a, b):\n    return a + b\n\nfun len(items, extra = 0):\n    return items.len() + extra\n").unwrap();
+    let build = common::vel(&dir, &["build", "--target", "python"]);
+    assert!(build.status.success(), "{}", common::transcript(&build));
+    let script = "from velvet_out import add, len\nprint(add(2, 3), add('a', 1), len([1, 2], extra=1))";
+    let run = Command::new("python3").args(["-c", script]).current_dir(&dir).output().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+    assert_eq!(common::stdout(&run), "5.0 a1 3.0\n", "{}", common::transcript(&run));
+}
+
+/// Like the js target, `#[export]` narrows `__all__` to the marked functions.
+#[test]
+fn python_module_exports_marked_functions() {
+    if !available("python3") {
+        eprintln!("python3 not found; skipping the python import check");
+        return;
+    }
+    let dir = common::project("python-marked-exports");
+    fs::write(dir.join("main.velvet"), "#[export]\n#[inline]\nfun add(a, b):\n    return a + b\n\nfun helper(x):\n    return x\n").unwrap();
+    let build = common::vel(&dir, &["build", "--target", "python"]);
+    assert!(build.status.success(), "{}", common::transcript(&build));
+    let script = "import velvet_out\nprint(velvet_out.__all__, velvet_out.add(2, 3))";
+    let run = Command::new("python3").args(["-c", script]).current_dir(&dir).output().unwrap();
+    fs::remove_dir_all(dir).unwrap();
+    assert_eq!(common::stdout(&run), "['add'] 5.0\n", "{}", common::transcript(&run));
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use std::fs;
+
+const DEPRECATED: &str = "#[deprecated(\"use add\")]
+fun plus(a: f64, b: f64) -> f64:
+    return a + b
+
+fun twice(x: f64) -> f64:
+    return plus(x, x)
+
+fun shadowed(plus: f64) -> f64:
+    return plus
+
+say plus(1, 2)
+say plus(3, 4) + twice(1) + shadowed(1)
+";
+
+#[test]
+fn check_warns_at_every_use_of_a_deprecated_name() {
+    let dir = common::project("check-deprecated");
+    fs::write(dir.join("main.velvet"), DEPRECATED).unwrap();
+    let output = common::vel(&dir, &["check"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    let warnings: Vec<String> = String::from_utf8_lossy(&output.stderr).lines().filter(|line| line.contains("deprecated")).map(str::to_string).collect();
+    assert_eq!(warnings.len(), 3, "{:?}", warnings);
+    assert!(warnings[0].ends_with("in fun 'twice': 'plus' is deprecated: use add"), "{:?}", warnings);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+const CAPTURE: &str = "fun outer(n: f64):
+    fun inner(x: f64):
+        return x + n
+    fun passed(x: f64, n: f64):
+        return x + n
+    return inner(1) + passed(1, n)
+
+say outer(5)
+";
+
+#[test]
+fn check_rejects_nested_functions_that_read_an_outer_param() {
+    let dir = common::project("check-capture");
+    fs::write(dir.join("main.velvet"), CAPTURE).unwrap();
+    let output = common::vel(&dir, &["check"]);
+    assert!(!output.status.success());
+    let errors: Vec<String> = String::from_utf8_lossy(&output.stderr).lines().filter(|line| line.contains("enclosing")).map(str::to_string).collect();
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert!(errors[0].contains("in fun 'inner': 'n' belongs to the enclosing function 'outer'"), "{:?}", errors);
+    fs::remove_dir_all(dir).unwrap();
+}
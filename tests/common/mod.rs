@@ -0,0 +1,64 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+pub fn project(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("velvet-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("velvet.json"), r#"{"name": "integration", "version": "0.1.0"}"#).unwrap();
+    dir
+}
+
+/// A project holding every `.velvet` file from the given repo directories,
+/// with `main.velvet` set to hello.velvet so `vel start` accepts it.
+/// Returns the project and the copied file names.
+pub fn workspace(name: &str, dirs: &[&str]) -> (PathBuf, Vec<String>) {
+    let dir = project(name);
+    let mut files = Vec::new();
+    for source in dirs {
+        for entry in fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join(source)).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "velvet") {
+                let file = path.file_name().unwrap().to_string_lossy().into_owned();
+                fs::copy(&path, dir.join(&file)).unwrap();
+                files.push(file);
+            }
+        }
+    }
+    files.sort();
+    fs::copy(dir.join("hello.velvet"), dir.join("main.velvet")).unwrap();
+    (dir, files)
+}
+
+pub fn vel(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_velvet")).args(args).current_dir(dir).env("RUST_BACKTRACE", "0").output().unwrap()
+}
+
+/// Stdout, with a `<failed>` line appended when the program exited unsuccessfully.
+pub fn stdout(output: &Output) -> String {
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() {
+        text.push_str("<failed>\n");
+    }
+    text
+}
+
+/// Stdout followed by stderr, without the thread ids that differ between panics.
+pub fn transcript(output: &Output) -> String {
+    let text = String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr);
+    text.lines()
+        .map(|line| match (line.find(" ("), line.find(") panicked")) {
+            (Some(open), Some(close)) if open < close => format!("{}{}", &line[..open], &line[close + 1..]),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A readable report for a file whose output differs between two runs.
+pub fn difference(label: &str, expected: &str, actual: &str) -> String {
+    format!("{}\n--- expected\n{}\n+++ actual\n{}", label, expected.trim_end(), actual.trim_end())
+}
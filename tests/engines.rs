@@ -0,0 +1,39 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+/// Every directory under tests/fixtures, relative to the crate root.
+fn fixture_dirs(dir: &str, dirs: &mut Vec<String>) {
+    for entry in fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join(dir)).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            let sub = format!("{}/{}", dir, path.file_name().unwrap().to_string_lossy());
+            fixture_dirs(&sub, dirs);
+            dirs.push(sub);
+        }
+    }
+}
+
+/// Runs every example, test and fixture under both engines; stdout, stderr and
+/// the exit code must agree.
+#[test]
+fn vm_matches_the_tree_walker() {
+    let mut dirs = vec!["examples".to_string(), "tests".to_string()];
+    fixture_dirs("tests/fixtures", &mut dirs);
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    let (dir, files) = common::workspace("engines", &dirs);
+    let mut differences = Vec::new();
+    for file in &files {
+        let tree = common::vel(&dir, &["start", file, "--engine=tree"]);
+        let vm = common::vel(&dir, &["start", file, "--engine=vm"]);
+        let (expected, actual) = (common::transcript(&tree), common::transcript(&vm));
+        if expected != actual || tree.stdout != vm.stdout {
+            differences.push(common::difference(file, &expected, &actual));
+        } else if tree.status.code() != vm.status.code() {
+            differences.push(format!("{} exits with {:?} under the tree walker but {:?} under the vm", file, tree.status.code(), vm.status.code()));
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+    assert!(differences.is_empty(), "{}", differences.join("\n\n"));
+}
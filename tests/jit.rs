@@ -0,0 +1,20 @@
+#![cfg(feature = "jit")]
+
+mod common;
+
+use std::fs;
+
+#[test]
+fn jit_programs_print_the_same_output() {
+    let (dir, files) = common::workspace("jit", &["examples", "tests", "benches"]);
+    let mut differences = Vec::new();
+    for file in &files {
+        let interpreted = common::transcript(&common::vel(&dir, &["start", file]));
+        let compiled = common::transcript(&common::vel(&dir, &["start", file, "--jit"]));
+        if interpreted != compiled {
+            differences.push(common::difference(file, &interpreted, &compiled));
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+    assert!(differences.is_empty(), "{}", differences.join("\n\n"));
+}
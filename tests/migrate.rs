@@ -0,0 +1,29 @@
+mod common;
+
+use std::fs;
+
+const OLD: &str = include_str!("fixtures/migrate/old.velvet");
+const NEW: &str = include_str!("fixtures/migrate/new.velvet");
+
+#[test]
+fn migrate_rewrites_old_syntax_and_keeps_comments_and_strings() {
+    let dir = common::project("migrate");
+    fs::write(dir.join("main.velvet"), OLD).unwrap();
+    let output = common::vel(&dir, &["migrate"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(dir.join("main.velvet")).unwrap(), NEW);
+    let output = common::vel(&dir, &["check"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn migrate_leaves_migrated_files_unchanged() {
+    let dir = common::project("migrate-again");
+    fs::write(dir.join("main.velvet"), NEW).unwrap();
+    let output = common::vel(&dir, &["migrate"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Migrated 0 file(s)."));
+    assert_eq!(fs::read_to_string(dir.join("main.velvet")).unwrap(), NEW);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use std::fs;
+
+#[test]
+fn optimized_programs_print_the_same_output() {
+    let (dir, files) = common::workspace("optimizer", &["examples", "tests"]);
+    let mut differences = Vec::new();
+    for file in &files {
+        for engine in ["--engine=tree", "--engine=vm"] {
+            let plain = common::transcript(&common::vel(&dir, &["start", file, engine]));
+            let optimized = common::transcript(&common::vel(&dir, &["start", file, engine, "-O"]));
+            if plain != optimized {
+                differences.push(common::difference(&format!("{} ({})", file, engine), &plain, &optimized));
+            }
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+    assert!(differences.is_empty(), "{}", differences.join("\n\n"));
+}
+
+#[test]
+fn inline_functions_leave_no_frame_behind() {
+    let dir = common::project("optimizer-inline");
+    fs::write(dir.join("main.velvet"), "#[inline]\nfun ratio(a: f64, b: f64) -> f64:\n    return a / b\n\nsay ratio(1, 0)\n").unwrap();
+    for engine in ["--engine=tree", "--engine=vm"] {
+        let plain = common::transcript(&common::vel(&dir, &["start", engine]));
+        let optimized = common::transcript(&common::vel(&dir, &["start", engine, "-O"]));
+        assert!(plain.contains("Division by zero\\n    at ratio"), "{}", plain);
+        assert!(optimized.contains("Division by zero") && !optimized.contains("at ratio"), "{}", optimized);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}